@@ -0,0 +1,87 @@
+/*
+
+Throughput of reading the rows of a text table, run with cargo bench
+
+A table of BENCH_SCAN_MB megabytes (2048 by default) is written next to the build, its
+header by CREATE TABLE and its rows straight into the file, then every query is run a
+few times by the server on its own. The filters match no row, so the time is spent
+reading and decoding rows instead of printing them.
+
+*/
+
+use std::fs::{self, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 3;
+
+const QUERIES: &[&str] = &[
+    "SELECT * FROM big WHERE id = -1;",
+    "SELECT name FROM big WHERE ok = FALSE AND score < 0;",
+    "SELECT id FROM big WHERE born > DATE '2030-01-01';",
+];
+
+fn run(dir: &Path, input: &str) -> Duration {
+    let start = Instant::now();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_actually_mysql"))
+        .arg("--compact-interval=0")
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("could not start the server");
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let out = child.wait_with_output().unwrap();
+    let out = String::from_utf8_lossy(&out.stdout);
+    assert!(!out.contains("ERROR"), "{input} failed: {out}");
+    start.elapsed()
+}
+
+// rows of every type there is a cell of until the file has len bytes, returns how many
+fn fill(path: &Path, len: u64) -> u64 {
+    let file = OpenOptions::new().append(true).open(path).unwrap();
+    let mut size = file.metadata().unwrap().len();
+    let mut out = BufWriter::new(file);
+    let mut n = 0;
+    while size < len {
+        let row = format!(
+            "RStart\n\"{n}\"\n\"name number {n:08} of the table\"\n\"{}.{:02}\"\n\"2001-02-{:02}\"\n\"{}\"\nREnd\n",
+            n % 100_000,
+            n % 100,
+            n % 28 + 1,
+            if n % 2 == 0 { "FALSE" } else { "TRUE" }
+        );
+        out.write_all(row.as_bytes()).unwrap();
+        size += row.len() as u64;
+        n += 1;
+    }
+    out.flush().unwrap();
+    n
+}
+
+fn main() {
+    let mb = std::env::var("BENCH_SCAN_MB").ok().and_then(|v| v.parse().ok()).unwrap_or(2048u64);
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("bench_scan");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    run(&dir, "CREATE TABLE big (id INT, name VARCHAR(40), score DECIMAL(10,2), born DATE, ok BOOLEAN);");
+    let rows = fill(&dir.join("big"), mb << 20);
+    let bytes = fs::metadata(dir.join("big")).unwrap().len();
+    println!("table of {rows} rows, {:.1} MB", bytes as f64 / 1e6);
+
+    for q in QUERIES {
+        let best = (0..RUNS).map(|_| run(&dir, q)).min().unwrap();
+        let secs = best.as_secs_f64();
+        println!(
+            "{q:<60} {:>8.3} s {:>8.1} MB/s {:>12.0} rows/s",
+            secs,
+            bytes as f64 / 1e6 / secs,
+            rows as f64 / secs
+        );
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
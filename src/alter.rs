@@ -0,0 +1,693 @@
+/*
+
+ALTER TABLE on a TableManager
+
+The columns of a table are in the header of its file, so changing them means writing
+the table again. The actions of a statement are applied to the schema in order, each
+becoming a step on the rows, and then the table is flushed from a Rewrite: the old
+table seen with the new schema, its rows turned into rows of the new one as they are
+read. A text table is read through a second handle on its file while the first one
+writes the new file next to it, so only a row at a time is in memory, and the new file
+replaces the old one once it is complete.
+
+RENAME TO moves the table file with its counter, .refs and index files, and the
+foreign keys and .refs files of the tables around it are changed to the new name.
+
+Adding a column with a constant DEFAULT and no key, dropping a column no key has
+and renaming columns don't have to write a text table again (ALGORITHM=INSTANT):
+the new schema is written in place of the old header, which keeps the schemas the
+rows there are were written under (see VersionStart in db.rs), and those rows are
+read as rows of the new schema. Any other change, or a header that no longer fits
+in front of the rows, writes the table again (ALGORITHM=COPY), as does OPTIMIZE
+TABLE, and the tables left with rows of older schemas are written again in the
+background when nobody holds a lock on them. They are locked against changes but not
+reads while the new file and its indexes are written next to theirs, the manager only
+has to be locked to move them in place.
+
+*/
+
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::buffer::{self, SharedPool};
+use crate::constraints::{self, KeyDef, KeyKind};
+use crate::db::OldSchema;
+use crate::index::{self, IndexFile, KeyRange};
+use crate::locks::LockMode;
+use crate::paged::{self, PageTable};
+use crate::query::{self, AlterAction, Expr};
+use crate::wal;
+use crate::{
+    check_rows, print_streamed, write_rows, scan_auto_increment, ColumnEntry, FileTable, NumType, RowId, RowIter, Table, TableCell, TableEntry, TableLike,
+    TableLikeError, TableManager,
+};
+
+// whatever a compaction left next to the files of a table, there is nothing to tell about
+// one that can't be removed, it is written over the next time
+fn remove_temps(name: &str, files: &[String]) {
+    for path in files.iter().map(String::as_str).chain([name]) {
+        let _ = std::fs::remove_file(wal::temp_path(path));
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Algorithm {
+    // in place when the actions allow it
+    #[default]
+    Default,
+    Instant,
+    Copy,
+}
+
+// what an action does to every row
+enum Step {
+    // a new last column, None when there is no value for the rows there are
+    Add { col: String, value: Option<TableCell> },
+    Drop(usize),
+    // the values of a column converted to its new definition
+    Convert(usize, ColumnEntry),
+}
+
+// a table seen with a new schema, read only
+struct Rewrite<'a> {
+    src: &'a dyn TableLike,
+    cols: Vec<ColumnEntry>,
+    keys: Vec<KeyDef>,
+    steps: &'a [Step],
+    strict: bool,
+    // the slot of a new AUTO_INCREMENT column and the value the first row without one gets
+    auto: Option<(usize, NumType)>,
+}
+
+impl Rewrite<'_> {
+    fn convert(&self, mut row: TableEntry, next: &mut NumType) -> Result<TableEntry, TableLikeError> {
+        for s in self.steps {
+            match s {
+                Step::Add { value: Some(v), .. } => row.col_data.push(v.clone()),
+                Step::Add { col, value: None } => {
+                    return Err(TableLikeError::new(&format!("Field '{col}' doesn't have a default value")))
+                }
+                Step::Drop(i) => {
+                    row.col_data.remove(*i);
+                }
+                Step::Convert(i, col) => row.col_data[*i] = col.coerce(&row.col_data[*i], self.strict)?,
+            }
+        }
+        if let Some((i, _)) = self.auto.filter(|(i, _)| row.col_data[*i].is_null()) {
+            row.col_data[i] = TableCell::Num(Some(*next));
+            *next += 1;
+        }
+        Ok(row)
+    }
+}
+
+fn read_only() -> TableLikeError {
+    TableLikeError::new("Table is being altered")
+}
+
+impl Display for Rewrite<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        print_streamed(self, f).map_err(|_| std::fmt::Error)
+    }
+}
+
+impl TableLike for Rewrite<'_> {
+    fn get_name(&self) -> Option<&str> {
+        self.src.get_name()
+    }
+
+    fn get_rows(&self) -> RowIter<'_> {
+        // every pass numbers the rows the same way
+        let mut next = self.auto.map_or(1, |(_, n)| n);
+        Box::new(self.src.get_rows().map(move |r| self.convert(r?, &mut next)))
+    }
+
+    fn get_row(&self, rid: RowId) -> Result<TableEntry, TableLikeError> {
+        self.convert(self.src.get_row(rid)?, &mut self.auto.map_or(1, |(_, n)| n))
+    }
+
+    fn get_cols(&self) -> Result<Vec<ColumnEntry>, TableLikeError> {
+        Ok(self.cols.clone())
+    }
+
+    fn get_keys(&self) -> Result<Vec<KeyDef>, TableLikeError> {
+        Ok(self.keys.clone())
+    }
+
+    fn add_rows(&mut self, _: &mut dyn Iterator<Item = TableEntry>) -> Result<(), TableLikeError> {
+        Err(read_only())
+    }
+
+    fn flush(&mut self, _: &dyn TableLike) -> Result<(), TableLikeError> {
+        Err(read_only())
+    }
+
+    fn move_to_memory(&mut self) -> Result<Table, TableLikeError> {
+        let mut t = Table::default();
+        t.flush(self)?;
+        Ok(t)
+    }
+
+    fn move_to_file(&mut self, _: &str, _: &SharedPool) -> Result<FileTable, TableLikeError> {
+        Err(read_only())
+    }
+
+    fn update_rows(&mut self, _: &mut dyn FnMut(&mut TableEntry) -> Result<bool, TableLikeError>) -> Result<usize, TableLikeError> {
+        Err(read_only())
+    }
+
+    fn delete_rows(&mut self, _: &mut dyn FnMut(&TableEntry) -> Result<bool, TableLikeError>) -> Result<usize, TableLikeError> {
+        Err(read_only())
+    }
+
+    fn next_auto_increment(&self) -> Result<NumType, TableLikeError> {
+        scan_auto_increment(self)
+    }
+
+    fn set_auto_increment(&mut self, _: NumType) -> Result<(), TableLikeError> {
+        Err(read_only())
+    }
+
+    fn contains_key(&self, _: &str, _: &[TableCell]) -> Result<bool, TableLikeError> {
+        Err(read_only())
+    }
+
+    fn index_rows(&self, _: &str, _: &KeyRange) -> Option<RowIter<'_>> {
+        None
+    }
+
+    fn close(&mut self) -> Result<(), TableLikeError> {
+        Ok(())
+    }
+}
+
+// what MySQL gives the rows there are for a new NOT NULL column without a DEFAULT, the
+// types without a zero value here have none
+fn implicit_default(col: &ColumnEntry) -> Option<TableCell> {
+    match col.col_type {
+        TableCell::Num(_) | TableCell::Decimal(..) | TableCell::Bool(_) => col.coerce(&TableCell::Num(Some(0)), false).ok(),
+        TableCell::Str(_) | TableCell::Blob(_) => col.coerce(&TableCell::Str(Some(String::new())), false).ok(),
+        _ => None,
+    }
+}
+
+// the value the rows there are get for a new column, also when they are read under an older
+// schema without it
+pub fn fill_value(col: &ColumnEntry) -> Option<TableCell> {
+    col.default_value(true).ok().or_else(|| implicit_default(col))
+}
+
+// whether all rows get the same value for a column added in place, a DEFAULT like NOW()
+// would be another one every time they are read
+fn constant_default(col: &ColumnEntry) -> bool {
+    match col.constraints.default.as_deref().map(query::parse_expr_text) {
+        None | Some(Ok(Expr::Literal(_))) => true,
+        Some(Ok(Expr::Neg(e))) => matches!(*e, Expr::Literal(_)),
+        _ => false,
+    }
+}
+
+fn slot(cols: &[ColumnEntry], name: &str) -> Option<usize> {
+    cols.iter().position(|c| c.col_name == name)
+}
+
+fn duplicate(name: &str) -> TableLikeError {
+    TableLikeError::new(&format!("Duplicate column name '{name}'"))
+}
+
+fn check_uses(check: &str, col: &str) -> TableLikeError {
+    TableLikeError::new(&format!("Check constraint '{check}' uses column '{col}', hence column cannot be dropped or renamed."))
+}
+
+fn incompatible(col: &str, parent: &str, key: &str) -> TableLikeError {
+    TableLikeError::new(&format!(
+        "Referencing column '{col}' and referenced column '{parent}' in foreign key constraint '{key}' are incompatible."
+    ))
+}
+
+impl TableManager {
+    pub fn alter_table(
+        &mut self,
+        strict: bool,
+        name: &str,
+        actions: Vec<AlterAction>,
+        algorithm: Algorithm,
+    ) -> Result<(), TableLikeError> {
+        // foreign keys of other tables on this one, they follow renamed columns and the table
+        let mut referencing = self.referencing(name)?;
+        let before = referencing.iter().map(|(_, k)| k.clone()).collect::<Vec<_>>();
+        let tb = self.open(name)?;
+        let mut cols = tb.get_cols()?;
+        let mut keys = tb.get_keys()?;
+        let unknown = |c: &str| TableLikeError::new(&format!("Unknown column '{c}' in '{name}'"));
+        let mut steps = Vec::new();
+        // keys whose columns were all dropped, with their index files
+        let mut dropped = Vec::new();
+        let mut rewrite = false;
+        // whether the rows there are can stay as they are, with the columns renamed in the
+        // older schemas, a dropped one to ""
+        let mut instant = true;
+        let mut renames = Vec::new();
+        let mut fill = false;
+        let mut to = None;
+        for a in actions {
+            rewrite |= !matches!(a, AlterAction::RenameTo { .. });
+            match a {
+                AlterAction::AddColumn { col, keys: k } => {
+                    if slot(&cols, &col.col_name).is_some() {
+                        return Err(duplicate(&col.col_name));
+                    }
+                    fill |= col.constraints.auto_increment;
+                    let value = fill_value(&col);
+                    instant &= k.is_empty()
+                        && value.is_some()
+                        && constant_default(&col)
+                        && !col.constraints.auto_increment
+                        && col.constraints.checks.is_empty();
+                    steps.push(Step::Add { col: col.col_name.clone(), value });
+                    cols.push(col);
+                    keys.extend(k);
+                }
+                AlterAction::DropColumn { name: c } => {
+                    let i = slot(&cols, &c)
+                        .ok_or_else(|| TableLikeError::new(&format!("Can't DROP '{c}'; check that column/key exists")))?;
+                    if cols.len() == 1 {
+                        return Err(TableLikeError::new("You can't delete all columns with ALTER TABLE; use DROP TABLE instead"));
+                    }
+                    if let Some(k) = keys.iter().find(|k| matches!(k.kind, KeyKind::Foreign(_)) && k.cols.contains(&c)) {
+                        return Err(TableLikeError::new(&format!(
+                            "Cannot drop column '{c}': needed in a foreign key constraint '{}'",
+                            k.name
+                        )));
+                    }
+                    if let Some(k) = keys.iter().find(|k| matches!(k.kind, KeyKind::Check(_)) && k.cols.contains(&c)) {
+                        return Err(check_uses(&k.name, &c));
+                    }
+                    if let Some((child, k)) = referencing.iter().find(|(_, k)| matches!(&k.kind, KeyKind::Foreign(r) if r.cols.contains(&c))) {
+                        return Err(TableLikeError::new(&format!(
+                            "Cannot drop column '{c}': needed in a foreign key constraint '{}' of table '{child}'",
+                            k.name
+                        )));
+                    }
+                    instant &= !keys.iter().any(|k| k.cols.contains(&c));
+                    cols.remove(i);
+                    steps.push(Step::Drop(i));
+                    renames.push((c.clone(), String::new()));
+                    // like MySQL the column leaves the keys it is in and a key left without any goes
+                    for k in &mut keys {
+                        k.cols.retain(|n| *n != c);
+                    }
+                    // a table CHECK that reads no column at all stays
+                    let gone = |k: &KeyDef| k.cols.is_empty() && !matches!(k.kind, KeyKind::Check(_));
+                    dropped.extend(keys.iter().filter(|k| gone(k)).map(|k| k.name.clone()));
+                    keys.retain(|k| !gone(k));
+                }
+                AlterAction::RenameColumn { from, to: new } => {
+                    let i = slot(&cols, &from).ok_or_else(|| unknown(&from))?;
+                    if new != from && slot(&cols, &new).is_some() {
+                        return Err(duplicate(&new));
+                    }
+                    // checks are kept as SQL text, which would still name the old column
+                    if let Some(chk) = cols[i].constraints.checks.first() {
+                        return Err(check_uses(chk.name.as_deref().unwrap_or_default(), &from));
+                    }
+                    if let Some(k) = keys.iter().find(|k| matches!(k.kind, KeyKind::Check(_)) && k.cols.contains(&from)) {
+                        return Err(check_uses(&k.name, &from));
+                    }
+                    cols[i].col_name = new.clone();
+                    renames.push((from.clone(), new.clone()));
+                    let refs = referencing.iter_mut().filter_map(|(_, k)| match &mut k.kind {
+                        KeyKind::Foreign(r) => Some(&mut r.cols),
+                        _ => None,
+                    });
+                    for n in keys.iter_mut().map(|k| &mut k.cols).chain(refs).flatten().filter(|n| **n == from) {
+                        *n = new.clone();
+                    }
+                }
+                AlterAction::ModifyColumn { col, keys: k } => {
+                    let i = slot(&cols, &col.col_name).ok_or_else(|| unknown(&col.col_name))?;
+                    if col.col_type != cols[i].col_type {
+                        for k in &keys {
+                            if let (KeyKind::Foreign(r), Some(pos)) = (&k.kind, k.cols.iter().position(|n| *n == col.col_name)) {
+                                return Err(incompatible(&col.col_name, &r.cols[pos], &k.name));
+                            }
+                        }
+                        for (_, k) in &referencing {
+                            if let KeyKind::Foreign(r) = &k.kind {
+                                if let Some(pos) = r.cols.iter().position(|n| *n == col.col_name) {
+                                    return Err(incompatible(&k.cols[pos], &col.col_name, &k.name));
+                                }
+                            }
+                        }
+                    }
+                    instant = false;
+                    fill |= col.constraints.auto_increment && !cols[i].constraints.auto_increment;
+                    steps.push(Step::Convert(i, col.clone()));
+                    cols[i] = col;
+                    keys.extend(k);
+                }
+                AlterAction::RenameTo { name: n } => to = Some(n),
+            }
+        }
+        constraints::prepare_keys(name, &mut cols, &mut keys)?;
+        constraints::prepare_schema(name, &mut cols, &mut keys)?;
+        let instant = rewrite
+            && algorithm != Algorithm::Copy
+            && instant
+            && !paged::is_paged(name)?
+            && self.alter_in_place(name, &cols, &keys, &renames)?;
+        if rewrite && !instant {
+            if algorithm == Algorithm::Instant {
+                return Err(TableLikeError::new(
+                    "ALGORITHM=INSTANT is not supported for this operation. Try ALGORITHM=COPY.",
+                ));
+            }
+            let auto = cols.iter().position(|c| c.constraints.auto_increment).filter(|_| fill);
+            self.rewrite(name, cols, keys.clone(), &steps, strict, auto)?;
+            for k in dropped {
+                match std::fs::remove_file(index::index_path(name, &k)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+        let to = to.filter(|t| t != name);
+        if let Some(to) = &to {
+            self.move_table(name, to, &keys)?;
+        }
+        for ((child, mut k), old) in referencing.into_iter().zip(before) {
+            if let (KeyKind::Foreign(r), Some(to)) = (&mut k.kind, &to) {
+                r.table = to.clone();
+            }
+            if k != old {
+                let tb = self.open(&child)?;
+                let cols = tb.get_cols()?;
+                let keys = tb.get_keys()?.into_iter().map(|o| if o.name == k.name { k.clone() } else { o }).collect();
+                self.rewrite(&child, cols, keys, &[], strict, None)?;
+            }
+        }
+        if let Some(to) = &to {
+            self.txns.rename_table(name, to);
+        }
+        Ok(())
+    }
+
+    // OPTIMIZE TABLE, writes the table again, which leaves its rows under its schema
+    pub fn optimize(&mut self, name: &str) -> Result<(), TableLikeError> {
+        let tb = self.open(name)?;
+        let (cols, keys) = (tb.get_cols()?, tb.get_keys()?);
+        self.rewrite(name, cols, keys, &[], true, None)
+    }
+
+    // an open table that has rows of an older schema and no locks on it, locked so it is
+    // read but not changed while it is written again, None when there is none
+    pub fn start_compaction(&mut self) -> Option<String> {
+        let name = self.tables.names().find(|n| {
+            // no session has id 0
+            self.tables.get(n).is_some_and(|t| t.old_schemas() > 0) && self.locks.can_lock(0, n, LockMode::Exclusive)
+        })?;
+        let name = name.to_string();
+        self.locks.lock_table(0, &name, LockMode::Shared, false).ok()?;
+        Some(name)
+    }
+
+    // writes the rows of a table start_compaction locked next to its file and its indexes next
+    // to theirs, which only reads so plain reads run on while it does, returns the index files
+    pub fn build_compacted(&self, name: &str) -> Result<Vec<String>, TableLikeError> {
+        let mut files = Vec::new();
+        let res = (|| {
+            let src = FileTable::new(name, &self.pool)?;
+            check_rows(name, &src)?;
+            let mut wri = BufWriter::new(File::create(wal::temp_path(name))?);
+            let (indexes, _) = write_rows(&src.get_cols()?, &src.get_keys()?, src.get_rows(), &mut wri)?;
+            wri.flush()?;
+            for (k, entries) in indexes {
+                files.push(index::index_path(name, &k.name));
+                IndexFile::build(&wal::temp_path(files.last().unwrap()), k.algorithm, entries)?;
+            }
+            Ok(())
+        })();
+        if res.is_err() {
+            remove_temps(name, &files);
+        }
+        res.map(|_| files)
+    }
+
+    // moves what build_compacted wrote over the table and its indexes in an operation of its
+    // own and lets go of the table
+    pub fn finish_compaction(&mut self, name: &str, files: &[String]) -> Result<(), TableLikeError> {
+        let res = self.operation(|tm| {
+            // closed before its file moves
+            if let Some(mut t) = tm.tables.remove(name) {
+                t.close()?;
+            }
+            buffer::lock(&tm.pool).move_file(&wal::temp_path(name), name)?;
+            // index files aren't logged, a rollback removes them to be built again
+            for path in files {
+                std::fs::rename(wal::temp_path(path), path)?;
+            }
+            Ok(())
+        });
+        if res.is_err() {
+            remove_temps(name, files);
+        }
+        self.end_compaction();
+        res
+    }
+
+    // lets go of the table start_compaction locked
+    pub fn end_compaction(&mut self) {
+        self.locks.release(0, false);
+    }
+
+    // writes the header of a text table in place with the new schema, the rows there are
+    // staying under the one they have, false when it doesn't fit
+    fn alter_in_place(
+        &mut self,
+        name: &str,
+        cols: &[ColumnEntry],
+        keys: &[KeyDef],
+        renames: &[(String, String)],
+    ) -> Result<bool, TableLikeError> {
+        let mut ft = FileTable::new(name, &self.pool)?;
+        let (table, mut schemas, start) = ft.parse_header()?;
+        // the rows since the last time the schema changed
+        let end = ft.inner.metadata()?.len();
+        if end > schemas.last().map_or(start, |s| s.end) {
+            schemas.push(OldSchema::new(end, table.col_names));
+        }
+        for (from, to) in renames {
+            for c in schemas.iter_mut().flat_map(|s| &mut s.cols).filter(|c| c.col_name == *from) {
+                c.col_name = to.clone();
+            }
+        }
+        // the last rows are read as they are when only names changed
+        while schemas.last().is_some_and(|s| s.cols == cols) {
+            schemas.pop();
+        }
+        if !ft.write_schema(cols, keys, &schemas)? {
+            return Ok(false);
+        }
+        // the open table still has the old header
+        if let Some(mut t) = self.tables.remove(name) {
+            t.close()?;
+        }
+        Ok(true)
+    }
+
+    // flushes a table from itself with the new schema, its rows going through the steps, and
+    // numbers the rows without a value in a new AUTO_INCREMENT column
+    fn rewrite(
+        &mut self,
+        name: &str,
+        cols: Vec<ColumnEntry>,
+        keys: Vec<KeyDef>,
+        steps: &[Step],
+        strict: bool,
+        auto: Option<usize>,
+    ) -> Result<(), TableLikeError> {
+        let src: Box<dyn TableLike> = match paged::is_paged(name)? {
+            // the rows are read through the pool while the new file is written next to the old one
+            true => Box::new(PageTable::open_reader(name, &self.pool)?),
+            false => Box::new(FileTable::new(name, &self.pool)?),
+        };
+        let mut rw = Rewrite { src: src.as_ref(), cols, keys, steps, strict, auto: None };
+        // numbered after the largest value there is
+        if let Some(i) = auto {
+            rw.auto = Some((i, scan_auto_increment(&rw)?));
+        }
+        let tb = self.open(name)?;
+        tb.flush(&rw)?;
+        if auto.is_some() {
+            let next = scan_auto_increment(&**tb)?;
+            tb.set_auto_increment(next)?;
+        }
+        Ok(())
+    }
+
+    fn move_table(&mut self, name: &str, to: &str, keys: &[KeyDef]) -> Result<(), TableLikeError> {
+        if std::fs::exists(to)? {
+            return Err(TableLikeError::new(&format!("Table '{to}' already exists")));
+        }
+        // closed before its file moves, a paged table writes back its pages when dropped
+        if let Some(mut t) = self.tables.remove(name) {
+            t.close()?;
+        }
+        {
+            let mut pool = buffer::lock(&self.pool);
+            pool.move_file(name, to)?;
+            let auto = format!("{name}.auto");
+            if std::fs::exists(&auto)? {
+                pool.move_file(&auto, &format!("{to}.auto"))?;
+            }
+        }
+        // index files aren't logged, a rollback removes them to be built again
+        for idx in index::index_files(name)? {
+            std::fs::rename(&idx, format!("{to}{}", &idx[name.len()..]))?;
+        }
+        self.rename_references(name, to, keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{error, run, select, TestDir};
+    use crate::{query, Session, TableLikeError, TableManager};
+
+    // the manager after a restart, so the table is read back from its files
+    fn reopened(dir: &TestDir, tm: TableManager) -> (TableManager, Session) {
+        drop(tm);
+        let mut tm = dir.manager();
+        let s = tm.session();
+        (tm, s)
+    }
+
+    #[test]
+    fn columns_change_through_a_full_rewrite() {
+        let dir = TestDir::new("alter_copy");
+        for engine in ["TEXT", "PAGED"] {
+            let t = format!("t{engine}");
+            let mut tm = dir.manager();
+            let mut s = tm.session();
+            run(&mut tm, &mut s, &format!("CREATE TABLE {t} (id INT PRIMARY KEY AUTO_INCREMENT, n INT, s VARCHAR(10), INDEX by_s (s)) ENGINE={engine};")).unwrap();
+            run(&mut tm, &mut s, &format!("INSERT INTO {t} (n, s) VALUES (12, 'abc'), (345, 'defgh'), (NULL, 'i');")).unwrap();
+            let alter = |tm: &mut TableManager, s: &mut Session, spec: &str| run(tm, s, &format!("ALTER TABLE {t} {spec}, ALGORITHM=COPY;"));
+            let rows = |tm: &mut TableManager, s: &mut Session, cols: &str| select(tm, s, &format!("SELECT {cols} FROM {t};"));
+            let type_of = |tm: &mut TableManager, s: &mut Session, col: &str| {
+                select(tm, s, &format!("DESCRIBE {t};")).into_iter().find(|r| r[0] == col).map(|r| r[1].clone())
+            };
+
+            alter(&mut tm, &mut s, "ADD COLUMN d VARCHAR(5) DEFAULT 'x'").unwrap();
+            (tm, s) = reopened(&dir, tm);
+            assert_eq!(tm.open(&t).unwrap().old_schemas(), 0);
+            let added = vec![vec!["1", "12", "abc", "x"], vec!["2", "345", "defgh", "x"], vec!["3", "NULL", "i", "x"]];
+            assert_eq!(rows(&mut tm, &mut s, "id, n, s, d"), added);
+
+            // values that don't fit fail the whole ALTER in strict mode
+            let too_long = error(&mut tm, &mut s, &format!("ALTER TABLE {t} MODIFY COLUMN n VARCHAR(2), ALGORITHM=COPY;"));
+            assert_eq!(too_long, "Data too long for column 'n'");
+            assert!(alter(&mut tm, &mut s, "MODIFY COLUMN s INT").is_err());
+            (tm, s) = reopened(&dir, tm);
+            assert_eq!(rows(&mut tm, &mut s, "id, n, s, d"), added);
+            assert_eq!(type_of(&mut tm, &mut s, "n").as_deref(), Some("bigint"));
+
+            // numbers become strings and back
+            alter(&mut tm, &mut s, "MODIFY COLUMN n VARCHAR(3)").unwrap();
+            (tm, s) = reopened(&dir, tm);
+            assert_eq!(type_of(&mut tm, &mut s, "n").as_deref(), Some("varchar(3)"));
+            assert_eq!(select(&mut tm, &mut s, &format!("SELECT id FROM {t} WHERE n = '345';")), vec![vec!["2"]]);
+            run(&mut tm, &mut s, "SET sql_mode = '';").unwrap();
+            alter(&mut tm, &mut s, "MODIFY COLUMN s VARCHAR(3), MODIFY COLUMN n INT").unwrap();
+            (tm, s) = reopened(&dir, tm);
+            assert_eq!(type_of(&mut tm, &mut s, "n").as_deref(), Some("bigint"));
+            assert_eq!(select(&mut tm, &mut s, &format!("SELECT id FROM {t} WHERE n > 100;")), vec![vec!["2"]]);
+            // the index on the truncated column has the values as they are now
+            assert_eq!(select(&mut tm, &mut s, &format!("SELECT id FROM {t} WHERE s = 'def';")), vec![vec!["2"]]);
+            assert_eq!(select(&mut tm, &mut s, &format!("SELECT id FROM {t} WHERE s = 'defgh';")), Vec::<Vec<String>>::new());
+
+            alter(&mut tm, &mut s, "RENAME COLUMN d TO e").unwrap();
+            (tm, s) = reopened(&dir, tm);
+            assert!(error(&mut tm, &mut s, &format!("SELECT d FROM {t};")).contains("'d'"));
+            assert_eq!(rows(&mut tm, &mut s, "id, e"), vec![vec!["1", "x"], vec!["2", "x"], vec!["3", "x"]]);
+
+            alter(&mut tm, &mut s, "DROP COLUMN n").unwrap();
+            (tm, s) = reopened(&dir, tm);
+            assert_eq!(rows(&mut tm, &mut s, "*"), vec![vec!["1", "abc", "x"], vec!["2", "def", "x"], vec!["3", "i", "x"]]);
+
+            // the index files and the AUTO_INCREMENT counter go along with the table
+            let renamed = format!("{t}_new");
+            run(&mut tm, &mut s, &format!("ALTER TABLE {t} RENAME TO {renamed};")).unwrap();
+            (tm, s) = reopened(&dir, tm);
+            let files = |name: &str| {
+                let f = std::fs::read_dir(".").unwrap().map(|e| e.unwrap().file_name().into_string().unwrap());
+                let mut f = f.filter(|f| f == name || f.starts_with(&format!("{name}."))).collect::<Vec<_>>();
+                f.sort();
+                f
+            };
+            assert_eq!(files(&t), Vec::<String>::new());
+            let expected = ["", ".PRIMARY.idx", ".auto", ".by_s.idx"].map(|ext| format!("{renamed}{ext}"));
+            assert_eq!(files(&renamed), expected);
+            run(&mut tm, &mut s, &format!("INSERT INTO {renamed} (s) VALUES ('z');")).unwrap();
+            assert_eq!(select(&mut tm, &mut s, &format!("SELECT id FROM {renamed} WHERE s = 'z';")), vec![vec!["4"]]);
+        }
+    }
+
+    #[test]
+    fn compaction_keeps_out_writes_but_not_reads() {
+        let dir = TestDir::new("compaction");
+        let mut tm = dir.manager();
+        let (mut a, mut b) = (tm.session(), tm.session());
+        run(&mut tm, &mut a, "CREATE TABLE t (id INT PRIMARY KEY, s VARCHAR(10));").unwrap();
+        run(&mut tm, &mut a, "INSERT INTO t VALUES (1, 'a'), (2, 'b');").unwrap();
+        run(&mut tm, &mut a, "ALTER TABLE t ADD COLUMN n INT DEFAULT 5, ALGORITHM=INSTANT;").unwrap();
+        // only open tables are compacted
+        assert_eq!(select(&mut tm, &mut a, "SELECT n FROM t WHERE id = 1;"), vec![vec!["5"]]);
+        let name = tm.start_compaction().unwrap();
+        assert_eq!(name, "t");
+        assert!(matches!(run(&mut tm, &mut b, "INSERT INTO t VALUES (3, 'c', 6);"), Err(TableLikeError::LockWait)));
+        tm.locks.stop_waiting(b.id);
+        let files = tm.build_compacted(&name).unwrap();
+        let mut stmt = query::parse_statement("SELECT id, n FROM t;").unwrap();
+        assert!(matches!(tm.try_read(&b, &mut stmt), Some(Ok(_))));
+        tm.finish_compaction(&name, &files).unwrap();
+        assert_eq!(tm.open("t").unwrap().old_schemas(), 0);
+        run(&mut tm, &mut b, "INSERT INTO t VALUES (3, 'c', 6);").unwrap();
+        assert!(run(&mut tm, &mut b, "INSERT INTO t VALUES (2, 'd', 7);").is_err());
+        assert_eq!(
+            select(&mut tm, &mut a, "SELECT id, s, n FROM t WHERE id >= 2;"),
+            vec![vec!["2", "b", "5"], vec!["3", "c", "6"]]
+        );
+        assert!(!std::fs::exists("t.tmp").unwrap());
+    }
+
+    #[test]
+    fn rows_written_under_older_schemas_are_read_with_the_new_one() {
+        let dir = TestDir::new("old_schemas");
+        let mut tm = dir.manager();
+        let mut s = tm.session();
+        for sql in [
+            "CREATE TABLE t (id INT PRIMARY KEY, a VARCHAR(10), b INT);",
+            "INSERT INTO t VALUES (1, 'x', 10);",
+            "ALTER TABLE t ADD COLUMN c INT DEFAULT 7, ALGORITHM=INSTANT;",
+            "INSERT INTO t VALUES (2, 'y', 20, 8);",
+            "ALTER TABLE t DROP COLUMN b, ALGORITHM=INSTANT;",
+            "INSERT INTO t VALUES (3, 'z', 9);",
+            "ALTER TABLE t RENAME COLUMN a TO s, ALGORITHM=INSTANT;",
+        ] {
+            run(&mut tm, &mut s, sql).unwrap();
+        }
+        let rows = vec![vec!["1", "x", "7"], vec!["2", "y", "8"], vec!["3", "z", "9"]];
+        assert_eq!(select(&mut tm, &mut s, "SELECT id, s, c FROM t;"), rows);
+        assert!(tm.open("t").unwrap().old_schemas() > 0);
+        // the schemas are read back from the file
+        drop(tm);
+        let mut tm = dir.manager();
+        let mut s = tm.session();
+        assert_eq!(select(&mut tm, &mut s, "SELECT id, s, c FROM t;"), rows);
+        assert_eq!(select(&mut tm, &mut s, "SELECT c FROM t WHERE s = 'y';"), vec![vec!["8"]]);
+        run(&mut tm, &mut s, "OPTIMIZE TABLE t;").unwrap();
+        assert_eq!(tm.open("t").unwrap().old_schemas(), 0);
+        assert_eq!(select(&mut tm, &mut s, "SELECT * FROM t;"), rows);
+    }
+}
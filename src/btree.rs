@@ -0,0 +1,417 @@
+/*
+
+B+tree kept in a file of fixed size pages, maps byte string keys to u64 values
+
+Page 0      "BTREE001" then the page number of the root and the number of pages, both u64
+Node page   kind u8 (0 leaf, 1 inner), entry count u16 and a link u64,
+            the next leaf for a leaf and the leftmost child for an inner node,
+            then the entries as key length u16, key bytes and value u64
+
+An inner entry holds the first key of its child, so a child has the keys from its own
+key up to the key of the next entry. All numbers are big endian.
+
+Keys are compared as bytes and have to be unique, callers that want several values
+for one key append something that tells them apart. Removing a key doesn't merge nodes,
+a leaf left empty stays linked in until the tree is built again.
+
+*/
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+
+const PAGE_SIZE: usize = 4096;
+const MAGIC: &[u8; 8] = b"BTREE001";
+const NODE_HEADER: usize = 11;
+// small enough that a node always holds a few entries so splitting it in half works
+pub const MAX_KEY_LEN: usize = 1000;
+
+enum Node {
+    Leaf { entries: Vec<(Vec<u8>, u64)>, next: u64 },
+    Inner { first: u64, entries: Vec<(Vec<u8>, u64)> },
+}
+
+impl Node {
+    fn entries(&self) -> &[(Vec<u8>, u64)] {
+        match self {
+            Self::Leaf { entries, .. } | Self::Inner { entries, .. } => entries,
+        }
+    }
+
+    fn size(&self) -> usize {
+        NODE_HEADER + self.entries().iter().map(|(k, _)| k.len() + 10).sum::<usize>()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let (kind, link) = match self {
+            Self::Leaf { next, .. } => (0, *next),
+            Self::Inner { first, .. } => (1, *first),
+        };
+        let mut out = Vec::with_capacity(PAGE_SIZE);
+        out.push(kind);
+        out.extend((self.entries().len() as u16).to_be_bytes());
+        out.extend(link.to_be_bytes());
+        for (k, v) in self.entries() {
+            out.extend((k.len() as u16).to_be_bytes());
+            out.extend(k);
+            out.extend(v.to_be_bytes());
+        }
+        out.resize(PAGE_SIZE, 0);
+        out
+    }
+
+    fn decode(page: &[u8]) -> Option<Node> {
+        let count = u16::from_be_bytes(page.get(1..3)?.try_into().ok()?) as usize;
+        let link = u64::from_be_bytes(page.get(3..11)?.try_into().ok()?);
+        let mut entries = Vec::with_capacity(count);
+        let mut at = NODE_HEADER;
+        for _ in 0..count {
+            let len = u16::from_be_bytes(page.get(at..at + 2)?.try_into().ok()?) as usize;
+            let key = page.get(at + 2..at + 2 + len)?.to_vec();
+            at += 2 + len;
+            entries.push((key, u64::from_be_bytes(page.get(at..at + 8)?.try_into().ok()?)));
+            at += 8;
+        }
+        match page[0] {
+            0 => Some(Self::Leaf { entries, next: link }),
+            1 => Some(Self::Inner { first: link, entries }),
+            _ => None,
+        }
+    }
+
+    // index of the child that holds key
+    fn child_of(first: u64, entries: &[(Vec<u8>, u64)], key: &[u8]) -> (usize, u64) {
+        match entries.partition_point(|(k, _)| k.as_slice() <= key) {
+            0 => (0, first),
+            n => (n, entries[n - 1].1),
+        }
+    }
+}
+
+fn corrupt() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "Corrupt index file")
+}
+
+// splits entries in two halves of about the same size in bytes
+fn split_at_half(entries: &[(Vec<u8>, u64)]) -> usize {
+    let total = entries.iter().map(|(k, _)| k.len() + 10).sum::<usize>();
+    let mut acc = 0;
+    for (i, (k, _)) in entries.iter().enumerate() {
+        acc += k.len() + 10;
+        if acc * 2 >= total {
+            return (i + 1).clamp(1, entries.len() - 1);
+        }
+    }
+    entries.len() / 2
+}
+
+pub struct BTree {
+    file: File,
+    root: u64,
+    pages: u64,
+}
+
+impl BTree {
+    pub fn open(path: &str) -> std::io::Result<BTree> {
+        let file = File::options().read(true).write(true).open(path)?;
+        let mut head = [0; 24];
+        (&file).rewind()?;
+        (&file).read_exact(&mut head)?;
+        if &head[..8] != MAGIC {
+            return Err(corrupt());
+        }
+        Ok(BTree {
+            file,
+            root: u64::from_be_bytes(head[8..16].try_into().unwrap()),
+            pages: u64::from_be_bytes(head[16..24].try_into().unwrap()),
+        })
+    }
+
+    // writes a new tree from entries sorted by key, replacing whatever was at path
+    pub fn build(path: &str, entries: impl IntoIterator<Item = (Vec<u8>, u64)>) -> std::io::Result<BTree> {
+        let file = File::options().read(true).write(true).create(true).truncate(true).open(path)?;
+        let mut tree = BTree { file, root: 1, pages: 1 };
+        // (first key, page) of every node of the level being built
+        let mut level = Vec::new();
+        let mut leaf = Vec::new();
+        let mut size = NODE_HEADER;
+        for (k, v) in entries {
+            if size + k.len() + 10 > PAGE_SIZE {
+                level.push(tree.append_node(Node::Leaf { entries: std::mem::take(&mut leaf), next: tree.pages + 1 })?);
+                size = NODE_HEADER;
+            }
+            size += k.len() + 10;
+            leaf.push((k, v));
+        }
+        level.push(tree.append_node(Node::Leaf { entries: leaf, next: 0 })?);
+        while level.len() > 1 {
+            let mut upper = Vec::new();
+            let mut it = level.into_iter().peekable();
+            while let Some((first_key, first)) = it.next() {
+                let mut entries = Vec::new();
+                let mut size = NODE_HEADER;
+                while let Some((k, _)) = it.peek() {
+                    if size + k.len() + 10 > PAGE_SIZE {
+                        break;
+                    }
+                    size += k.len() + 10;
+                    entries.push(it.next().unwrap());
+                }
+                let (_, page) = tree.append_node(Node::Inner { first, entries })?;
+                upper.push((first_key, page));
+            }
+            level = upper;
+        }
+        tree.root = level[0].1;
+        tree.write_header()?;
+        Ok(tree)
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let mut head = Vec::with_capacity(24);
+        head.extend(MAGIC);
+        head.extend(self.root.to_be_bytes());
+        head.extend(self.pages.to_be_bytes());
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&head)
+    }
+
+    fn read_node(&self, page: u64) -> std::io::Result<Node> {
+        if page == 0 || page >= self.pages {
+            return Err(corrupt());
+        }
+        let mut buf = vec![0; PAGE_SIZE];
+        self.file.read_exact_at(&mut buf, page * PAGE_SIZE as u64)?;
+        Node::decode(&buf).ok_or_else(corrupt)
+    }
+
+    fn write_node(&mut self, page: u64, node: &Node) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))?;
+        self.file.write_all(&node.encode())
+    }
+
+    // writes node to a new page at the end, returns its first key and page
+    fn append_node(&mut self, node: Node) -> std::io::Result<(Vec<u8>, u64)> {
+        let page = self.pages;
+        self.pages += 1;
+        self.write_node(page, &node)?;
+        Ok((node.entries().first().map(|(k, _)| k.clone()).unwrap_or_default(), page))
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: u64) -> std::io::Result<()> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Index key too long"));
+        }
+        if let Some((sep, page)) = self.insert_at(self.root, key, value)? {
+            // the root was split, the tree grows a level
+            let (_, root) = self.append_node(Node::Inner { first: self.root, entries: vec![(sep, page)] })?;
+            self.root = root;
+        }
+        self.write_header()
+    }
+
+    // inserts below page, returns the first key and page of the new right half when it had to be split
+    fn insert_at(&mut self, page: u64, key: &[u8], value: u64) -> std::io::Result<Option<(Vec<u8>, u64)>> {
+        let mut node = self.read_node(page)?;
+        match &mut node {
+            Node::Leaf { entries, .. } => {
+                let at = entries.partition_point(|(k, _)| k.as_slice() < key);
+                if entries.get(at).is_some_and(|(k, _)| k == key) {
+                    entries[at].1 = value;
+                } else {
+                    entries.insert(at, (key.to_vec(), value));
+                }
+            }
+            Node::Inner { first, entries } => {
+                let (at, child) = Node::child_of(*first, entries, key);
+                match self.insert_at(child, key, value)? {
+                    Some(split) => entries.insert(at, split),
+                    None => return Ok(None),
+                }
+            }
+        }
+        if node.size() <= PAGE_SIZE {
+            self.write_node(page, &node)?;
+            return Ok(None);
+        }
+        let right_page = self.pages;
+        self.pages += 1;
+        let (right, sep) = match &mut node {
+            Node::Leaf { entries, next } => {
+                let right = entries.split_off(split_at_half(entries));
+                let sep = right[0].0.clone();
+                let right = Node::Leaf { entries: right, next: *next };
+                *next = right_page;
+                (right, sep)
+            }
+            // the middle entry moves up, its child becomes the leftmost child of the right half
+            Node::Inner { entries, .. } => {
+                let mut right = entries.split_off(split_at_half(entries));
+                let (sep, first) = right.remove(0);
+                (Node::Inner { first, entries: right }, sep)
+            }
+        };
+        self.write_node(right_page, &right)?;
+        self.write_node(page, &node)?;
+        Ok(Some((sep, right_page)))
+    }
+
+    // takes key out of its leaf, false if it wasn't there
+    pub fn remove(&mut self, key: &[u8]) -> std::io::Result<bool> {
+        let mut page = self.root;
+        let mut node = self.read_node(page)?;
+        while let Node::Inner { first, entries } = &node {
+            page = Node::child_of(*first, entries, key).1;
+            node = self.read_node(page)?;
+        }
+        let Node::Leaf { entries, .. } = &mut node else {
+            return Err(corrupt());
+        };
+        let Ok(at) = entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) else {
+            return Ok(false);
+        };
+        entries.remove(at);
+        self.write_node(page, &node)?;
+        Ok(true)
+    }
+
+    // values of the keys in the range, in key order
+    pub fn range(&self, lo: Bound<&[u8]>, hi: Bound<&[u8]>) -> std::io::Result<Vec<u64>> {
+        let mut page = self.root;
+        let mut node = self.read_node(page)?;
+        while let Node::Inner { first, entries } = &node {
+            page = match lo {
+                Bound::Included(k) | Bound::Excluded(k) => Node::child_of(*first, entries, k).1,
+                Bound::Unbounded => *first,
+            };
+            node = self.read_node(page)?;
+        }
+        let mut out = Vec::new();
+        loop {
+            let Node::Leaf { entries, next } = &node else {
+                return Err(corrupt());
+            };
+            for (k, v) in entries {
+                let above_lo = match lo {
+                    Bound::Included(l) => k.as_slice() >= l,
+                    Bound::Excluded(l) => k.as_slice() > l,
+                    Bound::Unbounded => true,
+                };
+                let below_hi = match hi {
+                    Bound::Included(h) => k.as_slice() <= h,
+                    Bound::Excluded(h) => k.as_slice() < h,
+                    Bound::Unbounded => true,
+                };
+                if !below_hi {
+                    return Ok(out);
+                }
+                if above_lo {
+                    out.push(*v);
+                }
+            }
+            if *next == 0 {
+                return Ok(out);
+            }
+            page = *next;
+            node = self.read_node(page)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempPath;
+    use std::collections::BTreeMap;
+
+    // keys of different lengths in no particular order, so nodes split at different places
+    fn key(i: u64) -> Vec<u8> {
+        let n = i.wrapping_mul(2654435761) % 100_000;
+        let mut k = format!("{n:06}").into_bytes();
+        k.resize(6 + (n % 300) as usize, b'.');
+        k
+    }
+
+    fn range(t: &BTree, lo: Bound<&[u8]>, hi: Bound<&[u8]>) -> Vec<u64> {
+        t.range(lo, hi).unwrap()
+    }
+
+    #[test]
+    fn inserted_keys_come_back_in_order() {
+        let path = TempPath::new("btree", "insert");
+        let mut t = BTree::build(&path.0, []).unwrap();
+        let mut expected = BTreeMap::new();
+        for i in 0..3000 {
+            t.insert(&key(i), i).unwrap();
+            expected.insert(key(i), i);
+        }
+        // a key inserted again gets the new value
+        t.insert(&key(5), 99_999).unwrap();
+        expected.insert(key(5), 99_999);
+        let all = expected.values().copied().collect::<Vec<_>>();
+        assert_eq!(range(&t, Bound::Unbounded, Bound::Unbounded), all);
+        let t = BTree::open(&path.0).unwrap();
+        assert_eq!(range(&t, Bound::Unbounded, Bound::Unbounded), all);
+        let (lo, hi) = (key(10), key(20));
+        let (lo, hi) = if lo < hi { (lo, hi) } else { (hi, lo) };
+        let between = expected.range(lo.clone()..=hi.clone()).map(|(_, v)| *v).collect::<Vec<_>>();
+        assert_eq!(range(&t, Bound::Included(&lo), Bound::Included(&hi)), between);
+        assert_eq!(range(&t, Bound::Excluded(&lo), Bound::Excluded(&hi)), between[1..between.len() - 1]);
+        assert_eq!(range(&t, Bound::Included(&key(7)), Bound::Included(&key(7))), vec![7]);
+        assert!(range(&t, Bound::Included(b"x"), Bound::Unbounded).is_empty());
+    }
+
+    #[test]
+    fn built_trees_have_every_entry() {
+        let path = TempPath::new("btree", "build");
+        let entries = (0..20_000u64).map(|i| (format!("{i:08}{}", "k".repeat(100)).into_bytes(), i)).collect::<Vec<_>>();
+        let mut t = BTree::build(&path.0, entries.clone()).unwrap();
+        assert_eq!(range(&t, Bound::Unbounded, Bound::Unbounded), (0..20_000).collect::<Vec<_>>());
+        assert_eq!(range(&t, Bound::Included(&entries[12_345].0), Bound::Excluded(&entries[12_348].0)), vec![12_345, 12_346, 12_347]);
+        t.insert(b"00012345", 7).unwrap();
+        assert_eq!(range(&t, Bound::Excluded(&entries[12_344].0), Bound::Included(&entries[12_345].0)), vec![7, 12_345]);
+    }
+
+    #[test]
+    fn removed_keys_are_gone_from_every_level() {
+        let path = TempPath::new("btree", "remove");
+        let mut t = BTree::build(&path.0, (0..3000u64).map(|i| (format!("{i:08}").into_bytes(), i))).unwrap();
+        // every other key, and then all of a few leaves
+        for i in (0..3000u64).filter(|i| i % 2 == 0 || (1000..2000).contains(i)) {
+            assert!(t.remove(format!("{i:08}").as_bytes()).unwrap());
+        }
+        assert!(!t.remove(b"00000000").unwrap());
+        let left = (0..3000).filter(|i| i % 2 == 1 && !(1000..2000).contains(i)).collect::<Vec<_>>();
+        assert_eq!(range(&t, Bound::Unbounded, Bound::Unbounded), left);
+        assert!(range(&t, Bound::Included(b"00001000"), Bound::Excluded(b"00002000")).is_empty());
+        // the empty leaves take keys again
+        t.insert(b"00001500", 1500).unwrap();
+        let t = BTree::open(&path.0).unwrap();
+        assert_eq!(range(&t, Bound::Included(b"00000998"), Bound::Included(b"00002001")), vec![999, 1500, 2001]);
+    }
+
+    #[test]
+    fn bad_keys_and_files_are_errors() {
+        let path = TempPath::new("btree", "bad");
+        let mut t = BTree::build(&path.0, [(b"a".to_vec(), 1)]).unwrap();
+        assert!(t.insert(&[0; MAX_KEY_LEN + 1], 1).is_err());
+        // a root past the end of the file
+        let mut head = MAGIC.to_vec();
+        head.extend(7u64.to_be_bytes());
+        head.extend(9u64.to_be_bytes());
+        std::fs::write(&path.0, &head).unwrap();
+        assert!(BTree::open(&path.0).unwrap().range(Bound::Unbounded, Bound::Unbounded).is_err());
+        // a node that claims more entries than fit in its page
+        let mut file = head.clone();
+        file[8..16].copy_from_slice(&1u64.to_be_bytes());
+        file[16..24].copy_from_slice(&2u64.to_be_bytes());
+        file.resize(PAGE_SIZE, 0);
+        file.extend([0, 0xff, 0xff]);
+        file.resize(2 * PAGE_SIZE, 0xff);
+        std::fs::write(&path.0, &file).unwrap();
+        assert!(BTree::open(&path.0).unwrap().range(Bound::Unbounded, Bound::Unbounded).is_err());
+        std::fs::write(&path.0, b"not a tree at all").unwrap();
+        assert!(BTree::open(&path.0).is_err());
+    }
+}
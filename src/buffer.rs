@@ -0,0 +1,509 @@
+/*
+
+Buffer pool, the pages of table files kept in memory between queries
+
+Every table of a TableManager reads its file through one pool, a text table
+through a PoolReader over its pages and a paged table page by page. The pool
+holds at most budget bytes of pages, when a page has to be loaded and there is
+no room the clock hand goes round the frames, giving every page that was used
+since it last passed another round and evicting the first one that wasn't.
+
+Pages written through the pool are dirty until they are written back, on
+eviction or on flush. Pinned pages are never evicted, a paged table keeps its
+first page and its free space map pinned since every insert changes them. When
+everything is pinned the pool goes over its budget rather than fail.
+
+Every frame counts as a whole page against the budget.
+
+Every write to a table file goes through the pool and its write-ahead log (see
+wal.rs), written back pages as well as the writes of a text table around the pool.
+
+*/
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::wal::Wal;
+
+pub const PAGE_SIZE: usize = 4096;
+// like innodb_buffer_pool_size
+pub const DEFAULT_POOL_SIZE: usize = 128 << 20;
+
+pub type FileId = usize;
+pub type SharedPool = Arc<Mutex<BufferPool>>;
+
+// a panic while the pool was locked leaves it usable, every change to it is complete when it unlocks
+pub fn lock(pool: &SharedPool) -> MutexGuard<'_, BufferPool> {
+    pool.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// reads at an offset without going through the cursor of the file, which other handles
+// of it share, returns fewer bytes than buf holds only at the end of the file
+pub fn read_at(file: &File, buf: &mut [u8], at: u64) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match file.read_at(&mut buf[n..], at + n as u64) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+// a size in bytes with an optional K, M or G suffix
+pub fn parse_size(inp: &str) -> Option<usize> {
+    let inp = inp.trim();
+    let (num, shift) = match inp.chars().last()?.to_ascii_uppercase() {
+        'K' => (&inp[..inp.len() - 1], 10),
+        'M' => (&inp[..inp.len() - 1], 20),
+        'G' => (&inp[..inp.len() - 1], 30),
+        _ => (inp, 0),
+    };
+    num.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+struct Frame {
+    file: FileId,
+    page: u64,
+    // shorter than a page for the last page of a file
+    data: Vec<u8>,
+    dirty: bool,
+    pins: u32,
+    // set on every use, cleared by the clock hand
+    used: bool,
+}
+
+pub struct BufferPool {
+    budget: usize,
+    size: usize,
+    frames: Vec<Option<Frame>>,
+    free_frames: Vec<usize>,
+    lookup: HashMap<(FileId, u64), usize>,
+    hand: usize,
+    // path and handle of every file, kept to write dirty pages back
+    files: Vec<(String, File)>,
+    wal: Wal,
+}
+
+impl BufferPool {
+    pub fn new(budget: usize, wal: Wal) -> BufferPool {
+        BufferPool {
+            budget,
+            size: 0,
+            frames: Vec::new(),
+            free_frames: Vec::new(),
+            lookup: HashMap::new(),
+            hand: 0,
+            files: Vec::new(),
+            wal,
+        }
+    }
+
+    pub fn shared(budget: usize, wal: Wal) -> SharedPool {
+        Arc::new(Mutex::new(Self::new(budget, wal)))
+    }
+
+    pub fn wal(&mut self) -> &mut Wal {
+        &mut self.wal
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    // the id of the file at path, the pages cached for it by an earlier handle stay
+    pub fn attach(&mut self, path: &str, file: &File) -> std::io::Result<FileId> {
+        let handle = file.try_clone()?;
+        match self.files.iter().position(|(p, _)| p == path) {
+            Some(id) => {
+                self.files[id].1 = handle;
+                Ok(id)
+            }
+            None => {
+                self.files.push((path.to_string(), handle));
+                Ok(self.files.len() - 1)
+            }
+        }
+    }
+
+    fn load(&mut self, file: FileId, page: u64) -> std::io::Result<Vec<u8>> {
+        let at = page.checked_mul(PAGE_SIZE as u64).ok_or(std::io::ErrorKind::InvalidInput)?;
+        let mut data = vec![0; PAGE_SIZE];
+        let n = read_at(&self.files[file].1, &mut data, at)?;
+        data.truncate(n);
+        Ok(data)
+    }
+
+    fn log_page(&mut self, i: usize) -> std::io::Result<()> {
+        if let Some(fr) = self.frames[i].as_ref().filter(|f| f.dirty) {
+            let (path, f) = &self.files[fr.file];
+            self.wal.log_write(path, f, fr.page * PAGE_SIZE as u64, &fr.data)?;
+        }
+        Ok(())
+    }
+
+    // writes a page whose change is in the log already
+    fn store(&mut self, i: usize) -> std::io::Result<()> {
+        let Some(fr) = self.frames[i].as_mut().filter(|f| f.dirty) else {
+            return Ok(());
+        };
+        let mut f = &self.files[fr.file].1;
+        f.seek(SeekFrom::Start(fr.page * PAGE_SIZE as u64))?;
+        f.write_all(&fr.data)?;
+        fr.dirty = false;
+        Ok(())
+    }
+
+    fn write_back(&mut self, i: usize) -> std::io::Result<()> {
+        self.log_page(i)?;
+        self.wal.sync()?;
+        self.store(i)
+    }
+
+    // evicts pages until need more bytes fit in the budget, or nothing more can go
+    fn make_room(&mut self, need: usize) -> std::io::Result<()> {
+        while self.size + need > self.budget && !self.lookup.is_empty() {
+            let n = self.frames.len();
+            let mut victim = None;
+            // two rounds clear every used bit, so a frame that can go is found unless all are pinned
+            for _ in 0..2 * n {
+                let i = self.hand;
+                self.hand = (self.hand + 1) % n;
+                match &mut self.frames[i] {
+                    Some(f) if f.pins == 0 && f.used => f.used = false,
+                    Some(f) if f.pins == 0 => {
+                        victim = Some(i);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            let Some(i) = victim else {
+                return Ok(());
+            };
+            self.write_back(i)?;
+            let f = self.frames[i].take().unwrap();
+            self.lookup.remove(&(f.file, f.page));
+            self.size -= PAGE_SIZE;
+            self.free_frames.push(i);
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, file: FileId, page: u64, data: Vec<u8>) -> std::io::Result<usize> {
+        self.make_room(PAGE_SIZE)?;
+        self.size += PAGE_SIZE;
+        let frame = Frame { file, page, data, dirty: false, pins: 0, used: true };
+        let i = match self.free_frames.pop() {
+            Some(i) => {
+                self.frames[i] = Some(frame);
+                i
+            }
+            None => {
+                self.frames.push(Some(frame));
+                self.frames.len() - 1
+            }
+        };
+        self.lookup.insert((file, page), i);
+        Ok(i)
+    }
+
+    fn frame(&mut self, file: FileId, page: u64) -> std::io::Result<&mut Frame> {
+        let i = match self.lookup.get(&(file, page)) {
+            Some(i) => *i,
+            None => {
+                let data = self.load(file, page)?;
+                self.insert(file, page, data)?
+            }
+        };
+        let f = self.frames[i].as_mut().unwrap();
+        f.used = true;
+        Ok(f)
+    }
+
+    // the bytes of a page, fewer than a page at the end of the file and none past it
+    pub fn read(&mut self, file: FileId, page: u64) -> std::io::Result<&[u8]> {
+        Ok(&self.frame(file, page)?.data)
+    }
+
+    // changes part of a page, growing it when the bytes go past its end
+    pub fn write(&mut self, file: FileId, page: u64, at: usize, buf: &[u8]) -> std::io::Result<()> {
+        // a whole page doesn't need its old contents
+        if at == 0 && buf.len() == PAGE_SIZE && !self.lookup.contains_key(&(file, page)) {
+            self.insert(file, page, Vec::with_capacity(PAGE_SIZE))?;
+        }
+        let f = self.frame(file, page)?;
+        if f.data.len() < at + buf.len() {
+            f.data.resize(at + buf.len(), 0);
+        }
+        f.data[at..at + buf.len()].copy_from_slice(buf);
+        f.dirty = true;
+        Ok(())
+    }
+
+    pub fn pin(&mut self, file: FileId, page: u64) -> std::io::Result<()> {
+        self.frame(file, page)?.pins += 1;
+        Ok(())
+    }
+
+    pub fn unpin(&mut self, file: FileId, page: u64) {
+        if let Some(f) = self.lookup.get(&(file, page)).and_then(|i| self.frames[*i].as_mut()) {
+            f.pins = f.pins.saturating_sub(1);
+        }
+    }
+
+    // forgets the pages of a file from page on, dirty or not, for a file that was
+    // truncated or written around the pool
+    pub fn discard(&mut self, file: FileId, from: u64) {
+        for i in 0..self.frames.len() {
+            if self.frames[i].as_ref().is_some_and(|f| f.file == file && f.page >= from) {
+                let f = self.frames[i].take().unwrap();
+                self.lookup.remove(&(f.file, f.page));
+                self.size -= PAGE_SIZE;
+                self.free_frames.push(i);
+            }
+        }
+    }
+
+    // forgets every page, for files a rollback changed around the pool
+    pub fn discard_all(&mut self) {
+        for id in 0..self.files.len() {
+            self.discard(id, 0);
+        }
+    }
+
+    // writes the dirty pages of a file back, or of every file
+    pub fn flush(&mut self, file: Option<FileId>) -> std::io::Result<()> {
+        let frames = (0..self.frames.len())
+            .filter(|i| self.frames[*i].as_ref().is_some_and(|f| f.dirty && file.is_none_or(|id| f.file == id)))
+            .collect::<Vec<_>>();
+        // all of them go into the log first so it is synced once
+        for i in &frames {
+            self.log_page(*i)?;
+        }
+        self.wal.sync()?;
+        for i in frames {
+            self.store(i)?;
+        }
+        Ok(())
+    }
+
+    // a new empty file at path, to write the new contents of a file to before they replace
+    // it with rename or the rows of a result too big for memory, an undo removes it
+    pub fn create_temp(&mut self, path: &str) -> std::io::Result<FileId> {
+        // one a crash left behind is of no use
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let handle = self.wal.create(path)?;
+        let id = self.attach(path, &handle)?;
+        // whatever is cached is from an earlier file of the same name
+        self.discard(id, 0);
+        Ok(id)
+    }
+
+    // removes a file of create_temp that isn't needed after all
+    pub fn remove_temp(&mut self, file: FileId) -> std::io::Result<()> {
+        self.discard(file, 0);
+        std::fs::remove_file(&self.files[file].0)
+    }
+
+    // moves the file of from over the one of to as a whole, its pages become the pages of to,
+    // returns a handle of the file to is now
+    pub fn rename(&mut self, from: FileId, to: FileId) -> std::io::Result<File> {
+        self.flush(Some(from))?;
+        self.discard(to, 0);
+        let path = self.files[to].0.clone();
+        self.wal.rename(&self.files[from].0, &path)?;
+        let handle = File::options().read(true).write(true).open(&path)?;
+        self.files[to].1 = handle.try_clone()?;
+        for i in 0..self.frames.len() {
+            if let Some(f) = self.frames[i].as_mut().filter(|f| f.file == from) {
+                f.file = to;
+                self.lookup.remove(&(from, f.page));
+                self.lookup.insert((to, f.page), i);
+            }
+        }
+        Ok(handle)
+    }
+
+    // moves a file no table has open to another path, what is cached of either path is forgotten
+    pub fn move_file(&mut self, from: &str, to: &str) -> std::io::Result<()> {
+        for id in 0..self.files.len() {
+            if self.files[id].0 == from || self.files[id].0 == to {
+                self.flush(Some(id))?;
+                self.discard(id, 0);
+            }
+        }
+        self.wal.rename(from, to)
+    }
+
+    // writes to a file around the pool, the pages it changes have to be discarded
+    pub fn write_file(&mut self, file: FileId, at: u64, buf: &[u8]) -> std::io::Result<()> {
+        let (path, f) = &self.files[file];
+        self.wal.write(path, f, at, buf)
+    }
+}
+
+// writes a file around the pool, from pos on
+pub struct PoolWriter {
+    pool: SharedPool,
+    file: FileId,
+    pos: u64,
+}
+
+impl PoolWriter {
+    pub fn new(pool: SharedPool, file: FileId, pos: u64) -> PoolWriter {
+        PoolWriter { pool, file, pos }
+    }
+}
+
+impl Write for PoolWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        lock(&self.pool).write_file(self.file, self.pos, buf)?;
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// reads a file through the pool
+pub struct PoolReader {
+    pool: SharedPool,
+    file: FileId,
+    pos: u64,
+}
+
+impl PoolReader {
+    pub fn new(pool: SharedPool, file: FileId, pos: u64) -> PoolReader {
+        PoolReader { pool, file, pos }
+    }
+}
+
+impl Read for PoolReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut pool = lock(&self.pool);
+        let off = self.pos as usize % PAGE_SIZE;
+        // nothing past the end of the file, which may be in the middle of its last page
+        let data = pool.read(self.file, self.pos / PAGE_SIZE as u64)?.get(off..).unwrap_or_default();
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for PoolReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let (base, d) = match pos {
+            SeekFrom::Start(n) => (n, 0),
+            SeekFrom::Current(d) => (self.pos, d),
+            SeekFrom::End(d) => (lock(&self.pool).files[self.file].1.metadata()?.len(), d),
+        };
+        self.pos = base
+            .checked_add_signed(d)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before the start of the file"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempPath;
+    use crate::wal::SyncPolicy;
+
+    // a pool of a few pages over a file of pages filled with their number
+    fn pool_over(path: &TempPath, pages: u8, budget: usize) -> (SharedPool, FileId) {
+        std::fs::write(&path.0, (0..pages).flat_map(|p| [p; PAGE_SIZE]).collect::<Vec<_>>()).unwrap();
+        let pool = BufferPool::shared(budget * PAGE_SIZE, Wal::open(&format!("{}.log", path.0), SyncPolicy::Full).unwrap());
+        let file = File::options().read(true).write(true).open(&path.0).unwrap();
+        let id = lock(&pool).attach(&path.0, &file).unwrap();
+        (pool, id)
+    }
+
+    #[test]
+    fn pages_stay_within_the_budget_unless_pinned() {
+        let path = TempPath::new("buffer", "budget");
+        let (pool, id) = pool_over(&path, 20, 4);
+        let mut p = lock(&pool);
+        p.pin(id, 0).unwrap();
+        for page in 0..20 {
+            assert_eq!(p.read(id, page).unwrap(), &[page as u8; PAGE_SIZE]);
+            assert!(p.size <= 4 * PAGE_SIZE);
+        }
+        assert!(p.lookup.contains_key(&(id, 0)));
+        // with every page pinned the pool goes over its budget instead of failing
+        for page in 1..6 {
+            p.pin(id, page).unwrap();
+        }
+        assert_eq!(p.size, 6 * PAGE_SIZE);
+        for page in 0..6 {
+            p.unpin(id, page);
+        }
+        p.read(id, 10).unwrap();
+        assert!(p.size <= 4 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn dirty_pages_are_written_back() {
+        let path = TempPath::new("buffer", "dirty");
+        let (pool, id) = pool_over(&path, 8, 2);
+        {
+            let mut p = lock(&pool);
+            p.write(id, 0, 10, b"hello").unwrap();
+            // the page is evicted on the way and written back
+            for page in 1..8 {
+                p.read(id, page).unwrap();
+            }
+            assert_eq!(&std::fs::read(&path.0).unwrap()[10..15], b"hello");
+            p.write(id, 3, 0, &[9; PAGE_SIZE]).unwrap();
+            // a page past the end grows the file once written back
+            p.write(id, 8, 0, b"tail").unwrap();
+            p.flush(None).unwrap();
+            p.wal().commit().unwrap();
+        }
+        let data = std::fs::read(&path.0).unwrap();
+        assert_eq!(data.len(), 8 * PAGE_SIZE + 4);
+        assert_eq!(&data[3 * PAGE_SIZE..4 * PAGE_SIZE], &[9; PAGE_SIZE]);
+        assert_eq!(&data[8 * PAGE_SIZE..], b"tail");
+    }
+
+    #[test]
+    fn readers_stop_at_the_end_of_the_file() {
+        let path = TempPath::new("buffer", "reader");
+        let (pool, id) = pool_over(&path, 1, 2);
+        std::fs::OpenOptions::new().append(true).open(&path.0).unwrap().write_all(b"abc").unwrap();
+        let mut r = PoolReader::new(pool.clone(), id, 0);
+        let mut all = Vec::new();
+        r.read_to_end(&mut all).unwrap();
+        assert_eq!(all.len(), PAGE_SIZE + 3);
+        // past the end, in the last page and beyond it
+        for at in [PAGE_SIZE as u64 + 100, 5 * PAGE_SIZE as u64] {
+            r.seek(SeekFrom::Start(at)).unwrap();
+            assert_eq!(r.read(&mut [0; 10]).unwrap(), 0);
+        }
+        assert_eq!(r.seek(SeekFrom::End(-1)).unwrap(), PAGE_SIZE as u64 + 2);
+        assert!(r.seek(SeekFrom::Current(-(PAGE_SIZE as i64) - 3)).is_err());
+    }
+
+    #[test]
+    fn sizes_take_a_suffix() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("16k"), Some(16 << 10));
+        assert_eq!(parse_size(" 128M "), Some(128 << 20));
+        assert_eq!(parse_size("2G"), Some(2 << 30));
+        for bad in ["", "M", "-1K", "1.5M", "99999999999999999999G", "ü"] {
+            assert_eq!(parse_size(bad), None, "{bad}");
+        }
+    }
+}
@@ -0,0 +1,142 @@
+/*
+
+Cache of the open tables of a TableManager
+
+A table stays open, with its file and whatever it keeps of it in memory, until
+more than capacity tables are open. Then the one used longest ago is closed,
+which writes back anything it hasn't written yet, and is opened again from its
+file the next time it is needed.
+
+*/
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{TableLike, TableLikeError};
+
+pub const DEFAULT_CAPACITY: usize = 400;
+
+pub struct TableCache {
+    capacity: usize,
+    // bumped on every use, the table with the lowest one goes first. Reads use tables
+    // side by side through get, so they count their uses without a lock
+    tick: AtomicU64,
+    tables: HashMap<String, (Box<dyn TableLike>, AtomicU64)>,
+}
+
+impl TableCache {
+    pub fn new(capacity: usize) -> TableCache {
+        TableCache { capacity: capacity.max(1), tick: AtomicU64::new(0), tables: HashMap::new() }
+    }
+
+    fn touch(&self, used: &AtomicU64) {
+        used.store(self.tick.fetch_add(1, Ordering::Relaxed) + 1, Ordering::Relaxed);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tables.contains_key(name)
+    }
+
+    // the table, which counts as used
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Box<dyn TableLike>> {
+        let tick = self.tick.get_mut();
+        *tick += 1;
+        let (t, used) = self.tables.get_mut(name)?;
+        *used.get_mut() = *tick;
+        Some(t)
+    }
+
+    // the table to read, which counts as used as well
+    pub fn get(&self, name: &str) -> Option<&dyn TableLike> {
+        let (t, used) = self.tables.get(name)?;
+        self.touch(used);
+        Some(t.as_ref())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.tables.keys().map(String::as_str)
+    }
+
+    // takes a table out without closing it, for a table whose file is about to move
+    pub fn remove(&mut self, name: &str) -> Option<Box<dyn TableLike>> {
+        self.tables.remove(name).map(|(t, _)| t)
+    }
+
+    // closes every table, to be opened again from its file
+    pub fn clear(&mut self) -> Result<(), TableLikeError> {
+        for (_, (mut t, _)) in self.tables.drain() {
+            t.close()?;
+        }
+        Ok(())
+    }
+
+    // adds a table that was just opened, closing others when there are too many
+    pub fn insert(&mut self, name: &str, table: Box<dyn TableLike>) -> Result<&mut Box<dyn TableLike>, TableLikeError> {
+        let tick = self.tick.get_mut();
+        *tick += 1;
+        if let Some((mut old, _)) = self.tables.insert(name.to_string(), (table, AtomicU64::new(*tick))) {
+            old.close()?;
+        }
+        while self.tables.len() > self.capacity {
+            let Some(lru) = self.tables.iter().filter(|(n, _)| *n != name).min_by_key(|(_, (_, used))| used.load(Ordering::Relaxed)).map(|(n, _)| n.clone())
+            else {
+                break;
+            };
+            let (mut t, _) = self.tables.remove(&lru).unwrap();
+            t.close()?;
+        }
+        Ok(&mut self.tables.get_mut(name).unwrap().0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{run, select, TestDir};
+    use crate::wal::{self, SyncPolicy, Wal};
+    use crate::{Table, TableManager};
+
+    fn table() -> Box<dyn TableLike> {
+        Box::new(Table::default())
+    }
+
+    #[test]
+    fn the_table_used_longest_ago_is_closed() {
+        let mut c = TableCache::new(2);
+        c.insert("a", table()).unwrap();
+        c.insert("b", table()).unwrap();
+        c.get_mut("a").unwrap();
+        c.insert("c", table()).unwrap();
+        // a table that was used isn't closed
+        assert!(c.contains("a") && !c.contains("b") && c.contains("c"));
+        // neither do reads through get
+        c.get("a").unwrap();
+        c.insert("d", table()).unwrap();
+        assert!(c.contains("a") && !c.contains("c") && c.contains("d"));
+        // there is always room for the table just opened
+        let mut c = TableCache::new(0);
+        c.insert("a", table()).unwrap();
+        c.insert("b", table()).unwrap();
+        assert_eq!(c.names().collect::<Vec<_>>(), vec!["b"]);
+    }
+
+    #[test]
+    fn closed_tables_are_opened_again_from_their_files() {
+        let _dir = TestDir::new("cache");
+        let wal = Wal::open(wal::LOG_PATH, SyncPolicy::Full).unwrap();
+        let mut tm = TableManager::new(1 << 20, 1, wal).unwrap();
+        let mut s = tm.session();
+        for (t, engine) in [("a", "TEXT"), ("b", "PAGED"), ("c", "PAGED")] {
+            run(&mut tm, &mut s, &format!("CREATE TABLE {t} (id INT PRIMARY KEY) ENGINE={engine};")).unwrap();
+        }
+        for i in 0..3 {
+            for t in ["a", "b", "c"] {
+                run(&mut tm, &mut s, &format!("INSERT INTO {t} VALUES ({i});")).unwrap();
+                assert_eq!(tm.tables.names().count(), 1);
+            }
+        }
+        for t in ["a", "b", "c"] {
+            assert_eq!(select(&mut tm, &mut s, &format!("SELECT id FROM {t};")), vec![vec!["0"], vec!["1"], vec!["2"]]);
+        }
+    }
+}
@@ -0,0 +1,507 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::query::{self, Eval, Projection};
+use crate::{ColumnEntry, TableCell, TableEntry, TableLikeError};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConstraintKind {
+    NotNull,
+    NoDefault,
+    Check,
+    // entry is the key value, parts joined by '-' like MySQL
+    DuplicateKey { entry: String },
+    // a child row without a parent row, name is the child table and fk the constraint
+    ForeignKeyChild { fk: String },
+    // a parent row still referenced by a RESTRICT foreign key
+    ForeignKeyParent { fk: String },
+}
+
+impl ConstraintKind {
+    // error text as MySQL words it, name is the column or the constraint
+    pub fn message(&self, name: &str) -> String {
+        match self {
+            Self::NotNull => format!("Column '{name}' cannot be null"),
+            Self::NoDefault => format!("Field '{name}' doesn't have a default value"),
+            Self::Check => format!("Check constraint '{name}' is violated."),
+            Self::DuplicateKey { entry } => format!("Duplicate entry '{entry}' for key '{name}'"),
+            Self::ForeignKeyChild { fk } => {
+                format!("Cannot add or update a child row: a foreign key constraint fails (`{name}`, {fk})")
+            }
+            Self::ForeignKeyParent { fk } => {
+                format!("Cannot delete or update a parent row: a foreign key constraint fails (`{name}`, {fk})")
+            }
+        }
+    }
+}
+
+// checks the rows written to a table against the constraints of its columns and its table
+// CHECKs, built once per write so CHECK expressions are only compiled once
+pub struct RowValidator {
+    not_null: Vec<(usize, String)>,
+    // constraint name, slots of the columns it reads and the compiled expression
+    checks: Vec<(String, Vec<usize>, Eval)>,
+}
+
+impl RowValidator {
+    pub fn new(cols: &[ColumnEntry], keys: &[KeyDef]) -> Result<RowValidator, TableLikeError> {
+        let lookup = cols
+            .iter()
+            .enumerate()
+            .map(|(ind, s)| (&s.col_name, ind))
+            .collect::<HashMap<_, _>>();
+        let mut not_null = Vec::new();
+        let mut checks = Vec::new();
+        let mut add = |name: String, expr: &str| -> Result<(), TableLikeError> {
+            let p = Projection::from_expr(&query::parse_expr_text(expr)?, cols)?;
+            let slots = p.col_name.iter().map(|n| lookup[n]).collect();
+            checks.push((name, slots, p.act_clo));
+            Ok(())
+        };
+        for (ind, c) in cols.iter().enumerate() {
+            if c.constraints.not_null {
+                not_null.push((ind, c.col_name.clone()));
+            }
+            for chk in &c.constraints.checks {
+                add(chk.name.clone().unwrap_or_else(|| c.col_name.clone()), &chk.expr)?;
+            }
+        }
+        for k in keys {
+            if let KeyKind::Check(expr) = &k.kind {
+                add(k.name.clone(), expr)?;
+            }
+        }
+        Ok(RowValidator { not_null, checks })
+    }
+
+    pub fn validate(&self, row: &TableEntry) -> Result<(), TableLikeError> {
+        for (ind, name) in &self.not_null {
+            if row.col_data[*ind].is_null() {
+                return Err(TableLikeError::ConstraintViolation { kind: ConstraintKind::NotNull, name: name.clone() });
+            }
+        }
+        // only a FALSE result violates a check, NULL lets the row through like in SQL
+        for (name, slots, clo) in &self.checks {
+            let v = slots.iter().map(|i| &row.col_data[*i]).collect::<Vec<_>>();
+            if clo(v.as_slice()).truth() == Some(false) {
+                return Err(TableLikeError::ConstraintViolation { kind: ConstraintKind::Check, name: name.clone() });
+            }
+        }
+        Ok(())
+    }
+}
+
+// validates the constraints of a new table and names its unnamed checks, the ones of its
+// columns first and then its table CHECKs
+pub fn prepare_schema(table: &str, cols: &mut [ColumnEntry], keys: &mut [KeyDef]) -> Result<(), TableLikeError> {
+    let mut n = 0;
+    for ind in 0..cols.len() {
+        let c = &cols[ind];
+        if c.constraints.default.is_some() {
+            let d = c.default_value(true).map_err(|_| {
+                TableLikeError::new(&format!("Invalid default value for '{}'", c.col_name))
+            })?;
+            if d.is_null() && c.constraints.not_null {
+                return Err(TableLikeError::new(&format!("Invalid default value for '{}'", c.col_name)));
+            }
+        }
+        for chk in &c.constraints.checks {
+            let p = Projection::from_expr(&query::parse_expr_text(&chk.expr)?, cols)?;
+            if p.col_name.iter().any(|r| r != &c.col_name) {
+                return Err(TableLikeError::new(&format!(
+                    "Column check constraint on '{}' references other column.",
+                    c.col_name
+                )));
+            }
+        }
+        for i in 0..cols[ind].constraints.checks.len() {
+            if cols[ind].constraints.checks[i].name.is_none() {
+                n = free_check_name(table, n, cols, keys);
+                cols[ind].constraints.checks[i].name = Some(format!("{table}_chk_{n}"));
+            }
+        }
+    }
+    for i in 0..keys.len() {
+        let KeyKind::Check(expr) = &keys[i].kind else {
+            continue;
+        };
+        // a table CHECK may read any column of the table
+        Projection::from_expr(&query::parse_expr_text(expr)?, cols)?;
+        if keys[i].name.is_empty() {
+            n = free_check_name(table, n, cols, keys);
+            keys[i].name = format!("{table}_chk_{n}");
+        }
+    }
+    let mut names = HashSet::new();
+    let table_checks = keys.iter().filter(|k| matches!(k.kind, KeyKind::Check(_))).map(|k| k.name.as_str());
+    for name in cols.iter().flat_map(|c| &c.constraints.checks).map(|c| c.name.as_deref().unwrap_or_default()).chain(table_checks) {
+        if !names.insert(name) {
+            return Err(TableLikeError::new(&format!("Duplicate check constraint name '{name}'.")));
+        }
+    }
+    Ok(())
+}
+
+// the number after n of the next <table>_chk_<n> name, skipping the names taken, by checks
+// of their own or kept by ALTER TABLE
+fn free_check_name(table: &str, mut n: usize, cols: &[ColumnEntry], keys: &[KeyDef]) -> usize {
+    let taken = |name: &str| {
+        cols.iter().flat_map(|c| &c.constraints.checks).any(|c| c.name.as_deref() == Some(name))
+            || keys.iter().any(|k| matches!(k.kind, KeyKind::Check(_)) && k.name == name)
+    };
+    n += 1;
+    while taken(&format!("{table}_chk_{n}")) {
+        n += 1;
+    }
+    n
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyKind {
+    Primary,
+    Unique,
+    // a plain INDEX, lets lookups skip the full scan without constraining anything
+    Index,
+    Foreign(ForeignRef),
+    // a CHECK of the table as SQL text, over any of its columns, that no index is kept for
+    Check(String),
+}
+
+impl KeyKind {
+    pub fn is_unique(&self) -> bool {
+        matches!(self, Self::Primary | Self::Unique)
+    }
+}
+
+// the REFERENCES part of a FOREIGN KEY
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForeignRef {
+    pub table: String,
+    pub cols: Vec<String>,
+    pub on_delete: RefAction,
+    pub on_update: RefAction,
+}
+
+// NO ACTION is RESTRICT like in MySQL
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RefAction {
+    #[default]
+    Restrict,
+    Cascade,
+    SetNull,
+}
+
+impl RefAction {
+    pub fn sql(&self) -> &'static str {
+        match self {
+            Self::Restrict => "RESTRICT",
+            Self::Cascade => "CASCADE",
+            Self::SetNull => "SET NULL",
+        }
+    }
+}
+
+// how the on-disk index of a key is kept, a hash index only finds rows by their whole key
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IndexAlgorithm {
+    #[default]
+    BTree,
+    Hash,
+}
+
+// PRIMARY KEY, UNIQUE, INDEX or FOREIGN KEY over one or more columns
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyDef {
+    pub kind: KeyKind,
+    // PRIMARY for the primary key, the first column for an unnamed unique key or index
+    // and <table>_ibfk_<n> for an unnamed foreign key
+    pub name: String,
+    pub cols: Vec<String>,
+    pub algorithm: IndexAlgorithm,
+}
+
+impl KeyDef {
+    // descriptor written to the table file header, read back by query::parse_key_desc
+    pub fn write_desc(&self) -> String {
+        let cols = self.cols.join(", ");
+        // BTREE is the default and left out
+        let using = if self.algorithm == IndexAlgorithm::Hash { " USING HASH" } else { "" };
+        match &self.kind {
+            KeyKind::Primary => format!("PRIMARY KEY ({cols}){using}"),
+            KeyKind::Unique => format!("UNIQUE KEY {} ({cols}){using}", self.name),
+            KeyKind::Index => format!("INDEX {} ({cols}){using}", self.name),
+            KeyKind::Foreign(r) => format!(
+                "CONSTRAINT {} FOREIGN KEY ({cols}) REFERENCES {} ({}) ON DELETE {} ON UPDATE {}",
+                self.name,
+                r.table,
+                r.cols.join(", "),
+                r.on_delete.sql(),
+                r.on_update.sql()
+            ),
+            KeyKind::Check(expr) => format!("CONSTRAINT {} CHECK ({expr})", self.name),
+        }
+    }
+
+    // the constraint as MySQL quotes it in foreign key errors
+    pub fn foreign_desc(&self) -> String {
+        let quote = |cols: &[String]| cols.iter().map(|c| format!("`{c}`")).collect::<Vec<_>>().join(", ");
+        match &self.kind {
+            KeyKind::Foreign(r) => format!(
+                "CONSTRAINT `{}` FOREIGN KEY ({}) REFERENCES `{}` ({})",
+                self.name,
+                quote(&self.cols),
+                r.table,
+                quote(&r.cols)
+            ),
+            _ => self.write_desc(),
+        }
+    }
+}
+
+// positions of the named columns, for reading key values out of rows
+pub fn slots_of(cols: &[ColumnEntry], names: &[String]) -> Result<Vec<usize>, TableLikeError> {
+    names
+        .iter()
+        .map(|n| {
+            cols.iter()
+                .position(|c| &c.col_name == n)
+                .ok_or_else(|| TableLikeError::new(&format!("Key column '{n}' doesn't exist in table")))
+        })
+        .collect()
+}
+
+// key values of every row for each key of a memory table, or of rows written all at once,
+// so duplicates are found without going through the rows again. Tables on disk look keys
+// up in their index files instead, see index::check_unique
+#[derive(Debug, Default)]
+pub struct KeyIndex {
+    keys: Vec<(KeyDef, Vec<usize>, HashSet<Vec<String>>)>,
+    // table name used in the error, keys are reported as <table>.<key>
+    table: String,
+}
+
+impl KeyIndex {
+    pub fn new(table: &str, cols: &[ColumnEntry], keys: &[KeyDef]) -> Result<KeyIndex, TableLikeError> {
+        let mut idx = KeyIndex { keys: Vec::with_capacity(keys.len()), table: table.to_string() };
+        // foreign keys aren't unique, they are checked against the key index of the table they reference
+        for k in keys.iter().filter(|k| k.kind.is_unique()) {
+            idx.keys.push((k.clone(), slots_of(cols, &k.cols)?, HashSet::new()));
+        }
+        Ok(idx)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn contains(&self, key: &str, entry: &[String]) -> bool {
+        self.keys.iter().any(|(def, _, set)| def.name == key && set.contains(entry))
+    }
+
+    // NULL never equals anything so a key with a NULL part can't be a duplicate
+    pub fn key_of(slots: &[usize], row: &TableEntry) -> Option<Vec<String>> {
+        slots
+            .iter()
+            .map(|i| {
+                let c = &row.col_data[*i];
+                (!c.is_null()).then(|| c.to_string())
+            })
+            .collect()
+    }
+
+    // adds the keys of all rows or none of them when one is a duplicate, a deleted
+    // version of a row doesn't hold its key anymore
+    pub fn insert_all<'a>(&mut self, rows: impl IntoIterator<Item = &'a TableEntry>) -> Result<(), TableLikeError> {
+        let mut added: Vec<(usize, Vec<String>)> = Vec::new();
+        let mut dup = None;
+        'rows: for row in rows.into_iter().filter(|r| r.version.is_live()) {
+            for (ind, (def, slots, set)) in self.keys.iter_mut().enumerate() {
+                let Some(key) = Self::key_of(slots, row) else {
+                    continue;
+                };
+                if set.contains(&key) {
+                    dup = Some((def.name.clone(), key.join("-")));
+                    break 'rows;
+                }
+                set.insert(key.clone());
+                added.push((ind, key));
+            }
+        }
+        match dup {
+            None => Ok(()),
+            Some((key, entry)) => {
+                for (i, k) in added {
+                    self.keys[i].2.remove(&k);
+                }
+                Err(duplicate_key(&self.table, &key, &entry))
+            }
+        }
+    }
+}
+
+// the error for a row repeating the value entry of a key, reported as <table>.<key>
+pub fn duplicate_key(table: &str, key: &str, entry: &str) -> TableLikeError {
+    TableLikeError::ConstraintViolation {
+        kind: ConstraintKind::DuplicateKey { entry: entry.to_string() },
+        name: format!("{table}.{key}"),
+    }
+}
+
+// checks the keys of a new table, names them and makes primary key columns NOT NULL
+pub fn prepare_keys(table: &str, cols: &mut [ColumnEntry], keys: &mut [KeyDef]) -> Result<(), TableLikeError> {
+    if keys.iter().filter(|k| k.kind == KeyKind::Primary).count() > 1 {
+        return Err(TableLikeError::new("Multiple primary key defined"));
+    }
+    // like MySQL there is at most one AUTO_INCREMENT column and it has to lead a key
+    let autos = cols.iter().filter(|c| c.constraints.auto_increment).collect::<Vec<_>>();
+    let leads = |c: &ColumnEntry| keys.iter().any(|k| !matches!(k.kind, KeyKind::Check(_)) && k.cols[0] == c.col_name);
+    if autos.len() > 1 || autos.iter().any(|c| !leads(c)) {
+        return Err(TableLikeError::new(
+            "Incorrect table definition; there can be only one auto column and it must be defined as a key",
+        ));
+    }
+    if let Some(c) = autos.iter().find(|c| !matches!(c.col_type, TableCell::Num(_))) {
+        return Err(TableLikeError::new(&format!("Incorrect column specifier for column '{}'", c.col_name)));
+    }
+    let mut names = HashSet::new();
+    let mut fks = 0;
+    // table CHECKs are named along with the checks of the columns, see prepare_schema
+    for k in keys.iter_mut().filter(|k| !matches!(k.kind, KeyKind::Check(_))) {
+        if let KeyKind::Foreign(r) = &k.kind {
+            if k.name.is_empty() {
+                fks += 1;
+                k.name = format!("{table}_ibfk_{fks}");
+            }
+            if r.cols.len() != k.cols.len() {
+                return Err(TableLikeError::new(&format!(
+                    "Failed to add the foreign key constraint '{}', the number of referencing and referenced columns differ",
+                    k.name
+                )));
+            }
+        }
+        for n in &k.cols {
+            let col = cols
+                .iter_mut()
+                .find(|c| &c.col_name == n)
+                .ok_or_else(|| TableLikeError::new(&format!("Key column '{n}' doesn't exist in table")))?;
+            match &k.kind {
+                KeyKind::Primary => col.constraints.not_null = true,
+                KeyKind::Foreign(r)
+                    if col.constraints.not_null && (r.on_delete == RefAction::SetNull || r.on_update == RefAction::SetNull) =>
+                {
+                    return Err(TableLikeError::new(&format!(
+                        "Column '{n}' cannot be NOT NULL: needed in a foreign key constraint '{}' SET NULL",
+                        k.name
+                    )))
+                }
+                _ => {}
+            }
+        }
+        if k.name.is_empty() {
+            // like MySQL an unnamed key is named after its first column, with a suffix if taken
+            let base = k.cols[0].clone();
+            let mut name = base.clone();
+            let mut n = 1;
+            while names.contains(&name) {
+                n += 1;
+                name = format!("{base}_{n}");
+            }
+            k.name = name;
+        }
+        if !names.insert(k.name.clone()) {
+            return Err(TableLikeError::new(&format!("Duplicate key name '{}'", k.name)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{error, run, select, TestDir};
+
+    #[test]
+    fn rows_are_checked_like_mysql() {
+        let dir = TestDir::new("constraints");
+        let mut tm = dir.manager();
+        let mut s = tm.session();
+        for engine in ["TEXT", "PAGED"] {
+            let create = format!(
+                "CREATE TABLE t{engine} (id INT PRIMARY KEY, name VARCHAR(10) NOT NULL, n INT DEFAULT 3 CHECK (n > 0), u INT UNIQUE) ENGINE={engine};"
+            );
+            let t = format!("t{engine}");
+            let sql = |v: &str| format!("INSERT INTO {t} {v};");
+            run(&mut tm, &mut s, &create).unwrap();
+            run(&mut tm, &mut s, &sql("(id, name) VALUES (1, 'a')")).unwrap();
+            let mut fails = |v: &str| error(&mut tm, &mut s, &sql(v));
+            assert_eq!(fails("(id) VALUES (2)"), "Field 'name' doesn't have a default value");
+            assert_eq!(fails("VALUES (2, NULL, 1, 1)"), "Column 'name' cannot be null");
+            assert_eq!(fails("VALUES (2, 'b', 0, 1)"), format!("Check constraint '{t}_chk_1' is violated."));
+            assert_eq!(fails("VALUES (2, 'abcdefghijk', 1, 1)"), "Data too long for column 'name'");
+            assert_eq!(fails("VALUES (1, 'b', 1, 1)"), format!("Duplicate entry '1' for key '{t}.PRIMARY'"));
+            // the rows of a statement are checked against each other too, and none is written
+            assert_eq!(fails("VALUES (4, 'd', 1, 5), (5, 'e', 1, 5)"), format!("Duplicate entry '5' for key '{t}.u'"));
+            // NULL is never a duplicate
+            run(&mut tm, &mut s, &sql("VALUES (2, 'b', 1, NULL), (3, 'c', 1, NULL)")).unwrap();
+            assert_eq!(error(&mut tm, &mut s, &format!("UPDATE {t} SET u = 9;")), format!("Duplicate entry '9' for key '{t}.u'"));
+            let rows = select(&mut tm, &mut s, &format!("SELECT id, n, u FROM {t};"));
+            assert_eq!(rows, vec![vec!["1", "3", "NULL"], vec!["2", "1", "NULL"], vec!["3", "1", "NULL"]]);
+        }
+    }
+
+    #[test]
+    fn table_checks_read_several_columns_and_outlive_a_restart() {
+        let dir = TestDir::new("table_checks");
+        for engine in ["TEXT", "PAGED"] {
+            let t = format!("t{engine}");
+            let mut tm = dir.manager();
+            let mut s = tm.session();
+            // line breaks in the SQL text of a DEFAULT or CHECK stay as they are
+            let create = format!(
+                "CREATE TABLE {t} (lo INT, hi INT, s VARCHAR(10) DEFAULT 'a\nb' CHECK (s <> 'x\r\ny'), CHECK (lo <=\nhi), CONSTRAINT small CHECK (hi < 100)) ENGINE={engine};"
+            );
+            run(&mut tm, &mut s, &create).unwrap();
+            let other = format!("CREATE TABLE {t}2 (lo INT, hi INT CHECK (lo <= hi));");
+            assert_eq!(error(&mut tm, &mut s, &other), "Column check constraint on 'hi' references other column.");
+            for restarted in [false, true] {
+                if restarted {
+                    drop(tm);
+                    tm = dir.manager();
+                    s = tm.session();
+                }
+                // NULL lets a row through like in a column check
+                run(&mut tm, &mut s, &format!("INSERT INTO {t} (lo, hi) VALUES ({}, 5), (NULL, 1);", restarted as i32)).unwrap();
+                let mut fails = |sql: &str| error(&mut tm, &mut s, sql);
+                assert_eq!(fails(&format!("INSERT INTO {t} (lo, hi) VALUES (2, 1);")), format!("Check constraint '{t}_chk_2' is violated."));
+                assert_eq!(fails(&format!("INSERT INTO {t} (lo, hi) VALUES (1, 100);")), "Check constraint 'small' is violated.");
+                assert_eq!(fails(&format!("INSERT INTO {t} VALUES (1, 2, 'x\r\ny');")), format!("Check constraint '{t}_chk_1' is violated."));
+                assert_eq!(fails(&format!("UPDATE {t} SET lo = 6;")), format!("Check constraint '{t}_chk_2' is violated."));
+                let uses = |c: &str| format!("Check constraint '{t}_chk_2' uses column '{c}', hence column cannot be dropped or renamed.");
+                assert_eq!(fails(&format!("ALTER TABLE {t} DROP COLUMN lo;")), uses("lo"));
+                assert_eq!(fails(&format!("ALTER TABLE {t} RENAME COLUMN hi TO top;")), uses("hi"));
+            }
+            // no index is kept for a check
+            assert_eq!(crate::index::index_files(&t).unwrap(), Vec::<String>::new());
+            let rows = select(&mut tm, &mut s, &format!("SELECT lo, hi, s FROM {t};"));
+            assert_eq!(rows, vec![vec!["0", "5", "a\nb"], vec!["1", "5", "a\nb"], vec!["NULL", "1", "a\nb"], vec!["NULL", "1", "a\nb"]]);
+        }
+    }
+
+    #[test]
+    fn keys_with_null_parts_have_no_entry() {
+        let cols = vec![ColumnEntry::new("a".to_string(), TableCell::Num(None)), ColumnEntry::new("b".to_string(), TableCell::Str(None))];
+        let key = KeyDef { kind: KeyKind::Unique, name: "ab".to_string(), cols: vec!["a".to_string(), "b".to_string()], algorithm: IndexAlgorithm::BTree };
+        let slots = slots_of(&cols, &key.cols).unwrap();
+        let row = |a: Option<i64>, b: Option<&str>| TableEntry {
+            col_data: vec![TableCell::Num(a), TableCell::Str(b.map(str::to_string))],
+            ..Default::default()
+        };
+        assert_eq!(KeyIndex::key_of(&slots, &row(Some(1), Some("x"))), Some(vec!["1".to_string(), "x".to_string()]));
+        assert_eq!(KeyIndex::key_of(&slots, &row(Some(1), None)), None);
+        let mut idx = KeyIndex::new("t", &cols, &[key]).unwrap();
+        idx.insert_all([&row(Some(1), None), &row(Some(1), None), &row(Some(1), Some("x"))]).unwrap();
+        let err = idx.insert_all([&row(Some(1), Some("x"))]).unwrap_err();
+        assert_eq!(err.to_string(), "Duplicate entry '1-x' for key 't.ab'");
+        assert!(idx.contains("ab", &["1".to_string(), "x".to_string()]));
+        assert!(slots_of(&cols, &["c".to_string()]).is_err());
+    }
+}
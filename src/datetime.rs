@@ -0,0 +1,428 @@
+/*
+
+Calendar types for DATE, TIME and DATETIME columns
+
+Everything is UTC, there is no time zone database involved.
+Text form is ISO-8601 (YYYY-MM-DD, HH:MM:SS, YYYY-MM-DD HH:MM:SS)
+and that is also what gets written to table files.
+
+Day number conversions are the ones from
+http://howardhinnant.github.io/date_algorithms.html
+
+*/
+
+use std::fmt::Display;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: i64 = 86400;
+// MySQL keeps TIME within -838:59:59 and 838:59:59
+const MAX_TIME_SECS: i64 = 838 * 3600 + 59 * 60 + 59;
+
+const MONTH_NAMES: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June", "July", "August", "September",
+    "October", "November", "December",
+];
+const DAY_NAMES: [&str; 7] = [
+    "Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday",
+];
+
+// days since 1970-01-01
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    days: i64,
+}
+
+// signed duration, may exceed 24 hours like MySQL TIME
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Time {
+    secs: i64,
+}
+
+// seconds since 1970-01-01 00:00:00
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    secs: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DateUnit {
+    Year,
+    Quarter,
+    Month,
+    Week,
+    Day,
+    Hour,
+    Minute,
+    Second,
+}
+
+impl DateUnit {
+    pub fn parse(inp: &str) -> Option<DateUnit> {
+        Some(match inp.to_ascii_uppercase().as_str() {
+            "YEAR" => Self::Year,
+            "QUARTER" => Self::Quarter,
+            "MONTH" => Self::Month,
+            "WEEK" => Self::Week,
+            "DAY" => Self::Day,
+            "HOUR" => Self::Hour,
+            "MINUTE" => Self::Minute,
+            "SECOND" => Self::Second,
+            _ => return None,
+        })
+    }
+
+    // true if adding this unit to a DATE keeps it a DATE
+    pub fn is_date_part(&self) -> bool {
+        matches!(self, Self::Year | Self::Quarter | Self::Month | Self::Week | Self::Day)
+    }
+}
+
+fn is_leap(y: i64) -> bool {
+    y % 4 == 0 && (y % 100 != 0 || y % 400 == 0)
+}
+
+fn days_in_month(y: i64, m: u32) -> u32 {
+    match m {
+        2 if is_leap(y) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (if m <= 2 { yoe + era * 400 + 1 } else { yoe + era * 400 }, m, d)
+}
+
+fn parse_fixed(inp: &str, len: usize) -> Option<u32> {
+    if inp.len() != len || !inp.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    inp.parse().ok()
+}
+
+impl Date {
+    pub fn from_ymd(y: i64, m: u32, d: u32) -> Option<Date> {
+        if !(0..=9999).contains(&y) || !(1..=12).contains(&m) || d < 1 || d > days_in_month(y, m) {
+            return None;
+        }
+        Some(Date {
+            days: days_from_civil(y, m, d),
+        })
+    }
+
+    pub fn parse(inp: &str) -> Option<Date> {
+        let mut it = inp.trim().splitn(3, '-');
+        let y = parse_fixed(it.next()?, 4)?;
+        let m = parse_fixed(it.next()?, 2)?;
+        let d = parse_fixed(it.next()?, 2)?;
+        Self::from_ymd(y as i64, m, d)
+    }
+
+    pub fn ymd(&self) -> (i64, u32, u32) {
+        civil_from_days(self.days)
+    }
+
+    // 0 for Sunday like DAYOFWEEK() - 1
+    pub fn weekday(&self) -> u32 {
+        (self.days + 4).rem_euclid(7) as u32
+    }
+
+    pub fn day_of_year(&self) -> u32 {
+        let (y, _, _) = self.ymd();
+        (self.days - days_from_civil(y, 1, 1)) as u32 + 1
+    }
+
+    // WEEK() in its default mode, weeks start on Sunday and the days before
+    // the first Sunday of the year are in week 0
+    pub fn week(&self) -> u32 {
+        let (y, _, _) = self.ymd();
+        let jan1 = Date {
+            days: days_from_civil(y, 1, 1),
+        };
+        let first_sunday = (7 - jan1.weekday()) % 7;
+        let yday = self.day_of_year() - 1;
+        if yday < first_sunday {
+            0
+        } else {
+            (yday - first_sunday) / 7 + 1
+        }
+    }
+
+    pub fn days_since_epoch(&self) -> i64 {
+        self.days
+    }
+
+    pub fn add_days(&self, n: i64) -> Option<Date> {
+        let (y, m, d) = civil_from_days(self.days.checked_add(n)?);
+        Self::from_ymd(y, m, d)
+    }
+
+    // clamps the day to the end of the month, 2024-01-31 + 1 MONTH is 2024-02-29
+    pub fn add_months(&self, n: i64) -> Option<Date> {
+        let (y, m, d) = self.ymd();
+        let total = y.checked_mul(12)?.checked_add(m as i64 - 1)?.checked_add(n)?;
+        let (ny, nm) = (total.div_euclid(12), total.rem_euclid(12) as u32 + 1);
+        if !(0..=9999).contains(&ny) {
+            return None;
+        }
+        Self::from_ymd(ny, nm, d.min(days_in_month(ny, nm)))
+    }
+}
+
+impl Display for Date {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (y, m, d) = self.ymd();
+        format!("{y:04}-{m:02}-{d:02}").fmt(f)
+    }
+}
+
+impl Time {
+    pub fn from_secs(secs: i64) -> Option<Time> {
+        if secs.abs() > MAX_TIME_SECS {
+            return None;
+        }
+        Some(Time { secs })
+    }
+
+    pub fn parse(inp: &str) -> Option<Time> {
+        let inp = inp.trim();
+        let (neg, inp) = match inp.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, inp),
+        };
+        // fractional seconds are dropped
+        let inp = inp.split('.').next()?;
+        let mut it = inp.split(':');
+        let h = it.next()?;
+        if h.is_empty() || h.len() > 3 || !h.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let h: i64 = h.parse().ok()?;
+        let m = parse_fixed(it.next()?, 2)? as i64;
+        let s = match it.next() {
+            Some(s) => parse_fixed(s, 2)? as i64,
+            None => 0,
+        };
+        if it.next().is_some() || m > 59 || s > 59 {
+            return None;
+        }
+        let secs = h * 3600 + m * 60 + s;
+        Self::from_secs(if neg { -secs } else { secs })
+    }
+
+    pub fn secs(&self) -> i64 {
+        self.secs
+    }
+}
+
+impl Display for Time {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let a = self.secs.abs();
+        let sign = if self.secs < 0 { "-" } else { "" };
+        format!("{sign}{:02}:{:02}:{:02}", a / 3600, a / 60 % 60, a % 60).fmt(f)
+    }
+}
+
+impl DateTime {
+    pub fn new(date: Date, secs_of_day: i64) -> DateTime {
+        DateTime {
+            secs: date.days * SECS_PER_DAY + secs_of_day,
+        }
+    }
+
+    pub fn now() -> DateTime {
+        let secs = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        DateTime { secs }
+    }
+
+    // accepts a plain date too, which is taken as midnight
+    pub fn parse(inp: &str) -> Option<DateTime> {
+        let inp = inp.trim();
+        let inp = inp.strip_suffix('Z').unwrap_or(inp);
+        if inp.len() <= 10 {
+            return Some(Self::new(Date::parse(inp)?, 0));
+        }
+        // byte 10 may fall inside a character of something that isn't a datetime at all
+        let (d, t) = (inp.get(..10)?, inp.get(10..)?);
+        let t = t.strip_prefix(['T', ' '])?;
+        let t = Time::parse(t)?;
+        if t.secs < 0 || t.secs >= SECS_PER_DAY {
+            return None;
+        }
+        Some(Self::new(Date::parse(d)?, t.secs))
+    }
+
+    pub fn date(&self) -> Date {
+        Date {
+            days: self.secs.div_euclid(SECS_PER_DAY),
+        }
+    }
+
+    pub fn time(&self) -> Time {
+        Time {
+            secs: self.secs.rem_euclid(SECS_PER_DAY),
+        }
+    }
+
+    pub fn add(&self, n: i64, unit: DateUnit) -> Option<DateTime> {
+        let secs = match unit {
+            DateUnit::Year => return self.add_months(n.checked_mul(12)?),
+            DateUnit::Quarter => return self.add_months(n.checked_mul(3)?),
+            DateUnit::Month => return self.add_months(n),
+            DateUnit::Week => n.checked_mul(7 * SECS_PER_DAY)?,
+            DateUnit::Day => n.checked_mul(SECS_PER_DAY)?,
+            DateUnit::Hour => n.checked_mul(3600)?,
+            DateUnit::Minute => n.checked_mul(60)?,
+            DateUnit::Second => n,
+        };
+        let r = DateTime {
+            secs: self.secs.checked_add(secs)?,
+        };
+        // keep within the range of DATE
+        Date::from_ymd(r.date().ymd().0, 1, 1)?;
+        Some(r)
+    }
+
+    fn add_months(&self, n: i64) -> Option<DateTime> {
+        Some(Self::new(self.date().add_months(n)?, self.time().secs))
+    }
+
+    pub fn extract(&self, unit: DateUnit) -> i64 {
+        let (y, m, d) = self.date().ymd();
+        let t = self.time().secs;
+        match unit {
+            DateUnit::Year => y,
+            DateUnit::Quarter => (m as i64 - 1) / 3 + 1,
+            DateUnit::Month => m as i64,
+            DateUnit::Week => self.date().week() as i64,
+            DateUnit::Day => d as i64,
+            DateUnit::Hour => t / 3600,
+            DateUnit::Minute => t / 60 % 60,
+            DateUnit::Second => t % 60,
+        }
+    }
+
+    // subset of the MySQL DATE_FORMAT specifiers
+    pub fn format(&self, fmt: &str) -> String {
+        let date = self.date();
+        let (y, m, d) = date.ymd();
+        let t = self.time().secs;
+        let (h, mi, s) = (t / 3600, t / 60 % 60, t % 60);
+        let h12 = if h % 12 == 0 { 12 } else { h % 12 };
+        let mut out = String::with_capacity(fmt.len() * 2);
+        let mut it = fmt.chars();
+        while let Some(c) = it.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match it.next() {
+                Some('Y') => out.push_str(&format!("{y:04}")),
+                Some('y') => out.push_str(&format!("{:02}", y % 100)),
+                Some('m') => out.push_str(&format!("{m:02}")),
+                Some('c') => out.push_str(&m.to_string()),
+                Some('M') => out.push_str(MONTH_NAMES[m as usize - 1]),
+                Some('b') => out.push_str(&MONTH_NAMES[m as usize - 1][..3]),
+                Some('d') => out.push_str(&format!("{d:02}")),
+                Some('e') => out.push_str(&d.to_string()),
+                Some('j') => out.push_str(&format!("{:03}", date.day_of_year())),
+                Some('W') => out.push_str(DAY_NAMES[date.weekday() as usize]),
+                Some('a') => out.push_str(&DAY_NAMES[date.weekday() as usize][..3]),
+                Some('w') => out.push_str(&date.weekday().to_string()),
+                Some('U') => out.push_str(&format!("{:02}", date.week())),
+                Some('H') => out.push_str(&format!("{h:02}")),
+                Some('k') => out.push_str(&h.to_string()),
+                Some('h') | Some('I') => out.push_str(&format!("{h12:02}")),
+                Some('l') => out.push_str(&h12.to_string()),
+                Some('i') => out.push_str(&format!("{mi:02}")),
+                Some('s') | Some('S') => out.push_str(&format!("{s:02}")),
+                Some('f') => out.push_str("000000"),
+                Some('p') => out.push_str(if h < 12 { "AM" } else { "PM" }),
+                Some('T') => out.push_str(&format!("{h:02}:{mi:02}:{s:02}")),
+                Some('r') => out.push_str(&format!(
+                    "{h12:02}:{mi:02}:{s:02} {}",
+                    if h < 12 { "AM" } else { "PM" }
+                )),
+                Some(o) => out.push(o),
+                None => out.push('%'),
+            }
+        }
+        out
+    }
+}
+
+impl Display for DateTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        format!("{} {}", self.date(), self.time()).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_prints_iso_8601() {
+        let d = DateTime::parse("2024-02-29T23:59:58Z").unwrap();
+        assert_eq!(d.to_string(), "2024-02-29 23:59:58");
+        assert_eq!(DateTime::parse("2024-02-29").unwrap().to_string(), "2024-02-29 00:00:00");
+        assert_eq!(Time::parse("-838:59:59.5").unwrap().to_string(), "-838:59:59");
+        assert!(Date::parse("2023-02-29").is_none());
+        assert!(Date::parse("2024-13-01").is_none());
+        assert!(Time::parse("12:60").is_none());
+        assert!(DateTime::parse("2024-01-01 24:00:00").is_none());
+    }
+
+    #[test]
+    fn non_ascii_input_is_not_a_datetime() {
+        assert!(DateTime::parse("2024-01-0é 12:00:00").is_none());
+        assert!(DateTime::parse("2024-01-01é12:00:00").is_none());
+        assert!(DateTime::parse("ééééééééééé").is_none());
+        assert!(Date::parse("２０２４-01-01").is_none());
+        assert!(Time::parse("1２:00").is_none());
+    }
+
+    #[test]
+    fn calendar_arithmetic() {
+        let d = Date::parse("2024-01-31").unwrap();
+        assert_eq!(d.add_months(1).unwrap().to_string(), "2024-02-29");
+        assert_eq!(d.add_months(-13).unwrap().to_string(), "2022-12-31");
+        assert_eq!(d.add_days(366).unwrap().to_string(), "2025-01-31");
+        assert!(Date::parse("9999-12-31").unwrap().add_days(1).is_none());
+        // 1970-01-01 was a Thursday
+        assert_eq!(Date::parse("1970-01-01").unwrap().weekday(), 4);
+        assert_eq!(Date::parse("2024-12-31").unwrap().day_of_year(), 366);
+        let t = DateTime::parse("2024-03-10 01:30:00").unwrap();
+        assert_eq!(t.add(-90, DateUnit::Minute).unwrap().to_string(), "2024-03-10 00:00:00");
+        assert_eq!(t.extract(DateUnit::Quarter), 1);
+    }
+
+    #[test]
+    fn formats_like_date_format() {
+        let t = DateTime::parse("2024-07-04 15:05:09").unwrap();
+        assert_eq!(t.format("%W %M %e, %Y %r"), "Thursday July 4, 2024 03:05:09 PM");
+        assert_eq!(t.format("%a %b %d %T %j %%é%"), "Thu Jul 04 15:05:09 186 %é%");
+    }
+}
@@ -4,29 +4,95 @@ File structure
 table name is file name
 encoding is UTF-8
 
+KeyDescStart    only written when the table has keys
+"<Key>" PRIMARY KEY (<col>, ...), UNIQUE KEY <name> (<col>, ...), INDEX <name> (<col>, ...),
+        CONSTRAINT <name> FOREIGN KEY (<col>, ...) REFERENCES <table> (<col>, ...) ON DELETE <action> ON UPDATE <action>
+        or CONSTRAINT <name> CHECK (<expr>), escaped like a String cell below
+KeyDescEnd
+
+VersionStart <end>  one for every schema rows were written under before an ALTER TABLE changed it
+"<ColName>"         in place, oldest first, with the rows before <end> and after the ones of the
+"<ColType>"         schema before, see alter.rs. Its columns are named as the ones of the table
+...                 they became, a dropped column has an empty name. Rows are read as rows of
+VersionEnd          the table by taking the cells of the columns of the same name, a column
+                    that was added gets its DEFAULT
+
 ColDescStart
 "<ColName>"
-"<ColType>" either String or Num
-ColDescEnd
+"<ColType>" escaped like a String cell below, one of String, Num, Bool, Date, Time, DateTime, Decimal(<precision>,<scale>) or Blob
+            String, Char, Blob and Binary take an optional (<length>), Num and Decimal may be followed by UNSIGNED
+            then the constraints of the column as in CREATE TABLE:
+            NOT NULL, DEFAULT <expr>, AUTO_INCREMENT and CONSTRAINT <name> CHECK (<expr>)
+ColDescEnd  padded with spaces, so the header can be written again in place as long as it fits
 
-RStart
-"<Data>"
+RStart          or RStart <created> <deleted> for a version of the row that isn't seen by every
+                transaction, with the ids of the transactions that created and deleted it, see mvcc.rs
+"<Data>" NULL is written unquoted as NULL so it can't be mistaken for the string 'NULL',
+older files have "NULL" for non String cells which still reads as NULL. A String has \\, \n, \r
+and \" in place of a backslash, line breaks and quotes so it stays on its line. Bool as "TRUE" or "FALSE",
+dates and times in ISO-8601 (YYYY-MM-DD, HH:MM:SS, YYYY-MM-DD HH:MM:SS),
+Blob as 0x followed by two hex digits per byte so any byte is safe to store
 ...
 REnd
 
+the next AUTO_INCREMENT value is kept in <table name>.auto as a decimal number,
+the tables with a foreign key to this one in <table name>.refs, see foreign_keys.rs,
+and a B-tree per key in <table name>.<key name>.idx, see index.rs
+
+tables created with ENGINE=PAGED keep the same header in a binary file of pages instead, see paged.rs
+
 */
 
+use std::io::{BufRead, BufReader, Read, Seek};
+use std::ops::Range;
 use std::slice::Iter;
 
 use const_format::concatcp;
 
-use crate::{ColumnEntry, NumType, Table, TableCell, TableEntry, NUM_BASE};
+use crate::constraints::KeyDef;
+use crate::datetime::{Date, DateTime, Time};
+use crate::mvcc::Version;
+use crate::{alter, decimal, query};
+use crate::{ColumnEntry, NumType, RowId, Table, TableCell, TableEntry, TableLikeError, NUM_BASE};
+
+// room left for the header to grow when a table file is written
+pub fn header_space(len: usize) -> usize {
+    (len * 2).next_multiple_of(512)
+}
+
+// a schema the rows before end were written under, see VersionStart
+#[derive(Clone, Debug, Default)]
+pub struct OldSchema {
+    pub end: u64,
+    pub cols: Vec<ColumnEntry>,
+    // for every column of the table the slot of its cell in these rows, or the value it gets
+    map: Vec<Result<usize, TableCell>>,
+}
+
+impl OldSchema {
+    pub fn new(end: u64, cols: Vec<ColumnEntry>) -> OldSchema {
+        OldSchema { end, cols, map: Vec::new() }
+    }
+
+    fn upgrade(&self, cells: Vec<TableCell>) -> Vec<TableCell> {
+        self.map
+            .iter()
+            .map(|m| match m {
+                Ok(slot) => cells[*slot].clone(),
+                Err(v) => v.clone(),
+            })
+            .collect()
+    }
+}
 
+// reads the header of a table file a line at a time, its rows are read by a RowReader
 #[derive(Default, Debug)]
 pub struct TableParser {
     pub table: Table,
     pub state: ParseState,
-    pub buffer: Vec<String>,
+    // quoted values of the current section
+    pub buffer: Vec<Option<String>>,
+    pub schemas: Vec<OldSchema>,
 }
 
 #[derive(Debug)]
@@ -72,103 +138,409 @@ impl TableParser {
     pub fn next(&mut self, mut inp: String) -> Result<(), &str> {
         let next_s = ParseState::next(&self.state, inp.as_str())?;
 
-        if inp.as_str() == "ColDescEnd" {
+        if inp.as_str() == "KeyDescEnd" {
+            for desc in self.buffer.drain(..).flatten() {
+                self.table.keys.push(query::parse_key_desc(&desc).map_err(|_| "Validation failed")?);
+            }
+        }
+
+        if let Some(end) = inp.strip_prefix("VersionStart ") {
+            let end = end.parse().map_err(|_| "Syntax Error")?;
+            self.schemas.push(OldSchema::new(end, Vec::new()));
+        }
+
+        if inp.as_str() == "VersionEnd" {
+            let cols = self.take_cols()?;
+            self.schemas.last_mut().unwrap().cols = cols;
+        }
+
+        if inp.trim_end() == "ColDescEnd" {
             //finished parsing columns
-            let r = PairIter {
-                inner: self.buffer.iter(),
-            };
-            for (name, value_type) in r {
-                self.table.col_names.push(ColumnEntry {
-                    col_name: name.to_owned(),
-                    col_type: match value_type.as_str() {
-                        "String" => Ok(TableCell::Str(None)),
-                        "Num" => Ok(TableCell::Num(None)),
-                        _ => Err("Validation failed"),
-                    }?,
-                })
+            self.table.col_names = self.take_cols()?;
+            for s in &mut self.schemas {
+                s.map = self
+                    .table
+                    .col_names
+                    .iter()
+                    .map(|c| match s.cols.iter().position(|o| o.col_name == c.col_name) {
+                        Some(slot) => Ok(Ok(slot)),
+                        None => alter::fill_value(c).map(Err).ok_or("Validation failed"),
+                    })
+                    .collect::<Result<_, _>>()?;
             }
-            self.buffer.clear();
         }
 
         if inp.ends_with('"') && inp.starts_with('"') {
             inp.pop();
             inp.remove(0);
-            self.buffer.push(inp);
-        } else if inp == "REnd" {
-            //buffer has row items
-            //so make an entry and append to them
-            if self.buffer.len() != self.table.col_names.len() {
-                return Err("Incorrect table Length");
-            }
+            self.buffer.push(Some(unescape(&inp)));
+        }
+
+        self.state = next_s;
+        Ok(())
+    }
 
-            let mut row = TableEntry {
-                col_data: Vec::new(),
+    // the columns described in the buffer, as pairs of a name and a type
+    fn take_cols(&mut self) -> Result<Vec<ColumnEntry>, &'static str> {
+        let r = PairIter {
+            inner: self.buffer.iter(),
+        };
+        let mut cols = Vec::new();
+        for (name, value_type) in r {
+            let (Some(name), Some(value_type)) = (name, value_type) else {
+                return Err("Validation failed");
             };
+            cols.push(query::parse_column_type(name, value_type).map_err(|_| "Validation failed")?)
+        }
+        self.buffer.clear();
+        Ok(cols)
+    }
+}
+
+// the header of a table file with the offset of its first row
+pub fn parse_header(reader: &mut impl BufRead) -> Result<(Table, Vec<OldSchema>, RowId), TableLikeError> {
+    let mut par = TableParser::default();
+    let mut pos = 0;
+    let mut line = String::new();
+    while par.state != ParseState::ExpectingRowStart {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+        if n == 0 {
+            return Err(TableLikeError::new("Syntax Error"));
+        }
+        pos += n as u64;
+        par.next(line.trim_end_matches(['\n', '\r']).to_string()).map_err(TableLikeError::new)?;
+    }
+    Ok((par.table, par.schemas, pos))
+}
+
+// reads the rows of a table file one at a time straight from a BufRead. The lines of a row
+// are read into one buffer that is kept from row to row and its cells are decoded from
+// there, so the only allocations are those of the Str and Blob cells handed out
+pub struct RowReader<R> {
+    reader: R,
+    cols: Vec<ColumnEntry>,
+    schemas: Vec<OldSchema>,
+    // the lines of the row being read and where its cells are in them, None for NULL
+    row: Vec<u8>,
+    cells: Vec<Option<Range<usize>>>,
+    // byte offset of the next line
+    pos: u64,
+}
+
+impl<R: BufRead> RowReader<R> {
+    // reader positioned at pos, the offset of a row, of a table with these columns whose
+    // rows may have been written under older schemas
+    pub fn new(reader: R, pos: u64, cols: Vec<ColumnEntry>, schemas: Vec<OldSchema>) -> RowReader<R> {
+        RowReader { reader, cols, schemas, row: Vec::new(), cells: Vec::new(), pos }
+    }
+
+    // appends the next line to the row, returns where it is without its line break
+    fn read_line(&mut self) -> std::io::Result<Option<Range<usize>>> {
+        let start = self.row.len();
+        let n = self.reader.read_until(b'\n', &mut self.row)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        self.pos += n as u64;
+        let mut end = self.row.len();
+        while end > start && matches!(self.row[end - 1], b'\n' | b'\r') {
+            end -= 1;
+        }
+        Ok(Some(start..end))
+    }
+
+    // the next row with the offset of its RStart line, None at the end of the file
+    pub fn next_row(&mut self) -> Option<Result<(RowId, TableEntry), TableLikeError>> {
+        let start = self.pos;
+        self.row.clear();
+        self.cells.clear();
+        let rstart = match self.read_line() {
+            Ok(Some(l)) => l,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e.into())),
+        };
+        // anything but an RStart line means no row starts there
+        let version = std::str::from_utf8(&self.row[rstart])
+            .ok()
+            .filter(|l| is_row_start(l))
+            .and_then(parse_version)
+            .ok_or_else(|| TableLikeError::new(&format!("No row with id {start}")));
+        Some(version.and_then(|version| self.read_row(start, version)))
+    }
 
-            for (col, bu) in self.table.col_names.iter().zip(self.buffer.iter()) {
-                //TODO handle NULL and EMPTY
-                row.col_data.push(match col.col_type {
-                    TableCell::Str(_) => {
-                        Result::<TableCell, &str>::Ok(TableCell::Str(Some(bu.to_string())))
-                    }
-                    TableCell::Num(_) => {
-                        let ry = NumType::from_str_radix(bu.as_str(), NUM_BASE).map_err(|_| {
-                            concatcp!("Failed to decode integer in base ", NUM_BASE)
-                        })?;
-                        Ok(TableCell::Num(Some(ry)))
-                    }
-                }?);
+    fn read_row(&mut self, start: RowId, version: Version) -> Result<(RowId, TableEntry), TableLikeError> {
+        loop {
+            let l = self.read_line()?.ok_or_else(|| TableLikeError::new("Syntax Error"))?;
+            match &self.row[l.clone()] {
+                b"REnd" => break,
+                b"NULL" => self.cells.push(None),
+                c if c.len() > 1 && c.starts_with(b"\"") && c.ends_with(b"\"") => self.cells.push(Some(l.start + 1..l.end - 1)),
+                _ => return Err(TableLikeError::new("Syntax Error")),
             }
+        }
+        let text = std::str::from_utf8(&self.row).map_err(|_| TableLikeError::new("Validation failed"))?;
+        let schema = self.schemas.iter().find(|s| start < s.end);
+        let cols = schema.map_or(&self.cols, |s| &s.cols);
+        if self.cells.len() != cols.len() {
+            return Err(TableLikeError::new("Incorrect table Length"));
+        }
+        let mut col_data = cols
+            .iter()
+            .zip(&self.cells)
+            .map(|(col, cell)| decode_cell(col, cell.clone().map(|r| &text[r])))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(TableLikeError::new)?;
+        // rows of an older schema are read as rows of the table
+        if let Some(s) = schema {
+            col_data = s.upgrade(col_data);
+        }
+        Ok((start, TableEntry { col_data, version }))
+    }
+}
 
-            self.table.all.push(row);
-            self.buffer.clear();
+impl<R: Read + Seek> RowReader<BufReader<R>> {
+    // the row whose RStart line is at rid, what is buffered is kept when rid is in it
+    pub fn row_at(&mut self, rid: RowId) -> Result<TableEntry, TableLikeError> {
+        self.reader.seek_relative(rid as i64 - self.pos as i64)?;
+        self.pos = rid;
+        match self.next_row() {
+            Some(r) => r.map(|(_, row)| row),
+            None => Err(TableLikeError::new(&format!("No row with id {rid}"))),
         }
+    }
+}
 
-        self.state = next_s;
-        Ok(())
+impl<R: BufRead> Iterator for RowReader<R> {
+    type Item = Result<TableEntry, TableLikeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_row().map(|r| r.map(|(_, row)| row))
+    }
+}
+
+// a cell from its line without the quotes, None for NULL
+fn decode_cell(col: &ColumnEntry, bu: Option<&str>) -> Result<TableCell, &'static str> {
+    let Some(bu) = bu else {
+        return Ok(col.col_type.null());
+    };
+    match col.col_type {
+        TableCell::Str(_) => Ok(TableCell::Str(Some(unescape(bu)))),
+        _ if bu == "NULL" => Ok(col.col_type.null()),
+        TableCell::Bool(_) => match bu {
+            "TRUE" => Ok(TableCell::Bool(Some(true))),
+            "FALSE" => Ok(TableCell::Bool(Some(false))),
+            _ => Err("Failed to decode boolean"),
+        },
+        TableCell::Date(_) => Date::parse(bu)
+            .map(|d| TableCell::Date(Some(d)))
+            .ok_or("Failed to decode date"),
+        TableCell::Time(_) => Time::parse(bu)
+            .map(|t| TableCell::Time(Some(t)))
+            .ok_or("Failed to decode time"),
+        TableCell::DateTime(_) => DateTime::parse(bu)
+            .map(|t| TableCell::DateTime(Some(t)))
+            .ok_or("Failed to decode datetime"),
+        TableCell::Blob(_) => bu
+            .strip_prefix("0x")
+            .and_then(decode_hex)
+            .map(|b| TableCell::Blob(Some(b)))
+            .ok_or("Failed to decode blob"),
+        TableCell::Decimal(_, spec) => decimal::parse(bu)
+            .and_then(|(v, s)| decimal::rescale(v, s, spec.scale))
+            .map(|v| TableCell::Decimal(Some(v), spec))
+            .ok_or("Failed to decode decimal"),
+        TableCell::Num(_) => {
+            let ry = NumType::from_str_radix(bu, NUM_BASE).map_err(|_| {
+                concatcp!("Failed to decode integer in base ", NUM_BASE)
+            })?;
+            Ok(TableCell::Num(Some(ry)))
+        }
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
 pub enum ParseState {
+    #[default]
     ExpectingColStart,
+    ExpectingKeyDesc,
+    ExpectingVersionColName,
+    ExpectingVersionColValue,
     ExpectingColName,
     ExpectingColValue,
+    // the header is done, the rows are read by a RowReader
     ExpectingRowStart,
-    ExpectingCellValue,
-}
-
-impl Default for ParseState {
-    fn default() -> Self {
-        Self::ExpectingColStart
-    }
 }
 
 impl ParseState {
     pub fn next(prev_state: &Self, inp: &str) -> Result<ParseState, &'static str> {
         match prev_state {
             Self::ExpectingColStart if inp == "ColDescStart" => Ok(Self::ExpectingColName),
+            Self::ExpectingColStart if inp == "KeyDescStart" => Ok(Self::ExpectingKeyDesc),
+            Self::ExpectingColStart if inp.starts_with("VersionStart ") => Ok(Self::ExpectingVersionColName),
+            Self::ExpectingVersionColName if inp.ends_with('"') && inp.starts_with('"') => {
+                Ok(Self::ExpectingVersionColValue)
+            }
+            Self::ExpectingVersionColName if inp == "VersionEnd" => Ok(Self::ExpectingColStart),
+            Self::ExpectingVersionColValue if inp.len() > 1 && inp.ends_with('"') && inp.starts_with('"') => {
+                Ok(Self::ExpectingVersionColName)
+            }
+            Self::ExpectingKeyDesc if inp.len() > 1 && inp.ends_with('"') && inp.starts_with('"') => {
+                Ok(Self::ExpectingKeyDesc)
+            }
+            Self::ExpectingKeyDesc if inp == "KeyDescEnd" => Ok(Self::ExpectingColStart),
             Self::ExpectingColName if inp.ends_with('"') && inp.starts_with('"') => {
                 Ok(Self::ExpectingColValue)
             }
-            Self::ExpectingColName if inp == "ColDescEnd" => Ok(Self::ExpectingRowStart),
-            Self::ExpectingColValue
-                if inp.ends_with('"')
-                    && inp.starts_with('"')
-                    && (inp == "\"String\"" || inp == "\"Num\"") =>
-            {
+            Self::ExpectingColName if inp.trim_end() == "ColDescEnd" => Ok(Self::ExpectingRowStart),
+            //type descriptors are validated once all columns are read
+            Self::ExpectingColValue if inp.len() > 1 && inp.ends_with('"') && inp.starts_with('"') => {
                 Ok(Self::ExpectingColName)
             }
-            Self::ExpectingRowStart if inp == "RStart" => Ok(Self::ExpectingCellValue),
-            Self::ExpectingCellValue if inp.ends_with('"') && inp.starts_with('"') => {
-                Ok(Self::ExpectingCellValue)
-            }
-            Self::ExpectingCellValue if inp == "REnd" => Ok(Self::ExpectingRowStart),
             _ => Err("Syntax Error"),
         }
     }
 }
 
+// RStart with or without the version of the row
+pub fn is_row_start(line: &str) -> bool {
+    line == "RStart" || line.starts_with("RStart ")
+}
+
+fn parse_version(line: &str) -> Option<Version> {
+    match line.strip_prefix("RStart")? {
+        "" => Some(Version::default()),
+        ids => {
+            let (created, deleted) = ids.strip_prefix(' ')?.split_once(' ')?;
+            Some(Version { created: created.parse().ok()?, deleted: deleted.parse().ok()? })
+        }
+    }
+}
+
+// the lines of the table file before the rows, KeyDescStart to ColDescEnd
+pub fn encode_header(cols: &[ColumnEntry], keys: &[KeyDef]) -> String {
+    encode_header_with(cols, keys, &[])
+}
+
+// with the older schemas of the rows, see VersionStart
+pub fn encode_header_with(cols: &[ColumnEntry], keys: &[KeyDef], schemas: &[OldSchema]) -> String {
+    let mut out = String::new();
+    if !keys.is_empty() {
+        out.push_str("KeyDescStart\n");
+        for k in keys {
+            out.push_str(&format!("\"{}\"\n", escape(&k.write_desc())));
+        }
+        out.push_str("KeyDescEnd\n");
+    }
+    for s in schemas {
+        out.push_str(&format!("VersionStart {}\n", s.end));
+        out.push_str(&encode_cols(&s.cols));
+        out.push_str("VersionEnd\n");
+    }
+    out.push_str("ColDescStart\n");
+    out.push_str(&encode_cols(cols));
+    out.push_str("ColDescEnd\n");
+    out
+}
+
+fn encode_cols(cols: &[ColumnEntry]) -> String {
+    cols.iter().map(|c| format!("\"{}\"\n\"{}\"\n", c.col_name, escape(&c.write_type()))).collect()
+}
+
+// the header padded to len bytes, None if it doesn't fit
+pub fn pad_header(mut header: String, len: usize) -> Option<String> {
+    let pad = len.checked_sub(header.len())?;
+    header.pop();
+    header.extend(std::iter::repeat_n(' ', pad));
+    header.push('\n');
+    Some(header)
+}
+
+// a cell as a line of the table file
+pub fn encode_cell(cell: &TableCell) -> String {
+    match cell {
+        _ if cell.is_null() => "NULL".to_string(),
+        TableCell::Str(Some(s)) => format!("\"{}\"", escape(s)),
+        _ => format!("\"{cell}\""),
+    }
+}
+
+// a string with the characters that would end its line or its quotes escaped, see unescape
+fn escape(inp: &str) -> String {
+    let mut out = String::with_capacity(inp.len());
+    for c in inp.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '"' => out.push_str("\\\""),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// the string escape was given, a backslash before anything else is kept as it is
+fn unescape(inp: &str) -> String {
+    if !inp.contains('\\') {
+        return inp.to_string();
+    }
+    let mut out = String::with_capacity(inp.len());
+    let mut chars = inp.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(c @ ('\\' | '"')) => out.push(c),
+            Some(c) => {
+                out.push('\\');
+                out.push(c);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+// a row as the lines of the table file, RStart to REnd
+pub fn encode_row(row: &TableEntry) -> String {
+    let mut out = match row.version == Version::default() {
+        true => String::from("RStart\n"),
+        false => format!("RStart {} {}\n", row.version.created, row.version.deleted),
+    };
+    for cell in &row.col_data {
+        out.push_str(&encode_cell(cell));
+        out.push('\n');
+    }
+    out.push_str("REnd\n");
+    out
+}
+
+pub fn encode_hex(inp: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+    let mut out = String::with_capacity(inp.len() * 2);
+    for b in inp {
+        out.push(DIGITS[(b >> 4) as usize] as char);
+        out.push(DIGITS[(b & 0xF) as usize] as char);
+    }
+    out
+}
+
+// None unless every character is a hex digit and there is an even number of them
+pub fn decode_hex(inp: &str) -> Option<Vec<u8>> {
+    if !inp.len().is_multiple_of(2) {
+        return None;
+    }
+    inp.as_bytes()
+        .chunks(2)
+        .map(|c| Some((char::from(c[0]).to_digit(16)? * 16 + char::from(c[1]).to_digit(16)?) as u8))
+        .collect()
+}
+
 //ISSUE: make better generic
 struct PairIter<'a, T> {
     inner: Iter<'a, T>,
@@ -186,3 +558,139 @@ impl<'a, T> Iterator for PairIter<'a, T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn read_back(cols: Vec<ColumnEntry>, rows: &[TableEntry]) -> Vec<Vec<TableCell>> {
+        let text = rows.iter().map(encode_row).collect::<String>();
+        RowReader::new(Cursor::new(text.into_bytes()), 0, cols, Vec::new())
+            .map(|r| r.unwrap().col_data)
+            .collect()
+    }
+
+    #[test]
+    fn strings_keep_line_breaks_quotes_and_backslashes() {
+        let cols = vec![ColumnEntry::new("s".to_string(), TableCell::Str(None))];
+        let values = ["two\nlines", "cr\r\nlf", "\"quoted\"", "back\\slash", "\\n", "\\", "ends with \\"];
+        let rows = values
+            .iter()
+            .map(|v| TableEntry { col_data: vec![TableCell::Str(Some(v.to_string()))], ..Default::default() })
+            .collect::<Vec<_>>();
+        let got = read_back(cols, &rows);
+        assert_eq!(got, rows.iter().map(|r| r.col_data.clone()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn null_is_not_the_string_null() {
+        let cols = vec![
+            ColumnEntry::new("s".to_string(), TableCell::Str(None)),
+            ColumnEntry::new("n".to_string(), TableCell::Num(None)),
+        ];
+        let row = TableEntry {
+            col_data: vec![TableCell::Str(None), TableCell::Num(None)],
+            ..Default::default()
+        };
+        let text = TableEntry {
+            col_data: vec![TableCell::Str(Some("NULL".to_string())), TableCell::Num(Some(-7))],
+            ..Default::default()
+        };
+        assert_eq!(read_back(cols, &[row.clone(), text.clone()]), vec![row.col_data, text.col_data]);
+    }
+
+    #[test]
+    fn rows_of_older_schemas_are_upgraded() {
+        let header = "VersionStart 200\n\"a\"\n\"Num\"\n\"gone\"\n\"String\"\nVersionEnd\n\
+                      ColDescStart\n\"a\"\n\"Num\"\n\"b\"\n\"String DEFAULT 'x'\"\nColDescEnd\n";
+        let mut reader = Cursor::new(header.as_bytes().to_vec());
+        let (table, schemas, start) = parse_header(&mut reader).unwrap();
+        assert_eq!(start, header.len() as u64);
+        assert_eq!(schemas.len(), 1);
+        let old = "RStart\n\"1\"\n\"dropped\"\nREnd\n";
+        let new = "RStart\n\"2\"\n\"y\"\nREnd\n";
+        // the old row ends before 200, the new one after it
+        let mut text = header.to_string() + old;
+        text.extend(std::iter::repeat_n(' ', 200 - text.len()));
+        text.push('\n');
+        let new_at = text.len() as u64;
+        text.push_str(new);
+        let mut file = Cursor::new(text.into_bytes());
+        file.set_position(start);
+        let mut rows = RowReader::new(BufReader::new(file), start, table.col_names, schemas);
+        let (rid, first) = rows.next_row().unwrap().unwrap();
+        assert_eq!(rid, start);
+        assert_eq!(first.col_data, vec![TableCell::Num(Some(1)), TableCell::Str(Some("x".to_string()))]);
+        // the padding isn't a row
+        assert!(rows.next_row().unwrap().is_err());
+        let second = rows.row_at(new_at).unwrap();
+        assert_eq!(second.col_data, vec![TableCell::Num(Some(2)), TableCell::Str(Some("y".to_string()))]);
+        assert!(rows.next().is_none());
+    }
+
+    #[test]
+    fn rows_are_read_again_by_their_offsets() {
+        let cols = vec![ColumnEntry::new("n".to_string(), TableCell::Num(None))];
+        let rows = (1..=3)
+            .map(|n| TableEntry { col_data: vec![TableCell::Num(Some(n))], version: Version { created: n as u64, deleted: 0 } })
+            .collect::<Vec<_>>();
+        // line breaks of either kind
+        let text = rows.iter().map(encode_row).collect::<String>().replacen("\n", "\r\n", 3);
+        let mut reader = RowReader::new(BufReader::new(Cursor::new(text.into_bytes())), 0, cols, Vec::new());
+        let offsets = std::iter::from_fn(|| reader.next_row()).map(|r| r.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(offsets.len(), 3);
+        for (rid, row) in offsets.iter().zip(&rows).rev() {
+            let got = reader.row_at(*rid).unwrap();
+            assert_eq!((got.col_data, got.version), (row.col_data.clone(), row.version));
+        }
+        assert!(reader.row_at(offsets[1] + 1).is_err());
+        assert!(reader.row_at(1 << 20).is_err());
+    }
+
+    #[test]
+    fn broken_rows_are_errors() {
+        let cols = vec![
+            ColumnEntry::new("n".to_string(), TableCell::Num(None)),
+            ColumnEntry::new("s".to_string(), TableCell::Str(None)),
+        ];
+        let texts: [&[u8]; 11] = [
+            b"RStart\n\"1\"\n",
+            b"RStart\n\"1\"\n\"a\"\n",
+            b"RStart\n\"1\"\nREnd\n",
+            b"RStart\n\"1\"\n\"a\"\n\"b\"\nREnd\n",
+            b"RStart\n\"x\"\n\"a\"\nREnd\n",
+            b"RStart\n1\n\"a\"\nREnd\n",
+            b"RStart\n\"\n\"a\"\nREnd\n",
+            b"RStart 1\n\"1\"\n\"a\"\nREnd\n",
+            b"RStart x 0\n\"1\"\n\"a\"\nREnd\n",
+            b"Start\n\"1\"\n\"a\"\nREnd\n",
+            // not UTF-8
+            b"RStart\n\"1\"\n\"\xC3\"\nREnd\n",
+        ];
+        for text in texts {
+            let mut reader = RowReader::new(Cursor::new(text), 0, cols.clone(), Vec::new());
+            assert!(reader.next().unwrap().is_err(), "{}", String::from_utf8_lossy(text));
+        }
+    }
+
+    #[test]
+    fn blobs_keep_every_byte() {
+        let cols = vec![ColumnEntry::new("b".to_string(), TableCell::Blob(None))];
+        let rows = [(0..=255).collect::<Vec<u8>>(), Vec::new(), b"NULL".to_vec()]
+            .map(|b| TableEntry { col_data: vec![TableCell::Blob(Some(b))], ..Default::default() });
+        assert_eq!(read_back(cols, &rows), rows.iter().map(|r| r.col_data.clone()).collect::<Vec<_>>());
+        assert_eq!(encode_hex(&[0, 0xAB, 0xFF]), "00ABFF");
+        assert_eq!(decode_hex("00abFF"), Some(vec![0, 0xAB, 0xFF]));
+        for bad in ["0", "0g", "\u{e9}", "+1"] {
+            assert_eq!(decode_hex(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn unknown_escapes_are_kept() {
+        assert_eq!(unescape("a\\tb"), "a\\tb");
+        assert_eq!(unescape("plain"), "plain");
+    }
+}
@@ -0,0 +1,272 @@
+/*
+
+Fixed point arithmetic for DECIMAL(p, s) columns
+
+A value is kept unscaled in an i128 next to the scale it is expressed in,
+so 12.50 in a DECIMAL(5, 2) is 1250. Precision is capped at 38 digits since
+that is what fits in an i128. Rounding is half away from zero like MySQL.
+
+*/
+
+use std::cmp::Ordering;
+
+pub type DecimalType = i128;
+
+pub const MAX_PRECISION: u8 = 38;
+pub const MAX_SCALE: u8 = 30;
+// extra digits of scale a division gets, div_precision_increment in MySQL
+pub const DIV_SCALE_INCREMENT: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DecimalSpec {
+    pub precision: u8,
+    pub scale: u8,
+}
+
+impl Default for DecimalSpec {
+    // DECIMAL without arguments is DECIMAL(10, 0)
+    fn default() -> Self {
+        DecimalSpec {
+            precision: 10,
+            scale: 0,
+        }
+    }
+}
+
+impl DecimalSpec {
+    pub fn new(precision: u32, scale: u32) -> Result<DecimalSpec, String> {
+        if precision == 0 || precision > MAX_PRECISION as u32 {
+            return Err(format!(
+                "Too-big precision {precision} specified, maximum is {MAX_PRECISION}"
+            ));
+        }
+        if scale > MAX_SCALE as u32 {
+            return Err(format!("Too-big scale {scale} specified, maximum is {MAX_SCALE}"));
+        }
+        if scale > precision {
+            return Err("For DECIMAL(M,D) M must be >= D".to_string());
+        }
+        Ok(DecimalSpec {
+            precision: precision as u8,
+            scale: scale as u8,
+        })
+    }
+
+    // spec an integer is treated as when it meets a decimal
+    pub fn integer() -> DecimalSpec {
+        DecimalSpec {
+            precision: 19,
+            scale: 0,
+        }
+    }
+
+    // builds a spec from the integer and fraction digits a result needs
+    fn capped(int_digits: u32, scale: u32) -> DecimalSpec {
+        let scale = scale.min(MAX_SCALE as u32);
+        DecimalSpec {
+            precision: (int_digits + scale).clamp(1, MAX_PRECISION as u32) as u8,
+            scale: scale as u8,
+        }
+    }
+
+    fn int_digits(&self) -> u32 {
+        (self.precision - self.scale) as u32
+    }
+
+    pub fn for_add(a: DecimalSpec, b: DecimalSpec) -> DecimalSpec {
+        Self::capped(
+            a.int_digits().max(b.int_digits()) + 1,
+            a.scale.max(b.scale) as u32,
+        )
+    }
+
+    pub fn for_mul(a: DecimalSpec, b: DecimalSpec) -> DecimalSpec {
+        Self::capped(a.int_digits() + b.int_digits(), (a.scale + b.scale) as u32)
+    }
+
+    pub fn for_div(a: DecimalSpec, b: DecimalSpec) -> DecimalSpec {
+        Self::capped(
+            a.int_digits() + b.scale as u32,
+            (a.scale + DIV_SCALE_INCREMENT) as u32,
+        )
+    }
+
+    pub fn for_rem(a: DecimalSpec, b: DecimalSpec) -> DecimalSpec {
+        Self::capped(a.int_digits().max(b.int_digits()), a.scale.max(b.scale) as u32)
+    }
+
+    // true if the unscaled value has no more digits than the precision allows
+    pub fn fits(&self, v: DecimalType) -> bool {
+        v.unsigned_abs() < pow10(self.precision).unsigned_abs()
+    }
+}
+
+pub fn pow10(n: u8) -> DecimalType {
+    (10 as DecimalType).pow(n as u32)
+}
+
+// n / d rounded half away from zero
+fn div_round(n: DecimalType, d: DecimalType) -> Option<DecimalType> {
+    let q = n.checked_div(d)?;
+    let r = (n % d).unsigned_abs();
+    if r >= d.unsigned_abs() - r {
+        Some(if (n < 0) == (d < 0) { q + 1 } else { q - 1 })
+    } else {
+        Some(q)
+    }
+}
+
+// rounds half away from zero when the scale goes down
+pub fn rescale(v: DecimalType, from: u8, to: u8) -> Option<DecimalType> {
+    match to.cmp(&from) {
+        Ordering::Equal => Some(v),
+        Ordering::Greater => v.checked_mul((10 as DecimalType).checked_pow((to - from) as u32)?),
+        // no value has that many digits, so every one of them is cut
+        Ordering::Less if from - to > MAX_PRECISION => Some(0),
+        Ordering::Less => div_round(v, pow10(from - to)),
+    }
+}
+
+// drops digits past the new scale instead of rounding, for TRUNCATE()
+pub fn truncate(v: DecimalType, from: u8, to: u8) -> Option<DecimalType> {
+    if to >= from {
+        return rescale(v, from, to);
+    }
+    if from - to > MAX_PRECISION {
+        return Some(0);
+    }
+    Some(v / pow10(from - to))
+}
+
+// value and scale of a decimal literal like -12.50
+pub fn parse(inp: &str) -> Option<(DecimalType, u8)> {
+    let inp = inp.trim();
+    let (neg, inp) = match inp.as_bytes().first() {
+        Some(b'-') => (true, &inp[1..]),
+        Some(b'+') => (false, &inp[1..]),
+        _ => (false, inp),
+    };
+    let (int, frac) = inp.split_once('.').unwrap_or((inp, ""));
+    if int.is_empty() && frac.is_empty()
+        || !int.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit())
+        || frac.len() > MAX_SCALE as usize
+    {
+        return None;
+    }
+    let mut v: DecimalType = 0;
+    for b in int.bytes().chain(frac.bytes()) {
+        v = v.checked_mul(10)?.checked_add((b - b'0') as DecimalType)?;
+    }
+    Some((if neg { -v } else { v }, frac.len() as u8))
+}
+
+pub fn format(v: DecimalType, scale: u8) -> String {
+    if scale == 0 {
+        return v.to_string();
+    }
+    let s = scale as usize;
+    let digits = format!("{:0>width$}", v.unsigned_abs(), width = s + 1);
+    let (int, frac) = digits.split_at(digits.len() - s);
+    format!("{}{int}.{frac}", if v < 0 { "-" } else { "" })
+}
+
+// digits a value needs as a literal, used to type decimal literals
+pub fn spec_of(v: DecimalType, scale: u8) -> DecimalSpec {
+    let digits = v.unsigned_abs().to_string().len() as u32;
+    DecimalSpec::capped(digits.max(scale as u32 + 1) - scale as u32, scale as u32)
+}
+
+pub fn cmp(a: (DecimalType, u8), b: (DecimalType, u8)) -> Option<Ordering> {
+    let s = a.1.max(b.1);
+    Some(rescale(a.0, a.1, s)?.cmp(&rescale(b.0, b.1, s)?))
+}
+
+pub fn add(a: (DecimalType, u8), b: (DecimalType, u8), out: DecimalSpec) -> Option<DecimalType> {
+    let s = a.1.max(b.1);
+    let r = rescale(a.0, a.1, s)?.checked_add(rescale(b.0, b.1, s)?)?;
+    rescale(r, s, out.scale).filter(|v| out.fits(*v))
+}
+
+pub fn sub(a: (DecimalType, u8), b: (DecimalType, u8), out: DecimalSpec) -> Option<DecimalType> {
+    add(a, (b.0.checked_neg()?, b.1), out)
+}
+
+pub fn mul(a: (DecimalType, u8), b: (DecimalType, u8), out: DecimalSpec) -> Option<DecimalType> {
+    let r = a.0.checked_mul(b.0)?;
+    rescale(r, a.1 + b.1, out.scale).filter(|v| out.fits(*v))
+}
+
+// NULL on division by zero like MySQL
+pub fn div(a: (DecimalType, u8), b: (DecimalType, u8), out: DecimalSpec) -> Option<DecimalType> {
+    if b.0 == 0 {
+        return None;
+    }
+    // a / 10^sa / (b / 10^sb) * 10^out = a * 10^(sb + out - sa) / b
+    let e = b.1 as i32 + out.scale as i32 - a.1 as i32;
+    let r = if e >= 0 {
+        div_round(a.0.checked_mul(pow10(e as u8))?, b.0)?
+    } else {
+        div_round(a.0, b.0.checked_mul(pow10((-e) as u8))?)?
+    };
+    Some(r).filter(|v| out.fits(*v))
+}
+
+pub fn rem(a: (DecimalType, u8), b: (DecimalType, u8), out: DecimalSpec) -> Option<DecimalType> {
+    let s = a.1.max(b.1);
+    let r = rescale(a.0, a.1, s)?.checked_rem(rescale(b.0, b.1, s)?)?;
+    rescale(r, s, out.scale).filter(|v| out.fits(*v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(precision: u32, scale: u32) -> DecimalSpec {
+        DecimalSpec::new(precision, scale).unwrap()
+    }
+
+    #[test]
+    fn parses_and_formats() {
+        assert_eq!(parse("-12.50"), Some((-1250, 2)));
+        assert_eq!(parse(".5"), Some((5, 1)));
+        assert_eq!(parse("7."), Some((7, 0)));
+        assert_eq!(parse("."), None);
+        assert_eq!(parse("1e5"), None);
+        assert_eq!(parse(&"9".repeat(40)), None);
+        assert_eq!(format(-5, 3), "-0.005");
+        assert_eq!(format(1250, 2), "12.50");
+        assert!(DecimalSpec::new(39, 0).is_err());
+        assert!(DecimalSpec::new(5, 6).is_err());
+    }
+
+    #[test]
+    fn rounds_half_away_from_zero() {
+        assert_eq!(rescale(125, 2, 1), Some(13));
+        assert_eq!(rescale(-125, 2, 1), Some(-13));
+        assert_eq!(rescale(124, 2, 1), Some(12));
+        assert_eq!(truncate(-129, 2, 1), Some(-12));
+        assert_eq!(div((1, 0), (3, 0), spec(10, 4)), Some(3333));
+        assert_eq!(div((2, 0), (3, 0), spec(10, 4)), Some(6667));
+        assert_eq!(div((1, 0), (0, 0), spec(10, 4)), None);
+    }
+
+    #[test]
+    fn results_must_fit_their_spec() {
+        assert_eq!(add((99999, 2), (1, 2), spec(5, 2)), None);
+        assert_eq!(add((99999, 2), (1, 2), spec(6, 2)), Some(100000));
+        assert_eq!(mul((15, 1), (15, 1), spec(5, 1)), Some(23));
+        assert_eq!(rem((-75, 1), (2, 0), spec(3, 1)), Some(-15));
+        assert_eq!(cmp((10, 1), (1, 0)), Some(Ordering::Equal));
+    }
+
+    #[test]
+    fn scales_past_the_digits_of_an_i128() {
+        let max = DecimalType::MAX;
+        assert_eq!(rescale(max, 39, 0), Some(0));
+        assert_eq!(rescale(max, 38, 0), Some(2));
+        assert_eq!(rescale(-max, 200, 0), Some(0));
+        assert_eq!(truncate(max, 255, 0), Some(0));
+        assert_eq!(rescale(1, 0, 39), None);
+        assert_eq!(rescale(1, 0, 200), None);
+    }
+}
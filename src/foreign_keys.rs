@@ -0,0 +1,330 @@
+/*
+
+Foreign keys across the tables of a TableManager
+
+A FOREIGN KEY lives in the header of the referencing (child) table. So that a
+parent knows who references it without opening every table, each parent has a
+<table name>.refs file next to it listing its child tables one per line.
+
+Changes to a parent are planned before anything is written: the rows of the
+children that reference a deleted or changed key are found, RESTRICT fails the
+statement and CASCADE and SET NULL become steps that are applied once the
+parent itself has been changed.
+
+*/
+
+use std::collections::{HashMap, HashSet};
+
+use crate::buffer;
+use crate::locks::{self, LockMode};
+use crate::constraints::{slots_of, ConstraintKind, KeyDef, KeyIndex, KeyKind, RefAction};
+use crate::{ColumnEntry, TableCell, TableEntry, TableLikeError, TableManager};
+
+// a change to a child table caused by a change to its parent
+pub enum RefStep {
+    Delete {
+        table: String,
+        slots: Vec<usize>,
+        keys: HashSet<Vec<String>>,
+    },
+    // CASCADE moves the rows to the new key and SET NULL to a key of NULLs
+    Update {
+        table: String,
+        slots: Vec<usize>,
+        keys: HashMap<Vec<String>, Vec<TableCell>>,
+    },
+}
+
+fn refs_path(parent: &str) -> String {
+    format!("{parent}.refs")
+}
+
+impl TableManager {
+    // checks the foreign keys of a table about to be created against the tables they reference
+    pub fn check_references(&mut self, table: &str, cols: &[ColumnEntry], keys: &[KeyDef]) -> Result<(), TableLikeError> {
+        for k in keys {
+            let KeyKind::Foreign(r) = &k.kind else {
+                continue;
+            };
+            if r.table == table {
+                return Err(TableLikeError::new(&format!(
+                    "Foreign key '{}' references its own table, which is not supported",
+                    k.name
+                )));
+            }
+            let parent = self.open(&r.table).map_err(|_| {
+                TableLikeError::new(&format!(
+                    "Failed to open the referenced table '{}' for foreign key '{}'",
+                    r.table, k.name
+                ))
+            })?;
+            let p_cols = parent.get_cols()?;
+            if !parent.get_keys()?.iter().any(|pk| pk.kind.is_unique() && pk.cols == r.cols) {
+                return Err(TableLikeError::new(&format!(
+                    "Failed to add the foreign key constraint. Missing index for constraint '{}' in the referenced table '{}'",
+                    k.name, r.table
+                )));
+            }
+            for (c, pc) in k.cols.iter().zip(&r.cols) {
+                let child = &cols[slots_of(cols, std::slice::from_ref(c))?[0]];
+                let par = &p_cols[slots_of(&p_cols, std::slice::from_ref(pc))?[0]];
+                if child.col_type != par.col_type {
+                    return Err(TableLikeError::new(&format!(
+                        "Referencing column '{c}' and referenced column '{pc}' in foreign key constraint '{}' are incompatible.",
+                        k.name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // records a new table in the .refs file of every table it references
+    pub fn register_references(&self, table: &str, keys: &[KeyDef]) -> Result<(), TableLikeError> {
+        for k in keys {
+            let KeyKind::Foreign(r) = &k.kind else {
+                continue;
+            };
+            let mut children = self.children_of(&r.table)?;
+            if !children.iter().any(|c| c == table) {
+                children.push(table.to_string());
+                let refs = children.join("\n") + "\n";
+                buffer::lock(&self.pool).wal().replace(&refs_path(&r.table), Some(refs.as_bytes()))?;
+            }
+        }
+        Ok(())
+    }
+
+    // a table moved to a new name takes its .refs file along and is listed under the new name
+    // in those of the tables it references
+    pub fn rename_references(&self, from: &str, to: &str, keys: &[KeyDef]) -> Result<(), TableLikeError> {
+        let mut pool = buffer::lock(&self.pool);
+        if std::fs::exists(refs_path(from))? {
+            pool.move_file(&refs_path(from), &refs_path(to))?;
+        }
+        for k in keys {
+            let KeyKind::Foreign(r) = &k.kind else {
+                continue;
+            };
+            let children = self.children_of(&r.table)?;
+            if children.iter().any(|c| c == from) {
+                let refs = children.iter().map(|c| if c == from { to } else { c }).collect::<Vec<_>>().join("\n") + "\n";
+                pool.wal().replace(&refs_path(&r.table), Some(refs.as_bytes()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn children_of(&self, parent: &str) -> Result<Vec<String>, TableLikeError> {
+        match std::fs::read_to_string(refs_path(parent)) {
+            Ok(s) => Ok(s.lines().filter(|l| !l.is_empty()).map(str::to_string).collect()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // foreign keys of other tables that reference this one, with their table
+    pub fn referencing(&mut self, parent: &str) -> Result<Vec<(String, KeyDef)>, TableLikeError> {
+        let mut out = Vec::new();
+        for child in self.children_of(parent)? {
+            // a child whose file is gone references nothing anymore
+            let Ok(tb) = self.open(&child) else {
+                continue;
+            };
+            for k in tb.get_keys()? {
+                if matches!(&k.kind, KeyKind::Foreign(r) if r.table == parent) {
+                    out.push((child.clone(), k));
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    // every row written to a child table needs its key in the parent, unless part of it is NULL
+    pub fn check_parents(&mut self, table: &str, cols: &[ColumnEntry], keys: &[KeyDef], rows: &[&TableEntry]) -> Result<(), TableLikeError> {
+        for k in keys {
+            let KeyKind::Foreign(r) = &k.kind else {
+                continue;
+            };
+            let slots = slots_of(cols, &k.cols)?;
+            let parent = self.open(&r.table)?;
+            let Some(pk) = parent.get_keys()?.into_iter().find(|pk| pk.kind.is_unique() && pk.cols == r.cols) else {
+                return Err(TableLikeError::new(&format!("Missing index for constraint '{}'", k.name)));
+            };
+            // the values of the key of every row without a NULL in it, with their text for the lock
+            let entries = rows
+                .iter()
+                .filter_map(|row| Some((KeyIndex::key_of(&slots, row)?, slots.iter().map(|i| row.col_data[*i].clone()).collect::<Vec<_>>())))
+                .collect::<Vec<_>>();
+            // a parent row another transaction is changing is waited for
+            self.lock_names(&r.table, entries.iter().map(|(e, _)| locks::key_name(&pk.name, e)).collect(), LockMode::Shared)?;
+            let parent = self.open(&r.table)?;
+            for (_, values) in entries {
+                if !parent.contains_key(&pk.name, &values)? {
+                    return Err(TableLikeError::ConstraintViolation {
+                        kind: ConstraintKind::ForeignKeyChild { fk: k.foreign_desc() },
+                        name: table.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // works out what deleting (new row None) or changing rows of a table does to the tables
+    // referencing it, failing on RESTRICT before anything is written
+    pub fn plan_parent_changes(&mut self, table: &str, changes: &[(TableEntry, Option<TableEntry>)]) -> Result<Vec<RefStep>, TableLikeError> {
+        let mut steps = Vec::new();
+        if changes.is_empty() {
+            return Ok(steps);
+        }
+        let p_cols = self.open(table)?.get_cols()?;
+        for (child, fk) in self.referencing(table)? {
+            let KeyKind::Foreign(r) = &fk.kind else {
+                continue;
+            };
+            let p_slots = slots_of(&p_cols, &r.cols)?;
+            // old key to the new key values, None when the row goes away
+            let mut moved: HashMap<Vec<String>, Option<Vec<TableCell>>> = HashMap::new();
+            for (old, new) in changes {
+                let Some(old_key) = KeyIndex::key_of(&p_slots, old) else {
+                    continue;
+                };
+                match new {
+                    None => {
+                        moved.insert(old_key, None);
+                    }
+                    Some(n) if KeyIndex::key_of(&p_slots, n).as_ref() != Some(&old_key) => {
+                        moved.insert(old_key, Some(p_slots.iter().map(|i| n.col_data[*i].clone()).collect()));
+                    }
+                    Some(_) => {}
+                }
+            }
+            if moved.is_empty() {
+                continue;
+            }
+            let tb = self.open(&child)?;
+            let c_cols = tb.get_cols()?;
+            let c_slots = slots_of(&c_cols, &fk.cols)?;
+            let mut deleted = HashSet::new();
+            let mut updated = HashMap::new();
+            let mut child_changes = Vec::new();
+            for row in tb.get_rows() {
+                let row = row?;
+                if !row.version.is_live() {
+                    continue;
+                }
+                let Some(key) = KeyIndex::key_of(&c_slots, &row) else {
+                    continue;
+                };
+                let Some(to) = moved.get(&key) else {
+                    continue;
+                };
+                let action = if to.is_none() { r.on_delete } else { r.on_update };
+                let new_vals = match (action, to) {
+                    (RefAction::Restrict, _) => {
+                        return Err(TableLikeError::ConstraintViolation {
+                            kind: ConstraintKind::ForeignKeyParent { fk: fk.foreign_desc() },
+                            name: child,
+                        })
+                    }
+                    (RefAction::Cascade, None) => {
+                        deleted.insert(key);
+                        child_changes.push((row, None));
+                        continue;
+                    }
+                    (RefAction::Cascade, Some(vals)) => vals.clone(),
+                    (RefAction::SetNull, _) => c_slots.iter().map(|i| c_cols[*i].col_type.null()).collect(),
+                };
+                let mut new_row = row.clone();
+                for (i, v) in c_slots.iter().zip(&new_vals) {
+                    new_row.col_data[*i] = v.clone();
+                }
+                updated.insert(key, new_vals);
+                child_changes.push((row, Some(new_row)));
+            }
+            // the child may be a parent itself
+            let nested = self.plan_parent_changes(&child, &child_changes)?;
+            if !deleted.is_empty() {
+                steps.push(RefStep::Delete { table: child.clone(), slots: c_slots.clone(), keys: deleted });
+            }
+            if !updated.is_empty() {
+                steps.push(RefStep::Update { table: child.clone(), slots: c_slots, keys: updated });
+            }
+            steps.extend(nested);
+        }
+        Ok(steps)
+    }
+
+    pub fn apply_ref_steps(&mut self, steps: Vec<RefStep>) -> Result<(), TableLikeError> {
+        for step in steps {
+            match step {
+                RefStep::Delete { table, slots, keys } => {
+                    self.delete_rows_of(&table, &mut |row| {
+                        Ok(KeyIndex::key_of(&slots, row).is_some_and(|k| keys.contains(&k)))
+                    })?;
+                }
+                RefStep::Update { table, slots, keys } => {
+                    self.update_rows_of(&table, &mut |row| {
+                        let Some(vals) = KeyIndex::key_of(&slots, row).and_then(|k| keys.get(&k)) else {
+                            return Ok(false);
+                        };
+                        for (i, v) in slots.iter().zip(vals) {
+                            row.col_data[*i] = v.clone();
+                        }
+                        Ok(true)
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{error, run, select, TestDir};
+
+    #[test]
+    fn actions_run_on_the_children() {
+        let dir = TestDir::new("foreign_keys");
+        let mut tm = dir.manager();
+        let mut s = tm.session();
+        for sql in [
+            "CREATE TABLE p (id INT PRIMARY KEY) ENGINE=PAGED;",
+            "CREATE TABLE c (id INT, pid INT, FOREIGN KEY (pid) REFERENCES p (id) ON DELETE CASCADE ON UPDATE SET NULL);",
+            "CREATE TABLE r (id INT, pid INT, FOREIGN KEY (pid) REFERENCES p (id));",
+            "INSERT INTO p VALUES (1), (2), (3);",
+            "INSERT INTO c VALUES (10, 1), (11, 2), (12, NULL);",
+            "INSERT INTO r VALUES (20, 3);",
+        ] {
+            run(&mut tm, &mut s, sql).unwrap();
+        }
+        assert_eq!(
+            error(&mut tm, &mut s, "INSERT INTO c VALUES (13, 4);"),
+            "Cannot add or update a child row: a foreign key constraint fails (`c`, CONSTRAINT `c_ibfk_1` FOREIGN KEY (`pid`) REFERENCES `p` (`id`))"
+        );
+        assert_eq!(
+            error(&mut tm, &mut s, "DELETE FROM p WHERE id = 3;"),
+            "Cannot delete or update a parent row: a foreign key constraint fails (`r`, CONSTRAINT `r_ibfk_1` FOREIGN KEY (`pid`) REFERENCES `p` (`id`))"
+        );
+        run(&mut tm, &mut s, "DELETE FROM p WHERE id = 1;").unwrap();
+        run(&mut tm, &mut s, "UPDATE p SET id = 5 WHERE id = 2;").unwrap();
+        assert_eq!(select(&mut tm, &mut s, "SELECT id, pid FROM c;"), vec![vec!["11", "NULL"], vec!["12", "NULL"]]);
+        assert_eq!(select(&mut tm, &mut s, "SELECT id FROM p;"), vec![vec!["3"], vec!["5"]]);
+        // the new key of an updated parent is found
+        run(&mut tm, &mut s, "INSERT INTO c VALUES (14, 5);").unwrap();
+    }
+
+    #[test]
+    fn references_need_a_unique_key_of_the_same_type() {
+        let dir = TestDir::new("foreign_keys_refs");
+        let mut tm = dir.manager();
+        let mut s = tm.session();
+        run(&mut tm, &mut s, "CREATE TABLE p (id INT PRIMARY KEY, n INT, s VARCHAR(5) UNIQUE);").unwrap();
+        assert!(run(&mut tm, &mut s, "CREATE TABLE c (pid INT, FOREIGN KEY (pid) REFERENCES p (n));").is_err());
+        assert!(run(&mut tm, &mut s, "CREATE TABLE c (pid VARCHAR(5), FOREIGN KEY (pid) REFERENCES p (id));").is_err());
+        assert!(run(&mut tm, &mut s, "CREATE TABLE c (pid INT, FOREIGN KEY (pid) REFERENCES nope (id));").is_err());
+        run(&mut tm, &mut s, "CREATE TABLE c (ps VARCHAR(5), FOREIGN KEY (ps) REFERENCES p (s));").unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+use crate::datetime::{Date, DateTime, DateUnit};
+use crate::decimal::{self, DecimalSpec, DecimalType, MAX_PRECISION, MAX_SCALE};
+use crate::{db, NumType, TableCell, TableLikeError};
+
+pub type FuncImpl = Box<dyn Fn(&[TableCell]) -> TableCell>;
+
+fn check_args(name: &str, args: &[TableCell], count: usize) -> Result<(), TableLikeError> {
+    if args.len() != count {
+        return Err(TableLikeError::new(&format!(
+            "Incorrect parameter count in the call to native function '{name}'"
+        )));
+    }
+    Ok(())
+}
+
+// unit arguments are always literals so their "type" is the literal itself
+fn unit_arg(arg: &TableCell) -> Result<DateUnit, TableLikeError> {
+    match arg {
+        TableCell::Str(Some(s)) => DateUnit::parse(s),
+        _ => None,
+    }
+    .ok_or_else(|| TableLikeError::new("Expected a date unit"))
+}
+
+// resolves a function call given the types of its arguments, returning the type of
+// the result and the implementation which gets the evaluated arguments
+pub fn resolve(name: &str, args: &[TableCell]) -> Result<(TableCell, FuncImpl), TableLikeError> {
+    let up = name.to_ascii_uppercase();
+    Ok(match up.as_str() {
+        // constant for the whole statement like in MySQL
+        "NOW" | "CURRENT_TIMESTAMP" => {
+            check_args(name, args, 0)?;
+            let now = DateTime::now();
+            (TableCell::DateTime(None), Box::new(move |_| TableCell::DateTime(Some(now))))
+        }
+        "CURDATE" | "CURRENT_DATE" => {
+            check_args(name, args, 0)?;
+            let now = DateTime::now().date();
+            (TableCell::Date(None), Box::new(move |_| TableCell::Date(Some(now))))
+        }
+        "CURTIME" | "CURRENT_TIME" => {
+            check_args(name, args, 0)?;
+            let now = DateTime::now().time();
+            (TableCell::Time(None), Box::new(move |_| TableCell::Time(Some(now))))
+        }
+        "DATE" => {
+            check_args(name, args, 1)?;
+            (TableCell::Date(None), Box::new(|a| TableCell::Date(a[0].as_date())))
+        }
+        "DATE_ADD" | "DATE_SUB" => {
+            check_args(name, args, 3)?;
+            let unit = unit_arg(&args[2])?;
+            let sign = if up == "DATE_ADD" { 1 } else { -1 };
+            let add = move |a: &[TableCell]| a[0].as_datetime()?.add(a[1].as_num()?.checked_mul(sign)?, unit);
+            match &args[0] {
+                // a DATE stays one unless the interval has a time part
+                TableCell::Date(_) if unit.is_date_part() => {
+                    (TableCell::Date(None), Box::new(move |a| TableCell::Date(add(a).map(|d| d.date()))))
+                }
+                // a string gives a string like in MySQL, without a time when it had none
+                TableCell::Str(_) => (
+                    TableCell::Str(None),
+                    Box::new(move |a| {
+                        TableCell::Str(add(a).map(|d| match &a[0] {
+                            TableCell::Str(Some(s)) if unit.is_date_part() && Date::parse(s).is_some() => d.date().to_string(),
+                            _ => d.to_string(),
+                        }))
+                    }),
+                ),
+                _ => (TableCell::DateTime(None), Box::new(move |a| TableCell::DateTime(add(a)))),
+            }
+        }
+        "DATEDIFF" => {
+            check_args(name, args, 2)?;
+            (
+                TableCell::Num(None),
+                Box::new(|a| {
+                    TableCell::Num((|| {
+                        Some(a[0].as_date()?.days_since_epoch() - a[1].as_date()?.days_since_epoch())
+                    })())
+                }),
+            )
+        }
+        "EXTRACT" => {
+            check_args(name, args, 2)?;
+            let unit = unit_arg(&args[0])?;
+            (
+                TableCell::Num(None),
+                Box::new(move |a| {
+                    TableCell::Num((|| {
+                        match &a[1] {
+                            // a TIME has no date part and its hours may go past 24
+                            TableCell::Time(Some(t)) => match unit {
+                                DateUnit::Hour => Some(t.secs() / 3600),
+                                DateUnit::Minute => Some(t.secs() / 60 % 60),
+                                DateUnit::Second => Some(t.secs() % 60),
+                                _ => None,
+                            },
+                            v => Some(v.as_datetime()?.extract(unit)),
+                        }
+                    })())
+                }),
+            )
+        }
+        "YEAR" | "MONTH" | "DAY" | "HOUR" | "MINUTE" | "SECOND" => {
+            check_args(name, args, 1)?;
+            let unit = DateUnit::parse(&up).unwrap();
+            (
+                TableCell::Num(None),
+                Box::new(move |a| TableCell::Num(a[0].as_datetime().map(|d| d.extract(unit)))),
+            )
+        }
+        "DATE_FORMAT" => {
+            check_args(name, args, 2)?;
+            (
+                TableCell::Str(None),
+                Box::new(|a| {
+                    TableCell::Str((|| match &a[1] {
+                        TableCell::Str(Some(fmt)) => Some(a[0].as_datetime()?.format(fmt)),
+                        _ => None,
+                    })())
+                }),
+            )
+        }
+        // in bytes, strings count their UTF-8 encoding
+        "LENGTH" | "OCTET_LENGTH" => {
+            check_args(name, args, 1)?;
+            (
+                TableCell::Num(None),
+                Box::new(|a| TableCell::Num(a[0].as_bytes().map(|b| b.len() as NumType))),
+            )
+        }
+        "CHAR_LENGTH" => {
+            check_args(name, args, 1)?;
+            (
+                TableCell::Num(None),
+                Box::new(|a| {
+                    TableCell::Num(match &a[0] {
+                        TableCell::Str(s) => s.as_ref().map(|s| s.chars().count() as NumType),
+                        v => v.as_bytes().map(|b| b.len() as NumType),
+                    })
+                }),
+            )
+        }
+        // numbers are written in base 16 like MySQL, everything else byte by byte
+        "HEX" => {
+            check_args(name, args, 1)?;
+            (
+                TableCell::Str(None),
+                Box::new(|a| {
+                    TableCell::Str(match &a[0] {
+                        TableCell::Num(n) => n.map(|n| format!("{n:X}")),
+                        v => v.as_bytes().map(|b| db::encode_hex(&b)),
+                    })
+                }),
+            )
+        }
+        "UNHEX" => {
+            check_args(name, args, 1)?;
+            (
+                TableCell::Blob(None),
+                Box::new(|a| {
+                    TableCell::Blob(a[0].as_bytes().and_then(|b| db::decode_hex(&String::from_utf8_lossy(&b))))
+                }),
+            )
+        }
+        // ROUND(x[, d]) rounds half away from zero, TRUNCATE(x, d) towards zero,
+        // a negative d works on the digits left of the point
+        "ROUND" | "TRUNCATE" => {
+            if up == "TRUNCATE" || args.len() != 1 {
+                check_args(name, args, 2)?;
+            }
+            let places = match args.get(1) {
+                None => 0,
+                Some(TableCell::Num(Some(d))) => *d,
+                Some(_) => {
+                    return Err(TableLikeError::new(&format!(
+                        "{up} expects a constant number of decimal places"
+                    )))
+                }
+            };
+            let round = up == "ROUND";
+            // rounds v, which has scale from, at 10^-places and returns it at scale to
+            let apply = move |v: DecimalType, from: u8, to: u8| -> Option<DecimalType> {
+                let p = places.clamp(-(MAX_PRECISION as NumType), MAX_SCALE as NumType);
+                let (cut, back) = if p >= 0 { (p as u8, 0) } else { (0, (-p) as u8) };
+                // dropping from + back digits at once so there is no double rounding
+                let v = if round {
+                    decimal::rescale(v, from + back, cut)?
+                } else {
+                    decimal::truncate(v, from + back, cut)?
+                };
+                decimal::rescale(v.checked_mul(decimal::pow10(back))?, cut, to)
+            };
+            match &args[0] {
+                TableCell::Decimal(_, spec) => {
+                    let to = places.clamp(0, spec.scale as NumType) as u8;
+                    let out = DecimalSpec::new(
+                        (spec.precision - spec.scale + 1 + to).min(MAX_PRECISION) as u32,
+                        to as u32,
+                    )
+                    .map_err(|e| TableLikeError::new(&e))?;
+                    (
+                        TableCell::Decimal(None, out),
+                        Box::new(move |a| {
+                            TableCell::Decimal(
+                                a[0].as_decimal().and_then(|(v, s)| apply(v, s, out.scale)).filter(|v| out.fits(*v)),
+                                out,
+                            )
+                        }),
+                    )
+                }
+                _ => (
+                    TableCell::Num(None),
+                    Box::new(move |a| {
+                        TableCell::Num(
+                            a[0].as_num()
+                                .and_then(|n| apply(n as DecimalType, 0, 0))
+                                .and_then(|v| NumType::try_from(v).ok()),
+                        )
+                    }),
+                ),
+            }
+        }
+        _ => {
+            return Err(TableLikeError::new(&format!(
+                "FUNCTION {name} does not exist"
+            )))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::parse_expr_text;
+
+    fn eval(inp: &str) -> String {
+        parse_expr_text(inp).and_then(|e| e.eval_const()).unwrap().to_string()
+    }
+
+    #[test]
+    fn round_and_truncate() {
+        assert_eq!(eval("ROUND(1.5)"), "2");
+        assert_eq!(eval("ROUND(-2.45, 1)"), "-2.5");
+        assert_eq!(eval("TRUNCATE(-1.59, 1)"), "-1.5");
+        assert_eq!(eval("ROUND(155, -1)"), "160");
+        assert_eq!(eval("TRUNCATE(155, -2)"), "100");
+    }
+
+    #[test]
+    fn dates_stay_dates_when_whole_days_are_added() {
+        assert_eq!(eval("DATE_ADD('2024-01-31', INTERVAL 1 MONTH)"), "2024-02-29");
+        assert_eq!(eval("DATE_SUB('2024-03-31', INTERVAL 1 MONTH)"), "2024-02-29");
+        assert_eq!(eval("DATE_ADD(DATE '2024-12-31', INTERVAL 1 YEAR)"), "2025-12-31");
+        assert_eq!(eval("DATE_SUB(DATE '2024-03-01', INTERVAL 1 DAY) = DATE '2024-02-29'"), "TRUE");
+        // a time part in the interval or the value makes it a datetime
+        assert_eq!(eval("DATE_ADD('2024-01-31', INTERVAL 1 HOUR)"), "2024-01-31 01:00:00");
+        assert_eq!(eval("DATE_ADD(DATE '2024-12-31', INTERVAL 90 MINUTE)"), "2024-12-31 01:30:00");
+        assert_eq!(eval("DATE_ADD('2024-01-31 10:00:00', INTERVAL 1 DAY)"), "2024-02-01 10:00:00");
+        assert_eq!(eval("DATE_ADD(TIMESTAMP '2024-01-31 00:00:00', INTERVAL 1 MONTH)"), "2024-02-29 00:00:00");
+        assert_eq!(eval("DATE_ADD('not a date', INTERVAL 1 DAY)"), "NULL");
+    }
+
+    #[test]
+    fn every_digit_can_be_cut() {
+        assert_eq!(eval("ROUND(1.5, -38)"), "0");
+        assert_eq!(eval("ROUND(1.5, -1000)"), "0");
+        assert_eq!(eval("TRUNCATE(99999999999999999999.99999999999999999, -38)"), "0");
+        assert_eq!(eval("ROUND(9223372036854775807, -38)"), "0");
+        assert_eq!(eval("ROUND(5, -1)"), "10");
+        assert_eq!(eval("ROUND(1.5, 1000)"), "1.5");
+    }
+}
@@ -0,0 +1,396 @@
+/*
+
+Linear hash index kept in a file of fixed size pages, maps byte string keys to u64 values
+
+Page 0          "LHASH001" then level, split, bytes, pages, free and dir, all u64
+Directory page  next directory page u64 then the first page of each bucket as u64
+Bucket page     entry count u16 and the next page of the bucket u64,
+                then the entries as key length u16, key bytes and value u64
+Free page       next free page u64
+
+There are INITIAL_BUCKETS << level buckets plus the split ones, a key goes to
+hash % (INITIAL_BUCKETS << level) unless that bucket was already split this round,
+then to hash % (INITIAL_BUCKETS << (level + 1)). Once the entries take up more than
+LOAD_FACTOR of the buckets the bucket at split is split in two, so the table grows
+one bucket at a time and no lookup reads more than one bucket.
+
+A key may have several values, unlike in the B-tree. All numbers are big endian.
+
+*/
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+
+const PAGE_SIZE: usize = 4096;
+const MAGIC: &[u8; 8] = b"LHASH001";
+const INITIAL_BUCKETS: u64 = 4;
+const BUCKET_HEADER: usize = 10;
+const DIR_PER_PAGE: usize = (PAGE_SIZE - 8) / 8;
+// percent of the bucket pages the entries may fill before a bucket is split
+const LOAD_FACTOR: u64 = 75;
+// a level no index gets to, INITIAL_BUCKETS << MAX_LEVEL pages are more than a file holds
+const MAX_LEVEL: u64 = 48;
+
+// FNV-1a, the std hashers don't promise to hash the same way across releases
+fn hash(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf29ce484222325, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+type Entry = (Vec<u8>, u64);
+
+fn entry_size(key: &[u8]) -> usize {
+    key.len() + 10
+}
+
+fn corrupt() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "Corrupt index file")
+}
+
+pub struct HashIndex {
+    file: File,
+    level: u64,
+    // next bucket to split
+    split: u64,
+    // size of all entries, decides when to split
+    bytes: u64,
+    pages: u64,
+    free: u64,
+    // first page of every bucket and the directory pages they are listed in
+    buckets: Vec<u64>,
+    dir_pages: Vec<u64>,
+}
+
+impl HashIndex {
+    pub fn open(path: &str) -> std::io::Result<HashIndex> {
+        let file = File::options().read(true).write(true).open(path)?;
+        let mut idx = HashIndex {
+            file,
+            level: 0,
+            split: 0,
+            bytes: 0,
+            pages: 0,
+            free: 0,
+            buckets: Vec::new(),
+            dir_pages: Vec::new(),
+        };
+        let head = idx.read_page(0)?;
+        if &head[..8] != MAGIC {
+            return Err(corrupt());
+        }
+        let num = |i: usize| u64::from_be_bytes(head[8 + i * 8..16 + i * 8].try_into().unwrap());
+        (idx.level, idx.split, idx.bytes, idx.pages, idx.free) = (num(0), num(1), num(2), num(3), num(4));
+        // far more buckets than a file can have, or a split past the buckets of the round
+        if idx.level >= MAX_LEVEL || idx.split >= INITIAL_BUCKETS << idx.level {
+            return Err(corrupt());
+        }
+        let count = (INITIAL_BUCKETS << idx.level) + idx.split;
+        let mut dir = num(5);
+        while (idx.buckets.len() as u64) < count {
+            if dir == 0 || dir >= idx.pages {
+                return Err(corrupt());
+            }
+            let page = idx.read_page(dir)?;
+            idx.dir_pages.push(dir);
+            dir = u64::from_be_bytes(page[..8].try_into().unwrap());
+            for c in page[8..].chunks(8).take((count as usize - idx.buckets.len()).min(DIR_PER_PAGE)) {
+                idx.buckets.push(u64::from_be_bytes(c.try_into().unwrap()));
+            }
+        }
+        Ok(idx)
+    }
+
+    // writes a new index with room for entries, replacing whatever was at path
+    pub fn build(path: &str, entries: impl IntoIterator<Item = Entry>) -> std::io::Result<HashIndex> {
+        let file = File::options().read(true).write(true).create(true).truncate(true).open(path)?;
+        let entries = entries.into_iter().collect::<Vec<_>>();
+        let bytes = entries.iter().map(|(k, _)| entry_size(k) as u64).sum::<u64>();
+        let mut level = 0;
+        while bytes * 100 > (INITIAL_BUCKETS << level) * PAGE_SIZE as u64 * LOAD_FACTOR {
+            level += 1;
+        }
+        let mut idx = HashIndex {
+            file,
+            level,
+            split: 0,
+            bytes,
+            pages: 1,
+            free: 0,
+            buckets: Vec::new(),
+            dir_pages: Vec::new(),
+        };
+        let count = INITIAL_BUCKETS << level;
+        let mut grouped = vec![Vec::new(); count as usize];
+        for (k, v) in entries {
+            grouped[(hash(&k) % count) as usize].push((k, v));
+        }
+        for (i, bucket) in grouped.into_iter().enumerate() {
+            let first = idx.alloc()?;
+            idx.write_chain(vec![first], bucket)?;
+            idx.set_bucket(i, first)?;
+        }
+        idx.write_header()?;
+        Ok(idx)
+    }
+
+    fn read_page(&self, page: u64) -> std::io::Result<Vec<u8>> {
+        let mut buf = vec![0; PAGE_SIZE];
+        self.file.read_exact_at(&mut buf, page * PAGE_SIZE as u64)?;
+        Ok(buf)
+    }
+
+    fn write_page(&mut self, page: u64, buf: &[u8]) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))?;
+        self.file.write_all(buf)
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let mut head = MAGIC.to_vec();
+        let dir = self.dir_pages.first().copied().unwrap_or(0);
+        for n in [self.level, self.split, self.bytes, self.pages, self.free, dir] {
+            head.extend(n.to_be_bytes());
+        }
+        head.resize(PAGE_SIZE, 0);
+        self.write_page(0, &head)
+    }
+
+    // a page off the free list or a new one at the end
+    fn alloc(&mut self) -> std::io::Result<u64> {
+        if self.free != 0 {
+            let page = self.free;
+            self.free = u64::from_be_bytes(self.read_page(page)?[..8].try_into().unwrap());
+            return Ok(page);
+        }
+        self.pages += 1;
+        Ok(self.pages - 1)
+    }
+
+    fn free_page(&mut self, page: u64) -> std::io::Result<()> {
+        let mut buf = self.free.to_be_bytes().to_vec();
+        buf.resize(PAGE_SIZE, 0);
+        self.write_page(page, &buf)?;
+        self.free = page;
+        Ok(())
+    }
+
+    // points the directory entry of bucket i at page
+    fn set_bucket(&mut self, i: usize, page: u64) -> std::io::Result<()> {
+        if i / DIR_PER_PAGE == self.dir_pages.len() {
+            let dir = self.alloc()?;
+            self.write_page(dir, &[0; PAGE_SIZE])?;
+            if let Some(last) = self.dir_pages.last().copied() {
+                self.file.seek(SeekFrom::Start(last * PAGE_SIZE as u64))?;
+                self.file.write_all(&dir.to_be_bytes())?;
+            }
+            self.dir_pages.push(dir);
+        }
+        let at = self.dir_pages[i / DIR_PER_PAGE] * PAGE_SIZE as u64 + 8 + (i % DIR_PER_PAGE) as u64 * 8;
+        self.file.seek(SeekFrom::Start(at))?;
+        self.file.write_all(&page.to_be_bytes())?;
+        if i == self.buckets.len() {
+            self.buckets.push(page);
+        } else {
+            self.buckets[i] = page;
+        }
+        Ok(())
+    }
+
+    // pages and entries of the bucket starting at first
+    fn read_chain(&self, first: u64) -> std::io::Result<(Vec<u64>, Vec<Entry>)> {
+        let mut pages = Vec::new();
+        let mut entries = Vec::new();
+        let mut page = first;
+        while page != 0 {
+            if page >= self.pages || pages.contains(&page) {
+                return Err(corrupt());
+            }
+            pages.push(page);
+            let buf = self.read_page(page)?;
+            let count = u16::from_be_bytes(buf[..2].try_into().unwrap());
+            page = u64::from_be_bytes(buf[2..10].try_into().unwrap());
+            let mut at = BUCKET_HEADER;
+            for _ in 0..count {
+                let len = u16::from_be_bytes(buf.get(at..at + 2).ok_or_else(corrupt)?.try_into().unwrap()) as usize;
+                let key = buf.get(at + 2..at + 2 + len).ok_or_else(corrupt)?.to_vec();
+                at += 2 + len;
+                let val = u64::from_be_bytes(buf.get(at..at + 8).ok_or_else(corrupt)?.try_into().unwrap());
+                at += 8;
+                entries.push((key, val));
+            }
+        }
+        Ok((pages, entries))
+    }
+
+    // writes entries over the pages of a bucket, the first page stays the first,
+    // more are allocated when needed and the ones left over are freed
+    fn write_chain(&mut self, mut pages: Vec<u64>, entries: Vec<Entry>) -> std::io::Result<()> {
+        let mut groups = vec![Vec::new()];
+        let mut size = BUCKET_HEADER;
+        for e in entries {
+            if size + entry_size(&e.0) > PAGE_SIZE {
+                groups.push(Vec::new());
+                size = BUCKET_HEADER;
+            }
+            size += entry_size(&e.0);
+            groups.last_mut().unwrap().push(e);
+        }
+        while pages.len() < groups.len() {
+            let p = self.alloc()?;
+            pages.push(p);
+        }
+        for p in pages.split_off(groups.len()) {
+            self.free_page(p)?;
+        }
+        for (i, group) in groups.iter().enumerate() {
+            let mut buf = Vec::with_capacity(PAGE_SIZE);
+            buf.extend((group.len() as u16).to_be_bytes());
+            buf.extend(pages.get(i + 1).copied().unwrap_or(0).to_be_bytes());
+            for (k, v) in group {
+                buf.extend((k.len() as u16).to_be_bytes());
+                buf.extend(k);
+                buf.extend(v.to_be_bytes());
+            }
+            buf.resize(PAGE_SIZE, 0);
+            self.write_page(pages[i], &buf)?;
+        }
+        Ok(())
+    }
+
+    fn bucket_of(&self, key: &[u8]) -> usize {
+        let h = hash(key);
+        let b = h % (INITIAL_BUCKETS << self.level);
+        if b < self.split {
+            (h % (INITIAL_BUCKETS << (self.level + 1))) as usize
+        } else {
+            b as usize
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: u64) -> std::io::Result<()> {
+        let b = self.bucket_of(key);
+        let (pages, mut entries) = self.read_chain(self.buckets[b])?;
+        entries.push((key.to_vec(), value));
+        self.write_chain(pages, entries)?;
+        self.bytes += entry_size(key) as u64;
+        if self.bytes * 100 > self.buckets.len() as u64 * PAGE_SIZE as u64 * LOAD_FACTOR {
+            self.split_bucket()?;
+        }
+        self.write_header()
+    }
+
+    // takes one value stored under key out, false if it wasn't there, buckets are never merged
+    pub fn remove(&mut self, key: &[u8], value: u64) -> std::io::Result<bool> {
+        let b = self.bucket_of(key);
+        let (pages, mut entries) = self.read_chain(self.buckets[b])?;
+        let Some(at) = entries.iter().position(|(k, v)| k == key && *v == value) else {
+            return Ok(false);
+        };
+        entries.remove(at);
+        self.write_chain(pages, entries)?;
+        self.bytes -= entry_size(key) as u64;
+        self.write_header()?;
+        Ok(true)
+    }
+
+    // moves the entries of the bucket at split that belong to the next round to a new bucket
+    fn split_bucket(&mut self) -> std::io::Result<()> {
+        let old = self.split as usize;
+        let modulus = INITIAL_BUCKETS << (self.level + 1);
+        let (pages, entries) = self.read_chain(self.buckets[old])?;
+        let (stay, moved) = entries.into_iter().partition(|(k, _)| hash(k) % modulus == old as u64);
+        self.write_chain(pages, stay)?;
+        let first = self.alloc()?;
+        self.write_chain(vec![first], moved)?;
+        self.set_bucket(self.buckets.len(), first)?;
+        self.split += 1;
+        if self.split == INITIAL_BUCKETS << self.level {
+            self.level += 1;
+            self.split = 0;
+        }
+        Ok(())
+    }
+
+    // every value stored under key
+    pub fn get(&self, key: &[u8]) -> std::io::Result<Vec<u64>> {
+        let (_, entries) = self.read_chain(self.buckets[self.bucket_of(key)])?;
+        Ok(entries.into_iter().filter(|(k, _)| k == key).map(|(_, v)| v).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempPath;
+
+    fn key(i: u64) -> Vec<u8> {
+        format!("key {i} {}", "h".repeat((i % 50) as usize)).into_bytes()
+    }
+
+    fn sorted(mut v: Vec<u64>) -> Vec<u64> {
+        v.sort_unstable();
+        v
+    }
+
+    #[test]
+    fn buckets_split_as_entries_are_inserted() {
+        let path = TempPath::new("hash", "insert");
+        let mut h = HashIndex::build(&path.0, []).unwrap();
+        for i in 0..5000 {
+            h.insert(&key(i), i).unwrap();
+        }
+        // a key may have several values
+        h.insert(&key(3), 70_000).unwrap();
+        assert!(h.level > 0);
+        let h = HashIndex::open(&path.0).unwrap();
+        for i in (0..5000).step_by(7) {
+            let expected = if i == 3 { vec![3, 70_000] } else { vec![i] };
+            assert_eq!(sorted(h.get(&key(i)).unwrap()), expected);
+        }
+        assert!(h.get(b"no such key").unwrap().is_empty());
+    }
+
+    #[test]
+    fn built_indexes_have_every_entry() {
+        let path = TempPath::new("hash", "build");
+        let mut h = HashIndex::build(&path.0, (0..20_000).map(|i| (key(i), i))).unwrap();
+        for i in (0..20_000).step_by(13) {
+            assert_eq!(h.get(&key(i)).unwrap(), vec![i]);
+        }
+        h.insert(&key(20_000), 20_000).unwrap();
+        assert_eq!(h.get(&key(20_000)).unwrap(), vec![20_000]);
+    }
+
+    #[test]
+    fn removed_values_are_gone() {
+        let path = TempPath::new("hash", "remove");
+        let mut h = HashIndex::build(&path.0, (0..5000).map(|i| (key(i), i))).unwrap();
+        h.insert(&key(3), 70_000).unwrap();
+        for i in (0..5000).step_by(2) {
+            assert!(h.remove(&key(i), i).unwrap());
+        }
+        // only the value given goes, not the others under its key
+        assert!(h.remove(&key(3), 70_000).unwrap());
+        assert!(!h.remove(&key(3), 70_000).unwrap());
+        let h = HashIndex::open(&path.0).unwrap();
+        for i in 0..5000 {
+            let expected = if i % 2 == 0 { vec![] } else { vec![i] };
+            assert_eq!(h.get(&key(i)).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn corrupt_headers_are_errors() {
+        let path = TempPath::new("hash", "bad");
+        drop(HashIndex::build(&path.0, [(b"a".to_vec(), 1)]).unwrap());
+        let good = std::fs::read(&path.0).unwrap();
+        // the level, the next bucket to split and the first directory page
+        for (at, n) in [(8, 100), (8, 64), (16, INITIAL_BUCKETS), (48, 0), (48, 1 << 40)] {
+            let mut file = good.clone();
+            file[at..at + 8].copy_from_slice(&u64::to_be_bytes(n));
+            std::fs::write(&path.0, &file).unwrap();
+            assert!(HashIndex::open(&path.0).is_err(), "{at} {n}");
+        }
+        std::fs::write(&path.0, b"LHASH001").unwrap();
+        assert!(HashIndex::open(&path.0).is_err());
+    }
+}
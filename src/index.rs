@@ -0,0 +1,564 @@
+/*
+
+Secondary indexes of a FileTable or PageTable
+
+Every PRIMARY KEY, UNIQUE and plain INDEX of a table has an index file in
+<table name>.<key name>.idx, a B-tree (see btree.rs) or with USING HASH a linear
+hash (see hash.rs). Both map the key columns of a row, encoded so that comparing
+the bytes compares the values, to the id of the row, the byte offset of its RStart
+line in a text table file. The B-tree needs unique keys so the id is appended there too.
+
+Appending rows adds them to the indexes, anything that rewrites the table file or
+moves rows around in it rebuilds them.
+
+*/
+
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+use crate::btree::{self, BTree};
+use crate::constraints::{IndexAlgorithm, KeyDef, KeyIndex, KeyKind};
+use crate::hash::HashIndex;
+use crate::query::{BinOp, Expr};
+use crate::{constraints, ColumnEntry, RowId, TableCell, TableEntry, TableLikeError};
+
+pub fn index_path(table: &str, key: &str) -> String {
+    format!("{table}.{key}.idx")
+}
+
+// the index files there are of a table
+pub fn index_files(table: &str) -> std::io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    for e in std::fs::read_dir(".")? {
+        let name = e?.file_name().to_string_lossy().into_owned();
+        if name.strip_prefix(table).and_then(|n| n.strip_prefix('.')).is_some_and(|n| n.ends_with(".idx")) {
+            files.push(name);
+        }
+    }
+    Ok(files)
+}
+
+// foreign keys are looked up through the key they reference instead, and a table CHECK
+// has nothing to look up
+pub fn is_indexed(k: &KeyDef) -> bool {
+    !matches!(k.kind, KeyKind::Foreign(_) | KeyKind::Check(_))
+}
+
+fn encode_int(out: &mut Vec<u8>, n: i64) {
+    // flipping the sign bit puts negative numbers before positive ones
+    out.extend(((n as u64) ^ (1 << 63)).to_be_bytes());
+}
+
+// 0 bytes are escaped and the end is marked by 0 0 so a shorter string sorts first
+fn encode_bytes(out: &mut Vec<u8>, b: &[u8]) {
+    for c in b {
+        out.push(*c);
+        if *c == 0 {
+            out.push(0xFF);
+        }
+    }
+    out.extend([0, 0]);
+}
+
+// NULL sorts before every value like in MySQL
+fn encode_cell(out: &mut Vec<u8>, cell: &TableCell) {
+    if cell.is_null() {
+        out.push(0);
+        return;
+    }
+    out.push(1);
+    match cell {
+        TableCell::Num(Some(n)) => encode_int(out, *n),
+        TableCell::Bool(Some(b)) => out.push(*b as u8),
+        TableCell::Date(Some(d)) => encode_int(out, d.days_since_epoch()),
+        TableCell::Time(Some(t)) => encode_int(out, t.secs()),
+        TableCell::DateTime(Some(t)) => {
+            encode_int(out, t.date().days_since_epoch());
+            encode_int(out, t.time().secs());
+        }
+        // every value of a column has the scale of the column
+        TableCell::Decimal(Some(v), _) => out.extend(((*v as u128) ^ (1 << 127)).to_be_bytes()),
+        TableCell::Str(Some(s)) => encode_bytes(out, s.as_bytes()),
+        TableCell::Blob(Some(b)) => encode_bytes(out, b),
+        _ => unreachable!(),
+    }
+}
+
+pub fn encode_key<'a>(cells: impl IntoIterator<Item = &'a TableCell>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for c in cells {
+        encode_cell(&mut out, c);
+    }
+    out
+}
+
+// the index key of a row, room is left for the offset the B-tree appends
+pub fn entry_key(slots: &[usize], row: &TableEntry) -> Result<Vec<u8>, TableLikeError> {
+    let out = encode_key(slots.iter().map(|i| &row.col_data[*i]));
+    if out.len() + 8 > btree::MAX_KEY_LEN {
+        return Err(TableLikeError::new(&format!(
+            "Specified key was too long; max key length is {} bytes",
+            btree::MAX_KEY_LEN - 8
+        )));
+    }
+    Ok(out)
+}
+
+// the indexed keys of a table with the slots of their columns
+pub fn indexed_slots<'a>(cols: &[ColumnEntry], keys: &'a [KeyDef]) -> Result<Vec<(&'a KeyDef, Vec<usize>)>, TableLikeError> {
+    keys.iter()
+        .filter(|k| is_indexed(k))
+        .map(|k| Ok((k, constraints::slots_of(cols, &k.cols)?)))
+        .collect()
+}
+
+pub fn find_key<'a>(keys: &'a [KeyDef], name: &str) -> Result<&'a KeyDef, TableLikeError> {
+    keys.iter()
+        .find(|k| k.name == name && is_indexed(k))
+        .ok_or_else(|| TableLikeError::new(&format!("Key '{name}' doesn't exist in table")))
+}
+
+// the index file of a key, built from the rows with their ids if it is missing
+pub fn open_or_build<I>(
+    table: &str,
+    key: &KeyDef,
+    cols: &[ColumnEntry],
+    rows: impl FnOnce() -> Result<I, TableLikeError>,
+) -> Result<IndexFile, TableLikeError>
+where
+    I: Iterator<Item = Result<(RowId, TableEntry), TableLikeError>>,
+{
+    let path = index_path(table, &key.name);
+    match IndexFile::open(&path, key.algorithm) {
+        Ok(t) => return Ok(t),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let slots = constraints::slots_of(cols, &key.cols)?;
+    let mut entries = Vec::new();
+    for r in rows()? {
+        let (rid, row) = r?;
+        entries.push((entry_key(&slots, &row)?, rid));
+    }
+    Ok(IndexFile::build(&path, key.algorithm, entries)?)
+}
+
+// writes the index files of all indexed keys from the rows with their ids, in one pass
+pub fn build_all(
+    table: &str,
+    cols: &[ColumnEntry],
+    keys: &[KeyDef],
+    rows: impl Iterator<Item = Result<(RowId, TableEntry), TableLikeError>>,
+) -> Result<(), TableLikeError> {
+    let mut indexes = indexed_slots(cols, keys)?.into_iter().map(|(k, slots)| (k, slots, Vec::new())).collect::<Vec<_>>();
+    if indexes.is_empty() {
+        return Ok(());
+    }
+    for r in rows {
+        let (rid, row) = r?;
+        for (_, slots, entries) in &mut indexes {
+            entries.push((entry_key(slots, &row)?, rid));
+        }
+    }
+    for (k, _, entries) in indexes {
+        IndexFile::build(&index_path(table, &k.name), k.algorithm, entries)?;
+    }
+    Ok(())
+}
+
+// whether a live row has the encoded key, looked up in the index of the key and read with
+// get_row, a deleted version of a row doesn't hold its key anymore
+pub fn holds_key(
+    file: &IndexFile,
+    slots: &[usize],
+    key: &[u8],
+    get_row: impl Fn(RowId) -> Result<TableEntry, TableLikeError>,
+) -> Result<bool, TableLikeError> {
+    for rid in file.lookup(&KeyRange::Eq(key.to_vec()))? {
+        let row = get_row(rid)?;
+        if row.version.is_live() && entry_key(slots, &row)? == key {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// checks the PRIMARY and UNIQUE keys of rows about to be added to a table on disk, against
+// each other and through the index files against the rows there are, so the table isn't
+// gone through. indexes are the opened files of the indexed keys
+pub fn check_unique(
+    table: &str,
+    cols: &[ColumnEntry],
+    indexes: &[(&KeyDef, &IndexFile)],
+    rows: &[TableEntry],
+    get_row: impl Fn(RowId) -> Result<TableEntry, TableLikeError>,
+) -> Result<(), TableLikeError> {
+    let keys = indexes.iter().map(|(k, _)| (*k).clone()).collect::<Vec<_>>();
+    KeyIndex::new(table, cols, &keys)?.insert_all(rows)?;
+    for (k, file) in indexes.iter().filter(|(k, _)| k.kind.is_unique()) {
+        let slots = constraints::slots_of(cols, &k.cols)?;
+        for row in rows.iter().filter(|r| r.version.is_live()) {
+            // NULL never equals anything so a key with a NULL part can't be a duplicate
+            let Some(entry) = KeyIndex::key_of(&slots, row) else {
+                continue;
+            };
+            if holds_key(file, &slots, &entry_key(&slots, row)?, &get_row)? {
+                return Err(constraints::duplicate_key(table, &k.name, &entry.join("-")));
+            }
+        }
+    }
+    Ok(())
+}
+
+// smallest byte string greater than everything starting with p, None if there is none
+fn prefix_end(p: &[u8]) -> Option<Vec<u8>> {
+    let mut out = p.to_vec();
+    while let Some(last) = out.pop() {
+        if last < 0xFF {
+            out.push(last + 1);
+            return Some(out);
+        }
+    }
+    None
+}
+
+// part of an index to go through
+#[derive(Debug)]
+pub enum KeyRange {
+    // rows with these values in all key columns, all a hash index can look up
+    Eq(Vec<u8>),
+    Between(Bound<Vec<u8>>, Bound<Vec<u8>>),
+}
+
+// the index file of a key
+pub enum IndexFile {
+    BTree(BTree),
+    Hash(HashIndex),
+}
+
+impl IndexFile {
+    pub fn open(path: &str, algorithm: IndexAlgorithm) -> std::io::Result<IndexFile> {
+        Ok(match algorithm {
+            IndexAlgorithm::BTree => Self::BTree(BTree::open(path)?),
+            IndexAlgorithm::Hash => Self::Hash(HashIndex::open(path)?),
+        })
+    }
+
+    // writes a new index from the keys of all rows with their offsets, in any order
+    pub fn build(path: &str, algorithm: IndexAlgorithm, entries: Vec<(Vec<u8>, u64)>) -> std::io::Result<IndexFile> {
+        Ok(match algorithm {
+            IndexAlgorithm::BTree => {
+                let mut entries = entries.into_iter().map(|(k, off)| (tree_key(k, off), off)).collect::<Vec<_>>();
+                entries.sort_unstable();
+                Self::BTree(BTree::build(path, entries)?)
+            }
+            IndexAlgorithm::Hash => Self::Hash(HashIndex::build(path, entries)?),
+        })
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, offset: u64) -> std::io::Result<()> {
+        match self {
+            Self::BTree(t) => t.insert(&tree_key(key, offset), offset),
+            Self::Hash(h) => h.insert(&key, offset),
+        }
+    }
+
+    // takes the entry of the row at offset out again
+    pub fn remove(&mut self, key: Vec<u8>, offset: u64) -> std::io::Result<bool> {
+        match self {
+            Self::BTree(t) => t.remove(&tree_key(key, offset)),
+            Self::Hash(h) => h.remove(&key, offset),
+        }
+    }
+
+    // offsets of the rows in the range, in no particular order
+    pub fn lookup(&self, range: &KeyRange) -> std::io::Result<Vec<u64>> {
+        match (self, range) {
+            (Self::Hash(h), KeyRange::Eq(k)) => h.get(k),
+            (Self::Hash(_), KeyRange::Between(..)) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "A hash index can't look up a range",
+            )),
+            (Self::BTree(t), KeyRange::Eq(k)) => {
+                t.range(Bound::Included(k), prefix_end(k).as_deref().map_or(Bound::Unbounded, Bound::Excluded))
+            }
+            (Self::BTree(t), KeyRange::Between(lo, hi)) => {
+                t.range(lo.as_ref().map(Vec::as_slice), hi.as_ref().map(Vec::as_slice))
+            }
+        }
+    }
+}
+
+// B-tree keys have to be unique, the offset keeps rows with the same key values apart
+fn tree_key(mut key: Vec<u8>, offset: u64) -> Vec<u8> {
+    key.extend(offset.to_be_bytes());
+    key
+}
+
+// bounds on a key column taken from the WHERE clause
+#[derive(Default)]
+struct ColBounds {
+    eq: Option<TableCell>,
+    lo: Option<(TableCell, bool)>,
+    hi: Option<(TableCell, bool)>,
+}
+
+// the literal converted to the column type if the index orders them the way the
+// comparison in the WHERE clause does, '2024-01-01 10:00' against a DATE column doesn't
+fn key_value(col: &ColumnEntry, lit: &TableCell) -> Option<TableCell> {
+    let same_order = matches!(
+        (&col.col_type, lit),
+        (TableCell::Num(_), TableCell::Num(_))
+            | (TableCell::Bool(_), TableCell::Bool(_))
+            | (TableCell::Str(_), TableCell::Str(_))
+            | (TableCell::Blob(_), TableCell::Blob(_) | TableCell::Str(_))
+            | (TableCell::Decimal(..), TableCell::Num(_) | TableCell::Decimal(..))
+            | (TableCell::Date(_) | TableCell::DateTime(_), TableCell::Date(_) | TableCell::DateTime(_) | TableCell::Str(_))
+            | (TableCell::Time(_), TableCell::Time(_) | TableCell::Str(_))
+    );
+    if !same_order || lit.is_null() {
+        return None;
+    }
+    let v = col.col_type.cast(lit).ok()?;
+    (lit.sql_cmp(&v) == Some(Ordering::Equal)).then_some(v)
+}
+
+// <col> <op> <constant> or the other way around
+fn comparison(e: &Expr) -> Option<(&str, BinOp, TableCell)> {
+    let Expr::Binary(l, op, r) = e else {
+        return None;
+    };
+    let (col, op, other) = match (l.as_ref(), r.as_ref()) {
+        (Expr::Column(c), other) => (c, *op, other),
+        (other, Expr::Column(c)) => (
+            c,
+            match op {
+                BinOp::Lt => BinOp::Gt,
+                BinOp::Le => BinOp::Ge,
+                BinOp::Gt => BinOp::Lt,
+                BinOp::Ge => BinOp::Le,
+                op => *op,
+            },
+            other,
+        ),
+        _ => return None,
+    };
+    if !matches!(op, BinOp::Eq | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge) {
+        return None;
+    }
+    // only constants, anything reading a column has to be checked row by row
+    let v = other.eval_const().ok()?;
+    Some((col, op, v))
+}
+
+fn conjuncts<'a>(e: &'a Expr, out: &mut Vec<&'a Expr>) {
+    match e {
+        Expr::Binary(l, BinOp::And, r) => {
+            conjuncts(l, out);
+            conjuncts(r, out);
+        }
+        e => out.push(e),
+    }
+}
+
+// picks the index that narrows the rows down most for a WHERE clause, the rows it
+// yields still have to be checked against the whole clause
+pub fn plan(filter: &Expr, cols: &[ColumnEntry], keys: &[KeyDef]) -> Option<(String, KeyRange)> {
+    let mut parts = Vec::new();
+    conjuncts(filter, &mut parts);
+    let mut bounds: Vec<(&str, ColBounds)> = Vec::new();
+    for (name, op, lit) in parts.into_iter().filter_map(comparison) {
+        let Some(col) = cols.iter().find(|c| c.col_name == name) else {
+            continue;
+        };
+        let Some(v) = key_value(col, &lit) else {
+            continue;
+        };
+        let b = match bounds.iter().position(|(n, _)| *n == name) {
+            Some(i) => &mut bounds[i].1,
+            None => {
+                bounds.push((name, ColBounds::default()));
+                &mut bounds.last_mut().unwrap().1
+            }
+        };
+        match op {
+            BinOp::Eq => b.eq = Some(v),
+            BinOp::Gt | BinOp::Ge => b.lo = Some((v, op == BinOp::Ge)),
+            _ => b.hi = Some((v, op == BinOp::Le)),
+        }
+    }
+    let find = |c: &str| bounds.iter().find(|(n, _)| *n == c).map(|(_, b)| b);
+    let mut best: Option<(usize, &KeyDef)> = None;
+    for k in keys.iter().filter(|k| is_indexed(k)) {
+        // every leading column compared with = counts twice, a range on the next one once
+        let eqs = k.cols.iter().take_while(|c| find(c).is_some_and(|b| b.eq.is_some())).count();
+        let range = k.cols.get(eqs).and_then(|c| find(c)).is_some_and(|b| b.lo.is_some() || b.hi.is_some());
+        let score = match k.algorithm {
+            // a hash index needs every column and then beats a B-tree over the same ones
+            IndexAlgorithm::Hash if eqs == k.cols.len() => eqs * 2 + 1,
+            IndexAlgorithm::Hash => 0,
+            IndexAlgorithm::BTree => eqs * 2 + range as usize,
+        };
+        if score > 0 && best.is_none_or(|(s, _)| score > s) {
+            best = Some((score, k));
+        }
+    }
+    let (_, k) = best?;
+    let mut prefix = Vec::new();
+    let mut range = None;
+    // every key column compared with =
+    let mut whole = true;
+    for c in &k.cols {
+        match find(c).and_then(|b| b.eq.as_ref()) {
+            Some(v) => encode_cell(&mut prefix, v),
+            None => {
+                whole = false;
+                range = find(c);
+                break;
+            }
+        }
+    }
+    let upto = |p: Option<Vec<u8>>| p.map_or(Bound::Unbounded, Bound::Excluded);
+    let range = match range {
+        Some(b) if b.lo.is_some() || b.hi.is_some() => {
+            let with = |v: &TableCell| {
+                let mut p = prefix.clone();
+                encode_cell(&mut p, v);
+                p
+            };
+            // a range never matches NULL, which sorts first
+            let lo = match &b.lo {
+                Some((v, true)) => Bound::Included(with(v)),
+                Some((v, false)) => Bound::Included(prefix_end(&with(v))?),
+                None => Bound::Included([prefix.as_slice(), &[1]].concat()),
+            };
+            let hi = match &b.hi {
+                Some((v, true)) => upto(prefix_end(&with(v))),
+                Some((v, false)) => Bound::Excluded(with(v)),
+                None => upto(prefix_end(&prefix)),
+            };
+            KeyRange::Between(lo, hi)
+        }
+        _ if whole => KeyRange::Eq(prefix),
+        _ => KeyRange::Between(Bound::Included(prefix.clone()), upto(prefix_end(&prefix))),
+    };
+    Some((k.name.clone(), range))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mvcc::Version;
+    use crate::tests::{error, run, select, TempPath};
+
+    fn cols() -> Vec<ColumnEntry> {
+        vec![
+            ColumnEntry::new("id".to_string(), TableCell::Num(None)),
+            ColumnEntry::new("name".to_string(), TableCell::Str(None)),
+        ]
+    }
+
+    fn row(id: Option<i64>, name: &str) -> TableEntry {
+        TableEntry { col_data: vec![TableCell::Num(id), TableCell::Str(Some(name.to_string()))], ..Default::default() }
+    }
+
+    fn key(kind: KeyKind, name: &str, col: &str, algorithm: IndexAlgorithm) -> KeyDef {
+        KeyDef { kind, name: name.to_string(), cols: vec![col.to_string()], algorithm }
+    }
+
+    // the rows with their ids and the index of a key over them
+    fn indexed(path: &TempPath, k: &KeyDef, rows: Vec<TableEntry>) -> IndexFile {
+        let slots = constraints::slots_of(&cols(), &k.cols).unwrap();
+        let entries = rows.iter().enumerate().map(|(i, r)| (entry_key(&slots, r).unwrap(), i as u64)).collect();
+        IndexFile::build(&path.0, k.algorithm, entries).unwrap()
+    }
+
+    #[test]
+    fn keys_sort_like_their_values() {
+        let enc = |c: TableCell| encode_key([&c]);
+        assert!(enc(TableCell::Num(Some(-5))) < enc(TableCell::Num(Some(3))));
+        assert!(enc(TableCell::Num(None)) < enc(TableCell::Num(Some(i64::MIN))));
+        assert!(enc(TableCell::Str(Some("ab".into()))) < enc(TableCell::Str(Some("ab\0".into()))));
+        assert!(enc(TableCell::Str(Some("ab\0".into()))) < enc(TableCell::Str(Some("abc".into()))));
+    }
+
+    #[test]
+    fn duplicates_are_found_through_the_index() {
+        for (algorithm, name) in [(IndexAlgorithm::BTree, "dup_btree"), (IndexAlgorithm::Hash, "dup_hash")] {
+            let path = TempPath::new("index", name);
+            let pk = key(KeyKind::Primary, "PRIMARY", "id", algorithm);
+            let mut gone = row(Some(3), "c");
+            gone.version = Version { created: 0, deleted: 7 };
+            let table = vec![row(Some(1), "a"), row(Some(2), "b"), gone];
+            let file = indexed(&path, &pk, table.clone());
+            let get_row = |rid: RowId| Ok(table[rid as usize].clone());
+            let check = |rows: &[TableEntry]| check_unique("t", &cols(), &[(&pk, &file)], rows, get_row);
+            assert!(check(&[row(Some(4), "d"), row(Some(5), "e")]).is_ok());
+            let err = check(&[row(Some(4), "d"), row(Some(2), "x")]).unwrap_err();
+            assert_eq!(err.to_string(), "Duplicate entry '2' for key 't.PRIMARY'");
+            // the new rows repeat each other
+            assert!(check(&[row(Some(9), "d"), row(Some(9), "e")]).is_err());
+            // a deleted version doesn't hold its key anymore
+            assert!(check(&[row(Some(3), "c")]).is_ok());
+            assert!(holds_key(&file, &[0], &encode_key([&TableCell::Num(Some(1))]), get_row).unwrap());
+            assert!(!holds_key(&file, &[0], &encode_key([&TableCell::Num(Some(3))]), get_row).unwrap());
+        }
+    }
+
+    #[test]
+    fn lookups_find_the_rows_an_update_gave_new_keys() {
+        let dir = crate::tests::TestDir::new("index_update");
+        let mut tm = dir.manager();
+        let mut s = tm.session();
+        run(&mut tm, &mut s, "CREATE TABLE t (id INT PRIMARY KEY, name VARCHAR(20)) ENGINE=PAGED;").unwrap();
+        run(&mut tm, &mut s, "CREATE INDEX by_name ON t (name);").unwrap();
+        let values = (0..50).map(|i| format!("({i}, 'n{i}')")).collect::<Vec<_>>();
+        run(&mut tm, &mut s, &format!("INSERT INTO t VALUES {};", values.join(", "))).unwrap();
+        run(&mut tm, &mut s, "UPDATE t SET name = 'moved' WHERE id = 2;").unwrap();
+        run(&mut tm, &mut s, "UPDATE t SET id = id + 100 WHERE id >= 40;").unwrap();
+        // a change rolled back leaves the keys as they were
+        run(&mut tm, &mut s, "BEGIN;").unwrap();
+        run(&mut tm, &mut s, "UPDATE t SET id = 200, name = 'gone' WHERE id = 3;").unwrap();
+        run(&mut tm, &mut s, "ROLLBACK;").unwrap();
+        for restarted in [false, true] {
+            if restarted {
+                drop(tm);
+                tm = dir.manager();
+                s = tm.session();
+            }
+            let mut ids = |filter: &str| {
+                let rows = select(&mut tm, &mut s, &format!("SELECT id FROM t WHERE {filter};"));
+                let mut ids = rows.into_iter().map(|r| r[0].parse::<i64>().unwrap()).collect::<Vec<_>>();
+                ids.sort();
+                ids
+            };
+            assert_eq!(ids("name = 'n2'"), vec![]);
+            assert_eq!(ids("name = 'moved'"), vec![2]);
+            assert_eq!(ids("name = 'n41'"), vec![141]);
+            assert_eq!(ids("id = 41"), vec![]);
+            assert_eq!(ids("id = 141"), vec![141]);
+            assert_eq!(ids("id >= 38 AND id < 142"), vec![38, 39, 140, 141]);
+            assert_eq!(ids("id = 3 AND name = 'n3'"), vec![3]);
+            assert_eq!(ids("id = 200 OR name = 'gone'"), vec![]);
+        }
+        // the old keys are free again and the new ones taken
+        run(&mut tm, &mut s, "INSERT INTO t VALUES (41, 'again');").unwrap();
+        assert_eq!(error(&mut tm, &mut s, "INSERT INTO t VALUES (141, 'twice');"), "Duplicate entry '141' for key 't.PRIMARY'");
+    }
+
+    #[test]
+    fn only_unique_keys_without_null_are_checked() {
+        let path = TempPath::new("index", "dup_plain");
+        let idx = key(KeyKind::Index, "name", "name", IndexAlgorithm::BTree);
+        let table = vec![row(Some(1), "a")];
+        let file = indexed(&path, &idx, table.clone());
+        let get_row = |rid: RowId| Ok(table[rid as usize].clone());
+        assert!(check_unique("t", &cols(), &[(&idx, &file)], &[row(Some(1), "a")], get_row).is_ok());
+        let path = TempPath::new("index", "dup_null");
+        let uk = key(KeyKind::Unique, "id", "id", IndexAlgorithm::BTree);
+        let table = vec![row(None, "a")];
+        let file = indexed(&path, &uk, table.clone());
+        let get_row = |rid: RowId| Ok(table[rid as usize].clone());
+        assert!(check_unique("t", &cols(), &[(&uk, &file)], &[row(None, "b"), row(None, "c")], get_row).is_ok());
+    }
+}
@@ -0,0 +1,345 @@
+/*
+
+Locks of the transactions of a TableManager
+
+The versions of mvcc.rs keep readers out of the way of writers, locks keep writers out of
+each other's way. A table is locked as a whole by LOCK TABLES and by the statements that
+change its definition, the statements that change or lock rows take an intention lock on
+it so they can run side by side and plain reads take one that only keeps it from being
+locked for writing:
+
+            IS   IX   S    X
+    IS      yes  yes  yes  no
+    IX      yes  yes  no   no
+    S       yes  no   yes  no
+    X       no   no   no   no
+
+A row is locked by its values in each unique key of its table, or by all of its values
+when it has none, so inserting the key of a row another transaction deleted or inserted
+waits for that transaction too. The locks of a transaction are held until it ends, the
+ones of LOCK TABLES until UNLOCK TABLES or the session ends.
+
+A lock another session holds in a mode that conflicts isn't waited for here: the statement
+fails with LockWait and runs again once locks were released, until the session gives up
+after innodb_lock_wait_timeout seconds. A session waiting has edges to the sessions it
+waits on in a wait-for graph, a request that would close a cycle in it fails with
+Deadlock instead and the transaction that made it is rolled back.
+
+*/
+
+use std::collections::{HashMap, HashSet};
+
+use crate::constraints::{slots_of, KeyDef, KeyIndex};
+use crate::mvcc::SessionId;
+use crate::{ColumnEntry, TableEntry, TableLikeError};
+
+// seconds, like innodb_lock_wait_timeout
+pub const DEFAULT_WAIT_TIMEOUT: u64 = 50;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockMode {
+    IntentionShared,
+    IntentionExclusive,
+    Shared,
+    Exclusive,
+}
+
+impl LockMode {
+    fn compatible(self, other: LockMode) -> bool {
+        match (self, other) {
+            (Self::Exclusive, _) | (_, Self::Exclusive) => false,
+            (Self::IntentionShared, _) | (_, Self::IntentionShared) => true,
+            (a, b) => a == b,
+        }
+    }
+
+    // taken by the statements that change the table
+    pub fn is_write(self) -> bool {
+        matches!(self, Self::IntentionExclusive | Self::Exclusive)
+    }
+}
+
+struct Granted {
+    session: SessionId,
+    mode: LockMode,
+    // taken by LOCK TABLES, outlives transactions
+    explicit: bool,
+}
+
+// the sessions holding a lock in a mode that conflicts with mode
+fn blockers(granted: &[Granted], session: SessionId, mode: LockMode) -> impl Iterator<Item = SessionId> + '_ {
+    granted.iter().filter(move |g| g.session != session && !g.mode.compatible(mode)).map(|g| g.session)
+}
+
+// the name of a row by its values in a unique key
+pub fn key_name(key: &str, entry: &[String]) -> String {
+    format!("{key}\0{}", entry.join("\0"))
+}
+
+// works out the names a row of a table is locked by
+pub struct RowNames {
+    keys: Vec<(String, Vec<usize>)>,
+}
+
+impl RowNames {
+    pub fn new(cols: &[ColumnEntry], keys: &[KeyDef]) -> Result<RowNames, TableLikeError> {
+        let keys = keys
+            .iter()
+            .filter(|k| k.kind.is_unique())
+            .map(|k| Ok((k.name.clone(), slots_of(cols, &k.cols)?)))
+            .collect::<Result<_, TableLikeError>>()?;
+        Ok(RowNames { keys })
+    }
+
+    pub fn of(&self, row: &TableEntry) -> Vec<String> {
+        let mut names = self
+            .keys
+            .iter()
+            .filter_map(|(key, slots)| KeyIndex::key_of(slots, row).map(|entry| key_name(key, &entry)))
+            .collect::<Vec<_>>();
+        // no key to go by, or NULL in all of them
+        if names.is_empty() {
+            names.push(key_name("", &row.col_data.iter().map(|c| c.to_string()).collect::<Vec<_>>()));
+        }
+        names
+    }
+}
+
+#[derive(Default)]
+pub struct Locks {
+    tables: HashMap<String, Vec<Granted>>,
+    // by table and the name of the row
+    rows: HashMap<(String, String), Vec<Granted>>,
+    // the rows each session holds, released together
+    held: HashMap<SessionId, Vec<(String, String)>>,
+    // the wait-for graph, the sessions holding the locks a session waits for
+    waits: HashMap<SessionId, HashSet<SessionId>>,
+}
+
+impl Locks {
+    // a table lock for the transaction of a session, or for LOCK TABLES when explicit
+    pub fn lock_table(&mut self, session: SessionId, table: &str, mode: LockMode, explicit: bool) -> Result<(), TableLikeError> {
+        let blocked = self.tables.get(table).map(|g| blockers(g, session, mode).collect()).unwrap_or_default();
+        self.wait_for(session, blocked)?;
+        let granted = self.tables.entry(table.to_string()).or_default();
+        if !granted.iter().any(|g| g.session == session && g.mode == mode && g.explicit == explicit) {
+            granted.push(Granted { session, mode, explicit });
+        }
+        Ok(())
+    }
+
+    // the tables of LOCK TABLES, all of them or none
+    pub fn lock_tables(&mut self, session: SessionId, tables: &[(String, LockMode)]) -> Result<(), TableLikeError> {
+        let blocked = tables
+            .iter()
+            .filter_map(|(t, mode)| self.tables.get(t).map(|g| (g, *mode)))
+            .flat_map(|(g, mode)| blockers(g, session, mode))
+            .collect();
+        self.wait_for(session, blocked)?;
+        for (t, mode) in tables {
+            self.lock_table(session, t, *mode, true)?;
+        }
+        Ok(())
+    }
+
+    // row locks of a table for the transaction of a session, all of them or none
+    pub fn lock_rows(&mut self, session: SessionId, table: &str, names: Vec<String>, mode: LockMode) -> Result<(), TableLikeError> {
+        let keys = names.into_iter().map(|n| (table.to_string(), n)).collect::<Vec<_>>();
+        let blocked = keys.iter().filter_map(|k| self.rows.get(k)).flat_map(|g| blockers(g, session, mode)).collect();
+        self.wait_for(session, blocked)?;
+        for k in keys {
+            let granted = self.rows.entry(k.clone()).or_default();
+            match granted.iter_mut().find(|g| g.session == session) {
+                Some(g) if mode == LockMode::Exclusive => g.mode = mode,
+                Some(_) => {}
+                None => {
+                    granted.push(Granted { session, mode, explicit: false });
+                    self.held.entry(session).or_default().push(k);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn wait_for(&mut self, session: SessionId, blocked: HashSet<SessionId>) -> Result<(), TableLikeError> {
+        if blocked.is_empty() {
+            self.waits.remove(&session);
+            return Ok(());
+        }
+        if self.reaches(&blocked, session) {
+            self.waits.remove(&session);
+            return Err(TableLikeError::Deadlock);
+        }
+        self.waits.insert(session, blocked);
+        Err(TableLikeError::LockWait)
+    }
+
+    // whether one of the sessions waits for target, directly or through others
+    fn reaches(&self, from: &HashSet<SessionId>, target: SessionId) -> bool {
+        let mut seen = HashSet::new();
+        let mut todo = from.iter().copied().collect::<Vec<_>>();
+        while let Some(s) = todo.pop() {
+            if s == target {
+                return true;
+            }
+            if seen.insert(s) {
+                todo.extend(self.waits.get(&s).into_iter().flatten());
+            }
+        }
+        false
+    }
+
+    // a session that gave up on the lock it waited for
+    pub fn stop_waiting(&mut self, session: SessionId) {
+        self.waits.remove(&session);
+    }
+
+    // releases the locks of the transaction of a session, and the ones of LOCK TABLES when explicit
+    pub fn release(&mut self, session: SessionId, explicit: bool) {
+        for k in self.held.remove(&session).unwrap_or_default() {
+            if let Some(granted) = self.rows.get_mut(&k) {
+                granted.retain(|g| g.session != session);
+                if granted.is_empty() {
+                    self.rows.remove(&k);
+                }
+            }
+        }
+        self.tables.retain(|_, granted| {
+            granted.retain(|g| g.session != session || (g.explicit && !explicit));
+            !granted.is_empty()
+        });
+        // nobody waits for a session without locks
+        if !self.tables.values().flatten().any(|g| g.session == session) {
+            for w in self.waits.values_mut() {
+                w.remove(&session);
+            }
+        }
+    }
+
+    // whether the session holds a lock on the table, for its transaction or with LOCK TABLES
+    pub fn holds(&self, session: SessionId, table: &str) -> bool {
+        self.tables.get(table).is_some_and(|g| g.iter().any(|g| g.session == session))
+    }
+
+    // whether the session would get the table lock without waiting
+    pub fn can_lock(&self, session: SessionId, table: &str, mode: LockMode) -> bool {
+        self.tables.get(table).is_none_or(|g| blockers(g, session, mode).next().is_none())
+    }
+
+    pub fn has_explicit(&self, session: SessionId) -> bool {
+        self.tables.values().flatten().any(|g| g.session == session && g.explicit)
+    }
+
+    // with LOCK TABLES a session only uses the tables it locked and only changes the ones
+    // it locked for writing, like MySQL
+    pub fn check_explicit(&self, session: SessionId, table: &str, mode: LockMode) -> Result<(), TableLikeError> {
+        if !self.has_explicit(session) {
+            return Ok(());
+        }
+        let held = self.tables.get(table).into_iter().flatten().filter(|g| g.session == session && g.explicit);
+        match held.map(|g| g.mode).reduce(|a, b| if b == LockMode::Exclusive { b } else { a }) {
+            None => Err(TableLikeError::new(&format!("Table '{table}' was not locked with LOCK TABLES"))),
+            Some(LockMode::Shared) if mode.is_write() => {
+                Err(TableLikeError::new(&format!("Table '{table}' was locked with a READ lock and can't be updated")))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{run, select, TestDir};
+    use LockMode::*;
+
+    #[test]
+    fn modes_are_compatible_like_the_table_says() {
+        let modes = [IntentionShared, IntentionExclusive, Shared, Exclusive];
+        let table = [[true, true, true, false], [true, true, false, false], [true, false, true, false], [false; 4]];
+        for (a, row) in modes.iter().zip(table) {
+            for (b, yes) in modes.iter().zip(row) {
+                assert_eq!(a.compatible(*b), yes, "{a:?} {b:?}");
+                let mut locks = Locks::default();
+                locks.lock_table(1, "t", *a, false).unwrap();
+                assert_eq!(locks.can_lock(2, "t", *b), yes);
+                assert_eq!(locks.lock_table(2, "t", *b, false).is_ok(), yes);
+                // a session doesn't wait for itself
+                assert!(locks.lock_table(1, "t", *b, false).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn locks_are_held_until_released() {
+        let mut locks = Locks::default();
+        locks.lock_table(1, "t", IntentionExclusive, false).unwrap();
+        locks.lock_rows(1, "t", vec![key_name("PRIMARY", &["1".to_string()])], Exclusive).unwrap();
+        locks.lock_table(2, "t", IntentionExclusive, false).unwrap();
+        assert!(matches!(locks.lock_rows(2, "t", vec![key_name("PRIMARY", &["1".to_string()])], Shared), Err(TableLikeError::LockWait)));
+        locks.lock_rows(2, "t", vec![key_name("PRIMARY", &["2".to_string()])], Exclusive).unwrap();
+        locks.lock_rows(2, "u", vec![key_name("PRIMARY", &["1".to_string()])], Exclusive).unwrap();
+        locks.release(1, false);
+        assert!(!locks.holds(1, "t"));
+        locks.lock_rows(2, "t", vec![key_name("PRIMARY", &["1".to_string()])], Exclusive).unwrap();
+        assert!(matches!(locks.lock_table(1, "t", Shared, false), Err(TableLikeError::LockWait)));
+        locks.release(2, false);
+        locks.lock_table(1, "t", Shared, false).unwrap();
+    }
+
+    #[test]
+    fn a_wait_that_closes_a_cycle_is_a_deadlock() {
+        let mut locks = Locks::default();
+        for (s, t) in [(1, "a"), (2, "b"), (3, "c")] {
+            locks.lock_table(s, t, Exclusive, false).unwrap();
+        }
+        assert!(matches!(locks.lock_table(1, "b", Shared, false), Err(TableLikeError::LockWait)));
+        assert!(matches!(locks.lock_table(2, "c", Shared, false), Err(TableLikeError::LockWait)));
+        assert!(matches!(locks.lock_table(3, "a", Shared, false), Err(TableLikeError::Deadlock)));
+        // once 1 gave up nothing waits for 3
+        locks.stop_waiting(1);
+        assert!(matches!(locks.lock_table(3, "a", Shared, false), Err(TableLikeError::LockWait)));
+        locks.release(3, false);
+        locks.lock_table(2, "c", Shared, false).unwrap();
+    }
+
+    #[test]
+    fn lock_tables_outlive_transactions() {
+        let mut locks = Locks::default();
+        locks.lock_tables(1, &[("t".to_string(), Shared), ("u".to_string(), Exclusive)]).unwrap();
+        locks.lock_table(1, "v", IntentionExclusive, false).unwrap();
+        assert!(matches!(locks.lock_tables(2, &[("t".to_string(), Shared), ("u".to_string(), Shared)]), Err(TableLikeError::LockWait)));
+        // all of them or none
+        assert!(!locks.holds(2, "t"));
+        assert!(locks.check_explicit(1, "t", IntentionShared).is_ok());
+        assert!(locks.check_explicit(1, "t", IntentionExclusive).is_err());
+        assert!(locks.check_explicit(1, "u", IntentionExclusive).is_ok());
+        assert!(locks.check_explicit(1, "v", IntentionShared).is_err());
+        assert!(locks.check_explicit(2, "v", IntentionShared).is_ok());
+        locks.release(1, false);
+        assert!(locks.has_explicit(1) && !locks.holds(1, "v"));
+        locks.release(1, true);
+        assert!(!locks.has_explicit(1));
+        locks.lock_tables(2, &[("t".to_string(), Shared), ("u".to_string(), Shared)]).unwrap();
+    }
+
+    #[test]
+    fn transactions_wait_for_the_rows_others_changed() {
+        let dir = TestDir::new("row_locks");
+        let mut tm = dir.manager();
+        let mut a = tm.session();
+        let mut b = tm.session();
+        for sql in ["CREATE TABLE t (id INT PRIMARY KEY, n INT);", "INSERT INTO t VALUES (1, 1), (2, 2);", "BEGIN;", "UPDATE t SET n = 10 WHERE id = 1;"] {
+            run(&mut tm, &mut a, sql).unwrap();
+        }
+        run(&mut tm, &mut b, "BEGIN;").unwrap();
+        assert!(matches!(run(&mut tm, &mut b, "INSERT INTO t VALUES (1, 5);"), Err(TableLikeError::LockWait)));
+        run(&mut tm, &mut b, "UPDATE t SET n = 20 WHERE id = 2;").unwrap();
+        assert!(matches!(run(&mut tm, &mut b, "UPDATE t SET n = 20 WHERE id = 1;"), Err(TableLikeError::LockWait)));
+        // a waiting for b while b waits for a, a is rolled back and b goes on
+        assert!(matches!(run(&mut tm, &mut a, "DELETE FROM t WHERE id = 2;"), Err(TableLikeError::Deadlock)));
+        run(&mut tm, &mut b, "UPDATE t SET n = 20 WHERE id = 1;").unwrap();
+        run(&mut tm, &mut b, "COMMIT;").unwrap();
+        assert_eq!(select(&mut tm, &mut a, "SELECT id, n FROM t;"), vec![vec!["1", "20"], vec!["2", "20"]]);
+    }
+}
@@ -1,25 +1,55 @@
+pub mod alter;
+pub mod btree;
+pub mod buffer;
+pub mod cache;
+pub mod constraints;
+pub mod datetime;
 pub mod db;
+pub mod decimal;
+pub mod foreign_keys;
+pub mod functions;
+pub mod hash;
+pub mod index;
+pub mod locks;
+pub mod mvcc;
+pub mod paged;
 pub mod query;
+pub mod wal;
 
-use std::collections::HashMap;
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Seek, Write as ioWrite, Lines, Read};
-use std::marker::PhantomData;
-use std::rc::{Weak, Rc};
-
-use db::TableParser;
-use pest::Parser;
+use std::cmp::Ordering;
+use std::io::{BufRead, BufReader, BufWriter, Write as ioWrite};
+use std::net::TcpListener;
+use std::sync::atomic::{self, AtomicU32};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+use buffer::{FileId, PoolReader, PoolWriter, SharedPool};
+use cache::TableCache;
+
+use constraints::{slots_of, ConstraintKind, KeyDef, KeyIndex, KeyKind, RowValidator};
+use datetime::{Date, DateTime, Time};
+use db::{OldSchema, RowReader};
+use index::{IndexFile, KeyRange};
+use locks::{LockMode, Locks, RowNames};
+use mvcc::{SessionId, Snapshot, Transactions, TxnId, Version};
+use paged::PageTable;
+use decimal::{DecimalSpec, DecimalType};
 use pest_derive::Parser;
-use query::Criteria;
+use query::{Criteria, Expr, Projection, SelectItem, Statement};
+use wal::{SyncPolicy, Wal};
 
-use crate::db::ParseState;
 use crate::query::Closure;
 
-const MAX_MEM_LIM: usize = 4096; // ROWS
+const SESSION_STACK_SIZE: usize = 64 << 20; // BYTES of stack of the thread a session runs on
+const MAX_COL_WIDTH: usize = 256; // characters of a value printed, longer ones are cut short with ...
 
 pub type NumType = i64;
 pub type StringType = String;
+pub type BlobType = Vec<u8>;
 
 pub const NUM_BASE: u32 = 10;
 
@@ -27,26 +57,225 @@ pub const NUM_BASE: u32 = 10;
 pub struct Table {
     name: Option<String>,
     col_names: Vec<ColumnEntry>,
+    keys: Vec<KeyDef>,
     all: Vec<TableEntry>,
+    auto_increment: Option<NumType>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct TableEntry {
     col_data: Vec<TableCell>,
+    // the transactions that created and deleted this version of the row
+    version: Version,
+}
+
+impl TableEntry {
+    // rough size in memory, for limits on how many rows are kept around
+    pub fn mem_size(&self) -> usize {
+        let heap = |c: &TableCell| match c {
+            TableCell::Str(Some(s)) => s.len(),
+            TableCell::Blob(Some(b)) => b.len(),
+            _ => 0,
+        };
+        std::mem::size_of::<TableEntry>() + self.col_data.iter().map(|c| std::mem::size_of::<TableCell>() + heap(c)).sum::<usize>()
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct ColumnEntry {
     col_name: String,
     col_type: TableCell,
+    modifiers: TypeModifiers,
+    constraints: ColumnConstraints,
+}
+
+// type parameters that don't change the kind of value a column holds
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct TypeModifiers {
+    // in characters for strings and bytes for blobs
+    max_len: Option<usize>,
+    // CHAR and BINARY instead of VARCHAR and VARBINARY
+    fixed_len: bool,
+    unsigned: bool,
+}
+
+// NOT NULL, DEFAULT and CHECK, expressions are kept as SQL text like in the table header
+// and compiled when rows are written
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct ColumnConstraints {
+    not_null: bool,
+    default: Option<String>,
+    checks: Vec<CheckConstraint>,
+    auto_increment: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CheckConstraint {
+    // named <table>_chk_<n> on CREATE TABLE when not given
+    name: Option<String>,
+    expr: String,
 }
 
+const MAX_VARCHAR_LEN: u32 = 65535;
+const MAX_CHAR_LEN: u32 = 255;
+
 impl ColumnEntry {
-    pub fn write_type(&self) -> &str {
-        match self.col_type {
-            TableCell::Num(_) => "Num",
-            TableCell::Str(_) => "String",
+    pub fn new(col_name: String, col_type: TableCell) -> ColumnEntry {
+        ColumnEntry { col_name, col_type, modifiers: TypeModifiers::default(), constraints: ColumnConstraints::default() }
+    }
+
+    pub fn from_sql(col_name: String, ty: &str, args: &[u32], unsigned: bool) -> Result<ColumnEntry, TableLikeError> {
+        let up = ty.to_ascii_uppercase();
+        let mut modifiers = TypeModifiers { unsigned, ..Default::default() };
+        let len = |max: u32, fixed: bool| match args {
+            [] if fixed => Ok(1),
+            [n] if *n <= max => Ok(*n as usize),
+            [_] => Err(TableLikeError::new(&format!("Column length too big for column '{col_name}' (max = {max})"))),
+            _ => Err(TableLikeError::new(&format!("Invalid arguments for {up}"))),
+        };
+        let col_type = match up.as_str() {
+            "VARCHAR" | "STRING" if !args.is_empty() => {
+                modifiers.max_len = Some(len(MAX_VARCHAR_LEN, false)?);
+                TableCell::Str(None)
+            }
+            "CHAR" => {
+                modifiers.max_len = Some(len(MAX_CHAR_LEN, true)?);
+                modifiers.fixed_len = true;
+                TableCell::Str(None)
+            }
+            "VARBINARY" | "BLOB" if !args.is_empty() => {
+                modifiers.max_len = Some(len(MAX_VARCHAR_LEN, false)?);
+                TableCell::Blob(None)
+            }
+            "BINARY" => {
+                modifiers.max_len = Some(len(MAX_CHAR_LEN, true)?);
+                modifiers.fixed_len = true;
+                TableCell::Blob(None)
+            }
+            _ => TableCell::from_sql_type(ty, args)?,
+        };
+        if unsigned && !matches!(col_type, TableCell::Num(_) | TableCell::Decimal(..)) {
+            return Err(TableLikeError::new(&format!("UNSIGNED is not allowed for column '{col_name}'")));
+        }
+        Ok(ColumnEntry { col_name, col_type, modifiers, constraints: ColumnConstraints::default() })
+    }
+
+    // type descriptor written to the table file header, read back by query::parse_column_type
+    pub fn write_type(&self) -> String {
+        let mut desc = match (&self.col_type, self.modifiers.max_len) {
+            (TableCell::Decimal(_, spec), _) => format!("{}({},{})", self.col_type.type_name(), spec.precision, spec.scale),
+            (TableCell::Str(_), Some(n)) if self.modifiers.fixed_len => format!("Char({n})"),
+            (TableCell::Blob(_), Some(n)) if self.modifiers.fixed_len => format!("Binary({n})"),
+            (t, Some(n)) => format!("{}({n})", t.type_name()),
+            (t, None) => t.type_name().to_string(),
+        };
+        if self.modifiers.unsigned {
+            desc.push_str(" UNSIGNED");
+        }
+        let c = &self.constraints;
+        if c.not_null {
+            desc.push_str(" NOT NULL");
+        }
+        if let Some(d) = &c.default {
+            desc.push_str(&format!(" DEFAULT {d}"));
+        }
+        if c.auto_increment {
+            desc.push_str(" AUTO_INCREMENT");
+        }
+        for chk in &c.checks {
+            if let Some(n) = &chk.name {
+                desc.push_str(&format!(" CONSTRAINT {n}"));
+            }
+            desc.push_str(&format!(" CHECK ({})", chk.expr));
+        }
+        desc
+    }
+
+    // value a column gets when an INSERT leaves it out
+    pub fn default_value(&self, strict: bool) -> Result<TableCell, TableLikeError> {
+        match &self.constraints.default {
+            Some(d) => self.coerce(&query::parse_expr_text(d)?.eval_const()?, strict),
+            // filled in from the table's counter
+            None if self.constraints.auto_increment => Ok(self.col_type.null()),
+            None if self.constraints.not_null => Err(TableLikeError::ConstraintViolation {
+                kind: ConstraintKind::NoDefault,
+                name: self.col_name.clone(),
+            }),
+            None => Ok(self.col_type.null()),
+        }
+    }
+
+    // type as MySQL spells it, for DESCRIBE
+    pub fn sql_type(&self) -> String {
+        let m = &self.modifiers;
+        let mut desc = match (&self.col_type, m.max_len) {
+            (TableCell::Num(_), _) => "bigint".to_string(),
+            (TableCell::Str(_), Some(n)) => format!("{}({n})", if m.fixed_len { "char" } else { "varchar" }),
+            (TableCell::Str(_), None) => "text".to_string(),
+            (TableCell::Bool(_), _) => "boolean".to_string(),
+            (TableCell::Date(_), _) => "date".to_string(),
+            (TableCell::Time(_), _) => "time".to_string(),
+            (TableCell::DateTime(_), _) => "datetime".to_string(),
+            (TableCell::Decimal(_, spec), _) => format!("decimal({},{})", spec.precision, spec.scale),
+            (TableCell::Blob(_), Some(n)) => format!("{}({n})", if m.fixed_len { "binary" } else { "varbinary" }),
+            (TableCell::Blob(_), None) => "blob".to_string(),
+        };
+        if m.unsigned {
+            desc.push_str(" unsigned");
+        }
+        desc
+    }
+
+    // converts a value for storage in this column, too long or negative values for
+    // UNSIGNED columns are errors in strict mode and get truncated or clamped otherwise
+    pub fn coerce(&self, val: &TableCell, strict: bool) -> Result<TableCell, TableLikeError> {
+        let m = &self.modifiers;
+        let mut v = self.col_type.cast(val)?;
+        let too_long = || TableLikeError::new(&format!("Data too long for column '{}'", self.col_name));
+        let out_of_range = || TableLikeError::new(&format!("Out of range value for column '{}'", self.col_name));
+        match &mut v {
+            TableCell::Str(Some(s)) => {
+                // CHAR is space padded on disk in MySQL and the padding is stripped on read
+                if m.fixed_len {
+                    s.truncate(s.trim_end_matches(' ').len());
+                }
+                if let Some(n) = m.max_len.filter(|n| s.chars().count() > *n) {
+                    if strict {
+                        return Err(too_long());
+                    }
+                    let end = s.char_indices().nth(n).map(|(i, _)| i).unwrap_or(s.len());
+                    s.truncate(end);
+                }
+            }
+            TableCell::Blob(Some(b)) => {
+                if let Some(n) = m.max_len {
+                    if b.len() > n {
+                        if strict {
+                            return Err(too_long());
+                        }
+                        b.truncate(n);
+                    }
+                    // BINARY is zero padded and keeps the padding
+                    if m.fixed_len {
+                        b.resize(n, 0);
+                    }
+                }
+            }
+            TableCell::Num(Some(n)) if m.unsigned && *n < 0 => {
+                if strict {
+                    return Err(out_of_range());
+                }
+                *n = 0;
+            }
+            TableCell::Decimal(Some(n), _) if m.unsigned && *n < 0 => {
+                if strict {
+                    return Err(out_of_range());
+                }
+                *n = 0;
+            }
+            _ => {}
         }
+        Ok(v)
     }
 }
 
@@ -61,15 +290,6 @@ impl ColumnEntry {
 //     entity_offset_from: Weak<TableReference<'a>>,
 // }
 
-struct TableReference {
-    inner: TableKind
-}
-
-enum TableKind {
-    File,
-    Memory,
-}
-
 #[derive(Debug)]
 pub enum TableLikeError {
     IoError {
@@ -79,6 +299,15 @@ pub enum TableLikeError {
     SpecificError {
         message: String,
     },
+    // a row was rejected by a column or table constraint, name is the column or constraint
+    ConstraintViolation {
+        kind: ConstraintKind,
+        name: String,
+    },
+    // a lock another transaction holds, the statement can run again once it is released, see locks.rs
+    LockWait,
+    // waiting for the lock would close a cycle of transactions waiting for each other
+    Deadlock,
     Other
 }
 
@@ -88,6 +317,20 @@ impl TableLikeError {
     }
 }
 
+impl Display for TableLikeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError { source } => source.fmt(f),
+            Self::FmtError => "Formatting failed".fmt(f),
+            Self::SpecificError { message } => message.fmt(f),
+            Self::ConstraintViolation { kind, name } => kind.message(name).fmt(f),
+            Self::LockWait => "Lock wait timeout exceeded; try restarting transaction".fmt(f),
+            Self::Deadlock => "Deadlock found when trying to get lock; try restarting transaction".fmt(f),
+            Self::Other => "Unknown error".fmt(f),
+        }
+    }
+}
+
 impl From<std::io::Error> for TableLikeError {
     fn from(value: std::io::Error) -> Self {
         Self::IoError { source: value }
@@ -100,28 +343,62 @@ impl From<std::fmt::Error> for TableLikeError {
     }
 }
 
-pub trait TableLike: Display {
+pub type RowIter<'a> = Box<dyn Iterator<Item = Result<TableEntry, TableLikeError>> + 'a>;
+// where a row is kept, the byte offset of its RStart line in a FileTable, its page and
+// slot in a PageTable and its position in a Table, stays the same until the row moves
+pub type RowId = u64;
+
+pub trait TableLike: Display + Send + Sync {
 
     fn get_name(&self) -> Option<&str>;
     //TODO make get_rows return references
-    fn get_rows(&self) -> Box<dyn Iterator<Item = Result<TableEntry, TableLikeError>> + '_>;
+    fn get_rows(&self) -> RowIter<'_>;
+    fn get_row(&self, rid: RowId) -> Result<TableEntry, TableLikeError>;
     fn get_cols(&self) -> Result<Vec<ColumnEntry>, TableLikeError>;
+    fn get_keys(&self) -> Result<Vec<KeyDef>, TableLikeError>;
     fn add_rows(&mut self, rows: &mut dyn Iterator<Item = TableEntry>) -> Result<(), TableLikeError>;
     fn flush(&mut self, t: &dyn TableLike) -> Result<(), TableLikeError>;
     fn move_to_memory(&mut self) -> Result<Table, TableLikeError>;
-    fn move_to_file(&mut self, name: &str) -> Result<FileTable, TableLikeError>;
+    fn move_to_file(&mut self, name: &str, pool: &SharedPool) -> Result<FileTable, TableLikeError>;
+    // calls f on every version of every row (see mvcc.rs), f changes it in place and
+    // returns whether it did, the number of changed rows is returned
+    fn update_rows(&mut self, f: &mut dyn FnMut(&mut TableEntry) -> Result<bool, TableLikeError>) -> Result<usize, TableLikeError>;
+    // removes the rows f returns true for and returns how many
+    fn delete_rows(&mut self, f: &mut dyn FnMut(&TableEntry) -> Result<bool, TableLikeError>) -> Result<usize, TableLikeError>;
+    // next value of the AUTO_INCREMENT column, never lower than what was handed out before
+    fn next_auto_increment(&self) -> Result<NumType, TableLikeError>;
+    fn set_auto_increment(&mut self, next: NumType) -> Result<(), TableLikeError>;
+    // whether a row has these values for the PRIMARY or UNIQUE key of that name
+    fn contains_key(&self, key: &str, entry: &[TableCell]) -> Result<bool, TableLikeError>;
+    // rows in a range of the on-disk index of a key, None for tables without indexes
+    fn index_rows(&self, key: &str, range: &KeyRange) -> Option<RowIter<'_>>;
+    // writes back whatever hasn't reached the file yet, before the table is dropped
+    fn close(&mut self) -> Result<(), TableLikeError>;
+    // how many older schemas there are rows of, see alter.rs
+    fn old_schemas(&self) -> usize {
+        0
+    }
 
 }
 
 impl TableLike for Table {
 
     fn flush(&mut self, t: &dyn TableLike) -> Result<(), TableLikeError> {
-        self.col_names.clear();
-        self.col_names.extend(t.get_cols()?);
-        self.all.clear();
+        let cols = t.get_cols()?;
+        let keys = t.get_keys()?;
+        let val = RowValidator::new(&cols, &keys)?;
+        let mut all = Vec::new();
         for row in t.get_rows() {
-            self.all.push(row?);
+            let row = row?;
+            val.validate(&row)?;
+            all.push(row);
         }
+        KeyIndex::new(t.get_name().unwrap_or_default(), &cols, &keys)?.insert_all(&all)?;
+        self.name = t.get_name().map(str::to_string);
+        self.auto_increment = None;
+        self.col_names = cols;
+        self.keys = keys;
+        self.all = all;
         Ok(())
     }
 
@@ -130,20 +407,42 @@ impl TableLike for Table {
     }
 
     fn add_rows(&mut self, rows: &mut dyn Iterator<Item = TableEntry>) -> Result<(), TableLikeError> {
-        rows.for_each(|f| self.all.push(f));
+        let val = RowValidator::new(&self.col_names, &self.keys)?;
+        let rows = rows.collect::<Vec<_>>();
+        for row in &rows {
+            val.validate(row)?;
+        }
+        // memory tables are small enough to index on every write
+        let mut idx = self.key_index()?;
+        if !idx.is_empty() {
+            idx.insert_all(&self.all)?;
+            idx.insert_all(&rows)?;
+        }
+        self.all.extend(rows);
         Ok(())
     }
     
     fn get_cols(&self) -> Result<Vec<ColumnEntry>, TableLikeError> {
         Ok(self.col_names.clone())
     }
+
+    fn get_keys(&self) -> Result<Vec<KeyDef>, TableLikeError> {
+        Ok(self.keys.clone())
+    }
     
-    fn get_rows(&self) -> Box<dyn Iterator<Item = Result<TableEntry, TableLikeError>> + '_> {
+    fn get_rows(&self) -> RowIter<'_> {
         Box::new(self.all.iter().map(|f| Ok(f.clone())))
     }
 
-    fn move_to_file(&mut self, name: &str) -> Result<FileTable, TableLikeError> {
-        let mut f = FileTable::new(name)?;
+    fn get_row(&self, rid: RowId) -> Result<TableEntry, TableLikeError> {
+        self.all
+            .get(rid as usize)
+            .cloned()
+            .ok_or_else(|| TableLikeError::new(&format!("No row with id {rid}")))
+    }
+
+    fn move_to_file(&mut self, name: &str, pool: &SharedPool) -> Result<FileTable, TableLikeError> {
+        let mut f = FileTable::new(name, pool)?;
         f.flush(self)?;
         Ok(f)
     }
@@ -152,120 +451,273 @@ impl TableLike for Table {
         Err(TableLikeError::new("Already a Memory Table"))
     }
 
-}
-
-#[derive(PartialEq, PartialOrd, Debug)]
-pub enum PrintOption {
-    FullTable,
-    StartTable,
-    MidTable,
-    EndTable
-}
-
-impl Table {
-    pub fn print_table(&self, f: &mut std::fmt::Formatter<'_>, widths: &mut [usize], table_option: PrintOption) -> Result<(), TableLikeError> {
-        //get max width of each column
-        //then it's just simple prints all the way
-        //O(n) operations all the way
-        // | for column terminators and + for corners
-        // - for header into fields
-
-
-        for t in &self.all {
-            for (r, e) in t.col_data.iter().zip(widths.iter_mut()) {
-                *e = r.get_len().max(*e)
+    fn update_rows(&mut self, f: &mut dyn FnMut(&mut TableEntry) -> Result<bool, TableLikeError>) -> Result<usize, TableLikeError> {
+        // changes go to a copy so a row failing its constraints leaves the table as it was
+        let val = RowValidator::new(&self.col_names, &self.keys)?;
+        let mut all = self.all.clone();
+        let mut n = 0;
+        for row in all.iter_mut() {
+            if f(row)? {
+                val.validate(row)?;
+                n += 1;
             }
         }
-
-        if table_option == PrintOption::FullTable || table_option == PrintOption::StartTable {
-            for (t, q) in self.col_names.iter().zip(widths.iter_mut()) {
-                *q = t.col_name.len().max(*q);
-            }
-
-            //now actually print 
-            //one char space between col borders
-            //for col names
-            //border
-            for sz in widths.iter() {
-                write!(f, "+-{num:-<width$}-", num = '-', width = sz)?;
-            }
-            writeln!(f, "+")?;
-            //for col_header
-            for (t, sz) in self.col_names.iter().zip(widths.iter()) {
-                write!(f, "| {num:<width$} ", num = t.col_name.as_str(), width = sz)?;
-            }
-            writeln!(f, "|")?;
-            //for col_header bottom border
-            for sz in widths.iter() {
-                write!(f, "+-{num:-<width$}-", num = '-', width = sz)?;
-            }
-            writeln!(f, "+")?;
+        // keys are checked once every row is changed so UPDATE t SET id = id + 1 works
+        if n > 0 {
+            self.key_index()?.insert_all(&all)?;
         }
-        
-        let mut first = true;
-        //for row items
+        self.all = all;
+        Ok(n)
+    }
+
+    fn delete_rows(&mut self, f: &mut dyn FnMut(&TableEntry) -> Result<bool, TableLikeError>) -> Result<usize, TableLikeError> {
+        let mut keep = Vec::with_capacity(self.all.len());
         for row in &self.all {
-            if !first {
-                writeln!(f)?;
-            } else {
-                first = false;
-            }
-            for (t, sz) in row.col_data.iter().zip(widths.iter()) {
-                write!(f, "| {t:<sz$} ")?;
-            }
-            write!(f, "|")?;
+            keep.push(!f(row)?);
         }
+        let before = self.all.len();
+        let mut it = keep.into_iter();
+        self.all.retain(|_| it.next().unwrap());
+        Ok(before - self.all.len())
+    }
 
-        if table_option == PrintOption::EndTable || table_option == PrintOption::FullTable {
-            //for closing border
-            writeln!(f)?;
-            for sz in widths.iter() {
-                write!(f, "+-{num:-<width$}-", num = '-', width = sz)?;
-            }
-            write!(f, "+")?;
+    fn next_auto_increment(&self) -> Result<NumType, TableLikeError> {
+        match self.auto_increment {
+            Some(n) => Ok(n),
+            None => scan_auto_increment(self),
         }
-        
+    }
+
+    fn set_auto_increment(&mut self, next: NumType) -> Result<(), TableLikeError> {
+        self.auto_increment = Some(next);
+        Ok(())
+    }
+
+    fn contains_key(&self, key: &str, entry: &[TableCell]) -> Result<bool, TableLikeError> {
+        let mut idx = self.key_index()?;
+        idx.insert_all(&self.all)?;
+        Ok(idx.contains(key, &entry.iter().map(TableCell::to_string).collect::<Vec<_>>()))
+    }
+
+    fn close(&mut self) -> Result<(), TableLikeError> {
+        // nothing of a memory table is on disk
         Ok(())
     }
+
+    fn index_rows(&self, _: &str, _: &KeyRange) -> Option<RowIter<'_>> {
+        None
+    }
+
+}
+
+impl Table {
+    fn key_index(&self) -> Result<KeyIndex, TableLikeError> {
+        KeyIndex::new(self.name.as_deref().unwrap_or_default(), &self.col_names, &self.keys)
+    }
+
+    pub fn print_table(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), TableLikeError> {
+        let mut widths = col_widths(&self.col_names);
+        for row in &self.all {
+            widen(&mut widths, row);
+        }
+        print_rows(f, &self.col_names, &widths, self.all.iter().map(Ok))
+    }
 }
 
 impl Display for Table {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut v = Vec::with_capacity(self.col_names.len());
-        (0..self.col_names.len()).for_each(|_| v.push(0));
-        self.print_table(f, &mut v,PrintOption::FullTable).map_err(|_| std::fmt::Error)
+        self.print_table(f).map_err(|_| std::fmt::Error)
+    }
+}
+
+// how CREATE TABLE ... ENGINE=<name> stores a table, open tells them apart by the start of the file
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Engine {
+    // lines of text, see db.rs
+    #[default]
+    Text,
+    // binary rows in pages, see paged.rs
+    Paged,
+}
+
+impl Engine {
+    pub fn from_name(name: &str) -> Result<Engine, TableLikeError> {
+        match name.to_ascii_uppercase().as_str() {
+            "TEXT" => Ok(Self::Text),
+            "PAGED" => Ok(Self::Paged),
+            _ => Err(TableLikeError::new(&format!("Unknown storage engine '{name}'"))),
+        }
     }
 }
 
 pub struct FileTable {
     name: String,
     inner: File,
+    // rows are read through the pool, writes go around it through its log and drop the pages they change
+    pool: SharedPool,
+    id: FileId,
+    // columns and keys with the older schemas of the rows and the offset of the first row, so
+    // reading rows doesn't go through the header every time, None until the file has one
+    header: Option<(Table, Vec<OldSchema>, RowId)>,
+    // the rows of a result, removed when dropped
+    temp: bool,
 }
 
+// names of the files of results too big for memory, which can't be table names
+const SPILL_PREFIX: &str = "#sql-";
+
 impl FileTable {
-    fn new(name: &str) -> Result<FileTable, TableLikeError> {
-        Ok(FileTable { name: name.to_owned(), inner: File::options()
+    fn new(name: &str, pool: &SharedPool) -> Result<FileTable, TableLikeError> {
+        let inner = File::options()
             .read(true)
             .write(true)
             .create_new(false)
-            .open(name)? 
-        })
+            .open(name)?;
+        let id = buffer::lock(pool).attach(name, &inner)?;
+        let mut ft = FileTable { name: name.to_owned(), inner, pool: pool.clone(), id, header: None, temp: false };
+        // a file without a valid header may still be flushed over
+        ft.header = ft.parse_header().ok();
+        Ok(ft)
     }
 
-    fn create_new(name: &str) -> Result<FileTable, TableLikeError> {
-        Ok(FileTable { name: name.to_owned(), inner: File::options()
-            .read(true)
-            .write(true)
-            .create_new(true)
-            .open(name)? 
+    fn create_new(name: &str, pool: &SharedPool) -> Result<FileTable, TableLikeError> {
+        let (inner, id) = {
+            let mut p = buffer::lock(pool);
+            let inner = p.wal().create(name)?;
+            let id = p.attach(name, &inner)?;
+            // whatever is cached is from an earlier file of the same name
+            p.discard(id, 0);
+            (inner, id)
+        };
+        Ok(FileTable { name: name.to_owned(), inner, pool: pool.clone(), id, header: None, temp: false })
+    }
+
+    // a file for the rows of a result that don't fit in memory, gone again once it is dropped
+    fn create_temp(name: &str, pool: &SharedPool) -> Result<FileTable, TableLikeError> {
+        let id = buffer::lock(pool).create_temp(name)?;
+        let inner = File::options().read(true).write(true).open(name)?;
+        Ok(FileTable { name: name.to_owned(), inner, pool: pool.clone(), id, header: None, temp: true })
+    }
+
+    fn reader(&self, pos: u64) -> BufReader<PoolReader> {
+        BufReader::new(PoolReader::new(self.pool.clone(), self.id, pos))
+    }
+
+    // reads the header from the start of the file, with the offset of the first row
+    fn parse_header(&self) -> Result<(Table, Vec<OldSchema>, RowId), TableLikeError> {
+        db::parse_header(&mut self.reader(0))
+    }
+
+    fn read_header(&self) -> Result<Table, TableLikeError> {
+        match &self.header {
+            Some((t, _, _)) => Ok(Table { col_names: t.col_names.clone(), keys: t.keys.clone(), ..Default::default() }),
+            None => Ok(self.parse_header()?.0),
+        }
+    }
+
+    // reader that resumes parsing rows at rid
+    fn reader_at(&self, rid: RowId) -> Result<RowReader<BufReader<PoolReader>>, TableLikeError> {
+        let (table, schemas) = match &self.header {
+            Some((_, schemas, _)) => (self.read_header()?, schemas.clone()),
+            None => {
+                let (table, schemas, _) = self.parse_header()?;
+                (table, schemas)
+            }
+        };
+        Ok(RowReader::new(self.reader(rid), rid, table.col_names, schemas))
+    }
+
+    // reader positioned at the first row
+    fn scan(&self) -> Result<RowReader<BufReader<PoolReader>>, TableLikeError> {
+        let start = match &self.header {
+            Some((_, _, start)) => *start,
+            None => self.parse_header()?.2,
+        };
+        self.reader_at(start)
+    }
+
+    // writes the header again in place with the older schemas the rows there are were written
+    // under, false when it doesn't fit in front of the first row
+    fn write_schema(&mut self, cols: &[ColumnEntry], keys: &[KeyDef], schemas: &[OldSchema]) -> Result<bool, TableLikeError> {
+        let (_, _, start) = self.parse_header()?;
+        let Some(header) = db::pad_header(db::encode_header_with(cols, keys, schemas), start as usize) else {
+            return Ok(false);
+        };
+        {
+            let mut pool = buffer::lock(&self.pool);
+            pool.write_file(self.id, 0, header.as_bytes())?;
+            pool.discard(self.id, 0);
+        }
+        self.header = None;
+        Ok(true)
+    }
+
+    fn open_index(&self, key: &KeyDef, cols: &[ColumnEntry]) -> Result<IndexFile, TableLikeError> {
+        index::open_or_build(&self.name, key, cols, || {
+            let mut it = self.scan()?;
+            Ok(std::iter::from_fn(move || it.next_row()))
         })
     }
 
+    // rows are text of varying length so they can't be changed in place, the table is written
+    // again a row at a time as it is read. f returns the row to write instead, None to leave it
+    // out, and whether it changed it, the file is only replaced once one did and all of them
+    // passed their constraints
+    fn rewrite(&mut self, f: &mut dyn FnMut(TableEntry) -> Result<(Option<TableEntry>, bool), TableLikeError>) -> Result<usize, TableLikeError> {
+        let header = self.read_header()?;
+        let val = RowValidator::new(&header.col_names, &header.keys)?;
+        // keys are checked once every row is changed so UPDATE t SET id = id + 1 works
+        let mut keys = KeyIndex::new(&self.name, &header.col_names, &header.keys)?;
+        let mut n = 0;
+        let tmp = buffer::lock(&self.pool).create_temp(&wal::temp_path(&self.name))?;
+        let mut wri = BufWriter::new(PoolWriter::new(self.pool.clone(), tmp, 0));
+        let rows = self.scan()?.filter_map(|row| {
+            let edit = || {
+                let (row, changed) = f(row?)?;
+                if let Some(r) = &row {
+                    if changed {
+                        val.validate(r)?;
+                    }
+                    if !keys.is_empty() {
+                        keys.insert_all([r])?;
+                    }
+                }
+                n += changed as usize;
+                Ok(row)
+            };
+            edit().transpose()
+        });
+        let written = write_rows(&header.col_names, &header.keys, rows, &mut wri).and_then(|w| Ok((w, wri.flush()?)));
+        drop(wri);
+        let (indexes, start) = match written {
+            Ok(((indexes, start), _)) if n > 0 => (indexes, start),
+            res => {
+                buffer::lock(&self.pool).remove_temp(tmp)?;
+                return res.map(|_| 0);
+            }
+        };
+        self.inner = buffer::lock(&self.pool).rename(tmp, self.id)?;
+        for (k, entries) in indexes {
+            IndexFile::build(&index::index_path(&self.name, &k.name), k.algorithm, entries)?;
+        }
+        self.header = Some((header, Vec::new(), start));
+        Ok(n)
+    }
+}
+
+impl Drop for FileTable {
+    fn drop(&mut self) {
+        if self.temp {
+            buffer::lock(&self.pool).discard(self.id, 0);
+            // nowhere to report a failure, what is left is removed on the next start
+            let _ = std::fs::remove_file(&self.name);
+        }
+    }
 }
 
 impl Display for FileTable {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.print_table(f).map_err(|f| std::fmt::Error)?;
+        print_streamed(self, f).map_err(|_| std::fmt::Error)?;
         Ok(())
     }
 }
@@ -273,26 +725,21 @@ impl Display for FileTable {
 impl TableLike for FileTable {
 
     fn flush(&mut self, t: &dyn TableLike) -> Result<(), TableLikeError>{
-        let f = &mut self.inner;
-        f.set_len(0)?;
-        f.flush()?;
-        f.rewind()?;
-        let mut wri = BufWriter::new(f);
-        writeln!(&mut wri, "ColDescStart")?;
+        // validate everything before the old contents are truncated
+        check_rows(&self.name, t)?;
+        self.header = None;
+        // the rows go to a new file that replaces this one once it is complete, so the table
+        // is never seen half written
+        let tmp = buffer::lock(&self.pool).create_temp(&wal::temp_path(&self.name))?;
+        let mut wri = BufWriter::new(PoolWriter::new(self.pool.clone(), tmp, 0));
+        let (indexes, start) = write_rows(&t.get_cols()?, &t.get_keys()?, t.get_rows(), &mut wri)?;
         wri.flush()?;
-        for f2 in t.get_cols()? {
-            writeln!(&mut wri, "\"{}\"", f2.col_name)?;
-            writeln!(&mut wri, "\"{}\"", f2.write_type())?;
+        drop(wri);
+        self.inner = buffer::lock(&self.pool).rename(tmp, self.id)?;
+        for (k, entries) in indexes {
+            IndexFile::build(&index::index_path(&self.name, &k.name), k.algorithm, entries)?;
         }
-        writeln!(&mut wri, "ColDescEnd")?;
-        for row in t.get_rows() {
-            writeln!(&mut wri, "RStart")?;
-            for row_col in &row?.col_data {
-                writeln!(&mut wri, "\"{row_col}\"")?;
-            }
-            writeln!(&mut wri, "REnd")?;
-        }
-        wri.flush()?;
+        self.header = Some((Table { col_names: t.get_cols()?, keys: t.get_keys()?, ..Default::default() }, Vec::new(), start));
         Ok(())
     }
 
@@ -300,72 +747,65 @@ impl TableLike for FileTable {
         Some(&self.name)
     }
 
-    fn get_rows(&self) -> Box<dyn Iterator<Item = Result<TableEntry, TableLikeError>> + '_> {
-        if let Err(e) = (&mut &(self.inner)).rewind() {
-            return Box::new(ErrIter {
-                err: Some(e.into())
-            });
-        }
-        let rd = BufReader::new(&self.inner);
-        let mut par = TableParser::default();
-        let mut y = rd.lines();
-        while let Some(Ok(st)) = y.next() {
-            // let st = lt?;
-            let res = par.next(st);
-            
-            if res.is_err() {
-                return Box::new(ErrIter{ err: Some(res.map_err(TableLikeError::new).unwrap_err())});
-            }
-            if par.state == ParseState::ExpectingRowStart {
-                //cols done break
-                return Box::new(Iter {
-                    par,
-                    reader: y,
-                    // last_row_ref: None,
-                })
-            }
+    fn get_rows(&self) -> RowIter<'_> {
+        match self.scan() {
+            Ok(it) => Box::new(it),
+            Err(e) => Box::new(ErrIter { err: Some(e) }),
         }
-        Box::new(NoneIter {
-            __data: PhantomData
-        })
-        
+    }
+
+    fn get_row(&self, rid: RowId) -> Result<TableEntry, TableLikeError> {
+        self.reader_at(rid)?.row_at(rid)
     }
 
     fn get_cols(&self) -> Result<Vec<ColumnEntry>, TableLikeError> {
-        let f = &mut &self.inner;
-        f.rewind()?;
-        let rd = BufReader::new(f);
-        let mut par = TableParser {
-            table: Table::default(),
-            state: Default::default(),
-            buffer: Vec::new(),
-        };
-        let mut y = rd.lines();
-        while let Some(Ok(lt)) = y.next() {
-            if par.next(lt).is_ok() && par.state == ParseState::ExpectingRowStart {
-                //cols done break
-                return Ok(par.table.col_names);
-            }
-        }
-        Err(TableLikeError::new("Syntax Error"))
+        Ok(self.read_header()?.col_names)
+    }
+
+    fn get_keys(&self) -> Result<Vec<KeyDef>, TableLikeError> {
+        Ok(self.read_header()?.keys)
     }
 
     fn add_rows(&mut self, rows: &mut dyn Iterator<Item=TableEntry>) -> Result<(), TableLikeError>{
-        //no checks here it is the responsibility of the caller for sanity checks
-        self.inner.seek(std::io::SeekFrom::End(0))?;
-        let mut wri = &mut BufWriter::new(&self.inner);
-        for row in rows {
-            writeln!(&mut wri, "RStart")?;
-            for row_col in &row.col_data {
-                writeln!(&mut wri, "\"{row_col}\"")?;
+        //types are the responsibility of the caller, constraints are checked here before anything is written
+        let header = self.read_header()?;
+        let val = RowValidator::new(&header.col_names, &header.keys)?;
+        let rows = rows.collect::<Vec<_>>();
+        for row in &rows {
+            val.validate(row)?;
+        }
+        // opened before the rows are written so an index that has to be built doesn't get them twice
+        let mut indexes = index::indexed_slots(&header.col_names, &header.keys)?
+            .into_iter()
+            .map(|(k, slots)| Ok((self.open_index(k, &header.col_names)?, slots, k)))
+            .collect::<Result<Vec<_>, TableLikeError>>()?;
+        let opened = indexes.iter().map(|(f, _, k)| (*k, f)).collect::<Vec<_>>();
+        index::check_unique(&self.name, &header.col_names, &opened, &rows, |rid| self.get_row(rid))?;
+        let end = self.inner.metadata()?.len();
+        let mut pos = end;
+        let mut text = String::new();
+        let mut entries = Vec::new();
+        for row in &rows {
+            for (i, (_, slots, _)) in indexes.iter().enumerate() {
+                entries.push((i, index::entry_key(slots, row)?, pos));
             }
-            writeln!(&mut wri, "REnd")?;
+            let r = db::encode_row(row);
+            pos += r.len() as u64;
+            text.push_str(&r);
+        }
+        {
+            let mut pool = buffer::lock(&self.pool);
+            pool.write_file(self.id, end, text.as_bytes())?;
+            // the last page was cached without the new rows
+            pool.discard(self.id, end / buffer::PAGE_SIZE as u64);
+        }
+        for (i, key, off) in entries {
+            indexes[i].0.insert(key, off)?;
         }
-        wri.flush()?;
         Ok(())
     }
 
-    fn move_to_file(&mut self, _: &str) -> Result<FileTable, TableLikeError> {
+    fn move_to_file(&mut self, _: &str, _: &SharedPool) -> Result<FileTable, TableLikeError> {
         Err(TableLikeError::new("Already a File Table"))
     }
 
@@ -374,45 +814,218 @@ impl TableLike for FileTable {
         f.flush(self)?;
         Ok(f)
     }
+
+    fn update_rows(&mut self, f: &mut dyn FnMut(&mut TableEntry) -> Result<bool, TableLikeError>) -> Result<usize, TableLikeError> {
+        self.rewrite(&mut |mut row| {
+            let changed = f(&mut row)?;
+            Ok((Some(row), changed))
+        })
+    }
+
+    fn delete_rows(&mut self, f: &mut dyn FnMut(&TableEntry) -> Result<bool, TableLikeError>) -> Result<usize, TableLikeError> {
+        self.rewrite(&mut |row| {
+            let deleted = f(&row)?;
+            Ok(((!deleted).then_some(row), deleted))
+        })
+    }
+
+    fn next_auto_increment(&self) -> Result<NumType, TableLikeError> {
+        read_auto_increment(self, &self.name)
+    }
+
+    fn set_auto_increment(&mut self, next: NumType) -> Result<(), TableLikeError> {
+        write_auto_increment(&self.pool, &self.name, next)
+    }
+
+    fn contains_key(&self, key: &str, entry: &[TableCell]) -> Result<bool, TableLikeError> {
+        let header = self.read_header()?;
+        let k = index::find_key(&header.keys, key)?;
+        let slots = slots_of(&header.col_names, &k.cols)?;
+        let file = self.open_index(k, &header.col_names)?;
+        index::holds_key(&file, &slots, &index::encode_key(entry), |rid| self.get_row(rid))
+    }
+
+    fn index_rows(&self, key: &str, range: &KeyRange) -> Option<RowIter<'_>> {
+        let rows = (|| -> Result<OffsetIter, TableLikeError> {
+            let header = self.read_header()?;
+            let k = index::find_key(&header.keys, key)?;
+            let mut offsets = self.open_index(k, &header.col_names)?.lookup(range)?;
+            // in file order like a scan, which also keeps the reader moving forward
+            offsets.sort_unstable();
+            Ok(OffsetIter { it: self.scan()?, offsets: offsets.into_iter() })
+        })();
+        Some(match rows {
+            Ok(r) => Box::new(r),
+            Err(e) => Box::new(ErrIter { err: Some(e) }),
+        })
+    }
+
+    fn close(&mut self) -> Result<(), TableLikeError> {
+        // every write goes straight to the file, the pool only caches what was read
+        Ok(())
+    }
+
+    fn old_schemas(&self) -> usize {
+        match &self.header {
+            Some((_, schemas, _)) => schemas.len(),
+            None => self.parse_header().map_or(0, |(_, schemas, _)| schemas.len()),
+        }
+    }
 }
 
-impl FileTable {
-    pub fn print_table(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), TableLikeError> {
-        //create tmp Table object and keep piping output
-        //TODO: bug here introduce some way of memoizing column widths of blocks that haven't been parsed yet to get accurate col info
-        let mut t = Table::default();
-        t.col_names.extend(self.get_cols()?);
-        let mut c = 0;
-        let mut first = true;
-        let mut sizes = Vec::new();
-        (0..t.col_names.len()).for_each(|_| sizes.push(0));
-        for r in self.get_rows() {
-            t.all.push(r?);   
-            c += 1;
-            if c > MAX_MEM_LIM {
-                //print
-                t.print_table(f, &mut sizes, if first {PrintOption::StartTable} else {PrintOption::MidTable})?;
-                first = false;
-                "\n".fmt(f)?;
-                c = 0;
-                t.all.clear();
-            }
-        }
-        if !t.all.is_empty() {
-            t.print_table(f, &mut sizes, if first {PrintOption::FullTable} else {PrintOption::EndTable})?;
+// checks the rows of a table that is written again, every row is written again so the keys
+// are checked against each other as they go
+fn check_rows(name: &str, t: &dyn TableLike) -> Result<(), TableLikeError> {
+    let cols = t.get_cols()?;
+    let keys = t.get_keys()?;
+    let val = RowValidator::new(&cols, &keys)?;
+    let mut idx = KeyIndex::new(name, &cols, &keys)?;
+    let indexed = index::indexed_slots(&cols, &keys)?;
+    for row in t.get_rows() {
+        let row = row?;
+        val.validate(&row)?;
+        if !idx.is_empty() {
+            idx.insert_all([&row])?;
         }
-        Ok(())
+        for (_, slots) in &indexed {
+            index::entry_key(slots, &row)?;
+        }
+    }
+    Ok(())
+}
+
+// the entries of the index of every indexed key of a table that is written again
+type IndexEntries = Vec<(KeyDef, Vec<(Vec<u8>, RowId)>)>;
+
+// writes the header and the rows of a text table, returns the entries of the index of every
+// indexed key, rebuilt since all rows move, and the offset of the first row
+fn write_rows(
+    cols: &[ColumnEntry],
+    keys: &[KeyDef],
+    rows: impl Iterator<Item = Result<TableEntry, TableLikeError>>,
+    wri: &mut impl ioWrite,
+) -> Result<(IndexEntries, RowId), TableLikeError> {
+    let mut indexes = index::indexed_slots(cols, keys)?
+        .into_iter()
+        .map(|(k, slots)| (k.clone(), slots, Vec::new()))
+        .collect::<Vec<_>>();
+    // with room for ALTER TABLE to write it again in place
+    let header = db::encode_header(cols, keys);
+    let header = db::pad_header(header.clone(), db::header_space(header.len())).unwrap_or(header);
+    wri.write_all(header.as_bytes())?;
+    let mut pos = header.len() as u64;
+    let start = pos;
+    for row in rows {
+        let row = row?;
+        for (_, slots, entries) in &mut indexes {
+            entries.push((index::entry_key(slots, &row)?, pos));
+        }
+        let text = db::encode_row(&row);
+        wri.write_all(text.as_bytes())?;
+        pos += text.len() as u64;
+    }
+    Ok((indexes.into_iter().map(|(k, _, entries)| (k, entries)).collect(), start))
+}
+
+// the counter of a table on disk is kept in <table>.auto next to the table file so values
+// freed by a DELETE aren't handed out again, tables without one start after their largest value
+fn read_auto_increment(t: &dyn TableLike, name: &str) -> Result<NumType, TableLikeError> {
+    match std::fs::read_to_string(format!("{name}.auto")) {
+        Ok(s) => s.trim().parse().map_err(|_| TableLikeError::new("Corrupt AUTO_INCREMENT counter")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => scan_auto_increment(t),
+        Err(e) => Err(e.into()),
     }
 }
 
-struct NoneIter<T> {
-    __data: PhantomData<T>
+fn write_auto_increment(pool: &SharedPool, name: &str, next: NumType) -> Result<(), TableLikeError> {
+    buffer::lock(pool).wal().replace(&format!("{name}.auto"), Some(format!("{next}\n").as_bytes()))?;
+    Ok(())
 }
 
-impl<T> Iterator for NoneIter<T> {
-    type Item = T;
-    fn next(&mut self) -> Option<Self::Item> {
-        None
+// one past the largest value in the AUTO_INCREMENT column, 1 for an empty table
+fn scan_auto_increment(t: &dyn TableLike) -> Result<NumType, TableLikeError> {
+    let Some(ind) = t.get_cols()?.iter().position(|c| c.constraints.auto_increment) else {
+        return Ok(1);
+    };
+    let mut next = 1;
+    for row in t.get_rows() {
+        if let Some(n) = row?.col_data[ind].as_num() {
+            next = next.max(n.saturating_add(1));
+        }
+    }
+    Ok(next)
+}
+
+// prints a table from its rows as they are read, which are gone through once before to
+// find the widths of the columns, so there is never more than a row in memory
+fn print_streamed(table: &dyn TableLike, f: &mut std::fmt::Formatter<'_>) -> Result<(), TableLikeError> {
+    let cols = table.get_cols()?;
+    let mut widths = col_widths(&cols);
+    for row in table.get_rows() {
+        widen(&mut widths, &row?);
+    }
+    print_rows(f, &cols, &widths, table.get_rows())
+}
+
+// widths that fit the names of the columns, see widen
+fn col_widths(cols: &[ColumnEntry]) -> Vec<usize> {
+    cols.iter().map(|c| c.col_name.chars().count().min(MAX_COL_WIDTH)).collect()
+}
+
+// makes room for the values of a row
+fn widen(widths: &mut [usize], row: &TableEntry) {
+    for (w, cell) in widths.iter_mut().zip(&row.col_data) {
+        *w = cell.get_len().min(MAX_COL_WIDTH).max(*w);
+    }
+}
+
+// a box with the names of the columns and the rows, every value fits its column
+fn print_rows<B: Borrow<TableEntry>>(
+    f: &mut std::fmt::Formatter<'_>,
+    cols: &[ColumnEntry],
+    widths: &[usize],
+    rows: impl Iterator<Item = Result<B, TableLikeError>>,
+) -> Result<(), TableLikeError> {
+    // | for column terminators and + for corners
+    print_border(f, widths)?;
+    writeln!(f)?;
+    for (c, sz) in cols.iter().zip(widths) {
+        print_value(f, &c.col_name, c.col_name.chars().count(), *sz)?;
+    }
+    writeln!(f, "|")?;
+    print_border(f, widths)?;
+    writeln!(f)?;
+    let mut first = true;
+    for row in rows {
+        if !first {
+            writeln!(f)?;
+        }
+        first = false;
+        for (cell, sz) in row?.borrow().col_data.iter().zip(widths) {
+            print_value(f, cell, cell.get_len(), *sz)?;
+        }
+        write!(f, "|")?;
+    }
+    // an empty result has its header and nothing under it
+    if !first {
+        writeln!(f)?;
+    }
+    print_border(f, widths)?;
+    Ok(())
+}
+
+fn print_border(f: &mut std::fmt::Formatter<'_>, widths: &[usize]) -> std::fmt::Result {
+    for sz in widths {
+        write!(f, "+-{num:-<width$}-", num = '-', width = sz)?;
+    }
+    write!(f, "+")
+}
+
+// a value of len characters padded to sz, or cut short when it is too wide for any column
+fn print_value(f: &mut std::fmt::Formatter<'_>, value: &dyn Display, len: usize, sz: usize) -> std::fmt::Result {
+    match len > MAX_COL_WIDTH {
+        true => write!(f, "| {}... ", value.to_string().chars().take(MAX_COL_WIDTH - 3).collect::<String>()),
+        false => write!(f, "| {value:<sz$} "),
     }
 }
 
@@ -428,113 +1041,913 @@ impl Iterator for ErrIter {
     }
 }
 
-pub struct Iter<R> {
-    par: TableParser,
-    reader: Lines<BufReader<R>>,
+// the rows at a list of offsets, as found in an index
+struct OffsetIter {
+    it: RowReader<BufReader<PoolReader>>,
+    offsets: std::vec::IntoIter<u64>,
 }
 
-impl<R> Iterator for Iter< R> where R: Read{
+impl Iterator for OffsetIter {
     type Item = Result<TableEntry, TableLikeError>;
-    
+
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(Ok(st)) = self.reader.next() {
-            if self.par.next(st).is_ok() && self.par.state == ParseState::ExpectingRowStart {
-                //row ended safe to return
-                return Some(self.par.table.all.pop().ok_or(TableLikeError::new("No Row found")));
-            }
-        } 
-        None
+        let off = self.offsets.next()?;
+        Some(self.it.row_at(off))
     }
 }
 
-struct EmptyIter<'a> {
-    __data: PhantomData<&'a ()>
+pub enum QueryResult {
+    Table(Box<dyn TableLike>),
+    Affected(usize),
+    Done,
 }
-impl<'a> Iterator for EmptyIter<'a> {
-    type Item = Result<&'a TableEntry, TableLikeError>;
-    fn next(&mut self) -> Option<Self::Item> {
-        None
+
+fn no_savepoint(name: &str) -> TableLikeError {
+    TableLikeError::new(&format!("SAVEPOINT {name} does not exist"))
+}
+
+// a client of a TableManager with its settings, its transaction is kept by the manager
+pub struct Session {
+    id: SessionId,
+    // sql_mode has STRICT_TRANS_TABLES or STRICT_ALL_TABLES, bad values are errors instead of being adjusted
+    strict: bool,
+    // first AUTO_INCREMENT value generated by the last INSERT that generated one, for LAST_INSERT_ID()
+    last_insert_id: NumType,
+    // every statement outside BEGIN ... COMMIT commits on its own, off starts a transaction instead
+    autocommit: bool,
+    // seconds a statement waits for the locks other sessions hold, innodb_lock_wait_timeout
+    lock_wait_timeout: u64,
+}
+
+// the functions of a statement that are values of the session
+fn bind_session(session: &Session, stmt: &mut Statement) {
+    let last_id = TableCell::Num(Some(session.last_insert_id));
+    for e in stmt.exprs_mut() {
+        e.bind_func("LAST_INSERT_ID", &last_id);
     }
 }
 
+// the statement a TableManager is running
+#[derive(Clone, Copy)]
+struct Running {
+    session: SessionId,
+    // the id it stamps its changes with, 0 to make them in place
+    stamp: TxnId,
+    // how the tables it opens are locked for its transaction, see locks.rs
+    lock: LockMode,
+}
+
 struct TableManager {
-    tables: HashMap<String, Box<dyn TableLike>>,
-    count: u32,
+    // the tables opened recently, the others are closed until they are used again
+    tables: TableCache,
+    // names the files of results too big for memory
+    count: AtomicU32,
+    // open transactions of every session and the versions of rows they see
+    txns: Transactions,
+    // the statement running, if any
+    running: Option<Running>,
+    // table and row locks of every session
+    locks: Locks,
+    sessions: SessionId,
+    // pages of every table file, its budget also limits the rows a SELECT keeps in memory
+    pool: SharedPool,
 }
 
 impl TableManager {
 
-    pub fn new() -> TableManager {
-        TableManager { tables: HashMap::new(), count: 0 }
-    }
-
-    pub fn insert(&mut self, nm: String, to_ins: Box<dyn TableLike>) {
-        self.tables.insert(nm, to_ins);
-    }
+    // rolls back the transactions a crash left open, see mvcc.rs, and removes the results it
+    // left on disk
+    pub fn new(pool_size: usize, open_tables: usize, wal: Wal) -> Result<TableManager, TableLikeError> {
+        for e in std::fs::read_dir(".")? {
+            let e = e?;
+            if e.file_name().to_string_lossy().starts_with(SPILL_PREFIX) {
+                std::fs::remove_file(e.path())?;
+            }
+        }
+        let (txns, interrupted) = Transactions::load()?;
+        let mut tm = TableManager {
+            tables: TableCache::new(open_tables),
+            count: AtomicU32::new(0),
+            txns,
+            running: None,
+            locks: Locks::default(),
+            sessions: 0,
+            pool: buffer::BufferPool::shared(pool_size, wal),
+        };
+        tm.operation(|tm| {
+            for (ids, tables) in interrupted {
+                tm.undo_versions(&tables, &ids)?;
+            }
+            tm.collect_garbage()
+        })?;
+        Ok(tm)
+    }
 
-    pub fn insert_ft(&mut self, ft: FileTable) {
-        self.tables.insert(ft.name.to_string(), Box::new(ft));
+    pub fn session(&mut self) -> Session {
+        self.sessions += 1;
+        Session {
+            id: self.sessions,
+            strict: true,
+            last_insert_id: 0,
+            autocommit: true,
+            lock_wait_timeout: locks::DEFAULT_WAIT_TIMEOUT,
+        }
     }
 
-    pub fn select(&mut self, stmt: (String, Criteria)) -> Result<Box<dyn TableLike>, TableLikeError> {
-        //first check if we already have a record in tables
-        //if we don't then insert in memory
-        //TODO replace hashmap with LRU
+    // like a client disconnecting, a transaction still open is rolled back and LOCK TABLES released
+    pub fn end_session(&mut self, session: &Session) -> Result<(), TableLikeError> {
+        self.locks.stop_waiting(session.id);
+        self.locks.release(session.id, true);
+        self.operation(|tm| tm.finish(session.id, false))
+    }
 
-        if !self.tables.contains_key(stmt.0.as_str()) {
-            let ty = Box::new(FileTable::new(stmt.0.as_str())?);
-            self.tables.insert(stmt.0.clone(), ty);
-        } 
+    fn open(&mut self, name: &str) -> Result<&mut Box<dyn TableLike>, TableLikeError> {
+        if !self.tables.contains(name) {
+            let ty = (|| -> Result<Box<dyn TableLike>, TableLikeError> {
+                Ok(match paged::is_paged(name)? {
+                    true => Box::new(PageTable::open(name, &self.pool)?),
+                    false => Box::new(FileTable::new(name, &self.pool)?),
+                })
+            })()
+            .map_err(|e| match e {
+                TableLikeError::IoError { source } if source.kind() == std::io::ErrorKind::NotFound => {
+                    TableLikeError::new(&format!("Table '{name}' doesn't exist"))
+                }
+                e => e,
+            })?;
+            self.tables.insert(name, ty)?;
+        }
+        if let Some(r) = self.running {
+            self.locks.lock_table(r.session, name, r.lock, false)?;
+            if r.lock.is_write() {
+                self.txns.add_table(r.session, name);
+            }
+        }
+        Ok(self.tables.get_mut(name).unwrap())
+    }
 
-        let tb = self.tables.get(stmt.0.as_str()).unwrap().as_ref();
+    pub fn execute(&mut self, session: &mut Session, mut stmt: Statement) -> Result<QueryResult, TableLikeError> {
+        bind_session(session, &mut stmt);
+        // CREATE, DROP, ALTER and LOCK TABLES commit the open transaction even when they fail, like MySQL
+        let commits = matches!(
+            stmt,
+            Statement::CreateTable { .. }
+                | Statement::CreateIndex { .. }
+                | Statement::DropIndex { .. }
+                | Statement::AlterTable { .. }
+                | Statement::Optimize { .. }
+                | Statement::LockTables { .. }
+        );
+        if commits {
+            self.operation(|tm| tm.finish(session.id, true))?;
+        }
+        let res = self.operation(|tm| match stmt {
+            Statement::Begin => tm.begin(session.id).map(|_| QueryResult::Done),
+            Statement::Commit => tm.finish(session.id, true).map(|_| QueryResult::Done),
+            Statement::Rollback { savepoint: None } => tm.finish(session.id, false).map(|_| QueryResult::Done),
+            Statement::Rollback { savepoint: Some(name) } => {
+                let (ids, tables) = tm.txns.rollback_to(session.id, &name).ok_or_else(|| no_savepoint(&name))?;
+                tm.undo_versions(&tables, &ids).map(|_| QueryResult::Done)
+            }
+            Statement::Savepoint { name } => {
+                // with autocommit off there always is a transaction, otherwise there is nothing
+                // to roll back to and like MySQL it is accepted anyway
+                if !session.autocommit && !tm.txns.is_open(session.id) {
+                    tm.txns.begin(session.id, true);
+                }
+                tm.txns.savepoint(session.id, &name);
+                Ok(QueryResult::Done)
+            }
+            Statement::ReleaseSavepoint { name } => match tm.txns.release_savepoint(session.id, &name) {
+                true => Ok(QueryResult::Done),
+                false => Err(no_savepoint(&name)),
+            },
+            Statement::Set { var, value } => tm.set_variable(session, &var, &value).map(|_| QueryResult::Done),
+            Statement::LockTables { tables } => tm.lock_tables(session.id, &tables).map(|_| QueryResult::Done),
+            Statement::UnlockTables => tm.unlock_tables(session.id).map(|_| QueryResult::Done),
+            stmt => tm.run(session, stmt),
+        });
+        // the transaction whose lock request closed a cycle of waits is rolled back, so the
+        // others can go on
+        if let Err(TableLikeError::Deadlock) = res {
+            self.operation(|tm| tm.finish(session.id, false))?;
+        }
+        res
+    }
 
-        
-        //NOW try and fit result object in Table otherwise flush to file
+    // a statement that reads or changes tables, in the open transaction of the session or in one of its own
+    fn run(&mut self, session: &mut Session, stmt: Statement) -> Result<QueryResult, TableLikeError> {
+        // CREATE, DROP and ALTER aren't part of a transaction, like MySQL
+        let ddl = matches!(
+            stmt,
+            Statement::CreateTable { .. }
+                | Statement::CreateIndex { .. }
+                | Statement::DropIndex { .. }
+                | Statement::AlterTable { .. }
+                | Statement::Optimize { .. }
+        );
+        let lock = match &stmt {
+            Statement::Select { lock: Some(LockMode::Exclusive), .. } => LockMode::IntentionExclusive,
+            Statement::Select { .. } | Statement::Describe { .. } => LockMode::IntentionShared,
+            _ if ddl => LockMode::Exclusive,
+            _ => LockMode::IntentionExclusive,
+        };
+        if let Some(table) = stmt.table() {
+            self.locks.check_explicit(session.id, table, lock)?;
+        }
+        if !self.txns.is_open(session.id) {
+            self.txns.begin(session.id, !session.autocommit && !ddl);
+        }
+        let stamp = self.txns.start_statement(session.id);
+        self.running = Some(Running { session: session.id, stamp, lock });
+        let res = match stmt {
+            Statement::Select { table, criteria, lock } => self.select(session.id, (table, criteria), lock).map(QueryResult::Table),
+            Statement::CreateTable { table, cols, keys, engine } => self.create(&table, cols, keys, engine).map(|_| QueryResult::Affected(0)),
+            Statement::CreateIndex { table, key } => self.create_index(&table, key).map(|_| QueryResult::Affected(0)),
+            Statement::DropIndex { table, name } => self.drop_index(&table, &name).map(|_| QueryResult::Affected(0)),
+            Statement::AlterTable { table, actions, algorithm } => {
+                self.alter_table(session.strict, &table, actions, algorithm).map(|_| QueryResult::Affected(0))
+            }
+            Statement::Optimize { table } => self.optimize(&table).map(|_| QueryResult::Affected(0)),
+            Statement::Insert { table, cols, rows } => self.insert_into(session, &table, cols, rows).map(QueryResult::Affected),
+            Statement::Update { table, sets, filter } => self.update(session.strict, &table, sets, filter).map(QueryResult::Affected),
+            Statement::Delete { table, filter } => self.delete(&table, filter).map(QueryResult::Affected),
+            Statement::Describe { table } => self.describe(&table).map(QueryResult::Table),
+            _ => Err(TableLikeError::new("Not a statement on tables")),
+        };
+        self.running = None;
+        // a statement of its own commits, a failed one is undone with the rest of its operation
+        if !self.txns.is_explicit(session.id) {
+            match res.is_ok() {
+                true => self.finish(session.id, true)?,
+                false => {
+                    self.txns.end(session.id);
+                    self.locks.release(session.id, false);
+                }
+            }
+        }
+        res
+    }
+
+    // runs f as one operation of the log with whatever it changes in the transaction state,
+    // a failed one is undone as a whole
+    fn operation<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, TableLikeError>) -> Result<T, TableLikeError> {
+        let mut res = f(self);
+        // a statement leaves its changes on disk, not just in the pool, and the tables they
+        // are in go in the log before the state file so their indexes are rebuilt after a crash
+        buffer::lock(&self.pool).flush(None)?;
+        if let (Ok(_), Some(state)) = (&res, self.txns.take_state()) {
+            if let Err(e) = buffer::lock(&self.pool).wal().replace(mvcc::STATE_PATH, Some(state.as_bytes())) {
+                res = Err(e.into());
+            }
+        }
+        match res.is_err() && !buffer::lock(&self.pool).wal().is_empty() {
+            true => self.undo()?,
+            false => buffer::lock(&self.pool).wal().commit()?,
+        }
+        if res.is_err() {
+            self.txns.forget_state();
+        }
+        res
+    }
+
+    // rolls back the operation from the log
+    fn undo(&mut self) -> Result<(), TableLikeError> {
+        // open tables and cached pages don't match their files any more
+        self.tables.clear()?;
+        let mut pool = buffer::lock(&self.pool);
+        pool.flush(None)?;
+        pool.wal().rollback()?;
+        pool.discard_all();
+        Ok(())
+    }
+
+    // commits the open transaction and starts a new one, which releases LOCK TABLES like MySQL
+    pub fn begin(&mut self, session: SessionId) -> Result<(), TableLikeError> {
+        self.finish(session, true)?;
+        self.locks.release(session, true);
+        self.txns.begin(session, true);
+        Ok(())
+    }
+
+    // ends the open transaction of a session, taking back its changes unless it commits
+    pub fn finish(&mut self, session: SessionId, commit: bool) -> Result<(), TableLikeError> {
+        let Some(txn) = self.txns.end(session) else {
+            return Ok(());
+        };
+        if !commit {
+            self.undo_versions(&txn.tables, &txn.ids.iter().copied().collect())?;
+        }
+        self.locks.release(session, false);
+        self.collect_garbage()
+    }
+
+    // locks the tables in place of the ones the session locked before, the open transaction
+    // was committed already
+    pub fn lock_tables(&mut self, session: SessionId, tables: &[(String, LockMode)]) -> Result<(), TableLikeError> {
+        for (name, _) in tables {
+            self.open(name)?;
+        }
+        self.locks.release(session, true);
+        self.locks.lock_tables(session, tables)
+    }
+
+    // releases the tables of LOCK TABLES, which commits the open transaction if there were any
+    pub fn unlock_tables(&mut self, session: SessionId) -> Result<(), TableLikeError> {
+        if self.locks.has_explicit(session) {
+            self.finish(session, true)?;
+            self.locks.release(session, true);
+        }
+        Ok(())
+    }
+
+    // removes the versions created by these ids and takes back their deletions
+    fn undo_versions(&mut self, tables: &[String], ids: &HashSet<TxnId>) -> Result<(), TableLikeError> {
+        for name in tables {
+            let Ok(tb) = self.open(name) else {
+                continue;
+            };
+            // the created versions go first so the ones they replaced get their keys back
+            tb.delete_rows(&mut |row| Ok(ids.contains(&row.version.created)))?;
+            tb.update_rows(&mut |row| {
+                let undo = ids.contains(&row.version.deleted);
+                if undo {
+                    row.version.deleted = 0;
+                }
+                Ok(undo)
+            })?;
+        }
+        Ok(())
+    }
+
+    // removes the versions no snapshot can see anymore, see mvcc.rs
+    fn collect_garbage(&mut self) -> Result<(), TableLikeError> {
+        let horizon = self.txns.horizon();
+        for name in self.txns.take_garbage() {
+            // a table whose file is gone has nothing to collect
+            let Ok(tb) = self.open(&name) else {
+                continue;
+            };
+            let n = tb.delete_rows(&mut |row| Ok(row.version.deleted != 0 && row.version.deleted < horizon))?;
+            // the table is written again anyway, the versions left are seen by everyone
+            if n > 0 {
+                tb.update_rows(&mut |row| {
+                    let old = row.version.created != 0 && row.version.created < horizon;
+                    if old {
+                        row.version.created = 0;
+                    }
+                    Ok(old)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    // the id the statement changing tables stamps its changes with, 0 to make them in place
+    fn stamp(&self) -> TxnId {
+        self.running.map(|r| r.stamp).unwrap_or(0)
+    }
+
+    // locks rows by name for the transaction of the statement running, a statement making
+    // its changes in place is alone and has nobody to wait for
+    pub fn lock_names(&mut self, table: &str, names: Vec<String>, mode: LockMode) -> Result<(), TableLikeError> {
+        match self.running.filter(|r| r.stamp != 0) {
+            Some(r) => self.locks.lock_rows(r.session, table, names, mode),
+            None => Ok(()),
+        }
+    }
+
+    // locks the rows f returns true for, as they are and as f changes them, see locks.rs
+    pub fn lock_rows_of(&mut self, name: &str, mode: LockMode, f: &mut dyn FnMut(&mut TableEntry) -> Result<bool, TableLikeError>) -> Result<(), TableLikeError> {
+        let Some(r) = self.running.filter(|r| r.stamp != 0) else {
+            return Ok(());
+        };
+        self.open(name)?;
+        let tb = self.tables.get(name).unwrap();
+        let names = RowNames::new(&tb.get_cols()?, &tb.get_keys()?)?;
+        let mut locked = Vec::new();
+        for row in tb.get_rows() {
+            let row = row?;
+            // a version deleted by a transaction still open comes back if it rolls back
+            if !row.version.is_live() && !self.txns.is_open_elsewhere(row.version.deleted, r.session) {
+                continue;
+            }
+            let mut new = row.clone();
+            if f(&mut new)? {
+                locked.extend(names.of(&row));
+                locked.extend(names.of(&new));
+            }
+        }
+        self.lock_names(name, locked, mode)
+    }
+
+    // the versions a statement changed may be newer than its snapshot, see mvcc.rs
+    fn see_creators(&mut self, ids: Vec<TxnId>) {
+        if let Some(r) = self.running {
+            self.txns.see(r.session, ids);
+        }
+    }
+
+    // changes the rows f changes, as new versions of them unless the statement can make
+    // them in place, and returns how many
+    pub fn update_rows_of(&mut self, name: &str, f: &mut dyn FnMut(&mut TableEntry) -> Result<bool, TableLikeError>) -> Result<usize, TableLikeError> {
+        let stamp = self.stamp();
+        self.lock_rows_of(name, LockMode::Exclusive, f)?;
+        let tb = self.open(name)?;
+        if stamp == 0 {
+            return tb.update_rows(&mut |row| Ok(row.version.is_live() && f(row)?));
+        }
+        let mut added = Vec::new();
+        let mut creators = Vec::new();
+        let n = tb.update_rows(&mut |row| {
+            if !row.version.is_live() {
+                return Ok(false);
+            }
+            let mut new = row.clone();
+            if !f(&mut new)? {
+                return Ok(false);
+            }
+            creators.push(row.version.created);
+            row.version.deleted = stamp;
+            new.version = Version::new(stamp);
+            added.push(new);
+            Ok(true)
+        })?;
+        // the old versions are deleted by now so the new ones can take their keys
+        tb.add_rows(&mut added.into_iter())?;
+        if n > 0 {
+            self.txns.add_garbage(name, stamp);
+        }
+        self.see_creators(creators);
+        Ok(n)
+    }
 
-        let mut rt = Table {
+    // deletes the rows f returns true for, by marking the versions deleted unless the
+    // statement can remove them, and returns how many
+    pub fn delete_rows_of(&mut self, name: &str, f: &mut dyn FnMut(&TableEntry) -> Result<bool, TableLikeError>) -> Result<usize, TableLikeError> {
+        let stamp = self.stamp();
+        self.lock_rows_of(name, LockMode::Exclusive, &mut |row| f(row))?;
+        let tb = self.open(name)?;
+        if stamp == 0 {
+            return tb.delete_rows(&mut |row| Ok(row.version.is_live() && f(row)?));
+        }
+        let mut creators = Vec::new();
+        let n = tb.update_rows(&mut |row| {
+            let gone = row.version.is_live() && f(row)?;
+            if gone {
+                creators.push(row.version.created);
+                row.version.deleted = stamp;
+            }
+            Ok(gone)
+        })?;
+        if n > 0 {
+            self.txns.add_garbage(name, stamp);
+        }
+        self.see_creators(creators);
+        Ok(n)
+    }
+
+    pub fn set_variable(&mut self, session: &mut Session, var: &str, value: &Expr) -> Result<(), TableLikeError> {
+        match var.to_ascii_lowercase().as_str() {
+            "sql_mode" => {
+                let mode = match value.eval_const()? {
+                    TableCell::Str(Some(s)) => s.to_ascii_uppercase(),
+                    _ => return Err(TableLikeError::new("Variable 'sql_mode' can't be set to a non string value")),
+                };
+                session.strict = mode.split(',').any(|m| matches!(m.trim(), "STRICT_TRANS_TABLES" | "STRICT_ALL_TABLES"));
+                Ok(())
+            }
+            "autocommit" => {
+                let on = match value.eval_const()? {
+                    TableCell::Num(Some(1)) | TableCell::Bool(Some(true)) => true,
+                    TableCell::Num(Some(0)) | TableCell::Bool(Some(false)) => false,
+                    TableCell::Str(Some(s)) if s.eq_ignore_ascii_case("ON") => true,
+                    TableCell::Str(Some(s)) if s.eq_ignore_ascii_case("OFF") => false,
+                    v => return Err(TableLikeError::new(&format!("Variable 'autocommit' can't be set to the value of '{v}'"))),
+                };
+                // turning it back on commits the open transaction
+                if on && !session.autocommit {
+                    self.finish(session.id, true)?;
+                }
+                session.autocommit = on;
+                Ok(())
+            }
+            "innodb_lock_wait_timeout" => {
+                session.lock_wait_timeout = match value.eval_const()? {
+                    TableCell::Num(Some(n)) => n.clamp(1, 1 << 30) as u64,
+                    _ => return Err(TableLikeError::new("Incorrect argument type to variable 'innodb_lock_wait_timeout'")),
+                };
+                Ok(())
+            }
+            _ => Err(TableLikeError::new(&format!("Unknown system variable '{var}'"))),
+        }
+    }
+
+    pub fn describe(&mut self, name: &str) -> Result<Box<dyn TableLike>, TableLikeError> {
+        let tb = self.open(name)?;
+        let cols = tb.get_cols()?;
+        let keys = tb.get_keys()?;
+        // PRI for primary key columns, UNI for a single column unique key and MUL for
+        // the first column of a composite one, like MySQL
+        let key_of = |col: &str| {
+            if keys.iter().any(|k| k.kind == KeyKind::Primary && k.cols.iter().any(|c| c == col)) {
+                "PRI"
+            } else if keys.iter().any(|k| k.kind == KeyKind::Unique && k.cols.len() == 1 && k.cols[0] == col) {
+                "UNI"
+            } else if keys.iter().any(|k| !matches!(k.kind, KeyKind::Check(_)) && k.cols[0] == col) {
+                "MUL"
+            } else {
+                ""
+            }
+        };
+        let str_col = |n: &str| ColumnEntry::new(n.to_string(), TableCell::Str(None));
+        let s = |v: &str| TableCell::Str(Some(v.to_string()));
+        let mut all = Vec::with_capacity(cols.len());
+        for c in &cols {
+            let default = match &c.constraints.default {
+                Some(_) => c.default_value(true)?.to_string(),
+                None => "NULL".to_string(),
+            };
+            all.push(TableEntry {
+                col_data: vec![
+                    s(&c.col_name),
+                    s(&c.sql_type()),
+                    s(if c.constraints.not_null { "NO" } else { "YES" }),
+                    s(key_of(&c.col_name)),
+                    s(&default),
+                    s(if c.constraints.auto_increment { "auto_increment" } else { "" }),
+                ],
+                ..Default::default()
+            });
+        }
+        Ok(Box::new(Table {
             name: None,
-            all: Vec::new(),
-            col_names: Vec::new(),
+            col_names: ["Field", "Type", "Null", "Key", "Default", "Extra"].iter().map(|n| str_col(n)).collect(),
+            all,
+            ..Default::default()
+        }))
+    }
+
+    pub fn create(&mut self, name: &str, mut cols: Vec<ColumnEntry>, mut keys: Vec<KeyDef>, engine: Engine) -> Result<(), TableLikeError> {
+        constraints::prepare_keys(name, &mut cols, &mut keys)?;
+        constraints::prepare_schema(name, &mut cols, &mut keys)?;
+        self.check_references(name, &cols, &keys)?;
+        let created = match engine {
+            Engine::Text => FileTable::create_new(name, &self.pool).map(|t| Box::new(t) as Box<dyn TableLike>),
+            Engine::Paged => PageTable::create_new(name, &self.pool).map(|t| Box::new(t) as Box<dyn TableLike>),
         };
+        let mut ft = created.map_err(|e| match e {
+            TableLikeError::IoError { source } if source.kind() == std::io::ErrorKind::AlreadyExists => {
+                TableLikeError::new(&format!("Table '{name}' already exists"))
+            }
+            e => e,
+        })?;
+        let auto = cols.iter().any(|c| c.constraints.auto_increment);
+        self.register_references(name, &keys)?;
+        ft.flush(&Table { name: Some(name.to_string()), col_names: cols, keys, ..Default::default() })?;
+        if auto {
+            // replaces a counter left behind by an earlier table of the same name
+            ft.set_auto_increment(1)?;
+        }
+        self.tables.insert(name, ft)?;
+        Ok(())
+    }
 
-        let mut act_rt: Option<FileTable> = Default::default();
+    pub fn create_index(&mut self, name: &str, key: KeyDef) -> Result<(), TableLikeError> {
+        let tb = self.open(name)?;
+        let mut t = tb.move_to_memory()?;
+        // the new key is checked against the others like in CREATE TABLE
+        t.keys.push(key);
+        constraints::prepare_keys(name, &mut t.col_names.clone(), &mut t.keys)?;
+        // rewriting the table checks a UNIQUE index against the rows and builds its B-tree
+        tb.flush(&t)
+    }
 
+    pub fn drop_index(&mut self, name: &str, index: &str) -> Result<(), TableLikeError> {
+        let referencing = self.referencing(name)?;
+        let tb = self.open(name)?;
+        let mut t = tb.move_to_memory()?;
+        let Some(pos) = t.keys.iter().position(|k| k.name == index && index::is_indexed(k)) else {
+            return Err(TableLikeError::new(&format!("Can't DROP '{index}'; check that column/key exists")));
+        };
+        let k = t.keys.remove(pos);
+        // foreign keys to this table need a unique key on the columns they reference
+        if k.kind.is_unique()
+            && !t.keys.iter().any(|o| o.kind.is_unique() && o.cols == k.cols)
+            && referencing.iter().any(|(_, fk)| matches!(&fk.kind, KeyKind::Foreign(r) if r.cols == k.cols))
+        {
+            return Err(TableLikeError::new(&format!("Cannot drop index '{index}': needed in a foreign key constraint")));
+        }
+        // the AUTO_INCREMENT column still has to lead a key
+        constraints::prepare_keys(name, &mut t.col_names.clone(), &mut t.keys)?;
+        tb.flush(&t)?;
+        match std::fs::remove_file(index::index_path(name, index)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn insert_into(&mut self, session: &mut Session, name: &str, cols: Option<Vec<String>>, rows: Vec<Vec<Expr>>) -> Result<usize, TableLikeError> {
+        let strict = session.strict;
+        let version = Version::new(self.stamp());
+        let tb = self.open(name)?;
         let ori_cols = tb.get_cols()?;
+        let targets = match cols {
+            None => (0..ori_cols.len()).collect::<Vec<_>>(),
+            Some(c) => c
+                .iter()
+                .map(|n| ori_cols.iter().position(|f| &f.col_name == n)
+                    .ok_or_else(|| TableLikeError::new(&format!("Unknown column '{n}'"))))
+                .collect::<Result<_, _>>()?,
+        };
+        let auto = ori_cols.iter().position(|c| c.constraints.auto_increment);
+        let start = match auto {
+            Some(_) => tb.next_auto_increment()?,
+            None => 0,
+        };
+        let mut next = start;
+        let mut first_id = None;
+        let mut entries = Vec::with_capacity(rows.len());
+        for (ind, r) in rows.iter().enumerate() {
+            if r.len() != targets.len() {
+                return Err(TableLikeError::new(&format!("Column count doesn't match value count at row {}", ind + 1)));
+            }
+            let mut col_data = Vec::with_capacity(ori_cols.len());
+            for (ind, f) in ori_cols.iter().enumerate() {
+                col_data.push(if targets.contains(&ind) { f.col_type.null() } else { f.default_value(strict)? });
+            }
+            for (t, e) in targets.iter().zip(r) {
+                col_data[*t] = ori_cols[*t].coerce(&e.eval_const()?, strict)?;
+            }
+            // NULL or 0 takes the next value, anything larger moves the counter past it
+            if let Some(a) = auto {
+                match col_data[a].as_num() {
+                    None | Some(0) => {
+                        col_data[a] = TableCell::Num(Some(next));
+                        first_id.get_or_insert(next);
+                        next = next.checked_add(1).ok_or_else(|| {
+                            TableLikeError::new("Failed to read auto-increment value from storage engine")
+                        })?;
+                    }
+                    Some(v) if v >= next => next = v.saturating_add(1),
+                    Some(_) => {}
+                }
+            }
+            entries.push(TableEntry { col_data, version });
+        }
+        let keys = tb.get_keys()?;
+        let names = RowNames::new(&ori_cols, &keys)?;
+        self.lock_names(name, entries.iter().flat_map(|e| names.of(e)).collect(), LockMode::Exclusive)?;
+        self.check_parents(name, &ori_cols, &keys, &entries.iter().collect::<Vec<_>>())?;
+        let tb = self.open(name)?;
+        tb.add_rows(&mut entries.iter().cloned())?;
+        if next != start {
+            tb.set_auto_increment(next)?;
+        }
+        if let Some(id) = first_id {
+            session.last_insert_id = id;
+        }
+        Ok(entries.len())
+    }
 
+    pub fn update(&mut self, strict: bool, name: &str, sets: Vec<(String, Expr)>, filter: Option<Expr>) -> Result<usize, TableLikeError> {
+        let referenced = !self.referencing(name)?.is_empty();
+        let tb = self.open(name)?;
+        let ori_cols = tb.get_cols()?;
+        let keys = tb.get_keys()?;
+        let mut assigns = Vec::with_capacity(sets.len());
+        for (col, e) in &sets {
+            let ind = ori_cols.iter().position(|f| &f.col_name == col)
+                .ok_or_else(|| TableLikeError::new(&format!("Unknown column '{col}'")))?;
+            assigns.push((ind, Projection::from_expr(e, &ori_cols)?));
+        }
+        let cls = match &filter {
+            Some(e) => Closure::from_expr(e, &ori_cols)?,
+            None => Closure { col_name: Vec::new(), act_clo: Box::new(|_| true) },
+        };
+        let lookup = ori_cols
+            .iter()
+            .enumerate()
+            .map(|(ind, s)| (&s.col_name, ind))
+            .collect::<HashMap<_, _>>();
+        let mut apply = |row: &mut TableEntry| {
+            let v = cls.col_name.iter().map(|cr| &row.col_data[lookup[cr]]).collect::<Vec<_>>();
+            if !(cls.act_clo)(v.as_slice()) {
+                return Ok(false);
+            }
+            // like MySQL assignments go left to right and see the values assigned before them
+            let mut changed = false;
+            for (ind, p) in &assigns {
+                let v = p.col_name.iter().map(|cr| &row.col_data[lookup[cr]]).collect::<Vec<_>>();
+                let v = ori_cols[*ind].coerce(&(p.act_clo)(v.as_slice()), strict)?;
+                if v != row.col_data[*ind] {
+                    row.col_data[*ind] = v;
+                    changed = true;
+                }
+            }
+            Ok(changed)
+        };
+        if !referenced && !keys.iter().any(|k| matches!(k.kind, KeyKind::Foreign(_))) {
+            return self.update_rows_of(name, &mut apply);
+        }
+        // foreign keys need the rows before and after, worked out before anything is written
+        // and after the rows are locked
+        self.lock_rows_of(name, LockMode::Exclusive, &mut apply)?;
+        let tb = self.open(name)?;
+        let mut changes = Vec::new();
+        for row in tb.get_rows() {
+            let old = row?;
+            if !old.version.is_live() {
+                continue;
+            }
+            let mut new = old.clone();
+            if apply(&mut new)? {
+                changes.push((old, Some(new)));
+            }
+        }
+        let new_rows = changes.iter().filter_map(|(_, n)| n.as_ref()).collect::<Vec<_>>();
+        self.check_parents(name, &ori_cols, &keys, &new_rows)?;
+        let steps = self.plan_parent_changes(name, &changes)?;
+        let n = self.update_rows_of(name, &mut apply)?;
+        self.apply_ref_steps(steps)?;
+        Ok(n)
+    }
+
+    pub fn delete(&mut self, name: &str, filter: Option<Expr>) -> Result<usize, TableLikeError> {
+        let referenced = !self.referencing(name)?.is_empty();
+        let tb = self.open(name)?;
+        let ori_cols = tb.get_cols()?;
+        let cls = match &filter {
+            Some(e) => Closure::from_expr(e, &ori_cols)?,
+            None => Closure { col_name: Vec::new(), act_clo: Box::new(|_| true) },
+        };
         let lookup = ori_cols
             .iter()
             .enumerate()
             .map(|(ind, s)| (&s.col_name, ind))
             .collect::<HashMap<_, _>>();
+        let mut matches = |row: &TableEntry| {
+            let v = cls.col_name.iter().map(|cr| &row.col_data[lookup[cr]]).collect::<Vec<_>>();
+            Ok((cls.act_clo)(v.as_slice()))
+        };
+        if !referenced {
+            return self.delete_rows_of(name, &mut matches);
+        }
+        self.lock_rows_of(name, LockMode::Exclusive, &mut |row| matches(row))?;
+        let tb = self.open(name)?;
+        let mut changes = Vec::new();
+        for row in tb.get_rows() {
+            let row = row?;
+            if row.version.is_live() && matches(&row)? {
+                changes.push((row, None));
+            }
+        }
+        let steps = self.plan_parent_changes(name, &changes)?;
+        let n = self.delete_rows_of(name, &mut matches)?;
+        self.apply_ref_steps(steps)?;
+        Ok(n)
+    }
+
+    pub fn select(&mut self, session: SessionId, stmt: (String, Criteria), lock: Option<LockMode>) -> Result<Box<dyn TableLike>, TableLikeError> {
+        // FOR UPDATE and FOR SHARE lock the rows they read and read them as they are now, like InnoDB
+        if let Some(mode) = lock.filter(|_| !stmt.0.is_empty()) {
+            let cols = self.open(&stmt.0)?.get_cols()?;
+            let cls = match &stmt.1.filter {
+                Some(e) => Closure::from_expr(e, &cols)?,
+                None => Closure { col_name: Vec::new(), act_clo: Box::new(|_| true) },
+            };
+            let slots = slots_of(&cols, &cls.col_name)?;
+            self.lock_rows_of(&stmt.0, mode, &mut |row| {
+                Ok((cls.act_clo)(&slots.iter().map(|i| &row.col_data[*i]).collect::<Vec<_>>()))
+            })?;
+        }
+        if !stmt.0.is_empty() {
+            self.open(&stmt.0)?;
+        }
+        // otherwise the rows as they were when the transaction started, see mvcc.rs
+        let snapshot = self.txns.snapshot(session);
+        self.read((&stmt.0, &stmt.1), lock.is_none().then_some(&snapshot))
+    }
+
+    // a plain SELECT of a table that is open already doesn't change anything here, so it
+    // runs side by side with the others (see serve), None when it has to run alone
+    pub fn try_read(&self, session: &Session, stmt: &mut Statement) -> Option<Result<QueryResult, TableLikeError>> {
+        bind_session(session, stmt);
+        let Statement::Select { table, criteria, lock: None } = stmt else {
+            return None;
+        };
+        match self.txns.is_open(session.id) {
+            // the table was locked for the transaction by an earlier statement
+            true if !table.is_empty() && !self.locks.holds(session.id, table) => return None,
+            true => {}
+            // a statement of its own starts a transaction without autocommit
+            false if !session.autocommit => return None,
+            false if !table.is_empty() && !self.locks.can_lock(session.id, table, LockMode::IntentionShared) => return None,
+            false => {}
+        }
+        if !table.is_empty() && (!self.tables.contains(table) || self.locks.check_explicit(session.id, table, LockMode::IntentionShared).is_err()) {
+            return None;
+        }
+        let snapshot = self.txns.snapshot(session.id);
+        let res = self.read((table, criteria), Some(&snapshot)).map(QueryResult::Table);
+        // a result too big for memory went to a file of its own, there is nothing to undo
+        let mut pool = buffer::lock(&self.pool);
+        if let Err(e) = (!pool.wal().is_empty()).then(|| pool.wal().commit()).transpose() {
+            return Some(Err(e.into()));
+        }
+        Some(res)
+    }
+
+    // the rows of an open table the snapshot sees, or the live ones without one
+    fn read(&self, stmt: (&str, &Criteria), snapshot: Option<&Snapshot>) -> Result<Box<dyn TableLike>, TableLikeError> {
+        // without a FROM clause the select list is evaluated once against a row with no columns
+        let dual = Table { all: vec![TableEntry::default()], ..Default::default() };
+        let visible = |v: &Version| match snapshot {
+            Some(s) => s.sees(v),
+            None => v.is_live(),
+        };
+        let tb: &dyn TableLike = match stmt.0.is_empty() {
+            true => &dual,
+            false => self.tables.get(stmt.0).unwrap(),
+        };
+
         
+        //NOW try and fit result object in Table otherwise flush to file
+
+        let mut rt = Table::default();
+
+        let mut act_rt: Option<FileTable> = Default::default();
+        // bytes of the rows in rt
+        let mut mem = 0;
+        let mem_lim = buffer::lock(&self.pool).budget();
+
+        let ori_cols = tb.get_cols()?;
+
+        let lookup = ori_cols
+            .iter()
+            .enumerate()
+            .map(|(ind, s)| (&s.col_name, ind))
+            .collect::<HashMap<_, _>>();
+
+        let mut projs = Vec::new();
         for yt in &stmt.1.re {
-            rt.col_names.push(ori_cols[lookup[&yt]].clone())
+            match yt {
+                SelectItem::Wildcard => {
+                    for c in &ori_cols {
+                        projs.push((c.col_name.clone(), Projection::from_expr(&Expr::Column(c.col_name.clone()), &ori_cols)?));
+                    }
+                }
+                SelectItem::Expr { expr, alias } => projs.push((alias.clone(), Projection::from_expr(expr, &ori_cols)?)),
+            }
+        }
+        for (alias, p) in &projs {
+            rt.col_names.push(ColumnEntry::new(alias.clone(), p.col_type.clone()))
         }
-        for ten in tb.get_rows() {
+        let cls = match &stmt.1.filter {
+            Some(e) => Closure::from_expr(e, &ori_cols)?,
+            None => Closure { col_name: Vec::new(), act_clo: Box::new(|_| true) },
+        };
+        // an index on the WHERE clause narrows down the rows to go through, they are still filtered below
+        let plan = match &stmt.1.filter {
+            Some(e) => index::plan(e, &ori_cols, &tb.get_keys()?),
+            None => None,
+        };
+        let rows = plan.and_then(|(key, range)| tb.index_rows(&key, &range)).unwrap_or_else(|| tb.get_rows());
+
+        for ten in rows {
             let mut v = Vec::new();
-            let mut d = Vec::new();
             let t = ten?;
-            for cr in &stmt.1.cls.col_name {
-                v.push(&t.col_data[lookup[cr]])
+            if !visible(&t.version) {
+                continue;
             }
-            for crd in &stmt.1.re {
-                d.push(t.col_data[lookup[crd]].clone())
+            for cr in &cls.col_name {
+                v.push(&t.col_data[lookup[cr]])
             }
-            if (stmt.1.cls.act_clo)(v.as_slice()) {
-                rt.all.push(TableEntry { col_data: d });
-                if rt.all.len() > MAX_MEM_LIM {
-                    let res = match &mut act_rt {
-                        Some(t) => t,
+            if (cls.act_clo)(v.as_slice()) {
+                let mut d = Vec::with_capacity(projs.len());
+                for (_, p) in &projs {
+                    v.clear();
+                    for cr in &p.col_name {
+                        v.push(&t.col_data[lookup[cr]])
+                    }
+                    d.push((p.act_clo)(v.as_slice()));
+                }
+                let row = TableEntry { col_data: d, ..Default::default() };
+                mem += row.mem_size();
+                rt.all.push(row);
+                if mem > mem_lim {
+                    mem = 0;
+                    match &mut act_rt {
+                        Some(t) => t.add_rows(&mut rt.all.drain(..))?,
                         None => {
-                            self.count += 1;
-                            let mut fs = FileTable::create_new(self.count.to_string().as_str())?;
-                            //put logic to add columns
-                            //TODO interface required to add columns
+                            let count = self.count.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+                            let name = format!("{SPILL_PREFIX}{}-{count}.tmp", std::process::id());
+                            let mut fs = FileTable::create_temp(&name, &self.pool)?;
                             fs.flush(&rt)?;
-                            act_rt.get_or_insert(fs)
+                            rt.all.clear();
+                            act_rt = Some(fs);
                         },
                     };
-                    res.add_rows(&mut rt.all.drain(..))?;
                 }
             }
         }
@@ -552,16 +1965,234 @@ impl TableManager {
 pub enum TableCell {
     Num(Option<NumType>),
     Str(Option<StringType>),
+    Bool(Option<bool>),
+    Date(Option<Date>),
+    Time(Option<Time>),
+    DateTime(Option<DateTime>),
+    // unscaled value, the spec says where the decimal point goes
+    Decimal(Option<DecimalType>, DecimalSpec),
+    Blob(Option<BlobType>),
 }
 
 impl TableCell {
     pub fn get_len(&self) -> usize {
         match &self {
             Self::Num(Some(t)) => t.to_string().len(),
-            Self::Str(Some(t)) => t.len(),
+            Self::Str(Some(t)) => t.chars().count(),
+            Self::Bool(Some(true)) => "TRUE".len(),
+            Self::Bool(Some(false)) => "FALSE".len(),
+            Self::Date(Some(_)) => "YYYY-MM-DD".len(),
+            Self::Time(Some(t)) => t.to_string().len(),
+            Self::DateTime(Some(_)) => "YYYY-MM-DD HH:MM:SS".len(),
+            Self::Decimal(Some(t), spec) => decimal::format(*t, spec.scale).len(),
+            Self::Blob(Some(t)) => "0x".len() + 2 * t.len(),
             _ => "NULL".len(),
         }
     }
+
+    // name used for the column type in the table file
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Num(_) => "Num",
+            Self::Str(_) => "String",
+            Self::Bool(_) => "Bool",
+            Self::Date(_) => "Date",
+            Self::Time(_) => "Time",
+            Self::DateTime(_) => "DateTime",
+            Self::Decimal(..) => "Decimal",
+            Self::Blob(_) => "Blob",
+        }
+    }
+
+    pub fn from_sql_type(name: &str, args: &[u32]) -> Result<TableCell, TableLikeError> {
+        let up = name.to_ascii_uppercase();
+        let ty = match up.as_str() {
+            // the display width of INT(n) is accepted and ignored like in MySQL 8
+            "INT" | "INTEGER" | "BIGINT" if args.len() == 1 => return Ok(Self::Num(None)),
+            "NUM" | "INT" | "INTEGER" | "BIGINT" => Self::Num(None),
+            "STRING" | "VARCHAR" | "TEXT" => Self::Str(None),
+            "BOOL" | "BOOLEAN" => Self::Bool(None),
+            "DATE" => Self::Date(None),
+            "TIME" => Self::Time(None),
+            "DATETIME" | "TIMESTAMP" => Self::DateTime(None),
+            "BLOB" | "TINYBLOB" | "MEDIUMBLOB" | "LONGBLOB" | "VARBINARY" => Self::Blob(None),
+            "DECIMAL" | "NUMERIC" | "DEC" => {
+                let spec = match args {
+                    [] => Ok(DecimalSpec::default()),
+                    [p] => DecimalSpec::new(*p, 0),
+                    [p, s] => DecimalSpec::new(*p, *s),
+                    _ => Err(format!("Too many arguments for {up}")),
+                }
+                .map_err(|e| TableLikeError::new(&e))?;
+                return Ok(Self::Decimal(None, spec));
+            }
+            _ => return Err(TableLikeError::new(&format!("Unknown type '{name}'"))),
+        };
+        if !args.is_empty() {
+            return Err(TableLikeError::new(&format!("Type {up} does not take arguments")));
+        }
+        Ok(ty)
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(
+            self,
+            Self::Num(None) | Self::Str(None) | Self::Bool(None) | Self::Date(None) | Self::Time(None) | Self::DateTime(None)
+                | Self::Decimal(None, _) | Self::Blob(None)
+        )
+    }
+
+    // NULL of the same type
+    pub fn null(&self) -> TableCell {
+        match self {
+            Self::Num(_) => Self::Num(None),
+            Self::Str(_) => Self::Str(None),
+            Self::Bool(_) => Self::Bool(None),
+            Self::Date(_) => Self::Date(None),
+            Self::Time(_) => Self::Time(None),
+            Self::DateTime(_) => Self::DateTime(None),
+            Self::Decimal(_, spec) => Self::Decimal(None, *spec),
+            Self::Blob(_) => Self::Blob(None),
+        }
+    }
+
+    pub fn as_num(&self) -> Option<NumType> {
+        match self {
+            Self::Num(t) => *t,
+            Self::Bool(t) => t.map(NumType::from),
+            // like MySQL a string that isn't a number counts as 0
+            Self::Str(t) => t.as_ref().map(|s| s.trim().parse().unwrap_or(0)),
+            // dates as numbers read like YYYYMMDD, same as MySQL
+            Self::Date(t) => t.map(|d| {
+                let (y, m, d) = d.ymd();
+                y * 10000 + m as NumType * 100 + d as NumType
+            }),
+            Self::Time(t) => t.map(|t| {
+                let (a, sign) = (t.secs().abs(), t.secs().signum());
+                sign * (a / 3600 * 10000 + a / 60 % 60 * 100 + a % 60)
+            }),
+            Self::DateTime(t) => t.map(|t| {
+                let (y, m, d) = t.date().ymd();
+                let s = t.time().secs();
+                (y * 10000 + m as NumType * 100 + d as NumType) * 1000000 + s / 3600 * 10000 + s / 60 % 60 * 100 + s % 60
+            }),
+            Self::Decimal(t, spec) => t
+                .and_then(|v| decimal::rescale(v, spec.scale, 0))
+                .and_then(|v| NumType::try_from(v).ok()),
+            Self::Blob(t) => t.as_ref().map(|b| String::from_utf8_lossy(b).trim().parse().unwrap_or(0)),
+        }
+    }
+
+    // raw bytes, strings are taken as their UTF-8 encoding
+    pub fn as_bytes(&self) -> Option<BlobType> {
+        match self {
+            _ if self.is_null() => None,
+            Self::Blob(t) => t.clone(),
+            Self::Str(t) => t.as_ref().map(|s| s.as_bytes().to_vec()),
+            _ => Some(self.to_string().into_bytes()),
+        }
+    }
+
+    // unscaled value and its scale
+    pub fn as_decimal(&self) -> Option<(DecimalType, u8)> {
+        match self {
+            Self::Decimal(t, spec) => t.map(|v| (v, spec.scale)),
+            Self::Str(Some(s)) => decimal::parse(s).or(Some((0, 0))),
+            _ => self.as_num().map(|n| (n as DecimalType, 0)),
+        }
+    }
+
+    // spec the value takes part in decimal arithmetic with
+    pub fn decimal_spec(&self) -> DecimalSpec {
+        match self {
+            Self::Decimal(_, spec) => *spec,
+            _ => DecimalSpec::integer(),
+        }
+    }
+
+    pub fn as_datetime(&self) -> Option<DateTime> {
+        match self {
+            Self::DateTime(t) => *t,
+            Self::Date(t) => t.map(|d| DateTime::new(d, 0)),
+            Self::Str(Some(s)) => DateTime::parse(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_date(&self) -> Option<Date> {
+        self.as_datetime().map(|d| d.date())
+    }
+
+    pub fn as_time(&self) -> Option<Time> {
+        match self {
+            Self::Time(t) => *t,
+            Self::DateTime(Some(t)) => Some(t.time()),
+            Self::Str(Some(s)) => Time::parse(s).or_else(|| DateTime::parse(s).map(|d| d.time())),
+            _ => None,
+        }
+    }
+
+    pub fn truth(&self) -> Option<bool> {
+        match self {
+            Self::Bool(t) => *t,
+            _ => self.as_num().map(|n| n != 0),
+        }
+    }
+
+    // comparison in the SQL sense, None when either side is NULL
+    pub fn sql_cmp(&self, other: &TableCell) -> Option<Ordering> {
+        match (self, other) {
+            (Self::Str(Some(a)), Self::Str(Some(b))) => Some(a.cmp(b)),
+            (Self::Bool(Some(a)), Self::Bool(Some(b))) => Some(a.cmp(b)),
+            (Self::Blob(_), _) | (_, Self::Blob(_)) => Some(self.as_bytes()?.cmp(&other.as_bytes()?)),
+            (Self::Time(_), _) | (_, Self::Time(_)) => Some(self.as_time()?.cmp(&other.as_time()?)),
+            // a DATE against a DATETIME is compared as midnight of that day
+            (Self::Date(_) | Self::DateTime(_), _) | (_, Self::Date(_) | Self::DateTime(_)) => {
+                Some(self.as_datetime()?.cmp(&other.as_datetime()?))
+            }
+            (Self::Decimal(..), _) | (_, Self::Decimal(..)) => decimal::cmp(self.as_decimal()?, other.as_decimal()?),
+            _ => Some(self.as_num()?.cmp(&other.as_num()?)),
+        }
+    }
+
+    // converts val to the type of self, which acts as the column prototype
+    pub fn cast(&self, val: &TableCell) -> Result<TableCell, TableLikeError> {
+        if val.is_null() {
+            return Ok(self.null());
+        }
+        Ok(match (self, val) {
+            (Self::Num(_), Self::Str(Some(s))) => Self::Num(Some(NumType::from_str_radix(s.trim(), NUM_BASE)
+                .map_err(|_| TableLikeError::new(&format!("Incorrect integer value: '{s}'")))?)),
+            (Self::Num(_), _) => Self::Num(val.as_num()),
+            (Self::Str(_), Self::Blob(Some(b))) => Self::Str(Some(String::from_utf8_lossy(b).into_owned())),
+            (Self::Str(_), _) => Self::Str(Some(val.to_string())),
+            (Self::Blob(_), _) => Self::Blob(val.as_bytes()),
+            (Self::Bool(_), Self::Str(Some(s))) => Self::Bool(Some(match s.trim().to_ascii_uppercase().as_str() {
+                "TRUE" => true,
+                "FALSE" => false,
+                t => t.parse::<NumType>().map(|n| n != 0)
+                    .map_err(|_| TableLikeError::new(&format!("Incorrect boolean value: '{s}'")))?,
+            })),
+            (Self::Bool(_), _) => Self::Bool(val.truth()),
+            (Self::Date(_), _) => Self::Date(Some(val.as_date()
+                .ok_or_else(|| TableLikeError::new(&format!("Incorrect date value: '{val}'")))?)),
+            (Self::Time(_), _) => Self::Time(Some(val.as_time()
+                .ok_or_else(|| TableLikeError::new(&format!("Incorrect time value: '{val}'")))?)),
+            (Self::DateTime(_), _) => Self::DateTime(Some(val.as_datetime()
+                .ok_or_else(|| TableLikeError::new(&format!("Incorrect datetime value: '{val}'")))?)),
+            (Self::Decimal(_, spec), _) => {
+                let (v, s) = match val {
+                    Self::Str(Some(s)) => decimal::parse(s),
+                    _ => val.as_decimal(),
+                }
+                .ok_or_else(|| TableLikeError::new(&format!("Incorrect decimal value: '{val}'")))?;
+                let v = decimal::rescale(v, s, spec.scale).filter(|v| spec.fits(*v)).ok_or_else(|| {
+                    TableLikeError::new(&format!("Out of range value '{val}' for DECIMAL({},{})", spec.precision, spec.scale))
+                })?;
+                Self::Decimal(Some(v), *spec)
+            }
+        })
+    }
 }
 
 impl Display for TableCell {
@@ -569,118 +2200,599 @@ impl Display for TableCell {
         match &self {
             Self::Num(Some(t)) => t.fmt(f),
             Self::Str(Some(t)) => t.fmt(f),
+            Self::Bool(Some(true)) => "TRUE".fmt(f),
+            Self::Bool(Some(false)) => "FALSE".fmt(f),
+            Self::Date(Some(t)) => t.fmt(f),
+            Self::Time(Some(t)) => t.fmt(f),
+            Self::DateTime(Some(t)) => t.fmt(f),
+            Self::Decimal(Some(t), spec) => decimal::format(*t, spec.scale).fmt(f),
+            Self::Blob(Some(t)) => format!("0x{}", db::encode_hex(t)).fmt(f),
             _ => "NULL".fmt(f),
         }
     }
 }
 
-fn select<T>(t_ref: Vec<&mut TableReference>, criteria: &Criteria) -> Table {
-    todo!()
-}
-
 #[derive(Parser)]
 #[grammar = "sql_gram.pest"]
 struct SQLParser;
 
-fn main() {
-    let mut f1 = FileTable::new("asd").expect("pt1");
-    // println!("{}", f1.move_to_memory().expect("could not convert"));
-    // println!("{:?}", f1.get_cols());
-    // println!("{:?}", f1.get_rows().collect::<Vec<_>>());
-    // println!("{f1}");
-    // let vec = vec![
-    //     ColumnEntry {
-    //         col_name: "Hei".to_string(),
-    //         col_type: TableCell::Num(None),
-    //     },
-    //     ColumnEntry {
-    //         col_name: "asd".to_string(),
-    //         col_type: TableCell::Str(None),
-    //     },
-    // ];
-    // // let mut tb = create_table("asd", vec).expect("Shit happens");
-    // // let mut tb = get_table("asd").expect("Shit happens");
-
-    let mut t1 = f1;
-
-    // let t2 = 
-    //         Table {
-    //             name: None,
-    //             col_names: vec,
-    //             all: vec![
-    //                 TableEntry {
-    //                     col_data: vec![
-    //                         TableCell::Num(Some(55)),
-    //                         TableCell::Str(Some("hdhdh".to_string())),
-    //                     ],
-    //                 },
-    //                 TableEntry {
-    //                     col_data: vec![TableCell::Num(Some(46)), TableCell::Str(None)],
-    //                 },
-    //             ],
-    //         };
-
-    let mut tm = TableManager::new();
-    // let mut r1row = t2.get_rows().map(|f| f.unwrap());
-    // // t1.add_rows(&mut r1row).expect("SHHHHHIIIITT");
-    // println!("{t1}");
-    tm.insert_ft(t1);
-    let cri1 = Criteria {
-        cls: Closure {
-            col_name: vec!["Hei".to_string()],
-            act_clo:Box::new(|f| {
-                if let TableCell::Num(Some(rt)) = f[0] {
-                    rt > &30
-                } else {
-                    false
+// the manager of a server and what its sessions wait on
+struct Shared {
+    // plain reads run side by side, everything else alone
+    tm: RwLock<TableManager>,
+    // bumped when a statement ends, which may have released the locks another one waits for
+    ended: Mutex<u64>,
+    released: Condvar,
+}
+
+impl Shared {
+    fn new(tm: TableManager) -> Shared {
+        Shared { tm: RwLock::new(tm), ended: Mutex::new(0), released: Condvar::new() }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, TableManager> {
+        self.tm.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, TableManager> {
+        self.tm.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn ended(&self) -> MutexGuard<'_, u64> {
+        self.ended.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn signal(&self) {
+        *self.ended() += 1;
+        self.released.notify_all();
+    }
+}
+
+// runs the statements of a client, each ending with ; at the end of a line, and writes
+// back their results, the session ends with the input
+fn serve(shared: &Shared, input: impl BufRead, mut out: impl ioWrite) -> std::io::Result<()> {
+    let mut session = shared.write().session();
+    let res = (|| -> std::io::Result<()> {
+        let mut buf = String::new();
+        for line in input.lines() {
+            let line = line?;
+            buf.push_str(&line);
+            buf.push('\n');
+            if !line.trim_end().ends_with(';') {
+                continue;
+            }
+            let deadline = Instant::now() + Duration::from_secs(session.lock_wait_timeout);
+            let res = loop {
+                // whatever ends after this wakes the statement up if it has to wait
+                let ended = *shared.ended();
+                let res = query::parse_statement(buf.trim()).and_then(|mut stmt| {
+                    let res = shared.read().try_read(&session, &mut stmt);
+                    res.unwrap_or_else(|| shared.write().execute(&mut session, stmt))
+                });
+                // a lock another session holds, the statement runs again once locks were released
+                let now = Instant::now();
+                if !matches!(res, Err(TableLikeError::LockWait)) || now >= deadline {
+                    break res;
                 }
-            }),
-        },
-        re: vec!["asd".to_string()],
+                drop(shared.released.wait_timeout_while(shared.ended(), deadline - now, |n| *n == ended));
+            };
+            if let Err(TableLikeError::LockWait) = res {
+                shared.write().locks.stop_waiting(session.id);
+            }
+            shared.signal();
+            match res {
+                Ok(QueryResult::Table(ret)) => writeln!(out, "{ret}")?,
+                Ok(QueryResult::Affected(n)) => writeln!(out, "Query OK, {n} {} affected", if n == 1 { "row" } else { "rows" })?,
+                Ok(QueryResult::Done) => writeln!(out, "Query OK")?,
+                Err(e) => writeln!(out, "ERROR: {e}")?,
+            }
+            out.flush()?;
+            buf.clear();
+        }
+        Ok(())
+    })();
+    let end = shared.write().end_session(&session);
+    if let Err(e) = end {
+        writeln!(out, "ERROR: {e}")?;
+    }
+    shared.signal();
+    out.flush()?;
+    res
+}
+
+// a thread to run a session on, with room on its stack for the most deeply nested
+// statement the parser takes, see query::MAX_EXPR_DEPTH
+fn session_thread() -> std::thread::Builder {
+    std::thread::Builder::new().stack_size(SESSION_STACK_SIZE)
+}
+
+// every client that connects gets a session on a thread of its own
+fn listen(shared: Arc<Shared>, addr: &str) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let shared = shared.clone();
+        // a client there is no thread for is turned away
+        let _ = session_thread().spawn(move || {
+            let Ok(input) = stream.try_clone() else {
+                return;
+            };
+            // a client that went away has nobody to tell
+            let _ = serve(&shared, BufReader::new(input), BufWriter::new(stream));
+        });
+    }
+    Ok(())
+}
+
+// writes again the tables ALTER TABLE left with rows of older schemas, one at a time so
+// statements get to run in between
+fn compact(shared: &Shared, every: Duration) {
+    loop {
+        std::thread::sleep(every);
+        // a table is written under a read lock so plain reads run on, only moving it in place
+        // waits for them, one that fails is tried again the next time
+        loop {
+            let Some(name) = shared.write().start_compaction() else {
+                break;
+            };
+            let built = shared.read().build_compacted(&name);
+            let res = match built {
+                Ok(files) => shared.write().finish_compaction(&name, &files),
+                Err(e) => {
+                    shared.write().end_compaction();
+                    Err(e)
+                }
+            };
+            // the statements that waited for the table run again
+            shared.signal();
+            if res.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+fn main() {
+    let mut pool_size = buffer::DEFAULT_POOL_SIZE;
+    let mut open_tables = cache::DEFAULT_CAPACITY;
+    let mut sync = SyncPolicy::default();
+    let mut addr = None;
+    let mut compact_every = Some(Duration::from_secs(10));
+    for arg in std::env::args().skip(1) {
+        let (opt, val) = arg.split_once('=').unwrap_or((&arg, ""));
+        let err = match opt {
+            "--buffer-pool-size" => buffer::parse_size(val).map(|n| pool_size = n).ok_or("Invalid buffer pool size"),
+            "--table-open-cache" => val.parse().ok().filter(|n| *n > 0).map(|n| open_tables = n).ok_or("Invalid table open cache"),
+            "--wal-sync" => SyncPolicy::from_name(val).map(|p| sync = p).ok_or("Invalid WAL sync policy"),
+            "--compact-interval" => val
+                .parse()
+                .ok()
+                .map(|n| compact_every = (n > 0).then(|| Duration::from_secs(n)))
+                .ok_or("Invalid compaction interval"),
+            "--listen" => (!val.is_empty()).then(|| addr = Some(val.to_string())).ok_or("Invalid listen address"),
+            _ => Err("Unknown option"),
+        };
+        if let Err(e) = err {
+            eprintln!("{e} '{arg}'");
+            std::process::exit(1);
+        }
+    }
+    // a crash leaves the log to be recovered before any table is opened
+    let wal = Wal::open(wal::LOG_PATH, sync).unwrap_or_else(|e| {
+        eprintln!("Could not recover the write-ahead log: {e}");
+        std::process::exit(1);
+    });
+    let tm = TableManager::new(pool_size, open_tables, wal).unwrap_or_else(|e| {
+        eprintln!("Could not roll back the open transactions: {e}");
+        std::process::exit(1);
+    });
+    let shared = Arc::new(Shared::new(tm));
+    if let Some(every) = compact_every {
+        let shared = shared.clone();
+        std::thread::spawn(move || compact(&shared, every));
+    }
+    let res = match &addr {
+        Some(addr) => listen(shared, addr),
+        None => session_thread()
+            .spawn(move || serve(&shared, std::io::stdin().lock(), std::io::stdout().lock()))
+            .and_then(|t| t.join().unwrap_or_else(|e| std::panic::resume_unwind(e))),
     };
-    let ret1 = tm.select(("asd".to_string(), cri1)).expect("fuck");
-    println!("{ret1}");
-    println!("Hello, world!");
-
-    let pairs =
-        SQLParser::parse(Rule::sql, "SELECT Hei, asd from asd;").expect("Something happened");
-
-    for pair in pairs {
-        let mut stmt: (String, Criteria) = (
-            "".to_string(),
-            Criteria {
-                cls: Closure {
-                    col_name: Vec::new(),
-                    act_clo: Box::new(|_| true),
-                },
-                re: Vec::new(),
-            },
+    if let Err(e) = res {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // tables are files in the working directory, so the tests that use a TableManager run one
+    // at a time, each in a directory of its own that is gone again when dropped
+    pub struct TestDir {
+        dir: PathBuf,
+        prev: PathBuf,
+        _one_at_a_time: MutexGuard<'static, ()>,
+    }
+
+    impl TestDir {
+        pub fn new(name: &str) -> TestDir {
+            static LOCK: Mutex<()> = Mutex::new(());
+            let guard = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+            let dir = std::env::temp_dir().join(format!("actually_mysql_{}_{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir(&dir).unwrap();
+            let prev = std::env::current_dir().unwrap();
+            std::env::set_current_dir(&dir).unwrap();
+            TestDir { dir, prev, _one_at_a_time: guard }
+        }
+
+        // a manager on the tables of the directory, after what a crash would have left recovered
+        pub fn manager(&self) -> TableManager {
+            let wal = Wal::open(wal::LOG_PATH, SyncPolicy::Full).unwrap();
+            TableManager::new(1 << 20, cache::DEFAULT_CAPACITY, wal).unwrap()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::env::set_current_dir(&self.prev);
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // a file of its own for the tests of a module that work on files directly, gone again when
+    // dropped together with what was written next to it, like its log or temp file
+    pub struct TempPath(pub String);
+
+    impl TempPath {
+        pub fn new(module: &str, name: &str) -> TempPath {
+            let p = std::env::temp_dir().join(format!("actually_mysql_file_{}_{module}_{name}", std::process::id()));
+            TempPath(p.to_string_lossy().into_owned())
+        }
+
+        pub fn with(&self, ext: &str) -> String {
+            format!("{}.{ext}", self.0)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+            let path = PathBuf::from(&self.0);
+            let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else { return };
+            let siblings = format!("{}.", name.to_string_lossy());
+            for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+                if entry.file_name().to_string_lossy().starts_with(&siblings) {
+                    let _ = std::fs::remove_file(entry.path());
+                }
+            }
+        }
+    }
+
+    pub fn run(tm: &mut TableManager, session: &mut Session, sql: &str) -> Result<QueryResult, TableLikeError> {
+        tm.execute(session, query::parse_statement(sql)?)
+    }
+
+    #[test]
+    fn results_are_printed_as_tables() {
+        let dir = TestDir::new("print");
+        let mut tm = dir.manager();
+        let mut s = tm.session();
+        run(&mut tm, &mut s, "CREATE TABLE t (id INT, s VARCHAR(20));").unwrap();
+        let print = |tm: &mut TableManager, s: &mut Session, sql: &str| match run(tm, s, sql) {
+            Ok(QueryResult::Table(t)) => t.to_string(),
+            _ => panic!("{sql} has no rows"),
+        };
+        assert_eq!(print(&mut tm, &mut s, "SELECT * FROM t;"), "+----+---+\n| id | s |\n+----+---+\n+----+---+");
+        run(&mut tm, &mut s, "INSERT INTO t VALUES (1, 'a'), (22, NULL);").unwrap();
+        assert_eq!(
+            print(&mut tm, &mut s, "SELECT * FROM t;"),
+            "+----+------+\n| id | s    |\n+----+------+\n| 1  | a    |\n| 22 | NULL |\n+----+------+"
         );
-        for tokens in pair.into_inner() {
-            println!("{:?}", tokens.as_rule());
-            match tokens.as_rule() {
-                Rule::clauses => {
-                    for tk in tokens.into_inner() {
-                        match tk.as_rule() {
-                            Rule::select_clause => {
-                                let se = tk.into_inner().next().unwrap();
-                                let cols = se.as_str().to_string();
-                                stmt.1.re.append(
-                                    &mut cols.split(',').map(|f| f.trim().to_string()).collect(),
-                                );
-                            }
-                            Rule::from_clause => {
-                                let se = tk.into_inner().next().unwrap();
-                                stmt.0 = se.as_str().to_string();
-                            }
-                            _ => {}
+    }
+
+    #[test]
+    fn transactions_commit_roll_back_and_keep_savepoints() {
+        let dir = TestDir::new("transactions");
+        let mut tm = dir.manager();
+        let mut s = tm.session();
+        for engine in ["TEXT", "PAGED"] {
+            let t = format!("t{engine}");
+            // $t is the table of the engine
+            let mut q = |sql: &str| run(&mut tm, &mut s, &sql.replace("$t", &t)).unwrap();
+            q(&format!("CREATE TABLE $t (id INT PRIMARY KEY, n INT) ENGINE={engine};"));
+            q("INSERT INTO $t VALUES (1, 1);");
+            q("BEGIN;");
+            q("INSERT INTO $t VALUES (2, 2);");
+            q("SAVEPOINT a;");
+            q("UPDATE $t SET n = 10 WHERE id = 1;");
+            q("SAVEPOINT b;");
+            q("DELETE FROM $t WHERE id = 2;");
+            q("ROLLBACK TO SAVEPOINT a;");
+            q("INSERT INTO $t VALUES (3, 3);");
+            q("COMMIT;");
+            q("BEGIN;");
+            q("DELETE FROM $t WHERE id = 1;");
+            q("INSERT INTO $t VALUES (4, 4);");
+            q("ROLLBACK;");
+            // without autocommit a transaction starts with the first statement
+            q("SET autocommit = 0;");
+            q("UPDATE $t SET n = 30 WHERE id = 3;");
+            q("ROLLBACK;");
+            q("UPDATE $t SET n = 20 WHERE id = 2;");
+            q("COMMIT;");
+            q("SET autocommit = 1;");
+            assert_eq!(
+                select(&mut tm, &mut s, &format!("SELECT id, n FROM {t} WHERE id > 0;")),
+                vec![vec!["1", "1"], vec!["2", "20"], vec!["3", "3"]]
+            );
+            // savepoints go with the transaction they were set in
+            assert!(run(&mut tm, &mut s, "ROLLBACK TO a;").is_err());
+        }
+    }
+
+    #[test]
+    fn open_transactions_are_rolled_back_after_a_crash() {
+        let dir = TestDir::new("crash");
+        let mut tm = dir.manager();
+        let mut s = tm.session();
+        for sql in [
+            "CREATE TABLE t (id INT PRIMARY KEY, n INT);",
+            "CREATE TABLE p (id INT PRIMARY KEY, n INT) ENGINE=PAGED;",
+            "INSERT INTO t VALUES (1, 1), (2, 2);",
+            "INSERT INTO p VALUES (1, 1), (2, 2);",
+            "BEGIN;",
+            "UPDATE t SET n = 10;",
+            "DELETE FROM p WHERE id = 1;",
+            "INSERT INTO p VALUES (3, 3);",
+        ] {
+            run(&mut tm, &mut s, sql).unwrap();
+        }
+        // the manager goes away without the session ending
+        drop(tm);
+        let mut tm = dir.manager();
+        let mut s = tm.session();
+        for t in ["t", "p"] {
+            assert_eq!(select(&mut tm, &mut s, &format!("SELECT id, n FROM {t};")), vec![vec!["1", "1"], vec!["2", "2"]]);
+        }
+        run(&mut tm, &mut s, "INSERT INTO p VALUES (3, 3);").unwrap();
+    }
+
+    #[test]
+    fn text_tables_are_written_again_only_when_rows_change() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = TestDir::new("rewrite");
+        let mut tm = dir.manager();
+        let mut s = tm.session();
+        run(&mut tm, &mut s, "CREATE TABLE t (id INT PRIMARY KEY, n INT NOT NULL);").unwrap();
+        run(&mut tm, &mut s, "INSERT INTO t VALUES (1, 1), (2, 2), (3, 3);").unwrap();
+        let inode = || std::fs::metadata("t").unwrap().ino();
+        let before = inode();
+        for sql in ["UPDATE t SET n = 5 WHERE id = 9;", "DELETE FROM t WHERE n > 3;"] {
+            run(&mut tm, &mut s, sql).unwrap();
+        }
+        assert_eq!(inode(), before);
+        for sql in ["UPDATE t SET id = 1 WHERE id = 2;", "UPDATE t SET n = NULL WHERE id = 3;"] {
+            assert!(run(&mut tm, &mut s, sql).is_err());
+        }
+        assert_eq!(inode(), before);
+        run(&mut tm, &mut s, "UPDATE t SET id = id + 1;").unwrap();
+        run(&mut tm, &mut s, "DELETE FROM t WHERE id = 3;").unwrap();
+        assert_eq!(select(&mut tm, &mut s, "SELECT id, n FROM t;"), vec![vec!["2", "1"], vec!["4", "3"]]);
+        assert_eq!(select(&mut tm, &mut s, "SELECT n FROM t WHERE id = 4;"), vec![vec!["3"]]);
+        let left = std::fs::read_dir(".").unwrap().map(|e| e.unwrap().file_name().into_string().unwrap());
+        assert!(left.filter(|f| f.ends_with(".tmp") || f.contains(".old")).count() == 0);
+    }
+
+    #[test]
+    fn results_too_big_for_memory_go_to_a_file_that_is_removed_again() {
+        let _dir = TestDir::new("spill");
+        let spilled = || {
+            let names = std::fs::read_dir(".").unwrap().map(|e| e.unwrap().file_name().into_string().unwrap());
+            names.filter(|n| n.starts_with(SPILL_PREFIX)).count()
+        };
+        std::fs::write(format!("{SPILL_PREFIX}1-1.tmp"), "left by a crash").unwrap();
+        for first in [true, false] {
+            let wal = Wal::open(wal::LOG_PATH, SyncPolicy::Full).unwrap();
+            let mut tm = TableManager::new(16 << 10, cache::DEFAULT_CAPACITY, wal).unwrap();
+            assert_eq!(spilled(), 0);
+            let mut s = tm.session();
+            // the file names start over after a restart
+            if first {
+                run(&mut tm, &mut s, "CREATE TABLE t (id INT, s VARCHAR(100));").unwrap();
+                let values = (0..100).map(|i| format!("({i}, '{}')", "x".repeat(90))).collect::<Vec<_>>();
+                run(&mut tm, &mut s, &format!("INSERT INTO t VALUES {};", values.join(", "))).unwrap();
+            }
+            let Ok(QueryResult::Table(t)) = run(&mut tm, &mut s, "SELECT * FROM t;") else {
+                panic!("SELECT has no rows");
+            };
+            assert_eq!(spilled(), 1);
+            assert_eq!(t.to_string().lines().count(), 4 + t.get_rows().count());
+            drop(t);
+            assert_eq!(spilled(), 0);
+        }
+    }
+
+    #[test]
+    fn column_types_keep_their_lengths_and_signs() {
+        let dir = TestDir::new("type_modifiers");
+        for engine in ["TEXT", "PAGED"] {
+            let t = format!("t{engine}");
+            let mut tm = dir.manager();
+            let mut s = tm.session();
+            run(&mut tm, &mut s, &format!(
+                "CREATE TABLE {t} (id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT, c CHAR(4) DEFAULT 'ab', v VARCHAR(3) UNIQUE, \
+                 b BINARY(3), d DECIMAL(5,2) UNSIGNED NOT NULL DEFAULT 1.5, INDEX cv (c, v)) ENGINE={engine};"
+            ))
+            .unwrap();
+            let describe = vec![
+                vec!["b", "binary(3)", "YES", "", "NULL", ""],
+                vec!["c", "char(4)", "YES", "MUL", "ab", ""],
+                vec!["d", "decimal(5,2) unsigned", "NO", "", "1.50", ""],
+                vec!["id", "bigint unsigned", "NO", "PRI", "NULL", "auto_increment"],
+                vec!["v", "varchar(3)", "YES", "UNI", "NULL", ""],
+            ];
+            assert_eq!(select(&mut tm, &mut s, &format!("DESCRIBE {t};")), describe);
+
+            // CHAR drops trailing spaces, also the ones that would have made it too long
+            run(&mut tm, &mut s, &format!("INSERT INTO {t} (id, c, v, b) VALUES (1, 'ab  ', 'abc', 'x'), (2, 'abcd    ', 'a ', NULL);")).unwrap();
+            let mut fails = |sql: String| error(&mut tm, &mut s, &sql);
+            assert_eq!(fails(format!("INSERT INTO {t} (c) VALUES ('abcde');")), "Data too long for column 'c'");
+            assert_eq!(fails(format!("INSERT INTO {t} (v) VALUES ('abcd');")), "Data too long for column 'v'");
+            assert_eq!(fails(format!("INSERT INTO {t} (b) VALUES ('abcd');")), "Data too long for column 'b'");
+            assert_eq!(fails(format!("INSERT INTO {t} (id) VALUES (-1);")), "Out of range value for column 'id'");
+            assert_eq!(fails(format!("INSERT INTO {t} (d) VALUES (-0.5);")), "Out of range value for column 'd'");
+            assert_eq!(fails(format!("UPDATE {t} SET v = 'wxyz' WHERE id = 1;")), "Data too long for column 'v'");
+            assert_eq!(fails(format!("UPDATE {t} SET d = d - 5;")), "Out of range value for column 'd'");
+
+            // without a strict mode values are cut to fit and negative ones clamped to 0
+            run(&mut tm, &mut s, "SET sql_mode = '';").unwrap();
+            run(&mut tm, &mut s, &format!("INSERT INTO {t} VALUES (3, 'abcdefg', 'wxyz', 'abcd', -2.5);")).unwrap();
+            run(&mut tm, &mut s, &format!("UPDATE {t} SET v = 'zzzzz', d = d - 5 WHERE id = 1;")).unwrap();
+            let rows = vec![
+                vec!["1", "ab", "zzz", "0x780000", "0.00"],
+                vec!["2", "abcd", "a ", "NULL", "1.50"],
+                vec!["3", "abcd", "wxy", "0x616263", "0.00"],
+            ];
+            assert_eq!(select(&mut tm, &mut s, &format!("SELECT id, c, v, b, d FROM {t};")), rows);
+            assert_eq!(select(&mut tm, &mut s, &format!("SELECT CHAR_LENGTH(c) FROM {t} WHERE id = 1;")), vec![vec!["2"]]);
+
+            // the modifiers are read back from the table file
+            drop(tm);
+            let mut tm = dir.manager();
+            let mut s = tm.session();
+            assert_eq!(select(&mut tm, &mut s, &format!("DESCRIBE {t};")), describe);
+            assert_eq!(select(&mut tm, &mut s, &format!("SELECT id, c, v, b, d FROM {t};")), rows);
+            assert_eq!(error(&mut tm, &mut s, &format!("INSERT INTO {t} (v) VALUES ('abcd');")), "Data too long for column 'v'");
+        }
+        let mut tm = dir.manager();
+        let mut s = tm.session();
+        assert_eq!(error(&mut tm, &mut s, "CREATE TABLE u (s VARCHAR(3) UNSIGNED);"), "UNSIGNED is not allowed for column 's'");
+        assert_eq!(error(&mut tm, &mut s, "CREATE TABLE u (c CHAR(256));"), "Column length too big for column 'c' (max = 255)");
+    }
+
+    #[test]
+    fn auto_increment_counters_outlive_deletes_and_restarts() {
+        let dir = TestDir::new("auto_increment");
+        for engine in ["TEXT", "PAGED"] {
+            let t = format!("t{engine}");
+            let mut tm = dir.manager();
+            let mut s = tm.session();
+            run(&mut tm, &mut s, &format!("CREATE TABLE {t} (id INT PRIMARY KEY AUTO_INCREMENT, s VARCHAR(10)) ENGINE={engine};")).unwrap();
+            let insert = |tm: &mut TableManager, s: &mut Session, values: &str| {
+                run(tm, s, &format!("INSERT INTO {t} {values};")).unwrap();
+                select(tm, s, "SELECT LAST_INSERT_ID();")[0][0].parse::<i64>().unwrap()
+            };
+            // the first value a multi-row INSERT generated
+            assert_eq!(insert(&mut tm, &mut s, "(s) VALUES ('a'), ('b'), ('c')"), 1);
+            run(&mut tm, &mut s, &format!("DELETE FROM {t} WHERE id = 3;")).unwrap();
+            assert_eq!(insert(&mut tm, &mut s, "(s) VALUES ('d')"), 4);
+            run(&mut tm, &mut s, &format!("DELETE FROM {t};")).unwrap();
+            assert_eq!(insert(&mut tm, &mut s, "(s) VALUES ('e')"), 5);
+            // a larger value given moves the counter past it, a smaller one leaves it, and neither
+            // is a generated value for LAST_INSERT_ID
+            assert_eq!(insert(&mut tm, &mut s, "VALUES (10, 'x')"), 5);
+            assert_eq!(insert(&mut tm, &mut s, "VALUES (7, 'y')"), 5);
+            assert_eq!(insert(&mut tm, &mut s, "(s) VALUES ('f')"), 11);
+            // NULL and 0 take the next value
+            assert_eq!(insert(&mut tm, &mut s, "VALUES (NULL, 'g'), (0, 'h'), (20, 'i'), (NULL, 'j')"), 12);
+            let ids = select(&mut tm, &mut s, &format!("SELECT id FROM {t};")).concat();
+            assert_eq!(ids, ["10", "11", "12", "13", "20", "21", "5", "7"]);
+            // the value handed out last is gone but not given out again after a restart
+            run(&mut tm, &mut s, &format!("DELETE FROM {t} WHERE id > 10;")).unwrap();
+            drop(tm);
+            let mut tm = dir.manager();
+            let mut s = tm.session();
+            assert_eq!(select(&mut tm, &mut s, "SELECT LAST_INSERT_ID();"), vec![vec!["0"]]);
+            assert_eq!(insert(&mut tm, &mut s, "(s) VALUES ('k')"), 22);
+            // LAST_INSERT_ID belongs to the session that inserted
+            let mut other = tm.session();
+            assert_eq!(select(&mut tm, &mut other, "SELECT LAST_INSERT_ID();"), vec![vec!["0"]]);
+        }
+    }
+
+    #[test]
+    fn sessions_read_side_by_side_while_another_one_writes() {
+        let dir = TestDir::new("threads");
+        let mut tm = dir.manager();
+        let mut s = tm.session();
+        run(&mut tm, &mut s, "CREATE TABLE t (id INT PRIMARY KEY, n INT);").unwrap();
+        run(&mut tm, &mut s, "CREATE TABLE u (id INT);").unwrap();
+        run(&mut tm, &mut s, "INSERT INTO t VALUES (1, 0), (2, 0), (3, 0);").unwrap();
+        tm.end_session(&s).unwrap();
+        drop(tm);
+        // room for one of the two tables, so reads and writes take turns opening them
+        let wal = Wal::open(wal::LOG_PATH, SyncPolicy::Full).unwrap();
+        let shared = Arc::new(Shared::new(TableManager::new(1 << 20, 1, wal).unwrap()));
+        let session = |input: String| {
+            let shared = shared.clone();
+            session_thread()
+                .spawn(move || {
+                    let mut out = Vec::new();
+                    serve(&shared, input.as_bytes(), &mut out).unwrap();
+                    String::from_utf8(out).unwrap()
+                })
+                .unwrap()
+        };
+        let writer = session((1..=50).map(|i| format!("UPDATE t SET n = n + 1;\nINSERT INTO u VALUES ({i});\n")).collect());
+        let readers = (0..4).map(|_| session("SELECT n FROM t;\nSELECT id FROM u;\n".repeat(50))).collect::<Vec<_>>();
+        assert!(writer.join().unwrap().lines().all(|l| l.starts_with("Query OK, 1 row") || l.starts_with("Query OK, 3 rows")));
+        for reader in readers {
+            // every read sees whole statements, never fewer than the one before
+            let (mut n, mut ids, mut reads) = (0, 0, 0);
+            let mut block: Option<(bool, Vec<u32>)> = None;
+            for line in reader.join().unwrap().lines().chain(["+"]) {
+                assert!(!line.starts_with("ERROR"), "{line}");
+                let cell = line.split('|').nth(1).map(str::trim);
+                match cell {
+                    Some("n") | Some("id") => block = Some((cell == Some("n"), Vec::new())),
+                    Some(v) => block.as_mut().unwrap().1.push(v.parse().unwrap()),
+                    None => match block.take() {
+                        Some((true, vals)) if !vals.is_empty() => {
+                            assert_eq!(vals.len(), 3);
+                            assert!(vals.iter().all(|v| *v == vals[0]) && vals[0] >= n, "{vals:?} after {n}");
+                            n = vals[0];
+                            reads += 1;
                         }
-                    }
+                        Some((false, vals)) if !vals.is_empty() => {
+                            assert!(vals.len() >= ids, "{} rows after {ids}", vals.len());
+                            ids = vals.len();
+                        }
+                        // the first border of a table, its rows come after the header
+                        other => block = other,
+                    },
                 }
-                _ => {}
             }
+            assert_eq!(reads, 50);
+        }
+        let mut tm = Arc::into_inner(shared).unwrap().tm.into_inner().unwrap();
+        let mut s = tm.session();
+        assert_eq!(select(&mut tm, &mut s, "SELECT n FROM t;"), vec![vec!["50"]; 3]);
+        assert_eq!(select(&mut tm, &mut s, "SELECT id FROM u;").len(), 50);
+    }
+
+    // the message of a statement that has to fail
+    pub fn error(tm: &mut TableManager, session: &mut Session, sql: &str) -> String {
+        match run(tm, session, sql) {
+            Ok(_) => panic!("{sql} didn't fail"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    // the rows of a SELECT as text, NULL for NULL, sorted since there is no ORDER BY
+    pub fn select(tm: &mut TableManager, session: &mut Session, sql: &str) -> Vec<Vec<String>> {
+        match run(tm, session, sql) {
+            Ok(QueryResult::Table(t)) => {
+                let mut rows = t.get_rows().map(|r| r.unwrap().col_data.iter().map(|c| c.to_string()).collect::<Vec<_>>()).collect::<Vec<_>>();
+                rows.sort();
+                rows
+            }
+            Ok(_) => panic!("{sql} has no rows"),
+            Err(e) => panic!("{sql}: {e}"),
         }
-        let ret2 = tm.select(stmt).expect("ty");
-        println!("{ret2}");
     }
 }
@@ -0,0 +1,436 @@
+/*
+
+Multi-version concurrency control
+
+Every row of a table file is a version of the row, with the id of the transaction
+that created it and of the one that deleted it, 0 for none (see db.rs and paged.rs).
+A transaction gets a new id for every statement, so the statements after a savepoint
+can be told apart, and reads the tables through the snapshot taken when it started:
+it sees the versions created by the transactions that had committed by then and by
+its own statements, unless one of those deleted them.
+
+UPDATE and DELETE mark the versions they change as deleted and UPDATE adds the new
+ones, so a report that started before still finds the rows as they were while the
+writer goes on. Writes work on the versions nobody deleted, the rows they change are
+locked until the transaction ends so two of them don't change the same one (see
+locks.rs). A row another transaction changed after the snapshot was taken is changed
+as it is now, and from then on the snapshot sees what that statement did. ROLLBACK
+removes the versions the transaction created and takes back its deletions. A
+statement under autocommit while no other transaction is open has nobody to hide its
+changes from and makes them in place, without versions.
+
+A version deleted before every open snapshot was taken is garbage. When a
+transaction ends, the tables whose garbage is all that old have it removed, and
+the versions left get 0 as their creator since every snapshot sees them.
+
+The ids handed out, the open transactions that changed tables and the tables with
+garbage are kept in txn.state, written through the log along with the statement
+that changed them
+
+    next <id>                   every id in the tables is below it
+    open <id>,... <table>,...   the ids of a transaction and the tables it changed
+    garbage <table> <id>        the last id that deleted a version of the table
+
+so on startup the transactions that were open are rolled back and the garbage is
+collected.
+
+*/
+
+use std::collections::{HashMap, HashSet};
+
+use crate::TableLikeError;
+
+pub type TxnId = u64;
+// a client, which has one transaction open at most
+pub type SessionId = u64;
+
+pub const STATE_PATH: &str = "txn.state";
+// ids are recorded this many at a time so the state file doesn't change for every one
+const ID_BLOCK: TxnId = 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Version {
+    // 0 for a version every snapshot sees
+    pub created: TxnId,
+    // 0 until it is deleted
+    pub deleted: TxnId,
+}
+
+impl Version {
+    pub fn new(created: TxnId) -> Version {
+        Version { created, deleted: 0 }
+    }
+
+    // the version writes work on, one deleted by a transaction still open stays locked by it
+    pub fn is_live(&self) -> bool {
+        self.deleted == 0
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    // ids of the statements of its own transaction
+    own: HashSet<TxnId>,
+    // no id from here on was handed out when it was taken
+    xmax: TxnId,
+    // ids of the transactions that were open
+    active: HashSet<TxnId>,
+    // ids it sees anyway, of statements that created rows its own statements changed since
+    // writes go by the latest versions, so it doesn't see the row as it was as well
+    seen: HashSet<TxnId>,
+}
+
+impl Snapshot {
+    fn sees_id(&self, id: TxnId) -> bool {
+        id == 0 || self.own.contains(&id) || self.seen.contains(&id) || (id < self.xmax && !self.active.contains(&id))
+    }
+
+    // whether the version was created and not deleted yet as far as the snapshot can tell
+    pub fn sees(&self, v: &Version) -> bool {
+        self.sees_id(v.created) && (v.deleted == 0 || !self.sees_id(v.deleted))
+    }
+
+    // versions deleted before this id are garbage to it
+    fn xmin(&self) -> TxnId {
+        self.active.iter().copied().fold(self.xmax, TxnId::min)
+    }
+}
+
+pub struct Transaction {
+    // an id per statement, in order
+    pub ids: Vec<TxnId>,
+    pub snapshot: Snapshot,
+    // the tables it changed, nobody else changes them until it ends
+    pub tables: Vec<String>,
+    // with the number of statements before them
+    savepoints: Vec<(String, usize)>,
+    // BEGIN or autocommit off, otherwise it ends with its only statement
+    pub explicit: bool,
+}
+
+// ids of statements to roll back and the tables they changed
+pub type Undo = (HashSet<TxnId>, Vec<String>);
+
+pub struct Transactions {
+    next: TxnId,
+    // every id handed out is below it, as the state file says
+    reserved: TxnId,
+    open: HashMap<SessionId, Transaction>,
+    // tables with deleted versions and the last id that deleted one
+    garbage: HashMap<String, TxnId>,
+    // what the state file holds, None when that isn't known
+    written: Option<String>,
+}
+
+impl Transactions {
+    // reads the state file, with the ids and tables of the transactions that were open
+    pub fn load() -> Result<(Transactions, Vec<Undo>), TableLikeError> {
+        let text = match std::fs::read_to_string(STATE_PATH) {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut t = Transactions { next: 1, reserved: 1, open: HashMap::new(), garbage: HashMap::new(), written: None };
+        let mut interrupted = Vec::new();
+        let corrupt = || TableLikeError::new("Corrupt transaction state");
+        let id = |s: &str| s.parse::<TxnId>().map_err(|_| corrupt());
+        for line in text.lines() {
+            match line.split(' ').collect::<Vec<_>>().as_slice() {
+                ["next", n] => {
+                    t.reserved = id(n)?;
+                    t.next = t.reserved;
+                }
+                ["open", ids, tables] => interrupted.push((
+                    ids.split(',').map(id).collect::<Result<_, _>>()?,
+                    tables.split(',').map(str::to_string).collect(),
+                )),
+                ["garbage", table, n] => {
+                    t.garbage.insert(table.to_string(), id(n)?);
+                }
+                _ => return Err(corrupt()),
+            }
+        }
+        t.written = Some(text);
+        Ok((t, interrupted))
+    }
+
+    fn render(&self) -> String {
+        let mut out = format!("next {}\n", self.reserved);
+        let mut open = self.open.values().filter(|t| !t.tables.is_empty()).collect::<Vec<_>>();
+        open.sort_by_key(|t| t.ids.first().copied());
+        for t in open {
+            let ids = t.ids.iter().map(TxnId::to_string).collect::<Vec<_>>();
+            out.push_str(&format!("open {} {}\n", ids.join(","), t.tables.join(",")));
+        }
+        let mut garbage = self.garbage.iter().collect::<Vec<_>>();
+        garbage.sort();
+        for (table, id) in garbage {
+            out.push_str(&format!("garbage {table} {id}\n"));
+        }
+        out
+    }
+
+    // the contents of the state file when they changed, counted as written
+    pub fn take_state(&mut self) -> Option<String> {
+        let state = self.render();
+        if self.written.as_ref() == Some(&state) {
+            return None;
+        }
+        self.written = Some(state.clone());
+        Some(state)
+    }
+
+    // after the operation that wrote the state file was undone
+    pub fn forget_state(&mut self) {
+        self.written = None;
+    }
+
+    fn snapshot_now(&self) -> Snapshot {
+        let active = self.open.values().flat_map(|t| t.ids.iter().copied()).collect();
+        Snapshot { own: HashSet::new(), xmax: self.next, active, seen: HashSet::new() }
+    }
+
+    // the snapshot of the open transaction of a session, or one taken now
+    pub fn snapshot(&self, session: SessionId) -> Snapshot {
+        match self.open.get(&session) {
+            Some(t) => t.snapshot.clone(),
+            None => self.snapshot_now(),
+        }
+    }
+
+    pub fn begin(&mut self, session: SessionId, explicit: bool) {
+        let snapshot = self.snapshot_now();
+        self.open.insert(session, Transaction { ids: Vec::new(), snapshot, tables: Vec::new(), savepoints: Vec::new(), explicit });
+    }
+
+    pub fn is_open(&self, session: SessionId) -> bool {
+        self.open.contains_key(&session)
+    }
+
+    pub fn is_explicit(&self, session: SessionId) -> bool {
+        self.open.get(&session).is_some_and(|t| t.explicit)
+    }
+
+    // a new id for the next statement of the open transaction of a session, returns the
+    // id its changes are stamped with, 0 when it can make them in place
+    pub fn start_statement(&mut self, session: SessionId) -> TxnId {
+        let id = self.next;
+        self.next += 1;
+        if self.next > self.reserved {
+            self.reserved = id + ID_BLOCK;
+        }
+        let alone = self.open.len() == 1;
+        let Some(t) = self.open.get_mut(&session) else {
+            return id;
+        };
+        t.ids.push(id);
+        t.snapshot.own.insert(id);
+        match t.explicit || !alone {
+            true => id,
+            false => 0,
+        }
+    }
+
+    // the transaction of a session is about to change a table
+    pub fn add_table(&mut self, session: SessionId, table: &str) {
+        if let Some(t) = self.open.get_mut(&session) {
+            if !t.tables.iter().any(|n| n == table) {
+                t.tables.push(table.to_string());
+            }
+        }
+    }
+
+    // the transaction of a session changed rows these ids created
+    pub fn see(&mut self, session: SessionId, ids: impl IntoIterator<Item = TxnId>) {
+        if let Some(t) = self.open.get_mut(&session) {
+            for id in ids {
+                if !t.snapshot.sees_id(id) {
+                    t.snapshot.seen.insert(id);
+                }
+            }
+        }
+    }
+
+    // whether the id is of a statement of a transaction still open other than the one of session
+    pub fn is_open_elsewhere(&self, id: TxnId, session: SessionId) -> bool {
+        self.open.iter().any(|(s, t)| *s != session && t.ids.contains(&id))
+    }
+
+    pub fn end(&mut self, session: SessionId) -> Option<Transaction> {
+        self.open.remove(&session)
+    }
+
+    // a savepoint of the same name is moved, false without a transaction
+    pub fn savepoint(&mut self, session: SessionId, name: &str) -> bool {
+        self.release_savepoint(session, name);
+        let Some(t) = self.open.get_mut(&session) else {
+            return false;
+        };
+        t.savepoints.push((name.to_string(), t.ids.len()));
+        true
+    }
+
+    // whether there was a savepoint of that name
+    pub fn release_savepoint(&mut self, session: SessionId, name: &str) -> bool {
+        let Some(t) = self.open.get_mut(&session) else {
+            return false;
+        };
+        let before = t.savepoints.len();
+        t.savepoints.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        t.savepoints.len() != before
+    }
+
+    // the ids of the statements after a savepoint, which stays, and the tables to take
+    // their changes back from, the savepoints after it go
+    pub fn rollback_to(&mut self, session: SessionId, name: &str) -> Option<Undo> {
+        let t = self.open.get_mut(&session)?;
+        let at = t.savepoints.iter().position(|(n, _)| n.eq_ignore_ascii_case(name))?;
+        t.savepoints.truncate(at + 1);
+        let undone = t.ids.split_off(t.savepoints[at].1).into_iter().collect::<HashSet<_>>();
+        t.snapshot.own.retain(|id| !undone.contains(id));
+        Some((undone, t.tables.clone()))
+    }
+
+    // versions deleted before this id are garbage to every open snapshot
+    pub fn horizon(&self) -> TxnId {
+        self.open.values().map(|t| t.snapshot.xmin()).fold(self.next, TxnId::min)
+    }
+
+    pub fn add_garbage(&mut self, table: &str, deleted: TxnId) {
+        let last = self.garbage.entry(table.to_string()).or_default();
+        *last = deleted.max(*last);
+    }
+
+    // the garbage of a table goes with it to its new name
+    pub fn rename_table(&mut self, from: &str, to: &str) {
+        if let Some(id) = self.garbage.remove(from) {
+            self.garbage.insert(to.to_string(), id);
+        }
+    }
+
+    // the tables whose garbage can all be removed now, which stop counting as having any
+    pub fn take_garbage(&mut self) -> Vec<String> {
+        let horizon = self.horizon();
+        let tables = self.garbage.iter().filter(|(_, id)| **id < horizon).map(|(t, _)| t.clone()).collect::<Vec<_>>();
+        for t in &tables {
+            self.garbage.remove(t);
+        }
+        tables
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{run, select, TestDir};
+    use crate::{Session, TableManager};
+
+    #[test]
+    fn snapshots_see_what_had_committed_and_their_own_statements() {
+        let s = Snapshot { own: HashSet::from([7]), xmax: 6, active: HashSet::from([4]), seen: HashSet::from([5]) };
+        for (created, deleted, sees) in [
+            (0, 0, true),
+            (3, 0, true),
+            // open when it was taken, or started after
+            (4, 0, false),
+            (6, 0, false),
+            (7, 0, true),
+            (5, 0, true),
+            (3, 2, false),
+            (3, 4, true),
+            (3, 8, true),
+            (3, 7, false),
+            (7, 7, false),
+        ] {
+            assert_eq!(s.sees(&Version { created, deleted }), sees, "{created} {deleted}");
+        }
+        assert_eq!(s.xmin(), 4);
+    }
+
+    #[test]
+    fn savepoints_take_back_the_statements_after_them() {
+        let mut t = Transactions { next: 1, reserved: 1, open: HashMap::new(), garbage: HashMap::new(), written: None };
+        t.begin(1, true);
+        let first = t.start_statement(1);
+        assert!(t.savepoint(1, "a"));
+        let second = t.start_statement(1);
+        t.add_table(1, "t");
+        assert!(t.snapshot(1).sees(&Version::new(second)));
+        let (undone, tables) = t.rollback_to(1, "A").unwrap();
+        assert_eq!((undone, tables), (HashSet::from([second]), vec!["t".to_string()]));
+        assert!(t.snapshot(1).sees(&Version::new(first)));
+        assert!(!t.snapshot(1).sees(&Version::new(second)));
+        // the savepoint stays, one that isn't there is None
+        assert!(t.rollback_to(1, "a").is_some());
+        assert!(t.rollback_to(1, "b").is_none());
+        assert!(t.end(1).is_some());
+        assert!(!t.savepoint(1, "a"));
+    }
+
+    #[test]
+    fn garbage_waits_for_the_snapshots_that_can_see_it() {
+        let mut t = Transactions { next: 1, reserved: 1, open: HashMap::new(), garbage: HashMap::new(), written: None };
+        t.begin(1, true);
+        t.start_statement(1);
+        t.begin(2, true);
+        let deleted = t.start_statement(2);
+        t.add_garbage("t", deleted);
+        t.end(2);
+        assert!(t.take_garbage().is_empty());
+        t.rename_table("t", "u");
+        t.end(1);
+        assert_eq!(t.take_garbage(), vec!["u".to_string()]);
+        assert!(t.take_garbage().is_empty());
+    }
+
+    #[test]
+    fn the_state_file_is_read_back() {
+        let _dir = TestDir::new("txn_state");
+        let mut t = Transactions::load().unwrap().0;
+        t.begin(1, true);
+        let id = t.start_statement(1);
+        t.add_table(1, "t");
+        t.add_garbage("u", id);
+        let state = t.take_state().unwrap();
+        assert_eq!(state, format!("next {}\nopen {id} t\ngarbage u {id}\n", id + ID_BLOCK));
+        assert!(t.take_state().is_none());
+        std::fs::write(STATE_PATH, &state).unwrap();
+        let (t, interrupted) = Transactions::load().unwrap();
+        assert_eq!(interrupted, vec![(HashSet::from([id]), vec!["t".to_string()])]);
+        assert_eq!(t.next, id + ID_BLOCK);
+        std::fs::write(STATE_PATH, "next x\n").unwrap();
+        assert!(Transactions::load().is_err());
+    }
+
+    #[test]
+    fn transactions_read_the_rows_as_they_were_when_they_started() {
+        let dir = TestDir::new("snapshots");
+        let mut tm = dir.manager();
+        let mut a = tm.session();
+        let mut b = tm.session();
+        for engine in ["TEXT", "PAGED"] {
+            let t = format!("t{engine}");
+            let q = |tm: &mut TableManager, s: &mut Session, sql: &str| {
+                run(tm, s, &sql.replace("$t", &t)).unwrap();
+            };
+            let rows = |tm: &mut TableManager, s: &mut Session| select(tm, s, &format!("SELECT id, n FROM {t};"));
+            q(&mut tm, &mut a, &format!("CREATE TABLE $t (id INT PRIMARY KEY, n INT) ENGINE={engine};"));
+            q(&mut tm, &mut a, "INSERT INTO $t VALUES (1, 1), (2, 2);");
+            q(&mut tm, &mut a, "BEGIN;");
+            let before = rows(&mut tm, &mut a);
+            q(&mut tm, &mut b, "BEGIN;");
+            q(&mut tm, &mut b, "UPDATE $t SET n = 10 WHERE id = 1;");
+            q(&mut tm, &mut b, "INSERT INTO $t VALUES (3, 3);");
+            // neither sees the other's changes until they commit, and a only then
+            assert_eq!(rows(&mut tm, &mut a), before);
+            q(&mut tm, &mut b, "COMMIT;");
+            assert_eq!(rows(&mut tm, &mut a), before);
+            assert_eq!(rows(&mut tm, &mut b), vec![vec!["1", "10"], vec!["2", "2"], vec!["3", "3"]]);
+            q(&mut tm, &mut a, "COMMIT;");
+            assert_eq!(rows(&mut tm, &mut a), rows(&mut tm, &mut b));
+            // with every transaction over only the live versions are left, seen by everyone
+            let versions = tm.open(&t).unwrap().get_rows().map(|r| r.unwrap().version).collect::<Vec<_>>();
+            assert_eq!(versions, vec![Version::default(); 3]);
+        }
+    }
+}
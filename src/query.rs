@@ -1,42 +1,997 @@
-use crate::TableCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use pest::iterators::{Pair, Pairs};
+use pest::pratt_parser::{Assoc, Op, PrattParser};
+use pest::Parser;
+
+use crate::alter::Algorithm;
+use crate::constraints::{ForeignRef, IndexAlgorithm, KeyDef, KeyKind, RefAction};
+use crate::datetime::{Date, DateTime, Time};
+use crate::decimal::{self, DecimalSpec};
+use crate::locks::LockMode;
+use crate::{db, functions};
+use crate::{CheckConstraint, ColumnEntry, Engine, NumType, Rule, SQLParser, TableCell, TableLikeError, NUM_BASE};
 
 pub struct Criteria {
-    pub re: Vec<String>,
-    // closues that take col values and eval against given col
-    pub cls: Closure,
+    pub re: Vec<SelectItem>,
+    // predicate that rows have to satisfy, compiled against the table columns on select
+    pub filter: Option<Expr>,
+}
+
+pub enum SelectItem {
+    Wildcard,
+    Expr { expr: Expr, alias: String },
+}
+
+// one change of an ALTER TABLE, they are applied in order
+pub enum AlterAction {
+    // with the keys its column definition declares, like in CREATE TABLE
+    AddColumn { col: ColumnEntry, keys: Vec<KeyDef> },
+    DropColumn { name: String },
+    RenameColumn { from: String, to: String },
+    // a new definition for the column of that name, its values are converted to it
+    ModifyColumn { col: ColumnEntry, keys: Vec<KeyDef> },
+    RenameTo { name: String },
+}
+
+pub enum Statement {
+    Select {
+        table: String,
+        criteria: Criteria,
+        // FOR UPDATE or FOR SHARE, the rows it reads are locked in that mode
+        lock: Option<LockMode>,
+    },
+    CreateTable {
+        table: String,
+        cols: Vec<ColumnEntry>,
+        keys: Vec<KeyDef>,
+        engine: Engine,
+    },
+    CreateIndex {
+        table: String,
+        key: KeyDef,
+    },
+    DropIndex {
+        table: String,
+        name: String,
+    },
+    AlterTable {
+        table: String,
+        actions: Vec<AlterAction>,
+        algorithm: Algorithm,
+    },
+    Optimize {
+        table: String,
+    },
+    Insert {
+        table: String,
+        cols: Option<Vec<String>>,
+        rows: Vec<Vec<Expr>>,
+    },
+    Update {
+        table: String,
+        sets: Vec<(String, Expr)>,
+        filter: Option<Expr>,
+    },
+    Delete {
+        table: String,
+        filter: Option<Expr>,
+    },
+    Describe {
+        table: String,
+    },
+    Set {
+        var: String,
+        value: Expr,
+    },
+    Begin,
+    Commit,
+    // back to a savepoint, or the whole transaction for None
+    Rollback {
+        savepoint: Option<String>,
+    },
+    Savepoint {
+        name: String,
+    },
+    ReleaseSavepoint {
+        name: String,
+    },
+    LockTables {
+        tables: Vec<(String, LockMode)>,
+    },
+    UnlockTables,
+}
+
+impl Statement {
+    // every expression of the statement, for binding session state before it runs
+    pub fn exprs_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Self::Select { criteria, .. } => criteria
+                .re
+                .iter_mut()
+                .filter_map(|s| match s {
+                    SelectItem::Expr { expr, .. } => Some(expr),
+                    SelectItem::Wildcard => None,
+                })
+                .chain(criteria.filter.iter_mut())
+                .collect(),
+            Self::CreateTable { .. }
+            | Self::CreateIndex { .. }
+            | Self::DropIndex { .. }
+            | Self::AlterTable { .. }
+            | Self::Optimize { .. }
+            | Self::Describe { .. }
+            | Self::Begin
+            | Self::Commit
+            | Self::Rollback { .. }
+            | Self::Savepoint { .. }
+            | Self::ReleaseSavepoint { .. }
+            | Self::LockTables { .. }
+            | Self::UnlockTables => Vec::new(),
+            Self::Insert { rows, .. } => rows.iter_mut().flatten().collect(),
+            Self::Update { sets, filter, .. } => sets.iter_mut().map(|(_, e)| e).chain(filter.iter_mut()).collect(),
+            Self::Delete { filter, .. } => filter.iter_mut().collect(),
+            Self::Set { value, .. } => vec![value],
+        }
+    }
+
+    // the table the statement is on, if any
+    pub fn table(&self) -> Option<&str> {
+        match self {
+            Self::Select { table, .. } if !table.is_empty() => Some(table),
+            Self::CreateTable { table, .. }
+            | Self::CreateIndex { table, .. }
+            | Self::DropIndex { table, .. }
+            | Self::AlterTable { table, .. }
+            | Self::Optimize { table }
+            | Self::Insert { table, .. }
+            | Self::Update { table, .. }
+            | Self::Delete { table, .. }
+            | Self::Describe { table } => Some(table),
+            _ => None,
+        }
+    }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinOp {
+    And,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Column(String),
+    Literal(TableCell),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    IsNull(Box<Expr>),
+    IsNotNull(Box<Expr>),
+    Binary(Box<Expr>, BinOp, Box<Expr>),
+    Func(String, Vec<Expr>),
+}
+
+pub type Predicate = Box<dyn Fn(&[&TableCell]) -> bool>;
+pub type Eval = Box<dyn Fn(&[&TableCell]) -> TableCell>;
+
 pub struct Closure {
     pub col_name: Vec<String>,
-    pub act_clo: Box<dyn Fn(&[&TableCell]) -> bool>,
+    pub act_clo: Predicate,
+}
+
+// like Closure but yields a value instead of a verdict
+pub struct Projection {
+    pub col_name: Vec<String>,
+    pub col_type: TableCell,
+    pub act_clo: Eval,
 }
 
 impl Closure {
-    fn and(mut a: Closure, mut b: Closure) -> Closure {
-        let frs = a.col_name.len();
-        let sec = b.col_name.len();
-        let mut r = Vec::with_capacity(frs + sec);
-        r.append(&mut a.col_name);
-        r.append(&mut b.col_name);
-        Closure {
-            col_name: r,
-            act_clo: Box::new(move |v| {
-                (a.act_clo)(&v[0..frs]) && (b.act_clo)(&v[frs + 1..(frs + sec)])
-            }),
+    pub fn from_expr(expr: &Expr, cols: &[ColumnEntry]) -> Result<Closure, TableLikeError> {
+        let p = Projection::from_expr(expr, cols)?;
+        let f = p.act_clo;
+        Ok(Closure {
+            col_name: p.col_name,
+            act_clo: Box::new(move |v| f(v).truth() == Some(true)),
+        })
+    }
+}
+
+impl Projection {
+    pub fn from_expr(expr: &Expr, cols: &[ColumnEntry]) -> Result<Projection, TableLikeError> {
+        let mut col_name = Vec::new();
+        expr.collect_columns(&mut col_name);
+        let slots = col_name
+            .iter()
+            .enumerate()
+            .map(|(ind, s)| (s.as_str(), ind))
+            .collect::<HashMap<_, _>>();
+        Ok(Projection {
+            col_type: expr.col_type(cols)?,
+            act_clo: expr.compile(cols, &slots)?,
+            col_name,
+        })
+    }
+}
+
+impl Expr {
+    // replaces calls to a function without arguments by a value, used for functions
+    // that depend on the session like LAST_INSERT_ID()
+    pub fn bind_func(&mut self, name: &str, value: &TableCell) {
+        match self {
+            Self::Func(f, args) if args.is_empty() && f.eq_ignore_ascii_case(name) => {
+                *self = Self::Literal(value.clone())
+            }
+            Self::Column(_) | Self::Literal(_) => {}
+            Self::Not(e) | Self::Neg(e) | Self::IsNull(e) | Self::IsNotNull(e) => e.bind_func(name, value),
+            Self::Binary(l, _, r) => {
+                l.bind_func(name, value);
+                r.bind_func(name, value);
+            }
+            Self::Func(_, args) => args.iter_mut().for_each(|a| a.bind_func(name, value)),
         }
     }
 
-    fn or(mut a: Closure, mut b: Closure) -> Closure {
-        let frs = a.col_name.len();
-        let sec = b.col_name.len();
-        let mut r = Vec::with_capacity(frs + sec);
-        r.append(&mut a.col_name);
-        r.append(&mut b.col_name);
-        Closure {
-            col_name: r,
-            act_clo: Box::new(move |v| {
-                (a.act_clo)(&v[0..frs]) || (b.act_clo)(&v[frs + 1..(frs + sec)])
+    fn collect_columns(&self, out: &mut Vec<String>) {
+        match self {
+            Self::Column(c) => {
+                if !out.contains(c) {
+                    out.push(c.clone());
+                }
+            }
+            Self::Literal(_) => {}
+            Self::Not(e) | Self::Neg(e) | Self::IsNull(e) | Self::IsNotNull(e) => {
+                e.collect_columns(out)
+            }
+            Self::Binary(l, _, r) => {
+                l.collect_columns(out);
+                r.collect_columns(out);
+            }
+            Self::Func(_, args) => args.iter().for_each(|a| a.collect_columns(out)),
+        }
+    }
+
+    // type of the value produced, also rejects references to unknown columns
+    pub fn col_type(&self, cols: &[ColumnEntry]) -> Result<TableCell, TableLikeError> {
+        Ok(match self {
+            Self::Column(c) => cols
+                .iter()
+                .find(|f| &f.col_name == c)
+                .map(|f| f.col_type.clone())
+                .ok_or_else(|| TableLikeError::new(&format!("Unknown column '{c}'")))?,
+            Self::Literal(l) => l.clone(),
+            Self::Neg(e) => match e.col_type(cols)? {
+                TableCell::Decimal(_, spec) => TableCell::Decimal(None, spec),
+                _ => TableCell::Num(None),
+            },
+            Self::Not(e) | Self::IsNull(e) | Self::IsNotNull(e) => {
+                e.col_type(cols)?;
+                TableCell::Bool(None)
+            }
+            Self::Binary(l, op, r) => {
+                let (l, r) = (l.col_type(cols)?, r.col_type(cols)?);
+                match op {
+                    BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Mod => {
+                        arith_type(*op, &l, &r)
+                    }
+                    _ => TableCell::Bool(None),
+                }
+            }
+            Self::Func(name, args) => {
+                let types = args.iter().map(|a| a.col_type(cols)).collect::<Result<Vec<_>, _>>()?;
+                functions::resolve(name, &types)?.0
+            }
+        })
+    }
+
+    fn compile(&self, cols: &[ColumnEntry], slots: &HashMap<&str, usize>) -> Result<Eval, TableLikeError> {
+        Ok(match self {
+            Self::Column(c) => {
+                let ind = slots[c.as_str()];
+                Box::new(move |v| v[ind].clone())
+            }
+            Self::Literal(l) => {
+                let l = l.clone();
+                Box::new(move |_| l.clone())
+            }
+            Self::Not(e) => {
+                let e = e.compile(cols, slots)?;
+                Box::new(move |v| TableCell::Bool(e(v).truth().map(|b| !b)))
+            }
+            Self::Neg(e) => {
+                let ty = self.col_type(cols)?;
+                let e = e.compile(cols, slots)?;
+                match ty {
+                    TableCell::Decimal(_, spec) => Box::new(move |v| {
+                        TableCell::Decimal(e(v).as_decimal().and_then(|(d, s)| decimal::rescale(d, s, spec.scale)).map(|d| -d), spec)
+                    }),
+                    _ => Box::new(move |v| TableCell::Num(e(v).as_num().and_then(|n| n.checked_neg()))),
+                }
+            }
+            Self::IsNull(e) => {
+                let e = e.compile(cols, slots)?;
+                Box::new(move |v| TableCell::Bool(Some(e(v).is_null())))
+            }
+            Self::IsNotNull(e) => {
+                let e = e.compile(cols, slots)?;
+                Box::new(move |v| TableCell::Bool(Some(!e(v).is_null())))
+            }
+            Self::Binary(l, op, r) => {
+                let ty = self.col_type(cols)?;
+                let (l, r, op) = (l.compile(cols, slots)?, r.compile(cols, slots)?, *op);
+                Box::new(move |v| eval_binary(op, &ty, l(v), r(v)))
+            }
+            Self::Func(name, args) => {
+                let types = args.iter().map(|a| a.col_type(cols)).collect::<Result<Vec<_>, _>>()?;
+                let f = functions::resolve(name, &types)?.1;
+                let args = args.iter().map(|a| a.compile(cols, slots)).collect::<Result<Vec<_>, _>>()?;
+                Box::new(move |v| f(&args.iter().map(|a| a(v)).collect::<Vec<_>>()))
+            }
+        })
+    }
+
+    // evaluates an expression that doesn't reference any column, like the ones in VALUES
+    pub fn eval_const(&self) -> Result<TableCell, TableLikeError> {
+        let p = Projection::from_expr(self, &[])?;
+        Ok((p.act_clo)(&[]))
+    }
+}
+
+// integer arithmetic stays integer, anything involving a DECIMAL or a division is exact decimal
+fn arith_type(op: BinOp, l: &TableCell, r: &TableCell) -> TableCell {
+    let (ls, rs) = (l.decimal_spec(), r.decimal_spec());
+    match op {
+        BinOp::Div => TableCell::Decimal(None, DecimalSpec::for_div(ls, rs)),
+        _ if !matches!(l, TableCell::Decimal(..)) && !matches!(r, TableCell::Decimal(..)) => {
+            TableCell::Num(None)
+        }
+        BinOp::Add | BinOp::Sub => TableCell::Decimal(None, DecimalSpec::for_add(ls, rs)),
+        BinOp::Mul => TableCell::Decimal(None, DecimalSpec::for_mul(ls, rs)),
+        _ => TableCell::Decimal(None, DecimalSpec::for_rem(ls, rs)),
+    }
+}
+
+// ty is the type the expression was checked to produce
+fn eval_binary(op: BinOp, ty: &TableCell, l: TableCell, r: TableCell) -> TableCell {
+    match op {
+        // three valued logic, FALSE AND NULL is FALSE and TRUE OR NULL is TRUE
+        BinOp::And => TableCell::Bool(match (l.truth(), r.truth()) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        }),
+        BinOp::Or => TableCell::Bool(match (l.truth(), r.truth()) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
+        }),
+        BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+            TableCell::Bool(l.sql_cmp(&r).map(|o| match op {
+                BinOp::Eq => o == Ordering::Equal,
+                BinOp::Ne => o != Ordering::Equal,
+                BinOp::Lt => o == Ordering::Less,
+                BinOp::Le => o != Ordering::Greater,
+                BinOp::Gt => o == Ordering::Greater,
+                _ => o != Ordering::Less,
+            }))
+        }
+        BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Mod => {
+            if let TableCell::Decimal(_, spec) = ty {
+                return TableCell::Decimal(
+                    match (l.as_decimal(), r.as_decimal()) {
+                        (Some(a), Some(b)) => match op {
+                            BinOp::Add => decimal::add(a, b, *spec),
+                            BinOp::Sub => decimal::sub(a, b, *spec),
+                            BinOp::Mul => decimal::mul(a, b, *spec),
+                            BinOp::Div => decimal::div(a, b, *spec),
+                            _ => decimal::rem(a, b, *spec),
+                        },
+                        _ => None,
+                    },
+                    *spec,
+                );
+            }
+            TableCell::Num(match (l.as_num(), r.as_num()) {
+                (Some(a), Some(b)) => match op {
+                    BinOp::Add => a.checked_add(b),
+                    BinOp::Sub => a.checked_sub(b),
+                    BinOp::Mul => a.checked_mul(b),
+                    _ => a.checked_rem(b),
+                },
+                _ => None,
+            })
+        }
+    }
+}
+
+// deepest expression accepted, compiling and evaluating one recurses once per level
+pub const MAX_EXPR_DEPTH: usize = 1000;
+
+fn pratt() -> PrattParser<Rule> {
+    PrattParser::new()
+        .op(Op::infix(Rule::or_op, Assoc::Left))
+        .op(Op::infix(Rule::and_op, Assoc::Left))
+        .op(Op::prefix(Rule::not_op))
+        .op(Op::infix(Rule::eq_op, Assoc::Left)
+            | Op::infix(Rule::ne_op, Assoc::Left)
+            | Op::infix(Rule::lt_op, Assoc::Left)
+            | Op::infix(Rule::le_op, Assoc::Left)
+            | Op::infix(Rule::gt_op, Assoc::Left)
+            | Op::infix(Rule::ge_op, Assoc::Left)
+            | Op::postfix(Rule::is_null_op)
+            | Op::postfix(Rule::is_not_null_op))
+        .op(Op::infix(Rule::add_op, Assoc::Left) | Op::infix(Rule::sub_op, Assoc::Left))
+        .op(Op::infix(Rule::mul_op, Assoc::Left)
+            | Op::infix(Rule::div_op, Assoc::Left)
+            | Op::infix(Rule::mod_op, Assoc::Left))
+        .op(Op::prefix(Rule::neg_op))
+}
+
+pub fn parse_expr(pair: Pair<Rule>) -> Result<Expr, TableLikeError> {
+    parse_nested(pair).map(|(e, _)| e)
+}
+
+// an expression with its depth, which is checked as the expression is built so that a long
+// chain of operators is turned down before it can overflow the stack of whatever walks it
+fn parse_nested(pair: Pair<Rule>) -> Result<(Expr, usize), TableLikeError> {
+    pratt()
+        .map_primary(parse_primary)
+        .map_prefix(|op, rhs| {
+            let (rhs, depth) = rhs?;
+            nest(depth, match (op.as_rule(), rhs) {
+                (Rule::not_op, rhs) => Expr::Not(Box::new(rhs)),
+                // fold negative literals so that they stay constants
+                (_, Expr::Literal(TableCell::Num(Some(n)))) => Expr::Literal(TableCell::Num(Some(-n))),
+                (_, Expr::Literal(TableCell::Decimal(Some(n), spec))) => Expr::Literal(TableCell::Decimal(Some(-n), spec)),
+                (_, rhs) => Expr::Neg(Box::new(rhs)),
+            })
+        })
+        .map_postfix(|lhs, op| {
+            let (lhs, depth) = lhs?;
+            let lhs = Box::new(lhs);
+            nest(depth, match op.as_rule() {
+                Rule::is_null_op => Expr::IsNull(lhs),
+                _ => Expr::IsNotNull(lhs),
+            })
+        })
+        .map_infix(|lhs, op, rhs| {
+            let op = match op.as_rule() {
+                Rule::or_op => BinOp::Or,
+                Rule::and_op => BinOp::And,
+                Rule::eq_op => BinOp::Eq,
+                Rule::ne_op => BinOp::Ne,
+                Rule::lt_op => BinOp::Lt,
+                Rule::le_op => BinOp::Le,
+                Rule::gt_op => BinOp::Gt,
+                Rule::ge_op => BinOp::Ge,
+                Rule::add_op => BinOp::Add,
+                Rule::sub_op => BinOp::Sub,
+                Rule::mul_op => BinOp::Mul,
+                Rule::div_op => BinOp::Div,
+                _ => BinOp::Mod,
+            };
+            let ((lhs, l), (rhs, r)) = (lhs?, rhs?);
+            nest(l.max(r), Expr::Binary(Box::new(lhs), op, Box::new(rhs)))
+        })
+        .parse(pair.into_inner())
+}
+
+// e one level above a tree of depth deep
+fn nest(depth: usize, e: Expr) -> Result<(Expr, usize), TableLikeError> {
+    match depth < MAX_EXPR_DEPTH {
+        true => Ok((e, depth + 1)),
+        false => Err(TableLikeError::new(&format!("Expression nested more than {MAX_EXPR_DEPTH} levels deep"))),
+    }
+}
+
+fn parse_primary(pair: Pair<Rule>) -> Result<(Expr, usize), TableLikeError> {
+    let e = match pair.as_rule() {
+        Rule::expr => return parse_nested(pair),
+        Rule::column_ref => Expr::Column(pair.as_str().to_string()),
+        Rule::null_lit => Expr::Literal(TableCell::Str(None)),
+        Rule::true_lit => Expr::Literal(TableCell::Bool(Some(true))),
+        Rule::false_lit => Expr::Literal(TableCell::Bool(Some(false))),
+        Rule::number => Expr::Literal(TableCell::Num(Some(
+            NumType::from_str_radix(pair.as_str(), NUM_BASE)
+                .map_err(|_| TableLikeError::new("Number out of range"))?,
+        ))),
+        Rule::hex_lit => {
+            let digits = pair.into_inner().as_str();
+            Expr::Literal(TableCell::Blob(Some(db::decode_hex(digits).ok_or_else(|| {
+                TableLikeError::new(&format!("Invalid hexadecimal literal '{digits}'"))
+            })?)))
+        }
+        Rule::decimal => {
+            let (v, s) = decimal::parse(pair.as_str()).ok_or_else(|| TableLikeError::new("Number out of range"))?;
+            Expr::Literal(TableCell::Decimal(Some(v), decimal::spec_of(v, s)))
+        }
+        Rule::string => Expr::Literal(TableCell::Str(Some(parse_string(pair)))),
+        Rule::date_lit | Rule::time_lit | Rule::datetime_lit => {
+            let rule = pair.as_rule();
+            let s = parse_string(pair.into_inner().next().unwrap());
+            let cell = match rule {
+                Rule::date_lit => Date::parse(&s).map(|d| TableCell::Date(Some(d))),
+                Rule::time_lit => Time::parse(&s).map(|t| TableCell::Time(Some(t))),
+                _ => DateTime::parse(&s).map(|t| TableCell::DateTime(Some(t))),
+            };
+            Expr::Literal(cell.ok_or_else(|| TableLikeError::new(&format!("Incorrect datetime value: '{s}'")))?)
+        }
+        Rule::func_call => {
+            let mut it = pair.into_inner();
+            let name = it.next().unwrap().as_str().to_string();
+            let args = it.map(parse_nested).collect::<Result<Vec<_>, _>>()?;
+            let depth = args.iter().map(|(_, d)| *d).max().unwrap_or(0);
+            return nest(depth, Expr::Func(name, args.into_iter().map(|(a, _)| a).collect()));
+        }
+        // DATE_ADD(d, INTERVAL n unit) becomes DATE_ADD(d, n, 'unit')
+        Rule::date_add_call => {
+            let mut it = pair.into_inner();
+            let name = it.next().unwrap().as_str().to_ascii_uppercase();
+            let (d, dd) = parse_nested(it.next().unwrap())?;
+            let (n, nd) = parse_nested(it.next().unwrap())?;
+            let unit = Expr::Literal(TableCell::Str(Some(it.next().unwrap().as_str().to_string())));
+            return nest(dd.max(nd), Expr::Func(name, vec![d, n, unit]));
+        }
+        // EXTRACT(unit FROM d) becomes EXTRACT('unit', d)
+        Rule::extract_call => {
+            let mut it = pair.into_inner();
+            let unit = Expr::Literal(TableCell::Str(Some(it.next().unwrap().as_str().to_string())));
+            let (d, depth) = parse_nested(it.next().unwrap())?;
+            return nest(depth, Expr::Func("EXTRACT".to_string(), vec![unit, d]));
+        }
+        r => return Err(TableLikeError::new(&format!("Unexpected {r:?}"))),
+    };
+    Ok((e, 1))
+}
+
+fn parse_string(pair: Pair<Rule>) -> String {
+    pair.into_inner().as_str().replace("''", "'")
+}
+
+// column_type followed by its column options, PRIMARY KEY and UNIQUE on a column become keys
+fn parse_column(col_name: String, mut pairs: Pairs<Rule>, keys: &mut Vec<KeyDef>) -> Result<ColumnEntry, TableLikeError> {
+    let mut it = pairs.next().unwrap().into_inner();
+    let name = it.next().unwrap().as_str();
+    let mut args = Vec::new();
+    let mut unsigned = false;
+    for p in it {
+        match p.as_rule() {
+            Rule::number => args.push(
+                p.as_str()
+                    .parse::<u32>()
+                    .map_err(|_| TableLikeError::new("Type argument out of range"))?,
+            ),
+            _ => unsigned = true,
+        }
+    }
+    let mut col = ColumnEntry::from_sql(col_name, name, &args, unsigned)?;
+    for opt in pairs {
+        match opt.as_rule() {
+            Rule::not_null_opt => col.constraints.not_null = true,
+            Rule::null_opt => col.constraints.not_null = false,
+            Rule::default_opt => col.constraints.default = Some(expr_text(opt.into_inner().next().unwrap())),
+            Rule::check_opt => {
+                let mut it = opt.into_inner();
+                let (name, e) = match (it.next().unwrap(), it.next()) {
+                    (n, Some(e)) => (Some(n.as_str().to_string()), e),
+                    (e, None) => (None, e),
+                };
+                col.constraints.checks.push(CheckConstraint { name, expr: expr_text(e) });
+            }
+            Rule::primary_key_opt => keys.push(KeyDef {
+                kind: KeyKind::Primary,
+                name: "PRIMARY".to_string(),
+                cols: vec![col.col_name.clone()],
+                algorithm: IndexAlgorithm::BTree,
             }),
+            Rule::auto_increment_opt => col.constraints.auto_increment = true,
+            Rule::unique_opt => keys.push(KeyDef {
+                kind: KeyKind::Unique,
+                name: String::new(),
+                cols: vec![col.col_name.clone()],
+                algorithm: IndexAlgorithm::BTree,
+            }),
+            _ => unreachable!(),
+        }
+    }
+    Ok(col)
+}
+
+// source text of an expression kept in the table header, where its line breaks are escaped
+fn expr_text(pair: Pair<Rule>) -> String {
+    pair.as_str().to_string()
+}
+
+// PRIMARY KEY (..), UNIQUE [KEY] [name] (..), INDEX [name] (..), FOREIGN KEY (..) REFERENCES t (..)
+// or a table CHECK (..) on the columns it reads, unnamed keys get their name on CREATE TABLE
+fn parse_key(pair: Pair<Rule>) -> KeyDef {
+    let rule = pair.as_rule();
+    let mut name = String::new();
+    let mut cols = Vec::new();
+    let mut algorithm = IndexAlgorithm::BTree;
+    let mut fk = ForeignRef { table: String::new(), cols: Vec::new(), on_delete: RefAction::Restrict, on_update: RefAction::Restrict };
+    let mut check = String::new();
+    for p in pair.into_inner() {
+        match p.as_rule() {
+            Rule::expr => {
+                for c in p.clone().into_inner().flatten().filter(|c| c.as_rule() == Rule::column_ref) {
+                    if !cols.iter().any(|n| n == c.as_str()) {
+                        cols.push(c.as_str().to_string());
+                    }
+                }
+                check = expr_text(p);
+            }
+            Rule::key_cols if cols.is_empty() => cols = p.into_inner().map(|c| c.as_str().to_string()).collect(),
+            Rule::key_cols => fk.cols = p.into_inner().map(|c| c.as_str().to_string()).collect(),
+            // after the columns comes the referenced table
+            Rule::ident if !cols.is_empty() => fk.table = p.as_str().to_string(),
+            // the index name wins over the constraint name when both are given
+            Rule::ident => name = p.as_str().to_string(),
+            Rule::index_using => algorithm = parse_index_using(p),
+            Rule::fk_action => {
+                let mut it = p.into_inner();
+                let on = it.next().unwrap().as_rule();
+                let action = match it.next().unwrap().into_inner().next().unwrap().as_rule() {
+                    Rule::ref_cascade => RefAction::Cascade,
+                    Rule::ref_set_null => RefAction::SetNull,
+                    _ => RefAction::Restrict,
+                };
+                if on == Rule::fk_delete {
+                    fk.on_delete = action;
+                } else {
+                    fk.on_update = action;
+                }
+            }
+            _ => {}
         }
     }
+    let kind = match rule {
+        Rule::primary_key_def => {
+            name = "PRIMARY".to_string();
+            KeyKind::Primary
+        }
+        Rule::foreign_key_def => KeyKind::Foreign(fk),
+        Rule::index_def => KeyKind::Index,
+        Rule::check_opt => KeyKind::Check(check),
+        _ => KeyKind::Unique,
+    };
+    KeyDef { kind, name, cols, algorithm }
+}
+
+// USING BTREE or USING HASH
+fn parse_index_using(pair: Pair<Rule>) -> IndexAlgorithm {
+    match pair.into_inner().next().unwrap().as_rule() {
+        Rule::using_hash => IndexAlgorithm::Hash,
+        _ => IndexAlgorithm::BTree,
+    }
+}
+
+// parses a key descriptor as written in the table file header
+pub fn parse_key_desc(desc: &str) -> Result<KeyDef, TableLikeError> {
+    SQLParser::parse(Rule::key_desc, desc)
+        .map_err(|_| TableLikeError::new(&format!("Invalid key '{desc}'")))?
+        .next()
+        .and_then(|f| f.into_inner().next())
+        .map(parse_key)
+        .ok_or_else(|| TableLikeError::new(&format!("Invalid key '{desc}'")))
+}
+
+// parses an expression kept as text, like the DEFAULT and CHECK of a column
+pub fn parse_expr_text(inp: &str) -> Result<Expr, TableLikeError> {
+    let pair = SQLParser::parse(Rule::expr_desc, inp)
+        .map_err(|_| TableLikeError::new(&format!("Invalid expression '{inp}'")))?
+        .next()
+        .and_then(|f| f.into_inner().next())
+        .ok_or_else(|| TableLikeError::new(&format!("Invalid expression '{inp}'")))?;
+    parse_expr(pair)
+}
+
+// parses the type descriptor of a column as written in the table file header
+pub fn parse_column_type(col_name: &str, desc: &str) -> Result<ColumnEntry, TableLikeError> {
+    let pair = SQLParser::parse(Rule::column_type_desc, desc)
+        .map_err(|_| TableLikeError::new(&format!("Invalid type '{desc}'")))?
+        .next()
+        .ok_or_else(|| TableLikeError::new(&format!("Invalid type '{desc}'")))?;
+    let mut it = pair.into_inner();
+    it.next_back();
+    parse_column(col_name.to_string(), it, &mut Vec::new())
+}
+
+// the parser recurses once per parenthesis, so ones nested too deep are turned down before
+// it gets to see them, the ones in strings don't count
+fn check_nesting(inp: &str) -> Result<(), TableLikeError> {
+    let (mut depth, mut quoted) = (0usize, false);
+    for c in inp.chars() {
+        match c {
+            '\'' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if depth > MAX_EXPR_DEPTH {
+            return Err(TableLikeError::new(&format!("Expression nested more than {MAX_EXPR_DEPTH} levels deep")));
+        }
+    }
+    Ok(())
+}
+
+pub fn parse_statement(inp: &str) -> Result<Statement, TableLikeError> {
+    check_nesting(inp)?;
+    let pair = SQLParser::parse(Rule::sql, inp)
+        .map_err(|e| TableLikeError::new(&format!("Syntax Error\n{e}")))?
+        .next()
+        .and_then(|f| f.into_inner().next())
+        .ok_or_else(|| TableLikeError::new("Empty statement"))?;
+
+    match pair.as_rule() {
+        Rule::select_stmt => {
+            let mut table = String::new();
+            let mut criteria = Criteria {
+                re: Vec::new(),
+                filter: None,
+            };
+            let mut lock = None;
+            for clause in pair.into_inner() {
+                match clause.as_rule() {
+                    Rule::select_clause => {
+                        for item in clause.into_inner() {
+                            if item.as_rule() == Rule::star_operator {
+                                criteria.re.push(SelectItem::Wildcard);
+                                continue;
+                            }
+                            let mut it = item.into_inner();
+                            let ex = it.next().unwrap();
+                            let alias = match it.next() {
+                                Some(_) => it.next().unwrap().as_str().to_string(),
+                                None => ex.as_str().trim().to_string(),
+                            };
+                            criteria.re.push(SelectItem::Expr {
+                                expr: parse_expr(ex)?,
+                                alias,
+                            });
+                        }
+                    }
+                    Rule::from_clause => {
+                        table = clause.into_inner().next().unwrap().as_str().to_string();
+                    }
+                    Rule::where_clause => {
+                        criteria.filter = Some(parse_expr(clause.into_inner().next().unwrap())?);
+                    }
+                    Rule::locking_clause => {
+                        lock = Some(match clause.into_inner().next().unwrap().as_rule() {
+                            Rule::for_update => LockMode::Exclusive,
+                            _ => LockMode::Shared,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            Ok(Statement::Select { table, criteria, lock })
+        }
+        Rule::create_stmt => {
+            let mut it = pair.into_inner();
+            let table = it.next().unwrap().as_str().to_string();
+            let mut cols = Vec::new();
+            let mut keys = Vec::new();
+            let mut engine = Engine::default();
+            for def in it {
+                if def.as_rule() == Rule::engine_opt {
+                    engine = Engine::from_name(def.into_inner().next().unwrap().as_str())?;
+                    continue;
+                }
+                if def.as_rule() != Rule::column_def {
+                    keys.push(parse_key(def));
+                    continue;
+                }
+                let mut d = def.into_inner();
+                let col_name = d.next().unwrap().as_str().to_string();
+                cols.push(parse_column(col_name, d, &mut keys)?);
+            }
+            Ok(Statement::CreateTable { table, cols, keys, engine })
+        }
+        Rule::create_index_stmt => {
+            let mut kind = KeyKind::Index;
+            let mut algorithm = IndexAlgorithm::BTree;
+            let mut idents = Vec::new();
+            let mut cols = Vec::new();
+            for p in pair.into_inner() {
+                match p.as_rule() {
+                    Rule::unique_flag => kind = KeyKind::Unique,
+                    Rule::index_using => algorithm = parse_index_using(p),
+                    Rule::ident => idents.push(p.as_str().to_string()),
+                    Rule::key_cols => cols = p.into_inner().map(|c| c.as_str().to_string()).collect(),
+                    _ => {}
+                }
+            }
+            let table = idents.pop().unwrap();
+            let name = idents.pop().unwrap();
+            Ok(Statement::CreateIndex { table, key: KeyDef { kind, name, cols, algorithm } })
+        }
+        Rule::drop_index_stmt => {
+            let mut it = pair.into_inner().filter(|p| p.as_rule() == Rule::ident);
+            let name = it.next().unwrap().as_str().to_string();
+            let table = it.next().unwrap().as_str().to_string();
+            Ok(Statement::DropIndex { table, name })
+        }
+        Rule::alter_stmt => {
+            let mut it = pair.into_inner();
+            let table = it.next().unwrap().as_str().to_string();
+            let mut actions = Vec::new();
+            let mut algorithm = Algorithm::Default;
+            for spec in it {
+                let rule = spec.as_rule();
+                if rule == Rule::algorithm_opt {
+                    algorithm = match spec.into_inner().next().unwrap().as_str().to_lowercase().as_str() {
+                        "instant" => Algorithm::Instant,
+                        "copy" => Algorithm::Copy,
+                        _ => Algorithm::Default,
+                    };
+                    continue;
+                }
+                let mut parts = spec
+                    .into_inner()
+                    .filter(|p| !matches!(p.as_rule(), Rule::kw_column | Rule::kw_to | Rule::kw_as));
+                let mut name = || parts.next().unwrap().as_str().to_string();
+                actions.push(match rule {
+                    Rule::drop_column => AlterAction::DropColumn { name: name() },
+                    Rule::rename_column => AlterAction::RenameColumn { from: name(), to: name() },
+                    Rule::rename_table => AlterAction::RenameTo { name: name() },
+                    _ => {
+                        let mut d = parts.next().unwrap().into_inner();
+                        let col_name = d.next().unwrap().as_str().to_string();
+                        let mut keys = Vec::new();
+                        let col = parse_column(col_name, d, &mut keys)?;
+                        match rule {
+                            Rule::add_column => AlterAction::AddColumn { col, keys },
+                            _ => AlterAction::ModifyColumn { col, keys },
+                        }
+                    }
+                });
+            }
+            Ok(Statement::AlterTable { table, actions, algorithm })
+        }
+        Rule::optimize_stmt => Ok(Statement::Optimize {
+            table: pair.into_inner().next().unwrap().as_str().to_string(),
+        }),
+        Rule::insert_stmt => {
+            let mut it = pair.into_inner();
+            let table = it.next().unwrap().as_str().to_string();
+            let mut cols: Option<Vec<String>> = None;
+            let mut rows = Vec::new();
+            for p in it {
+                match p.as_rule() {
+                    Rule::ident => cols.get_or_insert_with(Vec::new).push(p.as_str().to_string()),
+                    _ => rows.push(p.into_inner().map(parse_expr).collect::<Result<_, _>>()?),
+                }
+            }
+            Ok(Statement::Insert { table, cols, rows })
+        }
+        Rule::update_stmt => {
+            let mut it = pair.into_inner();
+            let table = it.next().unwrap().as_str().to_string();
+            let mut sets = Vec::new();
+            let mut filter = None;
+            for p in it {
+                match p.as_rule() {
+                    Rule::assignment => {
+                        let mut a = p.into_inner();
+                        let col = a.next().unwrap().as_str().to_string();
+                        sets.push((col, parse_expr(a.next().unwrap())?));
+                    }
+                    _ => filter = Some(parse_expr(p.into_inner().next().unwrap())?),
+                }
+            }
+            Ok(Statement::Update { table, sets, filter })
+        }
+        Rule::delete_stmt => {
+            let mut it = pair.into_inner();
+            let table = it.next().unwrap().as_str().to_string();
+            let filter = match it.next() {
+                Some(w) => Some(parse_expr(w.into_inner().next().unwrap())?),
+                None => None,
+            };
+            Ok(Statement::Delete { table, filter })
+        }
+        Rule::describe_stmt => Ok(Statement::Describe {
+            table: pair.into_inner().next().unwrap().as_str().to_string(),
+        }),
+        Rule::set_stmt => {
+            let mut it = pair.into_inner();
+            let var = it.next().unwrap().as_str().to_string();
+            Ok(Statement::Set {
+                var,
+                value: parse_expr(it.next().unwrap())?,
+            })
+        }
+        Rule::begin_stmt => Ok(Statement::Begin),
+        Rule::commit_stmt => Ok(Statement::Commit),
+        Rule::rollback_stmt => Ok(Statement::Rollback {
+            savepoint: pair.into_inner().next().map(|p| p.as_str().to_string()),
+        }),
+        Rule::savepoint_stmt => Ok(Statement::Savepoint {
+            name: pair.into_inner().next().unwrap().as_str().to_string(),
+        }),
+        Rule::release_stmt => Ok(Statement::ReleaseSavepoint {
+            name: pair.into_inner().next().unwrap().as_str().to_string(),
+        }),
+        Rule::lock_tables_stmt => Ok(Statement::LockTables {
+            tables: pair
+                .into_inner()
+                .map(|t| {
+                    let mut it = t.into_inner();
+                    let table = it.next().unwrap().as_str().to_string();
+                    let mode = match it.next().unwrap().as_rule() {
+                        Rule::lock_write => LockMode::Exclusive,
+                        _ => LockMode::Shared,
+                    };
+                    (table, mode)
+                })
+                .collect(),
+        }),
+        Rule::unlock_tables_stmt => Ok(Statement::UnlockTables),
+        _ => Err(TableLikeError::new("Unsupported statement")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(inp: &str) -> Result<TableCell, TableLikeError> {
+        parse_expr_text(inp)?.eval_const()
+    }
+
+    #[test]
+    fn precedence_and_three_valued_logic() {
+        assert_eq!(eval("1 + 2 * 3 - 4 % 3").unwrap(), TableCell::Num(Some(6)));
+        assert_eq!(eval("-(1 + 2)").unwrap(), TableCell::Num(Some(-3)));
+        assert_eq!(eval("NOT 1 = 2 AND 3 > 2").unwrap(), TableCell::Bool(Some(true)));
+        assert_eq!(eval("FALSE AND NULL").unwrap(), TableCell::Bool(Some(false)));
+        assert_eq!(eval("TRUE OR NULL").unwrap(), TableCell::Bool(Some(true)));
+        assert_eq!(eval("TRUE AND NULL").unwrap(), TableCell::Bool(None));
+        assert_eq!(eval("NULL IS NULL").unwrap(), TableCell::Bool(Some(true)));
+        assert_eq!(eval("1 + NULL IS NOT NULL").unwrap(), TableCell::Bool(Some(false)));
+    }
+
+    #[test]
+    fn long_chains_are_turned_down() {
+        let chain = format!("1{}", "+1".repeat(8000));
+        assert!(parse_expr_text(&chain).is_err());
+        let stmt = format!("SELECT {chain};");
+        assert!(parse_statement(&stmt).is_err());
+    }
+
+    #[test]
+    fn deep_parentheses_are_turned_down_before_parsing() {
+        let n = 20000;
+        let stmt = format!("SELECT {}1{};", "(".repeat(n), ")".repeat(n));
+        assert!(parse_statement(&stmt).is_err());
+        // the ones in strings don't count
+        let stmt = format!("SELECT '{}';", "(".repeat(n));
+        assert!(parse_statement(&stmt).is_ok());
+    }
+
+    #[test]
+    fn expressions_up_to_the_limit_run_on_a_session_stack() {
+        let t = crate::session_thread()
+            .spawn(|| {
+                let chain = format!("1{}", "+1".repeat(MAX_EXPR_DEPTH - 1));
+                let nested = format!("{}1{}", "(".repeat(MAX_EXPR_DEPTH), ")".repeat(MAX_EXPR_DEPTH));
+                (eval(&chain).unwrap(), parse_statement(&format!("SELECT {nested};")).is_ok())
+            })
+            .unwrap();
+        assert_eq!(t.join().unwrap(), (TableCell::Num(Some(MAX_EXPR_DEPTH as NumType)), true));
+    }
 }
@@ -0,0 +1,911 @@
+/*
+
+Write-ahead log, every change to a table file is recorded in wal.log before it is made
+
+Every statement is one operation. Its records carry its number, and a record of a change to bytes already in a file
+carries the old bytes as well as the new ones. Once all its changes are made the
+operation gets a commit record, the files it changed are synced and the log is
+emptied again.
+
+The old bytes also roll back a failed statement, undoing its records and cutting
+them off. Transactions are rolled back through the versions of the rows instead,
+see mvcc.rs.
+
+On startup a log that wasn't emptied is recovered. An operation with a commit record
+is redone, its changes may not have reached the disk, one without is undone from the
+old bytes in reverse order, so every table is left as it was before or after each
+statement. Index files aren't logged, those of the tables in the log are removed and
+built again on first use.
+
+A file written as a whole, a table that is rewritten or a file like txn.state, goes to
+a sibling .tmp first that is moved over it once it is complete, so neither a reader
+nor a crash finds it half written. A file the operation created has a record of its
+own and its writes aren't logged: it is synced before the operation commits and an
+undo removes it. The move has a record too, and the file it replaces is kept as a
+hard link next to it, <path>.old<n>, until the operation ends instead of being copied
+into the log. A redo moves the .tmp again if it is still there and drops the link, an
+undo puts the linked file back, or moves the file back for ALTER TABLE ... RENAME
+moving a table to a new name. A file that is removed is moved aside the same way. A
+change redone to a file that was moved later in the same operation is made at the
+path it was moved to.
+
+Records are
+
+    length u64 | checksum u32 | kind u8 | operation u64 | path | ...
+
+with a path and every byte string prefixed by its length, an optional one by a byte
+that is 0 for none. The checksum catches a record cut short by a crash, recovery
+stops at the first one that doesn't match.
+
+*/
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::index;
+
+pub const LOG_PATH: &str = "wal.log";
+
+const WRITE: u8 = 1;
+const SET_LEN: u8 = 2;
+const REPLACE: u8 = 3;
+const COMMIT: u8 = 4;
+const RENAME: u8 = 5;
+const CREATE: u8 = 6;
+const MOVE: u8 = 7;
+const REMOVE: u8 = 8;
+
+// how much of the log reaches the disk, like PRAGMA synchronous of sqlite
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SyncPolicy {
+    // the log is synced before a file changes and on commit, the files before the log
+    // is emptied, statements survive a power loss
+    #[default]
+    Full,
+    // nothing is synced, statements survive the server crashing but not the machine
+    Normal,
+    // nothing is logged, a crash can leave a statement half done and a failed one
+    // isn't rolled back
+    Off,
+}
+
+impl SyncPolicy {
+    pub fn from_name(name: &str) -> Option<SyncPolicy> {
+        match name.to_ascii_uppercase().as_str() {
+            "FULL" => Some(Self::Full),
+            "NORMAL" => Some(Self::Normal),
+            "OFF" => Some(Self::Off),
+            _ => None,
+        }
+    }
+}
+
+enum Change {
+    // new bytes at an offset, old are the bytes they replace and len the length of the file before
+    Write { at: u64, len: u64, old: Vec<u8>, new: Vec<u8> },
+    // the file cut or grown to new_len, tail are the bytes that were cut off, only in the
+    // logs of versions that truncated a table to write it again
+    SetLen { len: u64, new_len: u64, tail: Vec<u8> },
+    // a whole file written, None for a file that doesn't exist, only in the logs of versions
+    // that copied the files they replaced into the log
+    Replace { old: Option<Vec<u8>>, new: Option<Vec<u8>> },
+    // the file at from moved over the path, old are the bytes it replaced, only in the logs
+    // of those versions as well
+    Rename { from: String, old: Option<Vec<u8>> },
+    // a new file whose writes aren't logged
+    Create,
+    // the file at from moved over the path, backup is where the file it replaced is linked
+    // until the operation ends, None when there was nothing like for a table moved to a new name
+    Move { from: String, backup: Option<String> },
+    // the file moved to backup until the operation ends
+    Remove { backup: String },
+    Commit,
+}
+
+struct Record {
+    op: u64,
+    path: String,
+    change: Change,
+}
+
+// fnv-1a
+fn checksum(buf: &[u8]) -> u32 {
+    buf.iter().fold(0x811c9dc5, |h, b| (h ^ *b as u32).wrapping_mul(0x01000193))
+}
+
+fn put_bytes(out: &mut Vec<u8>, b: &[u8]) {
+    out.extend((b.len() as u64).to_be_bytes());
+    out.extend(b);
+}
+
+fn put_opt(out: &mut Vec<u8>, b: &Option<Vec<u8>>) {
+    match b {
+        Some(b) => {
+            out.push(1);
+            put_bytes(out, b);
+        }
+        None => out.push(0),
+    }
+}
+
+impl Record {
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let kind = match &self.change {
+            Change::Write { .. } => WRITE,
+            Change::SetLen { .. } => SET_LEN,
+            Change::Replace { .. } => REPLACE,
+            Change::Rename { .. } => RENAME,
+            Change::Create => CREATE,
+            Change::Move { .. } => MOVE,
+            Change::Remove { .. } => REMOVE,
+            Change::Commit => COMMIT,
+        };
+        body.push(kind);
+        body.extend(self.op.to_be_bytes());
+        put_bytes(&mut body, self.path.as_bytes());
+        match &self.change {
+            Change::Write { at, len, old, new } => {
+                body.extend(at.to_be_bytes());
+                body.extend(len.to_be_bytes());
+                put_bytes(&mut body, old);
+                put_bytes(&mut body, new);
+            }
+            Change::SetLen { len, new_len, tail } => {
+                body.extend(len.to_be_bytes());
+                body.extend(new_len.to_be_bytes());
+                put_bytes(&mut body, tail);
+            }
+            Change::Replace { old, new } => {
+                put_opt(&mut body, old);
+                put_opt(&mut body, new);
+            }
+            Change::Rename { from, old } => {
+                put_bytes(&mut body, from.as_bytes());
+                put_opt(&mut body, old);
+            }
+            Change::Move { from, backup } => {
+                put_bytes(&mut body, from.as_bytes());
+                put_opt(&mut body, &backup.as_ref().map(|b| b.as_bytes().to_vec()));
+            }
+            Change::Remove { backup } => put_bytes(&mut body, backup.as_bytes()),
+            Change::Create | Change::Commit => {}
+        }
+        let mut out = Vec::with_capacity(body.len() + 12);
+        out.extend((body.len() as u64).to_be_bytes());
+        out.extend(checksum(&body).to_be_bytes());
+        out.extend(body);
+        out
+    }
+}
+
+// reads the fields of a record, None past its end
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: u64) -> Option<&'a [u8]> {
+        let n = usize::try_from(n).ok().filter(|n| *n <= self.0.len())?;
+        let (b, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(b)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Option<Vec<u8>> {
+        let n = self.u64()?;
+        Some(self.take(n)?.to_vec())
+    }
+
+    fn opt(&mut self) -> Option<Option<Vec<u8>>> {
+        match self.u8()? {
+            0 => Some(None),
+            _ => Some(Some(self.bytes()?)),
+        }
+    }
+
+    fn path(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?).ok()
+    }
+
+    fn record(&mut self) -> Option<Record> {
+        let len = self.u64()?;
+        let sum = self.u32()?;
+        let body = self.take(len)?;
+        if checksum(body) != sum {
+            return None;
+        }
+        let mut c = Cursor(body);
+        let kind = c.u8()?;
+        let op = c.u64()?;
+        let path = c.path()?;
+        let change = match kind {
+            WRITE => Change::Write { at: c.u64()?, len: c.u64()?, old: c.bytes()?, new: c.bytes()? },
+            SET_LEN => Change::SetLen { len: c.u64()?, new_len: c.u64()?, tail: c.bytes()? },
+            REPLACE => Change::Replace { old: c.opt()?, new: c.opt()? },
+            RENAME => Change::Rename { from: c.path()?, old: c.opt()? },
+            CREATE => Change::Create,
+            MOVE => Change::Move { from: c.path()?, backup: c.opt()?.map(String::from_utf8).transpose().ok()? },
+            REMOVE => Change::Remove { backup: c.path()? },
+            COMMIT => Change::Commit,
+            _ => return None,
+        };
+        Some(Record { op, path, change })
+    }
+}
+
+fn open_for_write(path: &str) -> std::io::Result<File> {
+    File::options().write(true).create(true).truncate(false).open(path)
+}
+
+fn write_at(path: &str, at: u64, buf: &[u8]) -> std::io::Result<()> {
+    let mut f = open_for_write(path)?;
+    f.seek(SeekFrom::Start(at))?;
+    f.write_all(buf)
+}
+
+fn set_len(path: &str, len: u64) -> std::io::Result<()> {
+    open_for_write(path)?.set_len(len)
+}
+
+// the sibling a file is written to before it is renamed over it, so nobody sees it half written
+pub fn temp_path(path: &str) -> String {
+    format!("{path}.tmp")
+}
+
+// the new contents go to a temp file that is synced before it is renamed over the old one, so
+// the file is either the old or the new one after a crash, never empty or half written
+fn replace(path: &str, contents: &Option<Vec<u8>>) -> std::io::Result<()> {
+    match contents {
+        Some(b) => {
+            let mut f = File::create(temp_path(path))?;
+            f.write_all(b)?;
+            f.sync_all()?;
+            std::fs::rename(temp_path(path), path)
+        }
+        None => remove_file(path),
+    }
+}
+
+fn remove_file(path: &str) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// whether both paths are links of the same file
+fn same_file(a: &str, b: &str) -> std::io::Result<bool> {
+    let (a, b) = match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => (a, b),
+        (Err(e), _) | (_, Err(e)) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => return Ok(false),
+    };
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+// a file that doesn't exist has nothing to sync
+fn sync_path(path: &str) -> std::io::Result<()> {
+    match File::open(path) {
+        Ok(f) => f.sync_all(),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// the directories of the paths, which have the files that were renamed or removed
+fn sync_dirs<'a>(paths: impl IntoIterator<Item = &'a str>) -> std::io::Result<()> {
+    let dirs = paths
+        .into_iter()
+        .map(|p| Path::new(p).parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new(".")))
+        .collect::<HashSet<_>>();
+    for d in dirs {
+        File::open(d)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// the table a file in the log belongs to, <table>.auto and <table>.refs go with <table>
+fn table_of(path: &str) -> &str {
+    path.split('.').next().unwrap_or(path)
+}
+
+fn decode(data: &[u8]) -> Vec<Record> {
+    let mut c = Cursor(data);
+    std::iter::from_fn(|| c.record()).collect()
+}
+
+// undoes records in reverse order, the index files of their tables are removed and rebuilt when needed
+fn undo_all(records: &[Record]) -> std::io::Result<()> {
+    for r in records.iter().rev() {
+        r.undo()?;
+    }
+    remove_indexes(records)
+}
+
+// where a change to the file at path is made again, a file the operation renamed afterwards
+// is at its new path once the rename was made, and one it replaced as a whole afterwards
+// doesn't need it
+fn redo_path<'a>(path: &'a str, later: &'a [Record]) -> std::io::Result<Option<&'a str>> {
+    let mut at = path;
+    for r in later {
+        match &r.change {
+            Change::Rename { from, .. } | Change::Move { from, .. } if from == at => at = &r.path,
+            Change::Rename { .. } | Change::Move { .. } | Change::Replace { .. } | Change::Remove { .. } if r.path == at => {
+                return Ok(None)
+            }
+            _ => {}
+        }
+    }
+    match std::fs::exists(path)? {
+        true => Ok(Some(path)),
+        false => Ok(Some(at)),
+    }
+}
+
+fn remove_indexes(records: &[Record]) -> std::io::Result<()> {
+    let tables = records
+        .iter()
+        .flat_map(|r| match &r.change {
+            Change::Rename { from, .. } | Change::Move { from, .. } => vec![table_of(&r.path), table_of(from)],
+            _ => vec![table_of(&r.path)],
+        })
+        .filter(|t| !t.is_empty())
+        .collect::<HashSet<_>>();
+    for table in tables {
+        for idx in index::index_files(table)? {
+            std::fs::remove_file(idx)?;
+        }
+    }
+    Ok(())
+}
+
+impl Record {
+    // later are the records of the operation after this one
+    fn redo(&self, later: &[Record]) -> std::io::Result<()> {
+        let path = match &self.change {
+            Change::Write { .. } | Change::SetLen { .. } => match redo_path(&self.path, later)? {
+                Some(p) => p,
+                None => return Ok(()),
+            },
+            _ => &self.path,
+        };
+        match &self.change {
+            Change::Write { at, new, .. } => write_at(path, *at, new),
+            Change::SetLen { new_len, .. } => set_len(path, *new_len),
+            Change::Replace { new, .. } => replace(&self.path, new),
+            // the file is gone once it was moved
+            Change::Rename { from, .. } => match std::fs::exists(from)? {
+                true => std::fs::rename(from, &self.path),
+                false => Ok(()),
+            },
+            // synced before the commit record
+            Change::Create => Ok(()),
+            Change::Move { from, backup } => {
+                if std::fs::exists(from)? {
+                    std::fs::rename(from, &self.path)?;
+                }
+                backup.as_deref().map_or(Ok(()), remove_file)
+            }
+            Change::Remove { backup } => remove_file(backup),
+            Change::Commit => Ok(()),
+        }
+    }
+
+    fn undo(&self) -> std::io::Result<()> {
+        match &self.change {
+            Change::Write { at, len, old, .. } => {
+                write_at(&self.path, *at, old)?;
+                set_len(&self.path, *len)
+            }
+            Change::SetLen { len, new_len, tail } => {
+                set_len(&self.path, *len)?;
+                write_at(&self.path, *new_len.min(len), tail)
+            }
+            Change::Replace { old, .. } => replace(&self.path, old),
+            // the file may not have been moved yet, then what is there goes to from and
+            // the old bytes take its place all the same
+            Change::Rename { from, old } => {
+                if std::fs::exists(&self.path)? {
+                    std::fs::rename(&self.path, from)?;
+                }
+                replace(&self.path, old)
+            }
+            Change::Create => remove_file(&self.path),
+            // the file that was replaced is put back, unless it is still in place and the
+            // move never happened, without a link it didn't get that far or was put back already
+            Change::Move { backup: Some(b), .. } => match std::fs::exists(b)? {
+                true if same_file(b, &self.path)? => remove_file(b),
+                true => std::fs::rename(b, &self.path),
+                false => Ok(()),
+            },
+            Change::Move { from, backup: None } => {
+                if std::fs::exists(&self.path)? && !std::fs::exists(from)? {
+                    std::fs::rename(&self.path, from)?;
+                }
+                Ok(())
+            }
+            Change::Remove { backup } => match std::fs::exists(backup)? {
+                true => std::fs::rename(backup, &self.path),
+                false => Ok(()),
+            },
+            Change::Commit => Ok(()),
+        }
+    }
+}
+
+pub struct Wal {
+    file: File,
+    policy: SyncPolicy,
+    op: u64,
+    // length of the log, the position of the next record
+    len: u64,
+    // files changed by the current operation
+    touched: Vec<String>,
+    // files it created, their writes aren't logged
+    created: HashSet<String>,
+    // the links to the files it replaced or removed
+    backups: Vec<String>,
+    // a record went out that has to be on disk before the change it describes is made
+    unsynced: bool,
+}
+
+impl Wal {
+    // opens the log at path, recovering what a crash left in it
+    pub fn open(path: &str, policy: SyncPolicy) -> std::io::Result<Wal> {
+        let mut file = File::options().read(true).append(true).create(true).open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        if !data.is_empty() {
+            recover(&data)?;
+            file.set_len(0)?;
+            file.sync_all()?;
+        }
+        Ok(Wal { file, policy, op: 0, len: 0, touched: Vec::new(), created: HashSet::new(), backups: Vec::new(), unsynced: false })
+    }
+
+    fn logging(&self) -> bool {
+        self.policy != SyncPolicy::Off
+    }
+
+    // whether the current operation logged anything to roll back
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn append(&mut self, path: &str, change: Change) -> std::io::Result<()> {
+        // the first record of a file has the length to undo to, anything past it may be on disk already
+        let first = !self.touched.iter().any(|p| p == path);
+        if first && !path.is_empty() {
+            self.touched.push(path.to_string());
+        }
+        let undoes = match &change {
+            Change::Write { old, .. } => !old.is_empty(),
+            Change::SetLen { tail, .. } => !tail.is_empty(),
+            _ => true,
+        };
+        let rec = Record { op: self.op, path: path.to_string(), change }.encode();
+        self.file.write_all(&rec)?;
+        self.len += rec.len() as u64;
+        self.unsynced |= first || undoes;
+        Ok(())
+    }
+
+    // makes the records written so far durable, before the changes they describe are made
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        if self.unsynced && self.policy == SyncPolicy::Full {
+            self.file.sync_data()?;
+        }
+        self.unsynced = false;
+        Ok(())
+    }
+
+    // records writing buf at an offset of the file at path without making the change,
+    // sync has to come before it is made
+    pub fn log_write(&mut self, path: &str, file: &File, at: u64, buf: &[u8]) -> std::io::Result<()> {
+        if !self.logging() || self.created.contains(path) {
+            return Ok(());
+        }
+        let len = file.metadata()?.len();
+        let mut old = Vec::new();
+        if at < len {
+            let mut f = file;
+            f.seek(SeekFrom::Start(at))?;
+            f.take((len - at).min(buf.len() as u64)).read_to_end(&mut old)?;
+        }
+        self.append(path, Change::Write { at, len, old, new: buf.to_vec() })
+    }
+
+    pub fn write(&mut self, path: &str, file: &File, at: u64, buf: &[u8]) -> std::io::Result<()> {
+        self.log_write(path, file, at, buf)?;
+        self.sync()?;
+        let mut f = file;
+        f.seek(SeekFrom::Start(at))?;
+        f.write_all(buf)
+    }
+
+    // writes a whole file, or removes it for None
+    pub fn replace(&mut self, path: &str, contents: Option<&[u8]>) -> std::io::Result<()> {
+        let Some(b) = contents else {
+            if !self.logging() {
+                return remove_file(path);
+            }
+            if !std::fs::exists(path)? {
+                return Ok(());
+            }
+            let backup = self.backup_path(path);
+            self.append(path, Change::Remove { backup: backup.clone() })?;
+            self.sync()?;
+            return std::fs::rename(path, &backup);
+        };
+        // the temp file isn't anything until it is moved, so it isn't logged
+        let temp = temp_path(path);
+        let mut f = File::create(&temp)?;
+        f.write_all(b)?;
+        f.sync_all()?;
+        self.move_over(&temp, path)
+    }
+
+    // where the file at path is kept while the operation can still be undone, a link left
+    // there by a crash the log doesn't know of anymore is in the way
+    fn backup_path(&mut self, path: &str) -> String {
+        let backup = format!("{path}.old{}", self.backups.len());
+        let _ = std::fs::remove_file(&backup);
+        self.backups.push(backup.clone());
+        backup
+    }
+
+    // moves the file at from over the one at path, which is replaced as a whole at once
+    pub fn rename(&mut self, from: &str, path: &str) -> std::io::Result<()> {
+        // the new file has to be on disk before the name is
+        if self.policy == SyncPolicy::Full {
+            sync_path(from)?;
+        }
+        self.move_over(from, path)
+    }
+
+    fn move_over(&mut self, from: &str, path: &str) -> std::io::Result<()> {
+        if self.logging() {
+            let backup = std::fs::exists(path)?.then(|| self.backup_path(path));
+            self.append(path, Change::Move { from: from.to_string(), backup: backup.clone() })?;
+            self.sync()?;
+            if let Some(b) = &backup {
+                std::fs::hard_link(path, b)?;
+            }
+        }
+        std::fs::rename(from, path)
+    }
+
+    // creates a new empty file, an error if there is one already
+    pub fn create(&mut self, path: &str) -> std::io::Result<File> {
+        if std::fs::exists(path)? {
+            return Err(std::io::Error::from(ErrorKind::AlreadyExists));
+        }
+        if self.logging() {
+            self.append(path, Change::Create)?;
+            self.sync()?;
+            self.created.insert(path.to_string());
+        }
+        File::options().read(true).write(true).create_new(true).open(path)
+    }
+
+    // undoes the whole operation and ends it, the files it changed have to be read
+    // again, cached pages and open tables are out of date
+    pub fn rollback(&mut self) -> std::io::Result<()> {
+        if self.len > 0 {
+            let mut data = Vec::new();
+            self.file.seek(SeekFrom::Start(0))?;
+            self.file.read_to_end(&mut data)?;
+            let records = decode(&data);
+            undo_all(&records)?;
+            if self.policy == SyncPolicy::Full {
+                for p in records.iter().map(|r| r.path.as_str()).filter(|p| !p.is_empty()).collect::<HashSet<_>>() {
+                    sync_path(p)?;
+                }
+            }
+            // a crash before the log is emptied undoes them again, which changes nothing
+            self.file.set_len(0)?;
+            if self.policy == SyncPolicy::Full {
+                self.file.sync_data()?;
+            }
+            self.len = 0;
+        }
+        self.end();
+        Ok(())
+    }
+
+    fn end(&mut self) {
+        self.touched.clear();
+        self.created.clear();
+        self.backups.clear();
+        self.op += 1;
+    }
+
+    // ends the current operation once all its changes are made
+    pub fn commit(&mut self) -> std::io::Result<()> {
+        if self.touched.is_empty() {
+            self.end();
+            return Ok(());
+        }
+        // the files it created are only as they should be once they are on disk, their writes can't be redone
+        if self.policy == SyncPolicy::Full {
+            for p in &self.created {
+                sync_path(p)?;
+            }
+            sync_dirs(self.created.iter().map(String::as_str))?;
+        }
+        self.append("", Change::Commit)?;
+        self.sync()?;
+        if self.policy == SyncPolicy::Full {
+            for table in self.touched.iter().map(|p| table_of(p)).collect::<HashSet<_>>() {
+                for idx in index::index_files(table)? {
+                    sync_path(&idx)?;
+                }
+            }
+            for p in &self.touched {
+                sync_path(p)?;
+            }
+            sync_dirs(self.touched.iter().map(String::as_str))?;
+        }
+        // until the log is emptied a crash removes them again
+        for b in &self.backups {
+            remove_file(b)?;
+        }
+        if self.policy == SyncPolicy::Full {
+            sync_dirs(self.backups.iter().map(String::as_str))?;
+        }
+        self.file.set_len(0)?;
+        if self.policy == SyncPolicy::Full {
+            self.file.sync_data()?;
+        }
+        self.len = 0;
+        self.end();
+        Ok(())
+    }
+}
+
+fn recover(data: &[u8]) -> std::io::Result<()> {
+    let records = decode(data);
+    let done = records.iter().filter(|r| matches!(r.change, Change::Commit)).map(|r| r.op).collect::<HashSet<_>>();
+    let (redo, undo): (Vec<_>, Vec<_>) = records.into_iter().partition(|r| done.contains(&r.op));
+    for (i, r) in redo.iter().enumerate() {
+        r.redo(&redo[i + 1..])?;
+    }
+    undo_all(&undo)?;
+    remove_indexes(&redo)?;
+    let paths = redo.iter().chain(&undo).map(|r| r.path.as_str()).filter(|p| !p.is_empty()).collect::<HashSet<_>>();
+    for p in &paths {
+        sync_path(p)?;
+    }
+    sync_dirs(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempPath;
+
+    #[test]
+    fn whole_files_are_replaced_through_a_temp_file() {
+        let t = TempPath::new("wal", "replace");
+        for policy in [SyncPolicy::Full, SyncPolicy::Off] {
+            let mut wal = Wal::open(&t.with("log"), policy).unwrap();
+            wal.replace(&t.with("auto"), Some(b"7\n")).unwrap();
+            wal.replace(&t.with("auto"), Some(b"8\n")).unwrap();
+            wal.commit().unwrap();
+            assert_eq!(std::fs::read(t.with("auto")).unwrap(), b"8\n");
+            assert!(!std::fs::exists(temp_path(&t.with("auto"))).unwrap());
+            assert!(!std::fs::exists(t.with("auto.old0")).unwrap());
+            wal.replace(&t.with("auto"), None).unwrap();
+            wal.commit().unwrap();
+            assert!(!std::fs::exists(t.with("auto")).unwrap());
+        }
+    }
+
+    fn record(op: u64, path: &str, change: Change) -> Vec<u8> {
+        Record { op, path: path.to_string(), change }.encode()
+    }
+
+    // a log a crash left behind, recovered by opening it
+    fn recover_from(t: &TempPath, log: &[u8]) {
+        std::fs::write(t.with("log"), log).unwrap();
+        drop(Wal::open(&t.with("log"), SyncPolicy::Full).unwrap());
+        assert_eq!(std::fs::read(t.with("log")).unwrap(), b"");
+    }
+
+    fn write(op: u64, path: &str, old: &[u8], new: &[u8]) -> Vec<u8> {
+        record(op, path, Change::Write { at: 0, len: old.len() as u64, old: old.to_vec(), new: new.to_vec() })
+    }
+
+    #[test]
+    fn committed_operations_are_redone_and_the_others_undone() {
+        let t = TempPath::new("wal", "recover");
+        let tbl = t.with("tbl");
+        // the change of the committed operation didn't reach the file, the other one's did
+        std::fs::write(&tbl, b"aaaa").unwrap();
+        let mut log = write(1, &tbl, b"aaaa", b"bbbb");
+        log.extend(record(1, "", Change::Commit));
+        recover_from(&t, &log);
+        assert_eq!(std::fs::read(&tbl).unwrap(), b"bbbb");
+        std::fs::write(&tbl, b"cccc").unwrap();
+        recover_from(&t, &write(2, &tbl, b"bbbb", b"cccc"));
+        assert_eq!(std::fs::read(&tbl).unwrap(), b"bbbb");
+        // a file the operation created is gone again, one it grew has its old length
+        let mut log = record(3, &t.with("auto"), Change::Replace { old: None, new: Some(b"5".to_vec()) });
+        log.extend(record(3, &tbl, Change::Write { at: 4, len: 4, old: Vec::new(), new: b"dd".to_vec() }));
+        std::fs::write(t.with("auto"), b"5").unwrap();
+        std::fs::write(&tbl, b"bbbbdd").unwrap();
+        recover_from(&t, &log);
+        assert!(!std::fs::exists(t.with("auto")).unwrap());
+        assert_eq!(std::fs::read(&tbl).unwrap(), b"bbbb");
+    }
+
+    #[test]
+    fn recovery_stops_at_a_torn_record() {
+        let t = TempPath::new("wal", "torn");
+        let tbl = t.with("tbl");
+        let first = write(1, &tbl, b"aaaa", b"bbbb");
+        let commit = record(1, "", Change::Commit);
+        // the commit record was cut short, so the operation is undone
+        for cut in [1, commit.len() / 2, commit.len() - 1] {
+            std::fs::write(&tbl, b"bbbb").unwrap();
+            let mut log = first.clone();
+            log.extend(&commit[..cut]);
+            recover_from(&t, &log);
+            assert_eq!(std::fs::read(&tbl).unwrap(), b"aaaa", "{cut}");
+        }
+        // a record with a byte that changed on the way doesn't count, nor what comes after it
+        let mut log = first.clone();
+        log.extend(&commit);
+        log.extend(write(2, &tbl, b"bbbb", b"cccc"));
+        let mut bad = log.clone();
+        let at = first.len() + commit.len() + 20;
+        bad[at] ^= 1;
+        bad.extend(record(2, "", Change::Commit));
+        std::fs::write(&tbl, b"aaaa").unwrap();
+        recover_from(&t, &bad);
+        assert_eq!(std::fs::read(&tbl).unwrap(), b"bbbb");
+        // a length that runs past the end of the log
+        let mut huge = first.clone();
+        huge.extend(u64::MAX.to_be_bytes());
+        std::fs::write(&tbl, b"bbbb").unwrap();
+        recover_from(&t, &huge);
+        assert_eq!(std::fs::read(&tbl).unwrap(), b"aaaa");
+    }
+
+    #[test]
+    fn renames_are_redone_and_undone() {
+        // as logged by versions that copied the file a rename replaced
+        let t = TempPath::new("wal", "rename");
+        let tbl = t.with("tbl");
+        // committed before the new file was moved in place
+        std::fs::write(&tbl, b"old").unwrap();
+        std::fs::write(temp_path(&tbl), b"new").unwrap();
+        let mut log = record(1, &tbl, Change::Rename { from: temp_path(&tbl), old: Some(b"old".to_vec()) });
+        log.extend(record(1, "", Change::Commit));
+        recover_from(&t, &log);
+        assert_eq!(std::fs::read(&tbl).unwrap(), b"new");
+        assert!(!std::fs::exists(temp_path(&tbl)).unwrap());
+        // moved in place but never committed
+        recover_from(&t, &record(2, &tbl, Change::Rename { from: temp_path(&tbl), old: Some(b"older".to_vec()) }));
+        assert_eq!(std::fs::read(&tbl).unwrap(), b"older");
+        assert!(!std::fs::exists(temp_path(&tbl)).unwrap());
+        // a table moved to a new name is moved back
+        let to = t.with("auto");
+        std::fs::rename(&tbl, &to).unwrap();
+        recover_from(&t, &record(3, &to, Change::Rename { from: tbl.clone(), old: None }));
+        assert_eq!(std::fs::read(&tbl).unwrap(), b"older");
+        assert!(!std::fs::exists(&to).unwrap());
+    }
+
+    #[test]
+    fn rollback_undoes_an_operation_in_reverse() {
+        let t = TempPath::new("wal", "rollback");
+        let tbl = t.with("tbl");
+        std::fs::write(&tbl, b"aaaa").unwrap();
+        let mut wal = Wal::open(&t.with("log"), SyncPolicy::Normal).unwrap();
+        let file = File::options().read(true).write(true).open(&tbl).unwrap();
+        wal.write(&tbl, &file, 2, b"bbbb").unwrap();
+        wal.write(&tbl, &file, 0, b"cc").unwrap();
+        wal.replace(&t.with("auto"), Some(b"9")).unwrap();
+        assert_eq!(std::fs::read(&tbl).unwrap(), b"ccbbbb");
+        assert!(!wal.is_empty());
+        wal.rollback().unwrap();
+        assert!(wal.is_empty());
+        assert_eq!(std::fs::read(&tbl).unwrap(), b"aaaa");
+        assert!(!std::fs::exists(t.with("auto")).unwrap());
+        // nothing is left to recover
+        drop(wal);
+        assert_eq!(std::fs::read(t.with("log")).unwrap(), b"");
+    }
+
+    #[test]
+    fn replaced_files_are_kept_aside_instead_of_logged() {
+        let t = TempPath::new("wal", "move");
+        let tbl = t.with("tbl");
+        let old = vec![b'a'; 1 << 20];
+        std::fs::write(&tbl, &old).unwrap();
+        let mut wal = Wal::open(&t.with("log"), SyncPolicy::Full).unwrap();
+        for commit in [false, true] {
+            let file = wal.create(&temp_path(&tbl)).unwrap();
+            wal.write(&temp_path(&tbl), &file, 0, &[b'b'; 1 << 20]).unwrap();
+            wal.rename(&temp_path(&tbl), &tbl).unwrap();
+            // neither the old nor the new file went into the log
+            assert!(std::fs::metadata(t.with("log")).unwrap().len() < 1000);
+            assert_eq!(std::fs::read(t.with("tbl.old0")).unwrap(), old);
+            match commit {
+                false => wal.rollback().unwrap(),
+                true => wal.commit().unwrap(),
+            }
+            let now = std::fs::read(&tbl).unwrap();
+            assert_eq!(now[0], if commit { b'b' } else { b'a' });
+            assert_eq!(now.len(), 1 << 20);
+            assert!(!std::fs::exists(temp_path(&tbl)).unwrap());
+            assert!(!std::fs::exists(t.with("tbl.old0")).unwrap());
+        }
+        wal.replace(&tbl, None).unwrap();
+        assert!(!std::fs::exists(&tbl).unwrap());
+        wal.rollback().unwrap();
+        assert_eq!(std::fs::read(&tbl).unwrap()[0], b'b');
+        wal.replace(&tbl, None).unwrap();
+        wal.commit().unwrap();
+        assert!(!std::fs::exists(&tbl).unwrap());
+        assert!(!std::fs::exists(t.with("tbl.old0")).unwrap());
+    }
+
+    #[test]
+    fn crashes_between_writing_moving_and_committing_are_recovered() {
+        let t = TempPath::new("wal", "crash");
+        let (tbl, temp, backup) = (t.with("tbl"), temp_path(&t.with("tbl")), t.with("tbl.old0"));
+        let created = record(1, &temp, Change::Create);
+        let moved = record(1, &tbl, Change::Move { from: temp.clone(), backup: Some(backup.clone()) });
+        let commit = record(1, "", Change::Commit);
+        // what is on disk at each step: the log, whether the link was made and the file moved
+        let steps: [(Vec<u8>, bool, bool, &[u8]); 6] = [
+            (created.clone(), false, false, b"old"),
+            ([created.clone(), moved.clone()].concat(), false, false, b"old"),
+            ([created.clone(), moved.clone()].concat(), true, false, b"old"),
+            ([created.clone(), moved.clone()].concat(), true, true, b"old"),
+            // the commit record was written, but maybe not the move
+            ([created.clone(), moved.clone(), commit.clone()].concat(), true, false, b"new"),
+            ([created.clone(), moved.clone(), commit.clone()].concat(), true, true, b"new"),
+        ];
+        for (i, (log, linked, moved, after)) in steps.into_iter().enumerate() {
+            std::fs::write(&tbl, b"old").unwrap();
+            std::fs::write(&temp, b"new").unwrap();
+            if linked {
+                std::fs::hard_link(&tbl, &backup).unwrap();
+            }
+            if moved {
+                std::fs::rename(&temp, &tbl).unwrap();
+            }
+            recover_from(&t, &log);
+            assert_eq!(std::fs::read(&tbl).unwrap(), after, "{i}");
+            assert!(!std::fs::exists(&temp).unwrap(), "{i}");
+            assert!(!std::fs::exists(&backup).unwrap(), "{i}");
+        }
+        // a crash after the move, with the log the way the operation left it
+        std::fs::write(&tbl, b"old").unwrap();
+        let mut wal = Wal::open(&t.with("log"), SyncPolicy::Full).unwrap();
+        wal.replace(&tbl, Some(b"new")).unwrap();
+        wal.replace(&t.with("auto"), Some(b"2")).unwrap();
+        drop(wal);
+        drop(Wal::open(&t.with("log"), SyncPolicy::Full).unwrap());
+        assert_eq!(std::fs::read(&tbl).unwrap(), b"old");
+        assert!(!std::fs::exists(t.with("auto")).unwrap());
+        assert!(!std::fs::exists(&backup).unwrap());
+    }
+}